@@ -10,24 +10,27 @@ import net.minecraft.client.render.chunk.ChunkBuilder;
 import net.minecraft.client.render.chunk.ChunkRendererRegionBuilder;
 import net.minecraft.client.world.ClientWorld;
 import net.minecraft.network.PacketByteBuf;
+import net.minecraft.registry.Registry;
+import net.minecraft.registry.RegistryKeys;
+import net.minecraft.registry.entry.RegistryEntry;
 import net.minecraft.util.collection.PackedIntegerArray;
 import net.minecraft.util.collection.PaletteStorage;
 import net.minecraft.util.math.BlockPos;
 import net.minecraft.util.math.ChunkSectionPos;
 import net.minecraft.util.math.Vec3i;
+import net.minecraft.world.biome.Biome;
 import net.minecraft.world.chunk.*;
 import net.minecraft.world.chunk.light.ChunkLightProvider;
 import org.spongepowered.asm.mixin.Final;
 import org.spongepowered.asm.mixin.Mixin;
 import org.spongepowered.asm.mixin.Overwrite;
 import org.spongepowered.asm.mixin.Shadow;
+import org.spongepowered.asm.mixin.Unique;
 import org.spongepowered.asm.mixin.injection.At;
 import org.spongepowered.asm.mixin.injection.Inject;
 import org.spongepowered.asm.mixin.injection.callback.CallbackInfoReturnable;
 import org.spongepowered.asm.mixin.injection.callback.LocalCapture;
 
-import java.util.Arrays;
-
 @Mixin(ChunkBuilder.BuiltChunk.class)
 public class BuiltChunkMixin {
     @Shadow @Final BlockPos.Mutable origin;
@@ -55,82 +58,108 @@ public class BuiltChunkMixin {
      */
     @Inject(method = "createRebuildTask", cancellable = true, at = @At("RETURN"), locals = LocalCapture.CAPTURE_FAILHARD)
     public void createRebuildTask(ChunkRendererRegionBuilder builder, CallbackInfoReturnable<ChunkBuilder.BuiltChunk.Task> cir) {
-        long[] paletteIndices = new long[27];
-        long[] storageIndices = new long[27];
-        Arrays.fill(paletteIndices,-1);
-        Arrays.fill(storageIndices,-1);
         ClientWorld world = MinecraftClient.getInstance().world;
+        Vec3i sectionCoord = new Vec3i(origin.getX()>>4,origin.getY()>>4,origin.getZ()>>4);
 
+        // This section changed so it's always sent over, the neighbours only when Rust doesn't have them yet
+        for(int x=-1;x<=1;x++){
+            for(int z=-1;z<=1;z++){
+                WorldChunk chunk = (WorldChunk)world.getChunk(sectionCoord.getX()+x, sectionCoord.getZ()+z,ChunkStatus.FULL,false);
+                if(chunk==null)continue;
+                for(int y=-1;y<=1;y++){
+                    int sectionX = sectionCoord.getX()+x;
+                    int sectionY = sectionCoord.getY()+y;
+                    int sectionZ = sectionCoord.getZ()+z;
+                    boolean center = x==0 && y==0 && z==0;
+                    if(!center && WgpuNative.hasSection(sectionX,sectionY,sectionZ))continue;
+                    uploadSection(world, chunk, sectionX, sectionY, sectionZ);
+                }
+            }
+        }
+        WgpuNative.bakeSection(sectionCoord.getX(),sectionCoord.getY(),sectionCoord.getZ());
+        cir.setReturnValue(null);
+    }
+
+    @Unique
+    private static void uploadSection(ClientWorld world, WorldChunk chunk, int sectionX, int sectionY, int sectionZ) {
         ChunkLightProvider<?, ?> skyLightProvider = world.getLightingProvider().skyLightProvider;
         ChunkLightProvider<?, ?> blockLightProvider = world.getLightingProvider().blockLightProvider;
+        Registry<Biome> biomeRegistry = world.getRegistryManager().get(RegistryKeys.BIOME);
+
+        Palette<?> palette;
+        PalettedContainer<?> section;
+        ReadableContainer<RegistryEntry<Biome>> biomes;
+        try {
+            ChunkSection chunkSection = chunk.getSection(world.sectionCoordToIndex(sectionY));
+            section = chunkSection.getBlockStateContainer();
+            biomes = chunkSection.getBiomeContainer();
+            palette = section.data.palette;
+        } catch (ArrayIndexOutOfBoundsException e) {
+            return;
+        }
+
+        int[] biomeIndices = new int[64];
+        for(int cell=0;cell<64;cell++){
+            biomeIndices[cell] = biomeRegistry.getRawId(biomes.get(cell&3,cell>>4,(cell>>2)&3).value());
+        }
 
+        byte[] skyLight = new byte[2048];
+        byte[] blockLight = new byte[2048];
+        long sectionPos = ChunkSectionPos.from(sectionX,sectionY,sectionZ).asLong();
+        if(skyLightProvider != null && blockLightProvider != null) {
+            ChunkNibbleArray skyNibble = skyLightProvider.lightStorage.uncachedStorage.get(sectionPos);
+            ChunkNibbleArray blockNibble = blockLightProvider.lightStorage.uncachedStorage.get(sectionPos);
+            if(skyNibble != null) {
+                skyLight=skyNibble.asByteArray();
+            }
+            if(blockNibble != null) {
+                blockLight=blockNibble.asByteArray();
+            }
+        }
 
-        byte[][] skyIndices = new byte[27][2048];
-        byte[][] blockIndices = new byte[27][2048];
-        Vec3i sectionCoord = new Vec3i(origin.getX()>>4,origin.getY()>>4,origin.getZ()>>4);
-        for(int x=0;x<3;x++){
-            for(int z=0;z<3;z++){
-                WorldChunk chunk = (WorldChunk)world.getChunk(sectionCoord.getX()+x-1, sectionCoord.getZ()+z-1,ChunkStatus.FULL,false);
-                if(chunk==null)continue;
-                for(int y=0;y<3;y++){
-                    int id = x+3*y+9*z;
-                    Palette<?> palette;
-                    PalettedContainer<?> section;
-                    try {
-                        section = chunk.getSection(world.sectionCoordToIndex(sectionCoord.getY()+y-1)).getBlockStateContainer();
-                        palette = section.data.palette;
-                    } catch (ArrayIndexOutOfBoundsException e) {
-                        continue;
-                    }
-
-                    long sectionPos = ChunkSectionPos.from(sectionCoord.getX()+x-1,sectionCoord.getY()+y-1,sectionCoord.getZ()+z-1).asLong();
-                    if(skyLightProvider != null && blockLightProvider != null) {
-                        ChunkNibbleArray skyNibble = skyLightProvider.lightStorage.uncachedStorage.get(sectionPos);
-                        ChunkNibbleArray blockNibble = blockLightProvider.lightStorage.uncachedStorage.get(sectionPos);
-                        if(skyNibble != null) {
-                            skyIndices[id]=skyNibble.asByteArray();
-                        }
-                        if(blockNibble != null) {
-                            blockIndices[id]=blockNibble.asByteArray();
-                        }
-                    }
-
-                    PaletteStorage paletteStorage = section.data.storage;
-
-
-                    if (paletteStorage instanceof PackedIntegerArray array) {
-                        //palette
-                        RustPalette rustPalette = new RustPalette(section.idList);
-
-                        ByteBuf buf = Unpooled.buffer(palette.getPacketSize());
-                        PacketByteBuf packetBuf = new PacketByteBuf(buf);
-                        if(palette.getSize() == 1){
-                            packetBuf.writeInt(1);
-                        }
-                        palette.writePacket(packetBuf);
-                        rustPalette.readPacket(packetBuf);
-
-                        paletteIndices[id] = rustPalette.getSlabIndex();
-
-                        //PackedIntegerArray
-                        long index = WgpuNative.createPaletteStorage(
-                                paletteStorage.getData(),
-                                array.elementsPerLong,
-                                paletteStorage.getElementBits(),
-                                array.maxValue,
-                                array.indexScale,
-                                array.indexOffset,
-                                array.indexShift,
-                                paletteStorage.getSize()
-                        );
-
-                        storageIndices[id] = index;
-                    }
-                }
+        long paletteIndex = -1;
+        long storageIndex = -1;
+        PaletteStorage paletteStorage = section.data.storage;
+
+        if (paletteStorage instanceof PackedIntegerArray array) {
+            //palette
+            RustPalette rustPalette = new RustPalette(section.idList);
+
+            ByteBuf buf = Unpooled.buffer(palette.getPacketSize());
+            PacketByteBuf packetBuf = new PacketByteBuf(buf);
+            if(palette.getSize() == 1){
+                packetBuf.writeInt(1);
             }
+            palette.writePacket(packetBuf);
+            rustPalette.readPacket(packetBuf);
+
+            paletteIndex = rustPalette.getSlabIndex();
+
+            //PackedIntegerArray
+            storageIndex = WgpuNative.createPaletteStorage(
+                    paletteStorage.getData(),
+                    array.elementsPerLong,
+                    paletteStorage.getElementBits(),
+                    array.maxValue,
+                    array.indexScale,
+                    array.indexOffset,
+                    array.indexShift,
+                    paletteStorage.getSize()
+            );
+        } else if (palette.getSize() == 1) {
+            //A section of a single block state has no storage, send its palette so Rust fills the section with it
+            RustPalette rustPalette = new RustPalette(section.idList);
+
+            ByteBuf buf = Unpooled.buffer(palette.getPacketSize() + 1);
+            PacketByteBuf packetBuf = new PacketByteBuf(buf);
+            packetBuf.writeVarInt(1);
+            palette.writePacket(packetBuf);
+            rustPalette.readPacket(packetBuf);
+
+            paletteIndex = rustPalette.getSlabIndex();
         }
-        WgpuNative.bakeSection(sectionCoord.getX(),sectionCoord.getY(),sectionCoord.getZ(),paletteIndices, storageIndices, blockIndices, skyIndices);
-        cir.setReturnValue(null);
+
+        WgpuNative.setSection(sectionX, sectionY, sectionZ, paletteIndex, storageIndex, blockLight, skyLight, biomeIndices);
     }
 
     /**
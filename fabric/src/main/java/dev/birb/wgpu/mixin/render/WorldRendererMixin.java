@@ -1,9 +1,11 @@
 package dev.birb.wgpu.mixin.render;
 
 import dev.birb.wgpu.entity.DummyVertexConsumer;
+import dev.birb.wgpu.palette.RustBlockStateAccessor;
 import dev.birb.wgpu.render.Wgpu;
 import dev.birb.wgpu.rust.WgpuNative;
 import it.unimi.dsi.fastutil.objects.ObjectArrayList;
+import net.minecraft.block.BlockState;
 import net.minecraft.client.MinecraftClient;
 import net.minecraft.client.network.ClientPlayerEntity;
 import net.minecraft.client.render.*;
@@ -15,10 +17,15 @@ import net.minecraft.client.world.ClientWorld;
 import net.minecraft.entity.Entity;
 import net.minecraft.entity.LivingEntity;
 import net.minecraft.entity.player.PlayerEntity;
+import net.minecraft.registry.Registry;
+import net.minecraft.registry.RegistryKeys;
 import net.minecraft.resource.ResourceManager;
+import net.minecraft.util.math.BlockPos;
 import net.minecraft.util.math.MathHelper;
 import net.minecraft.util.math.RotationAxis;
 import net.minecraft.util.math.Vec3d;
+import net.minecraft.world.BlockView;
+import net.minecraft.world.biome.Biome;
 import net.minecraft.world.tick.TickManager;
 
 import org.jetbrains.annotations.Nullable;
@@ -221,7 +228,7 @@ public abstract class WorldRendererMixin {
 
 
         if(player != null) {
-            WgpuNative.setSectionPos((int)Math.floor(translate.x/16.0),(int)Math.floor(translate.z/16.0));
+            WgpuNative.setCameraPos(translate.x, translate.y, translate.z);
             matrices.translate(-(translate.x%16+16)%16, -translate.y, -(translate.z%16+16)%16);
 
             floatBuffer = new float[16];
@@ -257,6 +264,31 @@ public abstract class WorldRendererMixin {
 
     @Inject(method = "reload", cancellable = true, at = @At("HEAD"))
     public void reload(CallbackInfo ci) {
-        WgpuNative.reload(this.client.options.getClampedViewDistance(),this.world.getBottomSectionCoord());
+        WgpuNative.reload(this.client.options.getClampedViewDistance(), this.world.getBottomSectionCoord(), this.world.countVerticalSections());
+
+        //Biomes are a dynamic registry, so the ids can change between worlds
+        Registry<Biome> biomeRegistry = this.world.getRegistryManager().get(RegistryKeys.BIOME);
+        for (Biome biome : biomeRegistry) {
+            WgpuNative.registerBiome(
+                biomeRegistry.getRawId(biome),
+                biome.getTemperature(),
+                biome.weather.downfall(),
+                biome.getEffects().getGrassColor().orElse(-1),
+                biome.getEffects().getFoliageColor().orElse(-1),
+                biome.getEffects().getWaterColor(),
+                biome.getEffects().getGrassColorModifier().ordinal()
+            );
+        }
+    }
+
+    /**
+     * Rust keeps its own copy of the world, so only the changed block is sent over and Rust works out which
+     * sections need rebaking. If Rust doesn't have the section yet, vanilla schedules the rebuild that sends it over.
+     */
+    @Inject(method = "updateBlock", cancellable = true, at = @At("HEAD"))
+    public void updateBlock(BlockView world, BlockPos pos, BlockState oldState, BlockState newState, int flags, CallbackInfo ci) {
+        if (WgpuNative.setBlock(pos.getX(), pos.getY(), pos.getZ(), ((RustBlockStateAccessor) newState).wgpu_mc$getRustBlockStateIndex())) {
+            ci.cancel();
+        }
     }
 }
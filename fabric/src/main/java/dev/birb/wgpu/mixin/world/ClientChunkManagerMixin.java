@@ -0,0 +1,41 @@
+package dev.birb.wgpu.mixin.world;
+
+import dev.birb.wgpu.rust.WgpuNative;
+import net.minecraft.client.world.ClientChunkManager;
+import net.minecraft.client.world.ClientWorld;
+import net.minecraft.util.math.ChunkPos;
+import net.minecraft.util.math.ChunkSectionPos;
+import net.minecraft.world.LightType;
+import net.minecraft.world.chunk.ChunkNibbleArray;
+import org.spongepowered.asm.mixin.Final;
+import org.spongepowered.asm.mixin.Mixin;
+import org.spongepowered.asm.mixin.Shadow;
+import org.spongepowered.asm.mixin.injection.At;
+import org.spongepowered.asm.mixin.injection.Inject;
+import org.spongepowered.asm.mixin.injection.callback.CallbackInfo;
+
+@Mixin(ClientChunkManager.class)
+public class ClientChunkManagerMixin {
+
+    @Shadow @Final ClientWorld world;
+
+    @Inject(method = "unload", at = @At("HEAD"))
+    public void unload(ChunkPos pos, CallbackInfo ci) {
+        WgpuNative.removeColumn(pos.x, pos.z);
+    }
+
+    // Only the light changed, so Rust relights the meshes instead of the sections being rebuilt
+    @Inject(method = "onLightUpdate", at = @At("HEAD"), cancellable = true)
+    public void onLightUpdate(LightType type, ChunkSectionPos pos, CallbackInfo ci) {
+        ChunkNibbleArray skyNibble = world.getLightingProvider().get(LightType.SKY).getLightSection(pos);
+        ChunkNibbleArray blockNibble = world.getLightingProvider().get(LightType.BLOCK).getLightSection(pos);
+        byte[] skyLight = skyNibble != null ? skyNibble.asByteArray() : new byte[2048];
+        byte[] blockLight = blockNibble != null ? blockNibble.asByteArray() : new byte[2048];
+
+        // Sections Rust doesn't have yet get their light with the rest of them when they're first rebuilt
+        if(WgpuNative.setSectionLight(pos.getSectionX(), pos.getSectionY(), pos.getSectionZ(), blockLight, skyLight)) {
+            ci.cancel();
+        }
+    }
+
+}
@@ -138,7 +138,17 @@ public class WgpuNative {
 
     public static native void setCamera(double x, double y, double z, float renderYaw, float renderPitch);
 
-    public static native void bakeSection(int x, int y, int z, long[] paletteIndices, long[] storageIndices, byte[][] blockIndices, byte[][] skyIndices);
+    public static native void setSection(int x, int y, int z, long paletteIndex, long storageIndex, byte[] blockLight, byte[] skyLight, int[] biomeIds);
+
+    public static native boolean hasSection(int x, int y, int z);
+
+    public static native boolean setSectionLight(int x, int y, int z, byte[] blockLight, byte[] skyLight);
+
+    public static native void removeColumn(int x, int z);
+
+    public static native boolean setBlock(int x, int y, int z, int blockState);
+
+    public static native void bakeSection(int x, int y, int z);
 
     public static native void setMatrix(int type, float[] mat);
 
@@ -172,9 +182,11 @@ public class WgpuNative {
 
     public static native void bindRenderEffectsData(float fogStart, float fogEnd, int fogShape, float[] fogColor, float[] colorModulator, float[] dimensionFogColor);
 
-    public static native void reload(int clampedViewDistance,int minSectionHeight);
+    public static native void reload(int clampedViewDistance, int bottomSectionCoord, int verticalSections);
+
+    public static native void registerBiome(int id, float temperature, float downfall, int grassColor, int foliageColor, int waterColor, int grassColorModifier);
 
-    public static native void setSectionPos(int x,int z);
+    public static native void setCameraPos(double x, double y, double z);
 
     public static native void render(float tickDelta, long startTime, boolean tick);
 
@@ -72,7 +72,13 @@ var t_texture: texture_2d<f32>;
 @group(2) @binding(1)
 var t_sampler: sampler;
 
+//Vanilla's directional shading, keep in sync with diffuse_shade in chunk.rs
+fn diffuse_shade(normal_in: vec3<f32>) -> f32 {
+    var normal: vec3<f32> = normalize(normal_in);
+    return min(normal.x * normal.x * 0.6 + normal.y * normal.y * (3.0 + normal.y) / 4.0 + normal.z * normal.z * 0.8, 1.0);
+}
+
 @fragment
 fn frag(in: VertexResult) -> @location(0) vec4<f32> {
-   return in.overlay * vec4<f32>(textureSample(t_texture, t_sampler, in.tex_coords).rgb, 1.0);
+   return in.overlay * vec4<f32>(textureSample(t_texture, t_sampler, in.tex_coords).rgb * diffuse_shade(in.normal), 1.0);
 }
\ No newline at end of file
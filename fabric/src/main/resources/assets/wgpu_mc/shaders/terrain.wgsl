@@ -22,8 +22,19 @@ struct ChunkOffset {
 @group(0) @binding(3) var t_texture: texture_2d<f32>;
 @group(0) @binding(4) var t_sampler: sampler;
 
+// Unpacked with unpack_terrain_vertex, which the renderer puts in front of this
 @group(1) @binding(0) var<storage> chunk_data: array<u32>;
 
+struct SectionDraw {
+    pos: vec3<i32>,
+    vertex_offset: u32,
+    fade: vec2<f32>,
+    light_offset: u32,
+    padding: u32
+}
+
+@group(2) @binding(0) var<storage> section_draws: array<SectionDraw>;
+
 struct VertexResult {
     @builtin(position) pos: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
@@ -31,59 +42,61 @@ struct VertexResult {
     @location(2) blend: f32,
     @location(3) normal: vec3<f32>,
     @location(4) world_pos: vec3<f32>,
-    @location(5) @interpolate(flat) light_coords: vec2<f32>,
-    @location(6) section: u32
+    @location(5) light_coords: vec2<f32>,
+    @location(6) section: u32,
+    @location(7) ao: f32,
+    @location(8) color: vec3<f32>,
+    @location(9) @interpolate(flat) fade: vec2<f32>,
+    @location(10) distance: f32,
+    @location(11) @interpolate(flat) tile: vec4<f32>
 };
 
-var<push_constant> section_pos: vec3i;
-
 @vertex
 fn vert(
     @builtin(vertex_index) vi: u32,
-    @builtin(instance_index) base_vertex: u32
+    @builtin(instance_index) draw: u32
 ) -> VertexResult {
     var vr: VertexResult;
-    let id = vi*4u+base_vertex;
-    let v1 = chunk_data[id];
-    let v2 = chunk_data[id + 1u];
-    let v3 = chunk_data[id + 2u];
-    let v4 = chunk_data[id + 3u];
-
-    var x: f32 = f32(v1 & 0xffu) * 0.0625;
-    var y: f32 = f32((v1 >> 8u) & 0xffu) * 0.0625;
-    var z: f32 = f32((v1 >> 16u) & 0xffu) * 0.0625;
-
-    var u: f32 = f32((v2 >> 16u) & 0xffffu) * 0.00048828125;
-    var v: f32 = f32(v3 & 0xffffu) * 0.00048828125;
-
-    if(((v3 >> 29u) & 1u) == 1u) {
-        x = 16.0;
-    }
-
-    if(((v3 >> 30u) & 1u) == 1u) {
-        y = 16.0;
-    }
-
-    if((v3 >> 31u) == 1u) {
-        z = 16.0;
-    }
-    var pos = vec3<f32>(x, y, z);
+    let section_pos = section_draws[draw].pos;
+    let id = vi*4u+section_draws[draw].vertex_offset;
+    // Two vertices' light per u32
+    let light = chunk_data[section_draws[draw].light_offset + vi / 2u] >> ((vi & 1u) * 16u);
+    // The vertex across the quad, its texture coordinates have the opposite corner of the tile
+    let opposite = (vi & ~3u) + ((vi + 2u) & 3u);
+    let opposite_tex_coords = chunk_data[opposite*4u+section_draws[draw].vertex_offset + 2u];
+    let vertex = unpack_terrain_vertex(vec4(chunk_data[id], chunk_data[id + 1u], chunk_data[id + 2u], chunk_data[id + 3u]), light, opposite_tex_coords);
+    let pos = vertex.position;
 
     var world_pos = pos + vec3<f32>(f32(section_pos.x) * 16.0, f32(section_pos.y) * 16.0, f32(section_pos.z) * 16.0);
 
-    vr.pos = mat4_persp * mat4_view * mat4_model * vec4(world_pos, 1.0);
-    vr.tex_coords = vec2<f32>(u, v);
+    let view_pos = mat4_view * mat4_model * vec4(world_pos, 1.0);
+    vr.pos = mat4_persp * view_pos;
+    vr.distance = length(view_pos.xyz);
+    vr.fade = section_draws[draw].fade;
+    vr.tex_coords = vertex.tile_coords;
+    vr.tile = vertex.tile;
     vr.tex_coords2 = vec2(0.0, 0.0);
     vr.world_pos = world_pos;
-
-    var light_coords = vec2<u32>(v4 & 15u, (v4 >> 4u) & 15u);
-    vr.light_coords = vec2(f32(light_coords.x) / 15.0, f32(light_coords.y) / 15.0);
+    vr.light_coords = vertex.light;
+    vr.ao = vertex.ao;
+    vr.color = vertex.color;
 
     vr.blend = 0.0;
 
     return vr;
 }
 
+// Sections dither out between fade.x and fade.y blocks away, or in if fade.x is further
+fn faded_out(in: VertexResult) -> bool {
+    let faded = clamp((in.distance - in.fade.x) / (in.fade.y - in.fade.x), 0.0, 1.0);
+    // Interleaved gradient noise
+    var noise = fract(52.9829189 * fract(dot(floor(in.pos.xy), vec2(0.06711056, 0.00583715))));
+    if(in.fade.x > in.fade.y) {
+        noise = 1.0 - noise;
+    }
+    return noise < faded;
+}
+
 fn minecraft_sample_lighting(uv: vec2<u32> ) -> f32 {
     return f32(max(uv.x, uv.y)) / 15.0;
 }
@@ -92,7 +105,11 @@ fn minecraft_sample_lighting(uv: vec2<u32> ) -> f32 {
 fn frag(
     in: VertexResult
 ) -> @location(0) vec4<f32> {
-    let col = textureSample(t_texture, t_sampler, in.tex_coords);
+    if(faded_out(in)) {
+        discard;
+    }
+
+    let col = sample_tile(t_texture, t_sampler, in.tex_coords, in.tile);
 
 //    let light = textureSample(lightmap_texture, lightmap_sampler, vec2(max(in.light_coords.x, in.light_coords.y), 0.0));
     let light = max(in.light_coords.x, in.light_coords.y);
@@ -100,5 +117,5 @@ fn frag(
     if(col.a == 0.0f){
         discard;
     }
-    return vec4(col.rgb*light,col.a);
+    return vec4(col.rgb*in.color*light*in.ao,col.a);
 }
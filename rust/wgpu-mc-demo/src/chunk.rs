@@ -66,7 +66,8 @@ pub fn make_chunks(wm: &WmRenderer, pos: IVec3, scene: &Scene){
         },
     );
     let time = Instant::now();
-    bake_section(pos, wm, &provider);
+    let version = wm.bake_versions.begin(pos);
+    bake_section(pos, version, wm, &provider);
 
     println!(
         "Built 1 chunk in {} microseconds",
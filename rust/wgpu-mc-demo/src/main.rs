@@ -22,7 +22,7 @@ use wgpu_mc::render::graph::{RenderGraph, ResourceBacking};
 use wgpu_mc::render::shaderpack::ShaderPackConfig;
 use wgpu_mc::wgpu::util::{BufferInitDescriptor, DeviceExt};
 use wgpu_mc::wgpu::{BufferBindingType, Extent3d, PresentMode};
-use wgpu_mc::{wgpu, HasWindowSize, Display, WindowSize, WmRenderer, Frustum};
+use wgpu_mc::{wgpu, HasWindowSize, Display, WindowSize, WmRenderer};
 
 use crate::camera::Camera;
 use crate::chunk::make_chunks;
@@ -105,6 +105,9 @@ impl ApplicationHandler for Application {
             max_push_constant_size: 128,
             max_bind_groups: 8,
             max_storage_buffers_per_shader_stage: 10000,
+            //The chunk buffer grows up to these
+            max_buffer_size: adapter.limits().max_buffer_size,
+            max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
             ..Default::default()
         };
 
@@ -114,7 +117,11 @@ impl ApplicationHandler for Application {
                 required_features: wgpu::Features::default()
                     | wgpu::Features::DEPTH_CLIP_CONTROL
                     | wgpu::Features::PUSH_CONSTANTS
-                    | wgpu::Features::MULTI_DRAW_INDIRECT,
+                    //Terrain falls back to culling on the CPU, and a draw per section, without these
+                    | (adapter.features()
+                        & (wgpu::Features::MULTI_DRAW_INDIRECT
+                            | wgpu::Features::INDIRECT_FIRST_INSTANCE
+                            | wgpu::Features::MULTI_DRAW_INDIRECT_COUNT)),
                 required_limits,
                 memory_hints: wgpu::MemoryHints::Performance,
             },
@@ -362,7 +369,9 @@ impl ApplicationHandler for Application {
                             bytemuck::cast_slice(&Mat4::IDENTITY.to_cols_array())
                         );
                     }
-                    *self.scene.as_mut().unwrap().camera_section_pos.write() = ivec2(camera.position.x.floor() as i32>>4, camera.position.z.floor() as i32>>4);
+                    let camera_section = ivec2(camera.position.x.floor() as i32>>4, camera.position.z.floor() as i32>>4);
+                    *self.scene.as_mut().unwrap().camera_section_pos.write() = camera_section;
+                    *self.scene.as_mut().unwrap().camera_pos.write() = camera.position - vec3(camera_section.x as f32 * 16.0, 0.0, camera_section.y as f32 * 16.0);
 
                     if let ResourceBacking::Buffer(buffer,_) = &self.render_graph.as_ref().unwrap().resources["@mat4_perspective"]{
                         wm.display.queue.write_buffer(
@@ -409,6 +418,7 @@ impl ApplicationHandler for Application {
                             });
 
                     wm.submit_chunk_updates(self.scene.as_ref().unwrap());
+                    wm.sort_transparent_sections(self.scene.as_ref().unwrap());
 
                     let mut command_encoder = wm.display.device.create_command_encoder(
                         &wgpu::CommandEncoderDescriptor { label: None },
@@ -416,7 +426,6 @@ impl ApplicationHandler for Application {
 
                     let mut geometry = HashMap::new();
 
-                    let mvp = (camera.build_perspective_matrix() * camera.build_view_matrix()).to_cols_array_2d();
 
                     self.render_graph.as_ref().unwrap().render(
                         &wm,
@@ -425,7 +434,7 @@ impl ApplicationHandler for Application {
                         &view,
                         [0; 3],
                         &mut geometry,
-                        &Frustum::from_modelview_projection(mvp)
+                        camera.build_perspective_matrix() * camera.build_view_matrix(),
                     );
 
                     wm.display.queue.submit([command_encoder.finish()]);
@@ -7,8 +7,9 @@ use parking_lot::lock_api::{Mutex, RwLock};
 use wgpu_mc::{render::graph::Geometry, wgpu::{self, util::{BufferInitDescriptor, DeviceExt}, BufferAddress, BufferBindingType, PresentMode, TextureFormat}, Display, Frustum, WmRenderer};
 use winit::{application::ApplicationHandler, dpi::PhysicalSize, event::{DeviceEvent, ElementState, KeyEvent, WindowEvent}, event_loop::ActiveEventLoop, keyboard::{KeyCode, ModifiersState, PhysicalKey}, platform::scancode::PhysicalKeyExtScancode};
 
-use crate::{gl::{ElectrumGeometry, ElectrumVertex}, renderer::MATRICES, MinecraftResourceManagerAdapter, RenderMessage, CHANNELS, CUSTOM_GEOMETRY, RENDERER, RENDER_GRAPH, SCENE};
+use crate::{gl::{ElectrumGeometry, ElectrumVertex}, renderer::MATRICES, MinecraftResourceManagerAdapter, RenderMessage, CHANNELS, CUSTOM_GEOMETRY, RENDERER, RENDER_GRAPH, SCENE, SETTINGS};
 use wgpu_mc::render::{shaderpack::ShaderPackConfig,graph::{RenderGraph,ResourceBacking}};
+use crate::settings::Settings;
 use std::collections::HashMap;
 
 
@@ -96,6 +97,9 @@ impl ApplicationHandler for Application {
             max_push_constant_size: 128,
             max_bind_groups: 8,
             max_storage_buffers_per_shader_stage: 1000,
+            //The chunk buffer grows up to these
+            max_buffer_size: adapter.limits().max_buffer_size,
+            max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
             ..Default::default()
         };
 
@@ -109,7 +113,11 @@ impl ApplicationHandler for Application {
                     | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY
                     | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
                     | wgpu::Features::PARTIALLY_BOUND_BINDING_ARRAY
-                    | wgpu::Features::MULTI_DRAW_INDIRECT,
+                    //Terrain falls back to culling on the CPU, and a draw per section, without these
+                    | (adapter.features()
+                        & (wgpu::Features::MULTI_DRAW_INDIRECT
+                            | wgpu::Features::INDIRECT_FIRST_INSTANCE
+                            | wgpu::Features::MULTI_DRAW_INDIRECT_COUNT)),
                 required_limits,
                 memory_hints:wgpu::MemoryHints::Performance,
             },
@@ -213,7 +221,13 @@ impl ApplicationHandler for Application {
         );
         CUSTOM_GEOMETRY.set(Mutex::new(geometry));
 
+        if let Some(settings) = SETTINGS.read().as_ref() {
+            settings.apply(&wm);
+        }
+
         let _ = RENDERER.set(wm);
+        let bake_threads = SETTINGS.read().as_ref().map_or_else(|| Settings::default().bake_threads(), Settings::bake_threads);
+        RENDERER.get().unwrap().start_bake_workers(bake_threads);
         env.set_static_field(
             "dev/birb/wgpu/render/Wgpu",
             ("dev/birb/wgpu/render/Wgpu", "initialized", "Z"),
@@ -13,19 +13,17 @@ use application::Application;
 use arc_swap::{ArcSwap, AsRaw};
 use byteorder::{LittleEndian, ReadBytesExt};
 use crossbeam_channel::{Receiver, Sender, unbounded};
-use glam::{ivec2, ivec3, IVec3, Mat4};
+use glam::{ivec2, ivec3, vec3, IVec3, Mat4};
 use jni::{JavaVM, JNIEnv};
 use jni::objects::{
-    AutoElements, GlobalRef, JByteArray, JClass, JFloatArray, JIntArray, JLongArray, JObject, JObjectArray, JPrimitiveArray, JString, JValue, JValueGen, JValueOwned, ReleaseMode, WeakRef
+    AutoElements, GlobalRef, JByteArray, JClass, JFloatArray, JIntArray, JObject, JString, JValue, JValueGen, JValueOwned, ReleaseMode, WeakRef
 };
 use jni::sys::{
-    jboolean, jbyte, jbyteArray, jfloat, jint, jlong, jsize, jstring, JNI_FALSE, JNI_TRUE
+    jboolean, jbyte, jbyteArray, jdouble, jfloat, jint, jlong, jstring, JNI_FALSE, JNI_TRUE
 };
 use jni_fn::jni_fn;
 use once_cell::sync::{Lazy, OnceCell};
-use palette::PALETTE_STORAGE;
 use parking_lot::{Mutex, RwLock};
-use pia::PIA_STORAGE;
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use renderer::MATRICES;
 use wgpu::Extent3d;
@@ -35,9 +33,10 @@ use winit::event::{ElementState, MouseButton};
 use winit::window::CursorGrabMode;
 
 use wgpu_mc::{Frustum, WmRenderer};
-use wgpu_mc::mc::block::{BlockstateKey, ChunkBlockState};
+use wgpu_mc::mc::biome::{Biome, GrassColorModifier, VANILLA_BLOCK_TINTS};
+use wgpu_mc::mc::block::{vanilla_cull_rules, BlockstateKey, ChunkBlockState, VANILLA_MODEL_OFFSETS};
 use wgpu_mc::mc::chunk::{
-    bake_section, BlockStateProvider, LightLevel, Section, CHUNK_HEIGHT
+    bake_section, BlockStateProvider, FluidState, LightLevel, Section, WorldHeight
 };
 use wgpu_mc::mc::resource::{ResourcePath, ResourceProvider};
 use wgpu_mc::mc::Scene;
@@ -62,6 +61,7 @@ mod pia;
 mod renderer;
 mod settings;
 mod application;
+mod world;
 
 #[allow(dead_code)]
 enum RenderMessage {
@@ -139,6 +139,8 @@ static SCENE: Lazy<Scene> = Lazy::new(|| {
 });
 
 static BLOCKS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// Indexed by the biome's id in the world's biome registry
+static BIOMES: RwLock<Vec<Biome>> = RwLock::new(Vec::new());
 static BLOCK_STATES: Mutex<Vec<(String, String, GlobalRef)>> = Mutex::new(Vec::new());
 pub static SETTINGS: RwLock<Option<Settings>> = RwLock::new(None);
 
@@ -154,16 +156,38 @@ pub fn call_static_from_class_loader<'env>(env:&mut JNIEnv<'env>,class:&str,meth
 }
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SectionHolder {
     pub block_data: Option<(JavaPalette, PackedIntegerArray)>,
     pub light_data: Option<DeserializedLightData>,
+    /// Biome registry ids of the 4x4x4 block cells in the section, in Minecraft's y, z, x order
+    pub biomes: Option<Box<[i32; 64]>>,
+}
+
+impl SectionHolder {
+    /// Change one block of the section, growing the palette and the storage if the state is new to it. A section
+    /// without block data is all air, the same as [MinecraftBlockstateProvider] reads it.
+    pub fn set_state(&mut self, pos: IVec3, state: BlockstateKey, air: BlockstateKey) {
+        let (palette, storage) = self.block_data.get_or_insert_with(|| {
+            let mut palette = JavaPalette::new();
+            palette.add(air);
+            (palette, PackedIntegerArray::new(4, 4096))
+        });
+
+        let index = palette.index(state) as i32;
+        if index as i64 > storage.max_value() {
+            *storage = storage.resized(32 - index.leading_zeros() as i32);
+        }
+
+        storage.set(pos.x, pos.y, pos.z, index);
+    }
 }
 
 #[derive(Debug)]
 pub struct MinecraftBlockstateProvider {
-    pub sections: [Option<SectionHolder>; 27],
+    pub sections: [Option<Arc<SectionHolder>>; 27],
     pub air: BlockstateKey,
+    pub biomes: Vec<Biome>,
 }
 impl BlockStateProvider for MinecraftBlockstateProvider {
     fn get_state(&self, pos:IVec3) -> ChunkBlockState {
@@ -229,6 +253,19 @@ impl BlockStateProvider for MinecraftBlockstateProvider {
 
         self.sections[(rel_pos+1).dot(ivec3(1, 3, 9)) as usize].is_none()
     }
+
+    fn get_biome(&self, pos: IVec3) -> Biome {
+        let section_pos:IVec3 = (pos>>4)+1;
+        let biomes = match self.sections[(section_pos.x + section_pos.y * 3 + section_pos.z * 9) as usize].as_deref() {
+            Some(SectionHolder { biomes: Some(biomes), .. }) => biomes,
+            _ => return Biome::default(),
+        };
+
+        let cell: IVec3 = (pos & 15) >> 2;
+        let id = biomes[((cell.y << 4) | (cell.z << 2) | cell.x) as usize];
+
+        self.biomes.get(id as usize).copied().unwrap_or_default()
+    }
 }
 
 
@@ -282,7 +319,10 @@ pub fn getSettings(env: JNIEnv, _class: JClass) -> jstring {
 #[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
 pub fn sendSettings(mut env: JNIEnv, _class: JClass, settings: JString) -> bool {
     let json: String = env.get_string(&settings).unwrap().into();
-    if let Ok(settings) = serde_json::from_str(json.as_str()) {
+    if let Ok(settings) = serde_json::from_str::<Settings>(json.as_str()) {
+        if let Some(wm) = RENDERER.get() {
+            settings.apply(wm);
+        }
         let mut guard = SETTINGS.write();
         *guard = Some(settings);
         true
@@ -329,95 +369,68 @@ pub fn registerBlockState(
 
 
 #[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
-pub fn reload(_env: JNIEnv, _class: JClass,clampedViewDistance:jint) {
+pub fn reload(_env: JNIEnv, _class: JClass,clampedViewDistance:jint,bottomSectionCoord:jint,verticalSections:jint) {
     let mut section_storage = SCENE.section_storage.write();
     section_storage.clear();
+    SCENE.evicted_sections.lock().clear();
+    if let Some(wm) = RENDERER.get() {
+        wm.bake_versions.clear();
+        *wm.mc.world_height.write() = WorldHeight {
+            min_section: bottomSectionCoord,
+            sections: verticalSections,
+        };
+    }
+    world::WORLD.clear();
     section_storage.set_width(clampedViewDistance);
+    SCENE.lod_storage.write().clear();
+    SCENE.lod_cells.write().clear();
+    *SCENE.lod_camera_section.lock() = None;
 }
 
+/// Colors are 0xRRGGBB, or -1 if the biome doesn't override the colormap. The grass color modifier is the ordinal
+/// of vanilla's `GrassColorModifier`.
 #[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
-pub fn setSectionPos(_env: JNIEnv, _class: JClass, x:jint,z:jint){
-    *SCENE.camera_section_pos.write() = ivec2(x, z);
-}
-#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
-pub fn bakeSection(
-    mut env: JNIEnv,
+pub fn registerBiome(
+    _env: JNIEnv,
     _class: JClass,
-    x: jint,
-    y: jint,
-    z: jint,
-    paletteIndices: JLongArray,
-    storageIndices: JLongArray,
-    blockBytes: JObjectArray,
-    skyBytes: JObjectArray,
+    id: jint,
+    temperature: jfloat,
+    downfall: jfloat,
+    grassColor: jint,
+    foliageColor: jint,
+    waterColor: jint,
+    grassColorModifier: jint,
 ) {
-    let palette_elements =
-        unsafe { env.get_array_elements(&paletteIndices, ReleaseMode::NoCopyBack) }.unwrap();
-    let palettes =
-        unsafe { slice::from_raw_parts(palette_elements.as_ptr(), palette_elements.len()) };
-    let storage_elements =
-        unsafe { env.get_array_elements(&storageIndices, ReleaseMode::NoCopyBack) }.unwrap();
-    let storages =
-        unsafe { slice::from_raw_parts(storage_elements.as_ptr(), storage_elements.len()) };
-    const NONE: Option<SectionHolder> = None;
-    let mut bsp = MinecraftBlockstateProvider {
-        sections: [NONE; 27],
-        air: *AIR,
-    };
-
-    for i in 0..27 {
-        let mut palette_storage = PALETTE_STORAGE.write();
-        let mut pia_storage = PIA_STORAGE.write();
-
-        let block_data = 
-        if palette_storage.contains(palettes[i] as usize) && pia_storage.contains(storages[i] as usize) {
-            Some((
-                palette_storage.remove(palettes[i] as usize),
-                pia_storage.remove(storages[i] as usize),
-            ))
-        } else {
-            None
-        };
-        let sky_array = unsafe {
-            JPrimitiveArray::from_raw(
-                env.get_object_array_element(&skyBytes, i as jsize)
-                    .unwrap()
-                    .into_raw(),
-            )
-        };
-        let sky_bytes =
-            unsafe { env.get_array_elements(&sky_array, ReleaseMode::NoCopyBack) }.unwrap();
-        let block_array = unsafe {
-            JPrimitiveArray::from_raw(
-                env.get_object_array_element(&blockBytes, i as jsize)
-                    .unwrap()
-                    .into_raw(),
-            )
-        };
-        let block_bytes =
-            unsafe { env.get_array_elements(&block_array, ReleaseMode::NoCopyBack) }.unwrap();
-        
-        bsp.sections[i] = Some(SectionHolder {
-            block_data,
-            light_data: Some(DeserializedLightData {
-                sky_light: Box::new(
-                    unsafe { slice::from_raw_parts(sky_bytes.as_ptr(), sky_bytes.len()) }
-                        .try_into()
-                        .unwrap(),
-                ),
-                block_light: Box::new(
-                    unsafe { slice::from_raw_parts(block_bytes.as_ptr(), block_bytes.len()) }
-                        .try_into()
-                        .unwrap(),
-                ),
-            }),
-        });
+    let mut biomes = BIOMES.write();
+    if biomes.len() <= id as usize {
+        biomes.resize(id as usize + 1, Biome::default());
     }
 
-    THREAD_POOL.spawn(move || {
-        let wm = RENDERER.get().unwrap();
-        bake_section(ivec3(x, y, z), wm ,&bsp);
-    })
+    biomes[id as usize] = Biome {
+        temperature,
+        downfall,
+        grass_color: (grassColor != -1).then_some(grassColor as u32),
+        foliage_color: (foliageColor != -1).then_some(foliageColor as u32),
+        water_color: waterColor as u32,
+        grass_color_modifier: match grassColorModifier {
+            1 => GrassColorModifier::DarkForest,
+            2 => GrassColorModifier::Swamp,
+            _ => GrassColorModifier::None,
+        },
+    };
+}
+
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn setCameraPos(_env: JNIEnv, _class: JClass, x:jdouble,y:jdouble,z:jdouble){
+    let section_x = (x / 16.0).floor() as i32;
+    let section_z = (z / 16.0).floor() as i32;
+
+    *SCENE.camera_section_pos.write() = ivec2(section_x, section_z);
+    *SCENE.camera_pos.write() = vec3(
+        (x - section_x as f64 * 16.0) as f32,
+        y as f32,
+        (z - section_z as f64 * 16.0) as f32,
+    );
 }
 
 #[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
@@ -468,10 +481,28 @@ pub fn render(mut env: JNIEnv, _class: JClass, tick_delta:jfloat, start_time:jlo
     let mut geometry = CUSTOM_GEOMETRY.get().unwrap().lock();
     wm.display.window.request_redraw();
     wm.submit_chunk_updates(&SCENE);
+    wm.relight(&SCENE, &*world::WORLD, world::WORLD.take_relit());
+    wm.rebake_evicted(&SCENE, &*world::WORLD);
+    wm.sort_transparent_sections(&SCENE);
     let pos = SCENE.camera_section_pos.read().clone();
-    SCENE.section_storage.write().trim(pos);
+    let width = {
+        let mut section_storage = SCENE.section_storage.write();
+        section_storage.trim(pos, &wm.bake_versions);
+        section_storage.width()
+    };
+    let lod_distance = wm.mc.bake_settings.read().lod_distance as i32;
+    wm.update_lods(&SCENE, width + lod_distance);
 
     let matrices = MATRICES.lock();
+    let view_projection = Mat4::from_cols_array_2d(&matrices.projection)
+        * Mat4::from_cols_array_2d(&matrices.view)
+        * Mat4::from_cols_array_2d(&matrices.terrain_transformation);
+    wm.bake_scheduler.set_camera(
+        pos,
+        *SCENE.camera_pos.read(),
+        Some(Frustum::from_modelview_projection(view_projection.to_cols_array_2d())),
+    );
+
     if let ResourceBacking::Buffer(buffer,_) = &render_graph.resources["@mat4_perspective"]{
         wm.display.queue.write_buffer(
             &buffer,
@@ -529,7 +560,7 @@ pub fn render(mut env: JNIEnv, _class: JClass, tick_delta:jfloat, start_time:jlo
             &view,
             [0; 3],
             &mut geometry,
-            &Frustum::from_modelview_projection([[0.0; 4]; 4])
+            view_projection,
         );
 
         wm.display.queue.submit([encoder.finish()]);
@@ -563,12 +594,24 @@ pub fn cacheBlockStates(mut env: JNIEnv, _class: JClass) {
                 .iter()
                 .map(|(string, resource)| (string, resource)),
         );
+
+        let mut block_manager = wm.mc.block_manager.write();
+        VANILLA_BLOCK_TINTS.iter().for_each(|(block_name, tint)| {
+            block_manager.set_tint(block_name, *tint);
+        });
+        VANILLA_MODEL_OFFSETS.iter().for_each(|(block_name, offset)| {
+            block_manager.set_offset(block_name, *offset);
+        });
+        vanilla_cull_rules().into_iter().for_each(|(block_name, rule)| {
+            block_manager.set_cull_rule(&block_name, rule);
+        });
     }
 
     let mut states = BLOCK_STATES.lock();
 
-    let block_manager = wm.mc.block_manager.write();
+    let mut block_manager = wm.mc.block_manager.write();
     let mut mappings = Vec::new();
+    let mut fluids = Vec::new();
 
     states
         .iter()
@@ -604,10 +647,7 @@ pub fn cacheBlockStates(mut env: JNIEnv, _class: JClass) {
                             .write();
             let atlas = &atlases[BLOCK_ATLAS];
             let model = wm_block.get_model_by_key(
-                key_iter
-                    .iter()
-                    .filter(|(a, _)| *a != "waterlogged")
-                    .map(|(a, b)| (*a, b)),
+                key_iter.iter().map(|(a, b)| (*a, b)),
                 &*wm.mc.resource_provider,
                 atlas,
                 0,
@@ -625,9 +665,18 @@ pub fn cacheBlockStates(mut env: JNIEnv, _class: JClass) {
                 },
             };
 
+            //States that fell back to bedrock don't get the fluid, or every bedrock block would have it
+            if key.block == id_key as u16 {
+                if let Some(fluid) = FluidState::from_blockstate(block_name, key_iter.iter().map(|(a, b)| (*a, b))) {
+                    fluids.push((key, fluid));
+                }
+            }
+
             mappings.push((key, global_ref));
         });
 
+    block_manager.fluids.extend(fluids);
+
     mappings.iter().for_each(|(blockstate_key, global_ref)| {
         env.call_static_method(
             "dev/birb/wgpu/render/Wgpu",
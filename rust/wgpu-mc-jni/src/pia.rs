@@ -25,6 +25,24 @@ pub struct PackedIntegerArray {
 }
 
 impl PackedIntegerArray {
+    /// An array of `size` zeroes, packed like Minecraft packs them with `element_bits` per element
+    pub fn new(element_bits: i32, size: i32) -> Self {
+        let elements_per_long = 64 / element_bits;
+        // Rounding the reciprocal up keeps the division exact for any index below 2^32 / elements_per_long
+        let index_scale = ((1i64 << 32) + elements_per_long as i64 - 1) / elements_per_long as i64;
+
+        Self {
+            data: vec![0; ((size + elements_per_long - 1) / elements_per_long) as usize].into_boxed_slice(),
+            elements_per_long,
+            element_bits,
+            max_value: (1 << element_bits) - 1,
+            index_scale: index_scale as u32 as i32,
+            index_offset: 0,
+            index_shift: 0,
+            size,
+        }
+    }
+
     pub fn get(&self, x: i32, y: i32, z: i32) -> i32 {
         let x = x & 0xf;
         let y = y & 0xf;
@@ -44,6 +62,38 @@ impl PackedIntegerArray {
         ((l >> j) & self.max_value) as i32
     }
 
+    pub fn set(&mut self, x: i32, y: i32, z: i32, value: i32) {
+        let x = x & 0xf;
+        let y = y & 0xf;
+        let z = z & 0xf;
+
+        self.set_by_index((((y << 4) | z) << 4) | x, value)
+    }
+
+    pub fn set_by_index(&mut self, index: i32, value: i32) {
+        assert!(index < self.size, "index: {}, size: {}", index, self.size);
+        assert!(value as i64 <= self.max_value, "value: {}, max: {}", value, self.max_value);
+
+        let i: i32 = self.compute_storage_index(index);
+        let j: i32 = (index - (i * self.elements_per_long)) * self.element_bits;
+
+        let l = &mut self.data[i as usize];
+        *l = (*l & !(self.max_value << j)) | ((value as i64 & self.max_value) << j);
+    }
+
+    pub fn max_value(&self) -> i64 {
+        self.max_value
+    }
+
+    /// The same elements repacked with more bits each, for when the palette has outgrown this array
+    pub fn resized(&self, element_bits: i32) -> Self {
+        let mut resized = Self::new(element_bits, self.size);
+        for index in 0..self.size {
+            resized.set_by_index(index, self.get_by_index(index));
+        }
+        resized
+    }
+
     pub fn compute_storage_index(&self, index: i32) -> i32 {
         let l = self.index_scale as u32 as i64;
         let m = self.index_offset as u32 as i64;
@@ -85,3 +135,50 @@ pub fn createPaletteStorage(
     let mut storage = PIA_STORAGE.write();
     storage.insert(packed_arr) as jlong
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PackedIntegerArray;
+
+    #[test]
+    fn round_trips_every_width() {
+        for bits in 1..=16 {
+            let mut array = PackedIntegerArray::new(bits, 4096);
+            let elements_per_long = 64 / bits as usize;
+            //Widths like 5 leave bits unused at the top of every long
+            assert_eq!(array.data.len(), 4096_usize.div_ceil(elements_per_long));
+
+            let max_value = array.max_value();
+            let value = |index: i32| ((index as i64 * 7919) & max_value) as i32;
+            for index in 0..4096 {
+                array.set_by_index(index, value(index));
+            }
+
+            for index in 0..4096 {
+                assert_eq!(array.get_by_index(index), value(index), "bits: {bits}, index: {index}");
+
+                //Packed the way Minecraft packs it, elements never straddle two longs
+                let long = array.data[index as usize / elements_per_long];
+                let shift = (index as usize % elements_per_long) * bits as usize;
+                assert_eq!((long >> shift) & array.max_value(), value(index) as i64);
+            }
+
+            let resized = array.resized(bits + 1);
+            for index in 0..4096 {
+                assert_eq!(resized.get_by_index(index), value(index));
+            }
+        }
+    }
+
+    #[test]
+    fn set_leaves_neighbours() {
+        let mut array = PackedIntegerArray::new(5, 4096);
+        array.set(15, 15, 15, 31);
+        array.set(14, 15, 15, 17);
+        array.set(15, 15, 15, 3);
+
+        assert_eq!(array.get(15, 15, 15), 3);
+        assert_eq!(array.get(14, 15, 15), 17);
+        assert_eq!(array.get(13, 15, 15), 0);
+    }
+}
@@ -8,6 +8,8 @@ use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, IntoStaticStr};
 
+use wgpu_mc::WmRenderer;
+
 use crate::RUN_DIRECTORY;
 
 static RENDERER_CONFIG_JSON: OnceCell<PathBuf> = OnceCell::new();
@@ -17,12 +19,20 @@ static RENDERER_CONFIG_JSON: OnceCell<PathBuf> = OnceCell::new();
 /// then add an appropriate field to SettingsInfo below,
 /// and a default value in the Default impl for this.
 ///
-/// TODO: handle the case of "json doesn't have every field",
-/// because that's what happens when adding a new setting
+/// Settings missing from the json, like ones added since it was
+/// written, get their default value and the rest are kept.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 #[non_exhaustive]
 pub struct Settings {
     pub vsync: BoolSetting,
+    pub smooth_lighting: BoolSetting,
+    pub biome_blend: IntSetting,
+    pub lod_distance: IntSetting,
+    pub gpu_meshing: BoolSetting,
+    pub transparent_resort_distance: FloatSetting,
+    pub bake_threads: IntSetting,
+    pub upload_limit: IntSetting,
     pub test_enum: EnumSetting,
     pub test_float: FloatSetting,
     pub test_int: IntSetting,
@@ -31,6 +41,13 @@ pub struct Settings {
 #[derive(Serialize)]
 pub struct SettingsInfo {
     vsync: SettingInfo,
+    smooth_lighting: SettingInfo,
+    biome_blend: SettingInfo,
+    lod_distance: SettingInfo,
+    gpu_meshing: SettingInfo,
+    transparent_resort_distance: SettingInfo,
+    bake_threads: SettingInfo,
+    upload_limit: SettingInfo,
     test_enum: EnumSettingInfo<TestEnumSetting>,
     test_float: SettingInfo,
     test_int: SettingInfo,
@@ -43,6 +60,42 @@ lazy_static! {
             May reduce screen tearing, on the cost of added latency.",
             needs_restart: true,
         },
+        smooth_lighting: SettingInfo {
+            desc: "Smoothly blend light between blocks and darken corners with ambient occlusion.\
+            Only applies to chunks rebuilt after changing it.",
+            needs_restart: false,
+        },
+        biome_blend: SettingInfo {
+            desc: "How many blocks away the colours of grass, leaves and water are blended from.\
+            Only applies to chunks rebuilt after changing it.",
+            needs_restart: false,
+        },
+        lod_distance: SettingInfo {
+            desc: "How many chunks past the render distance simplified terrain is drawn. 0 turns it off.\
+            The server only sends chunks within the render distance, so it's drawn where they were loaded before.\
+            Only applies to chunks rebuilt after changing it.",
+            needs_restart: false,
+        },
+        gpu_meshing: SettingInfo {
+            desc: "Mesh chunks with a compute shader where possible, which takes load off the CPU.\
+            Has no effect while the shader pack merges faces (greedy meshing).\
+            Only applies to chunks rebuilt after changing it.",
+            needs_restart: false,
+        },
+        transparent_resort_distance: SettingInfo {
+            desc: "How many blocks you have to move before water and stained glass are sorted again.\
+            Lower is more accurate, higher is faster. They're always sorted again when entering another chunk section.",
+            needs_restart: false,
+        },
+        bake_threads: SettingInfo {
+            desc: "How many threads build chunk meshes. 0 uses every core but one, which is left for rendering.",
+            needs_restart: true,
+        },
+        upload_limit: SettingInfo {
+            desc: "How many megabytes of chunk meshes are sent to the GPU each frame. 0 sends them all as soon as they're built.\
+            Lower evens out stutters when loading lots of chunks, at the cost of them appearing slower.",
+            needs_restart: false,
+        },
         test_enum: EnumSettingInfo::new("", true,),
         test_float: SettingInfo {
             desc: "test float - ignore this",
@@ -81,6 +134,27 @@ impl Settings {
         })
     }
 
+    /// Pushes the settings which don't need a restart into the renderer.
+    pub fn apply(&self, wm: &WmRenderer) {
+        let mut bake_settings = wm.mc.bake_settings.write();
+        bake_settings.smooth_lighting = self.smooth_lighting.value;
+        bake_settings.biome_blend_radius = self.biome_blend.value.clamp(0, 7) as u8;
+        bake_settings.lod_distance = self.lod_distance.value.clamp(0, 32) as u8;
+        bake_settings.gpu_meshing = self.gpu_meshing.value;
+        bake_settings.transparent_resort_distance = self.transparent_resort_distance.value.clamp(0.25, 8.0) as f32;
+        let upload_limit = self.upload_limit.value.clamp(0, 64) as usize;
+        wm.bake_scheduler.set_upload_limit((upload_limit > 0).then_some(upload_limit * 1_000_000));
+    }
+
+    /// How many threads to bake sections on, which only takes effect on startup
+    pub fn bake_threads(&self) -> usize {
+        match self.bake_threads.value.clamp(0, 32) {
+            //Leave a core for the render thread
+            0 => std::thread::available_parallelism().map_or(1, |threads| threads.get().saturating_sub(1)),
+            threads => threads as usize,
+        }
+    }
+
     pub fn write(&self) -> bool {
         let config_path = Self::config_path_get_or_init();
 
@@ -96,6 +170,38 @@ impl Default for Settings {
     fn default() -> Self {
         Settings {
             vsync: BoolSetting { value: true },
+            smooth_lighting: BoolSetting { value: true },
+            biome_blend: IntSetting {
+                min: 0,
+                max: 7,
+                step: 1,
+                value: 2,
+            },
+            lod_distance: IntSetting {
+                min: 0,
+                max: 32,
+                step: 1,
+                value: 8,
+            },
+            gpu_meshing: BoolSetting { value: false },
+            transparent_resort_distance: FloatSetting {
+                min: 0.25,
+                max: 8.0,
+                step: 0.25,
+                value: 1.0,
+            },
+            bake_threads: IntSetting {
+                min: 0,
+                max: 32,
+                step: 1,
+                value: 0,
+            },
+            upload_limit: IntSetting {
+                min: 0,
+                max: 64,
+                step: 1,
+                value: 0,
+            },
             test_enum: EnumSetting::from_variant(TestEnumSetting::Off),
             test_float: FloatSetting {
                 min: 70.0,
@@ -218,3 +324,25 @@ enum TestEnumSetting {
     Three,
     Off,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+
+    #[test]
+    fn old_json_keeps_its_values() {
+        //Written before the bake thread and upload limit settings existed
+        let json = r#"{
+            "vsync": { "type": "bool", "value": false },
+            "biome_blend": { "type": "int", "min": 0, "max": 7, "step": 1, "value": 5 }
+        }"#;
+        let settings: Settings = serde_json::from_str(json).unwrap();
+        let default = Settings::default();
+
+        assert!(!settings.vsync.value);
+        assert_eq!(settings.biome_blend.value, 5);
+        assert_eq!(settings.smooth_lighting.value, default.smooth_lighting.value);
+        assert_eq!(settings.bake_threads.value, default.bake_threads.value);
+        assert_eq!(settings.upload_limit.value, default.upload_limit.value);
+    }
+}
@@ -0,0 +1,231 @@
+//! The client world's blocks, light and biomes, kept on the Rust side.
+//!
+//! Java sends a section over when it's first needed and when it changes, single blocks when they're placed or
+//! broken, and the light of a section when only that changed. Bakes then read the section and its neighbours from here
+//! instead of Java copying all 27 of them again.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use glam::{ivec3, IVec3};
+use jni::objects::{JByteArray, JClass, JIntArray};
+use jni::sys::{jboolean, jint, jlong};
+use jni::JNIEnv;
+use jni_fn::jni_fn;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+
+use wgpu_mc::mc::block::{BlockstateKey, ChunkBlockState};
+use wgpu_mc::mc::chunk::BlockStateProvider;
+use wgpu_mc::mc::world::BlockStore;
+
+use crate::lighting::DeserializedLightData;
+use crate::palette::PALETTE_STORAGE;
+use crate::pia::{PackedIntegerArray, PIA_STORAGE};
+use crate::{MinecraftBlockstateProvider, SectionHolder, AIR, BIOMES, RENDERER};
+
+pub static WORLD: Lazy<ClientWorld> = Lazy::new(ClientWorld::default);
+
+/// Sections keyed by their world section position. They're shared with the providers of bakes which are still
+/// waiting, so changing one copies it instead of changing what those bakes see.
+#[derive(Default)]
+pub struct ClientWorld {
+    sections: RwLock<HashMap<IVec3, Arc<SectionHolder>>>,
+    /// Sections whose light changed since the last frame, see [wgpu_mc::WmRenderer::relight]
+    relit: Mutex<HashSet<IVec3>>,
+}
+
+impl ClientWorld {
+    pub fn contains(&self, pos: IVec3) -> bool {
+        self.sections.read().contains_key(&pos)
+    }
+
+    pub fn set_section(&self, pos: IVec3, section: SectionHolder) {
+        self.sections.write().insert(pos, Arc::new(section));
+    }
+
+    /// Replace the light of a section, and queue it to be relit. Returns false if the section isn't loaded.
+    pub fn set_light(&self, pos: IVec3, light_data: Option<DeserializedLightData>) -> bool {
+        match self.sections.write().get_mut(&pos) {
+            Some(section) => {
+                Arc::make_mut(section).light_data = light_data;
+                self.relit.lock().insert(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The sections whose light changed since this was last called
+    pub fn take_relit(&self) -> HashSet<IVec3> {
+        std::mem::take(&mut *self.relit.lock())
+    }
+
+    /// Forget every section of a chunk column, like when it's unloaded
+    pub fn remove_column(&self, x: i32, z: i32) {
+        self.sections.write().retain(|pos, _| pos.x != x || pos.z != z);
+    }
+
+    pub fn clear(&self) {
+        self.sections.write().clear();
+        self.relit.lock().clear();
+    }
+
+    /// The section at `pos` and its neighbours, for baking it
+    pub fn provider(&self, pos: IVec3) -> MinecraftBlockstateProvider {
+        let sections = self.sections.read();
+
+        MinecraftBlockstateProvider {
+            sections: std::array::from_fn(|index| {
+                let index = index as i32;
+                let offset = ivec3(index % 3, (index / 3) % 3, index / 9) - 1;
+                sections.get(&(pos + offset)).cloned()
+            }),
+            air: *AIR,
+            biomes: BIOMES.read().clone(),
+        }
+    }
+}
+
+impl BlockStore for ClientWorld {
+    fn set_block(&self, pos: IVec3, state: ChunkBlockState) -> bool {
+        let air = *AIR;
+        let state = match state {
+            ChunkBlockState::Air => air,
+            ChunkBlockState::State(key) => key,
+        };
+
+        match self.sections.write().get_mut(&(pos >> 4)) {
+            Some(section) => {
+                Arc::make_mut(section).set_state(pos & 15, state, air);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn section_provider(&self, section: IVec3) -> Option<Box<dyn BlockStateProvider>> {
+        if !self.contains(section) {
+            return None;
+        }
+
+        Some(Box::new(self.provider(section)))
+    }
+}
+
+/// Replace a whole section. The palette and storage are taken out of their slabs, -1 for the palette means the section
+/// has no block data. A section of a single block state only has a palette, its storage is all zeroes.
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn setSection(
+    env: JNIEnv,
+    _class: JClass,
+    x: jint,
+    y: jint,
+    z: jint,
+    palette: jlong,
+    storage: jlong,
+    blockLight: JByteArray,
+    skyLight: JByteArray,
+    biomeIds: JIntArray,
+) {
+    let block_data = {
+        let mut palette_storage = PALETTE_STORAGE.write();
+        let mut pia_storage = PIA_STORAGE.write();
+
+        if palette_storage.contains(palette as usize) {
+            let storage = if pia_storage.contains(storage as usize) {
+                pia_storage.remove(storage as usize)
+            } else {
+                PackedIntegerArray::new(4, 4096)
+            };
+            Some((palette_storage.remove(palette as usize), storage))
+        } else {
+            None
+        }
+    };
+
+    let light_data = light_data(&env, &blockLight, &skyLight);
+
+    let biomes = if biomeIds.is_null() {
+        None
+    } else {
+        let mut biomes = Box::new([0; 64]);
+        env.get_int_array_region(&biomeIds, 0, &mut biomes[..]).ok().map(|_| biomes)
+    };
+
+    WORLD.set_section(
+        ivec3(x, y, z),
+        SectionHolder {
+            block_data,
+            light_data,
+            biomes,
+        },
+    );
+}
+
+/// The light of a section from Java's nibble arrays, `None` if either is missing
+fn light_data(env: &JNIEnv, block_light: &JByteArray, sky_light: &JByteArray) -> Option<DeserializedLightData> {
+    let light_array = |array: &JByteArray| -> Option<Box<[u8; 2048]>> {
+        env.convert_byte_array(array).ok()?.into_boxed_slice().try_into().ok()
+    };
+
+    light_array(sky_light)
+        .zip(light_array(block_light))
+        .map(|(sky_light, block_light)| DeserializedLightData {
+            sky_light,
+            block_light,
+        })
+}
+
+/// Replace the light of a section which is already loaded. The sections it lights get relit on the next frame instead
+/// of being rebaked.
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn setSectionLight(
+    env: JNIEnv,
+    _class: JClass,
+    x: jint,
+    y: jint,
+    z: jint,
+    blockLight: JByteArray,
+    skyLight: JByteArray,
+) -> jboolean {
+    WORLD.set_light(ivec3(x, y, z), light_data(&env, &blockLight, &skyLight)) as jboolean
+}
+
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn hasSection(_env: JNIEnv, _class: JClass, x: jint, y: jint, z: jint) -> jboolean {
+    WORLD.contains(ivec3(x, y, z)) as jboolean
+}
+
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn removeColumn(_env: JNIEnv, _class: JClass, x: jint, z: jint) {
+    WORLD.remove_column(x, z);
+}
+
+/// Change one block and rebake the sections that can see it. Returns false if the block's section isn't loaded here,
+/// then Java has to rebuild it the usual way so it gets sent over.
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn setBlock(_env: JNIEnv, _class: JClass, x: jint, y: jint, z: jint, state: jint) -> jboolean {
+    let wm = match RENDERER.get() {
+        Some(wm) => wm,
+        None => return false as jboolean,
+    };
+
+    let state = BlockstateKey::from(state as u32);
+    let state = if state == *AIR {
+        ChunkBlockState::Air
+    } else {
+        ChunkBlockState::State(state)
+    };
+
+    wm.set_block(&*WORLD, ivec3(x, y, z), state) as jboolean
+}
+
+/// Bake a section from what's in the world, its neighbours included
+#[jni_fn("dev.birb.wgpu.rust.WgpuNative")]
+pub fn bakeSection(_env: JNIEnv, _class: JClass, x: jint, y: jint, z: jint) {
+    let wm = RENDERER.get().unwrap();
+    let pos = ivec3(x, y, z);
+
+    wm.bake_scheduler.request(wm, pos, WORLD.provider(pos));
+}
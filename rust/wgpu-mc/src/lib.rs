@@ -43,8 +43,9 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender};
 
-use glam::IVec3;
-use mc::chunk::BakedLayer;
+use glam::{IVec3, Vec3Swizzles};
+use mc::chunk::{bake_lod, BakeVersions, BakedLayer, BakedSection, SectionStorage, SectionVisibility};
+use mc::scheduler::BakeScheduler;
 use mc::Scene;
 pub use minecraft_assets;
 use parking_lot::{Mutex, RwLock};
@@ -56,6 +57,7 @@ use winit::window::Window;
 use crate::mc::resource::ResourceProvider;
 use crate::mc::MinecraftState;
 use crate::render::atlas::Atlas;
+use crate::render::gpu_mesher::GpuMesher;
 use crate::render::pipeline::{create_bind_group_layouts, BLOCK_ATLAS, ENTITY_ATLAS};
 
 pub mod mc;
@@ -87,7 +89,10 @@ pub struct WmRenderer {
     pub display: Display,
     pub bind_group_layouts: Arc<HashMap<String, BindGroupLayout>>,
     pub mc: MinecraftState,
-    pub chunk_update_queue: (Sender<(IVec3, Vec<BakedLayer>)>, Mutex<Receiver<(IVec3, Vec<BakedLayer>)>>),
+    pub chunk_update_queue: (Sender<BakedSection>, Mutex<Receiver<BakedSection>>),
+    pub bake_versions: BakeVersions,
+    pub bake_scheduler: BakeScheduler,
+    pub gpu_mesher: GpuMesher,
 }
 
 #[derive(Copy, Clone)]
@@ -107,9 +112,12 @@ impl WmRenderer {
         let (sender,receiver) = channel();
         Self {
             bind_group_layouts: Arc::new(create_bind_group_layouts(&display.device)),
+            gpu_mesher: GpuMesher::new(&display.device),
             display,
             mc,
             chunk_update_queue: (sender,Mutex::new(receiver)),
+            bake_versions: BakeVersions::default(),
+            bake_scheduler: BakeScheduler::default(),
         }
     }
 
@@ -168,20 +176,152 @@ impl WmRenderer {
         );
     }
 
+    /// Upload the sections that finished baking. With an upload limit set on the [BakeScheduler] this stops once
+    /// the limit is reached, and the rest wait for the next frame.
     pub fn submit_chunk_updates(&self,scene:&Scene) {
         let receiver = self.chunk_update_queue.1.lock();
-        let updates = receiver.try_iter();
-        
-        updates.for_each(|(pos, layers)| {
+        let mut upload_budget = self.bake_scheduler.upload_limit();
+
+        {
+            let mut storage = scene.section_storage.write();
+            if storage.needs_compaction() {
+                let capacity = storage.capacity();
+                scene.resize_chunk_buffer(self, &mut storage, capacity);
+            }
+        }
+
+        for BakedSection { pos, version, layers, visibility, lod, gpu_input } in receiver.try_iter() {
+            //A newer bake of the section is on the way, or it was unloaded
+            if !self.bake_versions.is_current(pos, version) {
+                continue;
+            }
+            //Its blocks point into a table the block models were baked again since, every section gets rebaked then
+            if gpu_input.as_ref().is_some_and(|input| !self.gpu_mesher.is_current(input)) {
+                continue;
+            }
+
             let mut storage = scene.section_storage.write();
-            let section = storage.replace(pos, &layers);
-            for (i,ranges) in section.layers.iter().enumerate(){
-                if let Some(ranges) = ranges{
-                    self.display.queue.write_buffer(&scene.chunk_buffer.buffer,ranges.vertex_range.start as u64 * 4,&layers[i].vertices);
-                    self.display.queue.write_buffer(&scene.chunk_buffer.buffer,ranges.index_range.start as u64 * 4,&layers[i].indices);
+            let replace = |storage: &mut SectionStorage| match &gpu_input {
+                Some(input) => storage.replace_gpu(pos, input.quads, visibility).map(|_| ()),
+                None => storage.replace(pos, &layers, visibility).map(|_| ()),
+            };
+            if let Err(needed) = replace(&mut storage) {
+                //Too far away to keep when the chunk buffer can't grow any more, until the camera comes closer
+                if !scene.make_room(self, &mut storage, pos, needed) || replace(&mut storage).is_err() {
+                    scene.evicted_sections.lock().insert(pos);
+                    continue;
+                }
+            }
+            //Not before it's in the chunk buffer, until then it stays pending so it gets baked again
+            self.bake_versions.finish(pos, version);
+            scene.evicted_sections.lock().remove(&pos);
+            let section = storage.get(&pos).unwrap();
+
+            if gpu_input.is_none() {
+                let chunk_buffer = scene.chunk_buffer.load();
+                for (i,ranges) in section.layers.iter().enumerate(){
+                    if let Some(ranges) = ranges{
+                        self.display.queue.write_buffer(&chunk_buffer.buffer,ranges.vertex_range.start as u64 * 4,&layers[i].vertices);
+                        self.display.queue.write_buffer(&chunk_buffer.buffer,ranges.light_range.start as u64 * 4,&layers[i].light);
+                    }
+                }
+            }
+
+            drop(storage);
+            let lod = lod.map(|(cells, lod)| {
+                let mut lod_cells = scene.lod_cells.write();
+                if cells.is_empty() {
+                    lod_cells.remove(&pos);
+                } else {
+                    lod_cells.insert(pos, cells);
+                }
+                vec![lod]
+            });
+            let gpu_quads = gpu_input.iter().flat_map(|input| input.quads);
+            let quads = layers.iter().chain(lod.iter().flatten()).map(BakedLayer::quads).chain(gpu_quads).max().unwrap_or(0);
+            scene.quad_indices.reserve(self, quads);
+            if let Some(lod) = &lod {
+                self.upload_lod(scene, pos, lod);
+            }
+
+            let gpu_bytes = gpu_input.as_ref().map_or(0, |input| input.size() as usize * 4);
+            //Meshed with the rest in one go below, wherever the section is in the chunk buffer by then
+            if let Some(input) = gpu_input {
+                self.gpu_mesher.queue(pos, input);
+            }
+
+            if let Some(budget) = &mut upload_budget {
+                let bytes = layers.iter().chain(lod.iter().flatten()).map(|layer| layer.size() as usize * 4).sum::<usize>() + gpu_bytes;
+                *budget = budget.saturating_sub(bytes);
+                if *budget == 0 {
+                    break;
                 }
             }
-        });
+        }
+
+        self.gpu_mesher.flush(&self.display.device, &self.display.queue, &scene.section_storage.read(), &scene.chunk_buffer.load().buffer);
+    }
+
+    /// Replace the simplified mesh of a section. When the LOD buffer is full those furthest from the camera are
+    /// dropped, and if that isn't enough the section goes without and false is returned.
+    fn upload_lod(&self,scene:&Scene,pos:IVec3,lod:&Vec<BakedLayer>) -> bool {
+        let mut storage = scene.lod_storage.write();
+        if let Err(needed) = storage.replace(pos, lod, SectionVisibility::ALL) {
+            let max_used = storage.capacity().saturating_sub(needed);
+            if storage.evict_farthest(*scene.camera_section_pos.read(), pos, max_used).is_none()
+                || storage.replace(pos, lod, SectionVisibility::ALL).is_err() {
+                return false;
+            }
+        }
+
+        if let Some(Some(ranges)) = storage.get(&pos).unwrap().layers.first() {
+            self.display.queue.write_buffer(&scene.lod_buffer.buffer,ranges.vertex_range.start as u64 * 4,&lod[0].vertices);
+            self.display.queue.write_buffer(&scene.lod_buffer.buffer,ranges.light_range.start as u64 * 4,&lod[0].light);
+        }
+        true
+    }
+
+    /// Forget the simplified sections more than `radius` columns from the camera. When the camera moved to another
+    /// section, those in [Scene::lod_cells] which were dropped or left out when the LOD buffer was full are meshed
+    /// again, nearest first, for as long as they fit.
+    pub fn update_lods(&self,scene:&Scene,radius:i32) {
+        let camera_section = *scene.camera_section_pos.read();
+        scene.lod_storage.write().retain_within(camera_section, radius);
+        scene.lod_cells.write().retain(|pos, _| (pos.xz() - camera_section).abs().max_element() <= radius);
+
+        if scene.lod_camera_section.lock().replace(camera_section) == Some(camera_section) {
+            return;
+        }
+
+        let lod_cells = scene.lod_cells.read();
+        let mut missing: Vec<IVec3> = {
+            let storage = scene.lod_storage.read();
+            lod_cells.keys().copied().filter(|pos| storage.get(pos).is_none()).collect()
+        };
+        missing.sort_unstable_by_key(|pos| (pos.xz() - camera_section).length_squared());
+
+        let block_manager = self.mc.block_manager.read();
+        for pos in missing {
+            let lod = vec![bake_lod(pos, &lod_cells[&pos], &block_manager)];
+            scene.quad_indices.reserve(self, lod[0].quads());
+            if !self.upload_lod(scene, pos, &lod) {
+                break;
+            }
+        }
+    }
+
+    /// Re-sort the transparent quads of the sections the camera moved in back to front, and upload their vertices and
+    /// light in that order. See [mc::chunk::BakeSettings::transparent_resort_distance].
+    pub fn sort_transparent_sections(&self,scene:&Scene) {
+        let camera_section = *scene.camera_section_pos.read();
+        let camera_pos = *scene.camera_pos.read();
+        let resort_distance = self.mc.bake_settings.read().transparent_resort_distance;
+
+        let sorted = scene.section_storage.write().sort_transparent(camera_section, camera_pos, resort_distance);
+        let chunk_buffer = scene.chunk_buffer.load();
+        for (start, data) in sorted {
+            self.display.queue.write_buffer(&chunk_buffer.buffer,start as u64 * 4,&data);
+        }
     }
 
     pub fn get_backend_description(&self) -> String {
@@ -0,0 +1,325 @@
+//! Biome colours for tinted block faces, like grass, leaves and water.
+//!
+//! Faces of a block model can have a `tintindex`, which means the texture gets multiplied by a colour
+//! that depends on the block and where it is. Which kind of colour a block uses is a [BlockTint], and
+//! the biome dependent ones are looked up in the `grass.png` and `foliage.png` colormaps.
+
+use std::sync::OnceLock;
+
+use glam::IVec3;
+use image::GenericImageView;
+
+use crate::mc::resource::{ResourcePath, ResourceProvider};
+use crate::mc::JavaRandom;
+
+/// The parts of a biome which decide the colour of tinted blocks in it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Biome {
+    pub temperature: f32,
+    pub downfall: f32,
+    /// Replaces the grass colormap lookup, like badlands do
+    pub grass_color: Option<u32>,
+    /// Replaces the foliage colormap lookup
+    pub foliage_color: Option<u32>,
+    pub water_color: u32,
+    pub grass_color_modifier: GrassColorModifier,
+}
+
+impl Default for Biome {
+    /// Plains
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            downfall: 0.4,
+            grass_color: None,
+            foliage_color: None,
+            water_color: 0x3f76e4,
+            grass_color_modifier: GrassColorModifier::None,
+        }
+    }
+}
+
+/// How a biome changes the colour of its grass once it's been looked up, vanilla's `GrassColorModifier`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GrassColorModifier {
+    #[default]
+    None,
+    /// Blends it with a dark green
+    DarkForest,
+    /// Replaces it with one of two colours, in patches decided by noise
+    Swamp,
+}
+
+impl GrassColorModifier {
+    /// The grass colour of the block at `pos`, from the colour it would have without a modifier
+    pub fn apply(&self, color: u32, pos: IVec3) -> u32 {
+        match self {
+            Self::None => color,
+            Self::DarkForest => ((color & 0xfefefe) + 0x28340a) >> 1,
+            Self::Swamp => {
+                static NOISE: OnceLock<SimplexNoise> = OnceLock::new();
+                let noise = NOISE.get_or_init(|| SimplexNoise::new(&mut JavaRandom::new(2345)));
+                match noise.sample(pos.x as f64 * 0.0225, pos.z as f64 * 0.0225) < -0.1 {
+                    true => 0x4c763c,
+                    false => 0x6a7039,
+                }
+            }
+        }
+    }
+}
+
+/// Vanilla's 2D `SimplexNoiseSampler`, with the permutation table it gets from its random
+struct SimplexNoise {
+    permutation: [u8; 256],
+}
+
+impl SimplexNoise {
+    const GRADIENTS: [[f64; 2]; 12] = [
+        [1.0, 1.0],
+        [-1.0, 1.0],
+        [1.0, -1.0],
+        [-1.0, -1.0],
+        [1.0, 0.0],
+        [-1.0, 0.0],
+        [1.0, 0.0],
+        [-1.0, 0.0],
+        [0.0, 1.0],
+        [0.0, -1.0],
+        [0.0, 1.0],
+        [0.0, -1.0],
+    ];
+
+    fn new(random: &mut JavaRandom) -> Self {
+        //The origin, which isn't used in 2D
+        (0..3).for_each(|_| {
+            random.next_double();
+        });
+
+        let mut permutation = std::array::from_fn(|index| index as u8);
+        for index in 0..256 {
+            let other = index + random.next_int(256 - index as i32) as usize;
+            permutation.swap(index, other);
+        }
+        Self { permutation }
+    }
+
+    fn map(&self, index: i32) -> i32 {
+        self.permutation[(index & 0xff) as usize] as i32
+    }
+
+    fn corner(gradient: i32, x: f64, y: f64) -> f64 {
+        let falloff = 0.5 - x * x - y * y;
+        if falloff < 0.0 {
+            return 0.0;
+        }
+        let [gradient_x, gradient_y] = Self::GRADIENTS[gradient as usize];
+        let falloff = falloff * falloff;
+        falloff * falloff * (gradient_x * x + gradient_y * y)
+    }
+
+    fn sample(&self, x: f64, y: f64) -> f64 {
+        let sqrt_3 = 3f64.sqrt();
+        let skew = 0.5 * (sqrt_3 - 1.0);
+        let unskew = (3.0 - sqrt_3) / 6.0;
+
+        let skewed = (x + y) * skew;
+        let (cell_x, cell_y) = ((x + skewed).floor() as i32, (y + skewed).floor() as i32);
+        let unskewed = (cell_x + cell_y) as f64 * unskew;
+        let (x0, y0) = (x - (cell_x as f64 - unskewed), y - (cell_y as f64 - unskewed));
+
+        let (step_x, step_y) = if x0 > y0 { (1, 0) } else { (0, 1) };
+        let (x1, y1) = (x0 - step_x as f64 + unskew, y0 - step_y as f64 + unskew);
+        let (x2, y2) = (x0 - 1.0 + 2.0 * unskew, y0 - 1.0 + 2.0 * unskew);
+
+        let (i, j) = (cell_x & 0xff, cell_y & 0xff);
+        let gradient0 = self.map(i + self.map(j)) % 12;
+        let gradient1 = self.map(i + step_x + self.map(j + step_y)) % 12;
+        let gradient2 = self.map(i + 1 + self.map(j + 1)) % 12;
+
+        70.0 * (Self::corner(gradient0, x0, y0) + Self::corner(gradient1, x1, y1) + Self::corner(gradient2, x2, y2))
+    }
+}
+
+/// Which colour the tinted faces of a block get, all colours are 0xRRGGBB
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockTint {
+    Grass,
+    Foliage,
+    Water,
+    /// Depends on the `power` property of the blockstate
+    Redstone,
+    Constant(u32),
+}
+
+impl BlockTint {
+    /// Whether the colour depends on the biome, and should be blended with the surrounding biomes
+    pub fn is_biome_dependent(&self) -> bool {
+        matches!(self, Self::Grass | Self::Foliage | Self::Water)
+    }
+}
+
+/// The tints of the vanilla blocks which have tinted faces, mirroring vanilla's `BlockColors`
+pub const VANILLA_BLOCK_TINTS: &[(&str, BlockTint)] = &[
+    ("minecraft:grass_block", BlockTint::Grass),
+    ("minecraft:short_grass", BlockTint::Grass),
+    ("minecraft:tall_grass", BlockTint::Grass),
+    ("minecraft:fern", BlockTint::Grass),
+    ("minecraft:large_fern", BlockTint::Grass),
+    ("minecraft:potted_fern", BlockTint::Grass),
+    ("minecraft:sugar_cane", BlockTint::Grass),
+    ("minecraft:oak_leaves", BlockTint::Foliage),
+    ("minecraft:jungle_leaves", BlockTint::Foliage),
+    ("minecraft:acacia_leaves", BlockTint::Foliage),
+    ("minecraft:dark_oak_leaves", BlockTint::Foliage),
+    ("minecraft:mangrove_leaves", BlockTint::Foliage),
+    ("minecraft:vine", BlockTint::Foliage),
+    ("minecraft:birch_leaves", BlockTint::Constant(0x80a755)),
+    ("minecraft:spruce_leaves", BlockTint::Constant(0x619961)),
+    ("minecraft:lily_pad", BlockTint::Constant(0x208030)),
+    ("minecraft:attached_melon_stem", BlockTint::Constant(0xe0c71c)),
+    ("minecraft:attached_pumpkin_stem", BlockTint::Constant(0xe0c71c)),
+    ("minecraft:water", BlockTint::Water),
+    ("minecraft:bubble_column", BlockTint::Water),
+    ("minecraft:water_cauldron", BlockTint::Water),
+    ("minecraft:redstone_wire", BlockTint::Redstone),
+];
+
+/// The colour of redstone wire for a power level from 0 to 15, the same curve vanilla uses
+pub fn redstone_color(power: u8) -> u32 {
+    let power = power.min(15) as f32 / 15.0;
+    let r = power * 0.6 + if power > 0.0 { 0.4 } else { 0.3 };
+    let g = (power * power * 0.7 - 0.5).clamp(0.0, 1.0);
+    let b = (power * power * 0.6 - 0.7).clamp(0.0, 1.0);
+
+    pack_rgb([r, g, b].map(|channel| (channel * 255.0) as u32))
+}
+
+pub fn unpack_rgb(color: u32) -> [u32; 3] {
+    [(color >> 16) & 0xff, (color >> 8) & 0xff, color & 0xff]
+}
+
+pub fn pack_rgb(rgb: [u32; 3]) -> u32 {
+    (rgb[0].min(255) << 16) | (rgb[1].min(255) << 8) | rgb[2].min(255)
+}
+
+/// A 256x256 colormap indexed by temperature and downfall
+pub struct ColorMap {
+    pixels: Vec<u32>,
+}
+
+impl ColorMap {
+    pub fn load(resource_provider: &dyn ResourceProvider, path: &ResourcePath) -> Option<Self> {
+        let image = image::load_from_memory(&resource_provider.get_bytes(path)?).ok()?;
+        if image.dimensions() != (256, 256) {
+            return None;
+        }
+
+        Some(Self {
+            pixels: image
+                .to_rgba8()
+                .pixels()
+                .map(|pixel| pack_rgb([pixel.0[0] as u32, pixel.0[1] as u32, pixel.0[2] as u32]))
+                .collect(),
+        })
+    }
+
+    pub fn get(&self, temperature: f32, downfall: f32) -> u32 {
+        let temperature = temperature.clamp(0.0, 1.0);
+        let downfall = downfall.clamp(0.0, 1.0) * temperature;
+
+        let x = ((1.0 - temperature) * 255.0) as usize;
+        let y = ((1.0 - downfall) * 255.0) as usize;
+
+        self.pixels[(y << 8) | x]
+    }
+}
+
+/// The grass and foliage colormaps from the resource pack
+#[derive(Default)]
+pub struct BiomeColors {
+    pub grass: Option<ColorMap>,
+    pub foliage: Option<ColorMap>,
+}
+
+impl BiomeColors {
+    /// What vanilla uses when the colormaps are missing
+    const DEFAULT_GRASS: u32 = 0x91bd59;
+    const DEFAULT_FOLIAGE: u32 = 0x48b518;
+
+    pub fn load(resource_provider: &dyn ResourceProvider) -> Self {
+        Self {
+            grass: ColorMap::load(
+                resource_provider,
+                &ResourcePath::from("minecraft:textures/colormap/grass.png"),
+            ),
+            foliage: ColorMap::load(
+                resource_provider,
+                &ResourcePath::from("minecraft:textures/colormap/foliage.png"),
+            ),
+        }
+    }
+
+    /// The colour of a biome dependent [BlockTint] at the world position `pos` in a single biome, other tints are
+    /// white
+    pub fn get(&self, biome: &Biome, tint: BlockTint, pos: IVec3) -> u32 {
+        match tint {
+            BlockTint::Grass => {
+                let color = biome.grass_color.unwrap_or_else(|| {
+                    self.grass
+                        .as_ref()
+                        .map_or(Self::DEFAULT_GRASS, |map| map.get(biome.temperature, biome.downfall))
+                });
+                biome.grass_color_modifier.apply(color, pos)
+            }
+            BlockTint::Foliage => biome.foliage_color.unwrap_or_else(|| {
+                self.foliage
+                    .as_ref()
+                    .map_or(Self::DEFAULT_FOLIAGE, |map| map.get(biome.temperature, biome.downfall))
+            }),
+            BlockTint::Water => biome.water_color,
+            BlockTint::Redstone | BlockTint::Constant(_) => 0xffffff,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{ivec3, IVec3};
+
+    use super::{Biome, BiomeColors, BlockTint, GrassColorModifier, SimplexNoise};
+    use crate::mc::JavaRandom;
+
+    #[test]
+    fn swamp_noise_matches_vanilla() {
+        //Values from vanilla's SimplexNoiseSampler, seeded like Biome.FOLIAGE_NOISE
+        let noise = SimplexNoise::new(&mut JavaRandom::new(2345));
+        let expected = [
+            (100, -200, 0.3227495974985246),
+            (-1234, 5678, 0.3941096907076514),
+            (7, 13, 0.17837421866957154),
+            (-50, -50, -0.1281155744396789),
+            (-300, 123, -0.44857714727342396),
+        ];
+
+        for (x, z, value) in expected {
+            assert_eq!(noise.sample(x as f64 * 0.0225, z as f64 * 0.0225), value, "x: {x}, z: {z}");
+        }
+    }
+
+    #[test]
+    fn grass_color_modifiers() {
+        let colors = BiomeColors { grass: None, foliage: None };
+        let grass = |grass_color_modifier, pos| {
+            colors.get(&Biome { grass_color_modifier, ..Default::default() }, BlockTint::Grass, pos)
+        };
+
+        assert_eq!(grass(GrassColorModifier::None, IVec3::ZERO), 0x91bd59);
+        assert_eq!(grass(GrassColorModifier::DarkForest, IVec3::ZERO), 0x5c7831);
+        //Swamps have patches of two colours
+        assert_eq!(grass(GrassColorModifier::Swamp, ivec3(-50, 64, -50)), 0x4c763c);
+        assert_eq!(grass(GrassColorModifier::Swamp, ivec3(100, 64, -200)), 0x6a7039);
+        //Only grass is modified
+        let swamp = Biome { grass_color_modifier: GrassColorModifier::Swamp, ..Default::default() };
+        assert_eq!(colors.get(&swamp, BlockTint::Foliage, ivec3(-50, 64, -50)), 0x48b518);
+    }
+}
@@ -1,4 +1,7 @@
 use crate::mc::chunk::RenderLayer;
+use std::fmt::Debug;
+use std::sync::Arc;
+
 use bytemuck::{Pod, Zeroable};
 use glam::{vec3, vec4, IVec3, Mat3, Mat4, Vec3};
 use itertools::Itertools;
@@ -10,6 +13,7 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::mc::resource::{ResourceProvider,ResourcePath};
 use crate::mc::direction::Direction;
+use crate::mc::position_seed;
 use crate::render::atlas::Atlas;
 use crate::texture::UV;
 
@@ -71,6 +75,12 @@ pub struct BlockModelFace {
     pub vertices:[BlockMeshVertex; 4],
     pub normal: Vec3,
     pub animation_uv_offset: u32,
+    /// The model's `tintindex`, -1 if the face isn't tinted
+    pub tint_index: i32,
+    /// Whether the face gets darker depending on which way it faces, false for elements with `"shade": false`
+    pub shade: bool,
+    /// The neighbour which hides this face when it's a full block, from the model's `cullface`
+    pub cull_face: Option<Direction>,
 }
 
 fn recurse_model_parents(
@@ -153,10 +163,181 @@ fn get_atlas_uv(face: &schemas::models::ElementFace, block_atlas: &Atlas) -> Opt
     })
 }
 
+/// Which side of the block a face lies flat against, if any
+fn boundary_direction(face: &BlockModelFace) -> Option<Direction> {
+    let on_plane = |axis: usize, value: f32| face.vertices.iter().all(|vertex| vertex.position[axis] == value);
+
+    [
+        (0, 0.0, Direction::West),
+        (0, 1.0, Direction::East),
+        (1, 0.0, Direction::Down),
+        (1, 1.0, Direction::Up),
+        (2, 0.0, Direction::North),
+        (2, 1.0, Direction::South),
+    ]
+    .into_iter()
+    .find(|(axis, value, _)| on_plane(*axis, *value))
+    .map(|(_, _, dir)| dir)
+}
+
+/// The outward normal of a face from the winding of its vertices, None if the face has no area
+fn face_normal(face: &BlockModelFace) -> Option<Vec3> {
+    let [v0, v1, v2, _] = face.vertices.map(|vertex| vertex.position);
+    (v1 - v0).cross(v2 - v0).try_normalize()
+}
+
+/// Work out which [RenderLayer] a texture belongs in by looking at the alpha channel of its atlas tile.
+/// Fully opaque textures are [RenderLayer::Solid], textures with only fully transparent holes are
+/// [RenderLayer::Cutout] and anything with partial alpha is [RenderLayer::Transparent].
+fn get_texture_layer(texture: &ResourcePath, block_atlas: &Atlas) -> RenderLayer {
+    let uv = match block_atlas.uv_map.read().get(texture) {
+        None => return RenderLayer::Solid,
+        Some(uv) => *uv,
+    };
+    let image = block_atlas.image.read();
+
+    let mut layer = RenderLayer::Solid;
+    for y in uv.0.1..uv.1.1 {
+        for x in uv.0.0..uv.1.0 {
+            match image.get_pixel(x as u32, y as u32).0[3] {
+                255 => {}
+                0 => layer = layer.max(RenderLayer::Cutout),
+                _ => return RenderLayer::Transparent,
+            }
+        }
+    }
+    layer
+}
+
 pub struct RenderSettings {
     pub opaque: bool,
 }
 
+/// How far a block's model is randomly moved off the block grid, like vanilla's `OffsetType`.
+/// The offset only depends on the block's x and z, so both halves of tall plants move together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelOffset {
+    None,
+    /// Moved horizontally by up to `max` blocks
+    XZ { max: f32 },
+    /// Moved horizontally by up to `max` blocks, and down by up to 0.2 blocks
+    XYZ { max: f32 },
+}
+
+impl ModelOffset {
+    pub fn get(&self, world_pos: IVec3) -> Vec3 {
+        let max = match self {
+            ModelOffset::None => return Vec3::ZERO,
+            ModelOffset::XZ { max } | ModelOffset::XYZ { max } => *max,
+        };
+
+        let seed = position_seed(IVec3::new(world_pos.x, 0, world_pos.z));
+        let nibble = |shift: u64| ((seed >> shift) & 15) as f32 / 15.0;
+
+        vec3(
+            ((nibble(0) - 0.5) * 0.5).clamp(-max, max),
+            if matches!(self, ModelOffset::XYZ { .. }) { (nibble(4) - 1.0) * 0.2 } else { 0.0 },
+            ((nibble(8) - 0.5) * 0.5).clamp(-max, max),
+        )
+    }
+}
+
+/// What a [CullRule::Custom] gets to decide with
+pub struct CullQuery<'a> {
+    pub block: BlockstateKey,
+    /// The side of `block` the face is on
+    pub dir: Direction,
+    pub neighbour: BlockstateKey,
+    pub neighbour_mesh: &'a ModelMesh,
+    pub neighbour_layer: RenderLayer,
+}
+
+/// Decides when a face against a neighbouring block is hidden, like vanilla's `isSideInvisible` overrides.
+/// Whatever the rule, faces covered by a full face of an opaque neighbour are always hidden.
+#[derive(Clone)]
+pub enum CullRule {
+    /// Also hide faces against any state of the same block, like glass, ice and panes do.
+    /// Setting this on leaves gives vanilla's fast leaves.
+    SameBlock,
+    /// Also hide faces whenever the function returns true
+    Custom(Arc<dyn Fn(&CullQuery) -> bool + Send + Sync>),
+}
+
+impl Debug for CullRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CullRule::SameBlock => f.write_str("SameBlock"),
+            CullRule::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+const DYE_COLORS: [&str; 16] = [
+    "white", "orange", "magenta", "light_blue", "yellow", "lime", "pink", "gray",
+    "light_gray", "cyan", "purple", "blue", "brown", "green", "red", "black",
+];
+
+/// The vanilla blocks which hide faces between themselves
+pub fn vanilla_cull_rules() -> Vec<(String, CullRule)> {
+    [
+        "minecraft:glass",
+        "minecraft:tinted_glass",
+        "minecraft:glass_pane",
+        "minecraft:iron_bars",
+        "minecraft:ice",
+        "minecraft:frosted_ice",
+        "minecraft:slime_block",
+        "minecraft:honey_block",
+        "minecraft:powder_snow",
+    ]
+    .into_iter()
+    .map(String::from)
+    .chain(DYE_COLORS.iter().flat_map(|color| {
+        [
+            format!("minecraft:{color}_stained_glass"),
+            format!("minecraft:{color}_stained_glass_pane"),
+        ]
+    }))
+    .map(|block_name| (block_name, CullRule::SameBlock))
+    .collect()
+}
+
+/// The blocks vanilla offsets, mirroring the `offsetType` of their block settings
+pub const VANILLA_MODEL_OFFSETS: &[(&str, ModelOffset)] = &[
+    ("minecraft:short_grass", ModelOffset::XYZ { max: 0.25 }),
+    ("minecraft:fern", ModelOffset::XYZ { max: 0.25 }),
+    ("minecraft:small_dripleaf", ModelOffset::XYZ { max: 0.25 }),
+    ("minecraft:tall_grass", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:large_fern", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:dandelion", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:torchflower", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:poppy", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:blue_orchid", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:allium", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:azure_bluet", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:red_tulip", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:orange_tulip", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:white_tulip", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:pink_tulip", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:oxeye_daisy", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:cornflower", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:lily_of_the_valley", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:wither_rose", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:sunflower", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:lilac", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:rose_bush", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:peony", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:pitcher_plant", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:crimson_roots", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:warped_roots", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:nether_sprouts", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:hanging_roots", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:mangrove_propagule", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:bamboo_sapling", ModelOffset::XZ { max: 0.25 }),
+    ("minecraft:bamboo", ModelOffset::XZ { max: 0.125 }),
+    ("minecraft:pointed_dripstone", ModelOffset::XZ { max: 0.125 }),
+];
+
 /// TODO: Use actual error handling library
 #[derive(Debug)]
 pub enum MeshBakeError {
@@ -165,6 +346,174 @@ pub enum MeshBakeError {
     JsonError(serde_json::Error),
 }
 
+/// Turn one model element into its faces, rotated by the element's own rotation and then by the variant's.
+/// `texture` gives the atlas UV and animation offset of a face, faces it gives nothing for are left out
+pub fn bake_element(
+    element: &Element,
+    model_properties: &ModelProperties,
+    declares_cull_faces: bool,
+    texture: impl Fn(&schemas::models::ElementFace) -> Option<(UV, u32)>,
+) -> Vec<BlockModelFace> {
+    let rot = &element.rotation;
+    let matrix = match rot.axis {
+        schemas::models::Axis::X => Mat3::from_rotation_x(rot.angle.to_radians()),
+        schemas::models::Axis::Y => Mat3::from_rotation_y(rot.angle.to_radians()),
+        schemas::models::Axis::Z => Mat3::from_rotation_z(rot.angle.to_radians()),
+    };
+    let vec_origin = Vec3::from_array(rot.origin)/16.0;
+
+    let rotate_x = |v:Vec3| match model_properties.x{
+        0=>v,
+        90=>vec3(v.x,1.0-v.z, v.y),
+        180=>vec3(v.x,1.0-v.y, 1.0-v.z),
+        270=>vec3(v.x,v.z, 1.0-v.y),
+        _=>panic!("invalid rotation")
+    };
+    let rotate_y = |v:Vec3| match model_properties.y{
+        0=>v,
+        90=>vec3(1.0-v.z, v.y, v.x),
+        180=>vec3(1.0-v.x, v.y,1.0-v.z),
+        270=>vec3(v.z, v.y, 1.0-v.x),
+        _=>panic!("invalid rotation")
+    };
+    let vertex_transform = |v:Vec3| rotate_y(matrix * (rotate_x(v)-vec_origin) + vec_origin);
+    //Cullfaces are declared in model space, so they turn with the variant but not with the element
+    let rotate_cull_face = |face: schemas::models::BlockFace| {
+        let center = vec3(0.5, 0.5, 0.5);
+        let normal = Direction::from(face).to_vec().as_vec3();
+        Direction::from_normal(rotate_y(rotate_x(center + normal)) - center)
+    };
+
+    //Face textures
+    let face_texture = |face: schemas::models::BlockFace| element.faces.get(&face).and_then(|tex|
+        texture(tex).map(|(uv, animation_offset)| (
+            uv,
+            animation_offset,
+            tex.tint_index,
+            tex.cull_face.and_then(rotate_cull_face),
+        ))
+    );
+
+    let north = face_texture(schemas::models::BlockFace::North);
+    let east = face_texture(schemas::models::BlockFace::East);
+    let south = face_texture(schemas::models::BlockFace::South);
+    let west = face_texture(schemas::models::BlockFace::West);
+    let up = face_texture(schemas::models::BlockFace::Up);
+    let down = face_texture(schemas::models::BlockFace::Down);
+
+    let p000 = vertex_transform(vec3(element.from[0] / 16.0, element.from[1] / 16.0, element.from[2] / 16.0));
+    let p001 = vertex_transform(vec3(element.from[0] / 16.0, element.from[1] / 16.0, element.to[2] / 16.0));
+    let p010 = vertex_transform(vec3(element.from[0] / 16.0, element.to[1] / 16.0, element.from[2] / 16.0));
+    let p011 = vertex_transform(vec3(element.from[0] / 16.0, element.to[1] / 16.0, element.to[2] / 16.0));
+    let p100 = vertex_transform(vec3(element.to[0] / 16.0, element.from[1] / 16.0, element.from[2] / 16.0));
+    let p101 = vertex_transform(vec3(element.to[0] / 16.0, element.from[1] / 16.0, element.to[2] / 16.0));
+    let p110 = vertex_transform(vec3(element.to[0] / 16.0, element.to[1] / 16.0, element.from[2] / 16.0));
+    let p111 = vertex_transform(vec3(element.to[0] / 16.0, element.to[1] / 16.0, element.to[2] / 16.0));
+
+    let mut faces = vec![];
+    faces.extend(south.map(|south_face|{
+            BlockModelFace{
+                vertices: [
+                    BlockMeshVertex { position: p101, tex_coords: [south_face.0.1.0, south_face.0.1.1]},
+                    BlockMeshVertex { position: p111, tex_coords: [south_face.0.1.0, south_face.0.0.1]},
+                    BlockMeshVertex { position: p011, tex_coords: [south_face.0.0.0, south_face.0.0.1]},
+                    BlockMeshVertex { position: p001, tex_coords: [south_face.0.0.0, south_face.0.1.1]},
+                ],
+                normal: vec3(0.0, 0.0, 1.0),
+                animation_uv_offset: south_face.1,
+                tint_index: south_face.2,
+                shade: element.shade,
+                cull_face: south_face.3,
+            }
+        }));
+    faces.extend(west.map(|west_face|{
+            BlockModelFace{
+                vertices: [
+                    BlockMeshVertex { position: p001, tex_coords: [west_face.0.1.0, west_face.0.1.1]},
+                    BlockMeshVertex { position: p011, tex_coords: [west_face.0.1.0, west_face.0.0.1]},
+                    BlockMeshVertex { position: p010, tex_coords: [west_face.0.0.0, west_face.0.0.1]},
+                    BlockMeshVertex { position: p000, tex_coords: [west_face.0.0.0, west_face.0.1.1]},
+                ],
+                normal: vec3(-1.0, 0.0, 0.0),
+                animation_uv_offset: west_face.1,
+                tint_index: west_face.2,
+                shade: element.shade,
+                cull_face: west_face.3,
+            }
+        }));
+    faces.extend(north.map(|north_face|{
+        BlockModelFace{
+            vertices: [
+                BlockMeshVertex { position: p000, tex_coords: [north_face.0.1.0, north_face.0.1.1]},
+                BlockMeshVertex { position: p010, tex_coords: [north_face.0.1.0, north_face.0.0.1]},
+                BlockMeshVertex { position: p110, tex_coords: [north_face.0.0.0, north_face.0.0.1]},
+                BlockMeshVertex { position: p100, tex_coords: [north_face.0.0.0, north_face.0.1.1]},
+            ],
+            normal: vec3(0.0, 0.0, -1.0),
+            animation_uv_offset: north_face.1,
+            tint_index: north_face.2,
+            shade: element.shade,
+            cull_face: north_face.3,
+        }
+    }));
+    faces.extend(east.map(|east_face|{
+        BlockModelFace{
+            vertices: [
+                BlockMeshVertex { position: p100, tex_coords: [east_face.0.1.0, east_face.0.1.1]},
+                BlockMeshVertex { position: p110, tex_coords: [east_face.0.1.0, east_face.0.0.1]},
+                BlockMeshVertex { position: p111, tex_coords: [east_face.0.0.0, east_face.0.0.1]},
+                BlockMeshVertex { position: p101, tex_coords: [east_face.0.0.0, east_face.0.1.1]},
+            ],
+            normal: vec3(1.0, 0.0, 0.0),
+            animation_uv_offset: east_face.1,
+            tint_index: east_face.2,
+            shade: element.shade,
+            cull_face: east_face.3,
+        }
+    }));
+    faces.extend(up.map(|up_face|{
+        BlockModelFace{
+            vertices: [
+                BlockMeshVertex { position: p010, tex_coords: [up_face.0.1.0, up_face.0.1.1]},
+                BlockMeshVertex { position: p011, tex_coords: [up_face.0.1.0, up_face.0.0.1]},
+                BlockMeshVertex { position: p111, tex_coords: [up_face.0.0.0, up_face.0.0.1]},
+                BlockMeshVertex { position: p110, tex_coords: [up_face.0.0.0, up_face.0.1.1]},
+            ],
+            normal: vec3(0.0, 1.0, 0.0),
+            animation_uv_offset: up_face.1,
+            tint_index: up_face.2,
+            shade: element.shade,
+            cull_face: up_face.3,
+        }
+    }));
+
+    faces.extend(down.map(|down_face|{
+        BlockModelFace{
+            vertices: [
+                BlockMeshVertex { position: p000, tex_coords: [down_face.0.1.0, down_face.0.1.1]},
+                BlockMeshVertex { position: p100, tex_coords: [down_face.0.1.0, down_face.0.0.1]},
+                BlockMeshVertex { position: p101, tex_coords: [down_face.0.0.0, down_face.0.0.1]},
+                BlockMeshVertex { position: p001, tex_coords: [down_face.0.0.0, down_face.0.1.1]},
+            ],
+            normal: vec3(0.0, -1.0, 0.0),
+            animation_uv_offset: down_face.1,
+            tint_index: down_face.2,
+            shade: element.shade,
+            cull_face: down_face.3,
+        }
+    }));
+
+    //Faces turn with the variant and the element, so their normal comes from where the vertices ended up
+    faces.iter_mut().for_each(|face| {
+        face.normal = face_normal(face).unwrap_or_else(|| vertex_transform(face.normal) - vertex_transform(Vec3::ZERO));
+    });
+
+    if !declares_cull_faces {
+        faces.iter_mut().for_each(|face| face.cull_face = boundary_direction(face));
+    }
+    faces
+}
+
 /// A block model which has been baked into a mesh and is ready for rendering
 #[derive(Debug)]
 pub struct ModelMesh {
@@ -185,7 +534,8 @@ impl ModelMesh {
         resource_provider: &dyn ResourceProvider,
         block_atlas: &Atlas,
     ) -> Result<Self, MeshBakeError> {
-        
+        let mut layer = RenderLayer::Solid;
+
         let mesh = model_properties.into_iter()
             .map(|model_properties: &ModelProperties| {
                 let model_resource_path = ResourcePath::from(&model_properties.model).prepend("models/").append(".json");
@@ -233,212 +583,40 @@ impl ModelMesh {
                     }
                 };
 
+                //The mesh goes in the most permissive layer any of its face textures need
+                model.elements.iter().flatten()
+                    .flat_map(|element| element.faces.values())
+                    .for_each(|face| layer = layer.max(get_texture_layer(&(&face.texture.0).into(), block_atlas)));
+
+                //Models which don't declare a single `cullface` get them guessed from where their faces are
+                let declares_cull_faces = model.elements.iter().flatten()
+                    .flat_map(|element| element.faces.values())
+                    .any(|face| face.cull_face.is_some());
+
                 Ok(model
                     .elements
                     .iter()
                     .flatten()
-                    .flat_map(|element| {
-                        //Face textures
-                        let north = element.faces.get(&schemas::models::BlockFace::North).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-
-                        let east = element.faces.get(&schemas::models::BlockFace::East).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-
-                        let south = element.faces.get(&schemas::models::BlockFace::South).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-
-                        let west = element.faces.get(&schemas::models::BlockFace::West).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-
-                        let up = element.faces.get(&schemas::models::BlockFace::Up).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-
-                        let down = element.faces.get(&schemas::models::BlockFace::Down).as_ref().and_then(|tex|
-                            get_atlas_uv(
-                                tex,
-                                block_atlas,
-                            ).map(|uv| (
-                                //The default UV for this texture
-                                uv,
-                                //If this texture has an animation, get the offset, otherwise default to 0
-                                *block_atlas.animated_texture_offsets.read()
-                                    .get(&(&tex.texture.0).into())
-                                    .unwrap_or(&0)
-                            ))
-                        );
-                        let rot = &element.rotation;
-                        let matrix = match rot.axis {
-                            schemas::models::Axis::X => Mat3::from_rotation_x(rot.angle.to_radians()),
-                            schemas::models::Axis::Y => Mat3::from_rotation_y(rot.angle.to_radians()),
-                            schemas::models::Axis::Z => Mat3::from_rotation_z(rot.angle.to_radians()),
-                        };
-                        let vec_origin = Vec3::from_array(rot.origin)/16.0;
-
-                        let vertex_transform = |v:Vec3|{
-                            let v = match model_properties.x{
-                                0=>v,
-                                90=>vec3(v.x,1.0-v.z, v.y),
-                                180=>vec3(v.x,1.0-v.y, 1.0-v.z),
-                                270=>vec3(v.x,v.z, 1.0-v.y),
-                                _=>panic!("invalid rotation")
-                            };
-                            let v = matrix * (v-vec_origin) + vec_origin;
-                            let v = match model_properties.y{
-                                0=>v,
-                                90=>vec3(1.0-v.z, v.y, v.x),
-                                180=>vec3(1.0-v.x, v.y,1.0-v.z),
-                                270=>vec3(v.z, v.y, 1.0-v.x),
-                                _=>panic!("invalid rotation")
-                            };
-                            v
-                        };
-                        
-                        let p000 = vertex_transform(vec3(element.from[0] / 16.0, element.from[1] / 16.0, element.from[2] / 16.0));
-                        let p001 = vertex_transform(vec3(element.from[0] / 16.0, element.from[1] / 16.0, element.to[2] / 16.0));
-                        let p010 = vertex_transform(vec3(element.from[0] / 16.0, element.to[1] / 16.0, element.from[2] / 16.0));
-                        let p011 = vertex_transform(vec3(element.from[0] / 16.0, element.to[1] / 16.0, element.to[2] / 16.0));
-                        let p100 = vertex_transform(vec3(element.to[0] / 16.0, element.from[1] / 16.0, element.from[2] / 16.0));
-                        let p101 = vertex_transform(vec3(element.to[0] / 16.0, element.from[1] / 16.0, element.to[2] / 16.0));
-                        let p110 = vertex_transform(vec3(element.to[0] / 16.0, element.to[1] / 16.0, element.from[2] / 16.0));
-                        let p111 = vertex_transform(vec3(element.to[0] / 16.0, element.to[1] / 16.0, element.to[2] / 16.0));
-
-                        let mut faces = vec![];
-                        faces.extend(south.map(|south_face|{
-                                BlockModelFace{
-                                    vertices: [
-                                        BlockMeshVertex { position: p101, tex_coords: [south_face.0.1.0, south_face.0.1.1]},
-                                        BlockMeshVertex { position: p111, tex_coords: [south_face.0.1.0, south_face.0.0.1]},
-                                        BlockMeshVertex { position: p011, tex_coords: [south_face.0.0.0, south_face.0.0.1]},
-                                        BlockMeshVertex { position: p001, tex_coords: [south_face.0.0.0, south_face.0.1.1]},            
-                                    ],
-                                    normal: vec3(0.0, 0.0, 1.0),
-                                    animation_uv_offset: south_face.1,
-                                }
-                            }));
-                        faces.extend(west.map(|west_face|{
-                                BlockModelFace{
-                                    vertices: [
-                                        BlockMeshVertex { position: p001, tex_coords: [west_face.0.1.0, west_face.0.1.1]},
-                                        BlockMeshVertex { position: p011, tex_coords: [west_face.0.1.0, west_face.0.0.1]},
-                                        BlockMeshVertex { position: p010, tex_coords: [west_face.0.0.0, west_face.0.0.1]},
-                                        BlockMeshVertex { position: p000, tex_coords: [west_face.0.0.0, west_face.0.1.1]},
-                                    ],
-                                    normal: vec3(-1.0, 0.0, 0.0),
-                                    animation_uv_offset: west_face.1 ,
-                                }
-                            }));
-                        faces.extend(north.map(|north_face|{
-                            BlockModelFace{
-                                vertices: [
-                                    BlockMeshVertex { position: p000, tex_coords: [north_face.0.1.0, north_face.0.1.1]},
-                                    BlockMeshVertex { position: p010, tex_coords: [north_face.0.1.0, north_face.0.0.1]},
-                                    BlockMeshVertex { position: p110, tex_coords: [north_face.0.0.0, north_face.0.0.1]},
-                                    BlockMeshVertex { position: p100, tex_coords: [north_face.0.0.0, north_face.0.1.1]},
-                                ],
-                                normal: vec3(0.0, 0.0, -1.0),
-                                animation_uv_offset: north_face.1,
-                            }
-                        }));
-                        faces.extend(east.map(|east_face|{
-                            BlockModelFace{
-                                vertices: [
-                                    BlockMeshVertex { position: p100, tex_coords: [east_face.0.1.0, east_face.0.1.1]},
-                                    BlockMeshVertex { position: p110, tex_coords: [east_face.0.1.0, east_face.0.0.1]},
-                                    BlockMeshVertex { position: p111, tex_coords: [east_face.0.0.0, east_face.0.0.1]},
-                                    BlockMeshVertex { position: p101, tex_coords: [east_face.0.0.0, east_face.0.1.1]},
-                                ],
-                                normal: vec3(1.0, 0.0, 0.0),
-                                animation_uv_offset: east_face.1,
-                            }
-                        }));
-                        faces.extend(up.map(|up_face|{
-                            BlockModelFace{
-                                vertices: [
-                                    BlockMeshVertex { position: p010, tex_coords: [up_face.0.1.0, up_face.0.1.1]},
-                                    BlockMeshVertex { position: p011, tex_coords: [up_face.0.1.0, up_face.0.0.1]},
-                                    BlockMeshVertex { position: p111, tex_coords: [up_face.0.0.0, up_face.0.0.1]},
-                                    BlockMeshVertex { position: p110, tex_coords: [up_face.0.0.0, up_face.0.1.1]},
-                                ],
-                                normal: vec3(0.0, 1.0, 0.0),
-                                animation_uv_offset: up_face.1,
-                            }
-                        }));
-
-                        faces.extend(down.map(|down_face|{
-                            BlockModelFace{
-                                vertices: [
-                                    BlockMeshVertex { position: p000, tex_coords: [down_face.0.1.0, down_face.0.1.1]},
-                                    BlockMeshVertex { position: p100, tex_coords: [down_face.0.1.0, down_face.0.0.1]},
-                                    BlockMeshVertex { position: p101, tex_coords: [down_face.0.0.0, down_face.0.0.1]},
-                                    BlockMeshVertex { position: p001, tex_coords: [down_face.0.0.0, down_face.0.1.1]},
-                                ],
-                                normal: vec3(0.0, -1.0, 0.0),
-                                animation_uv_offset: down_face.1,
-                            }
-                        }));
-                        faces
-                    }).collect::<Vec<BlockModelFace>>())
+                    .flat_map(|element| bake_element(element, model_properties, declares_cull_faces, |face| {
+                        get_atlas_uv(face, block_atlas).map(|uv| (
+                            uv,
+                            //If this texture has an animation, get the offset, otherwise default to 0
+                            *block_atlas.animated_texture_offsets.read()
+                                .get(&(&face.texture.0).into())
+                                .unwrap_or(&0),
+                        ))
+                    }))
+                    .collect::<Vec<BlockModelFace>>())
             })
             .flatten_ok()
             .collect::<Result<Vec<BlockModelFace>, MeshBakeError>>()?;
+        Ok(Self::from_faces(layer, &mesh))
+    }
+
+    /// Sort baked faces by the neighbour that culls them, and work out which sides of the block they cover
+    pub fn from_faces(layer: RenderLayer, faces: &[BlockModelFace]) -> Self {
         let mut result = Self {
-            layer: RenderLayer::Solid,
+            layer,
             north: vec![],
             south: vec![],
             west: vec![],
@@ -448,39 +626,143 @@ impl ModelMesh {
             any: vec![],
             cull: 0,
         };
-        mesh.iter().for_each(|face|{
-            let full_face = (face.vertices[0].position.fract() == vec3(0.0, 0.0, 0.0)
-                && face.vertices[1].position.fract() == vec3(0.0, 0.0, 0.0)
-                && face.vertices[2].position.fract() == vec3(0.0, 0.0, 0.0)
-                && face.vertices[3].position.fract() == vec3(0.0, 0.0, 0.0)) as u8;
-            if face.vertices[0].position.x==0.0 && face.vertices[1].position.x==0.0 && face.vertices[2].position.x==0.0{
-                result.west.push(*face);
-                result.cull |= full_face<<Direction::West as u8;
-            }
-            else if face.vertices[0].position.x==1.0 && face.vertices[1].position.x==1.0 && face.vertices[2].position.x==1.0{
-                result.east.push(*face);
-                result.cull |= full_face<<Direction::East as u8;
-            }
-            else if face.vertices[0].position.y==0.0 && face.vertices[1].position.y==0.0 && face.vertices[2].position.y==0.0{
-                result.down.push(*face);
-                result.cull |= full_face<<Direction::Down as u8;
+        faces.iter().for_each(|face|{
+            //Only a face covering a whole side of the block hides the neighbour's face on that side
+            let full_face = face.vertices.iter().all(|vertex| vertex.position.fract() == vec3(0.0, 0.0, 0.0));
+            if let (true, Some(dir)) = (full_face, boundary_direction(face)) {
+                result.cull |= 1<<dir as u8;
             }
-            else if face.vertices[0].position.y==1.0 && face.vertices[1].position.y==1.0 && face.vertices[2].position.y==1.0{
-                result.up.push(*face);
-                result.cull |= full_face<<Direction::Up as u8;
-            }
-            else if face.vertices[0].position.z==0.0 && face.vertices[1].position.z==0.0 && face.vertices[2].position.z==0.0{
-                result.north.push(*face);
-                result.cull |= full_face<<Direction::North as u8;
+
+            match face.cull_face {
+                Some(Direction::West) => result.west.push(*face),
+                Some(Direction::East) => result.east.push(*face),
+                Some(Direction::Down) => result.down.push(*face),
+                Some(Direction::Up) => result.up.push(*face),
+                Some(Direction::North) => result.north.push(*face),
+                Some(Direction::South) => result.south.push(*face),
+                None => result.any.push(*face),
             }
-            else if face.vertices[0].position.z==1.0 && face.vertices[1].position.z==1.0 && face.vertices[2].position.z==1.0{
-                result.south.push(*face);
-                result.cull |= full_face<<Direction::South as u8;
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Mat3};
+    use minecraft_assets::schemas::blockstates::ModelProperties;
+    use minecraft_assets::schemas::models::{Element, ElementFace};
+    use serde_json::json;
+
+    use crate::mc::chunk::{diffuse_shade, RenderLayer};
+    use crate::mc::direction::Direction;
+    use crate::texture::UV;
+
+    use super::{bake_element, boundary_direction, BlockModelFace, ModelMesh};
+
+    fn variant(x: u32, y: u32) -> ModelProperties {
+        serde_json::from_value(json!({ "model": "minecraft:block/test", "x": x, "y": y })).unwrap()
+    }
+
+    fn element(faces: &[&str]) -> Element {
+        let faces: serde_json::Map<_, _> = faces.iter().map(|face| (face.to_string(), json!({ "texture": "test" }))).collect();
+        serde_json::from_value(json!({ "from": [0, 0, 0], "to": [16, 16, 16], "faces": faces })).unwrap()
+    }
+
+    /// Vanilla's bottom slab, with the cullfaces of its model if `cull_faces`. The top never has one.
+    fn slab(cull_faces: bool) -> Element {
+        let face = |side: &str| match cull_faces && side != "up" {
+            true => json!({ "texture": "test", "cullface": side }),
+            false => json!({ "texture": "test" }),
+        };
+        let faces: serde_json::Map<_, _> = ["north", "south", "west", "east", "up", "down"]
+            .iter()
+            .map(|side| (side.to_string(), face(side)))
+            .collect();
+        serde_json::from_value(json!({ "from": [0, 0, 0], "to": [16, 8, 16], "faces": faces })).unwrap()
+    }
+
+    fn texture(_face: &ElementFace) -> Option<(UV, u32)> {
+        Some((((0, 0), (16, 16)), 0))
+    }
+
+    fn bake(element: &Element, x: u32, y: u32) -> Vec<BlockModelFace> {
+        bake_element(element, &variant(x, y), true, texture)
+    }
+
+    /// Every x and y a variant can have
+    fn rotations() -> impl Iterator<Item = (u32, u32)> {
+        [0, 90, 180, 270].into_iter().flat_map(|x| [0, 90, 180, 270].map(move |y| (x, y)))
+    }
+
+    #[test]
+    fn rotated_variants_face_outwards() {
+        let cube = element(&["north", "south", "west", "east", "up", "down"]);
+        for (x, y) in rotations() {
+            let faces = bake(&cube, x, y);
+            assert_eq!(faces.len(), 6);
+            for face in faces {
+                let side = boundary_direction(&face).unwrap();
+                assert!(face.normal.abs_diff_eq(side.to_vec().as_vec3(), 1e-6), "x={x} y={y} {side:?}: {}", face.normal);
             }
-            else{
-                result.any.push(*face);
+        }
+    }
+
+    #[test]
+    fn rotated_variants_are_shaded_by_where_they_face() {
+        //The ends of a log, which face up and down until the variant lays it on its side
+        let ends = element(&["up", "down"]);
+        let shades = |x, y| bake(&ends, x, y).iter().map(|face| diffuse_shade(face.normal)).collect::<Vec<_>>();
+
+        assert_eq!(shades(0, 0), [1.0, 0.5]);
+        //axis=z
+        assert_eq!(shades(90, 0), [0.8, 0.8]);
+        //axis=x
+        assert_eq!(shades(90, 90), [0.6, 0.6]);
+    }
+
+    #[test]
+    fn element_rotation_turns_normals() {
+        let mut turned = element(&["north"]);
+        turned.rotation = serde_json::from_value(json!({ "origin": [8, 8, 8], "axis": "y", "angle": 45 })).unwrap();
+        let north = vec3(0.0, 0.0, -1.0);
+        let expected = Mat3::from_rotation_y(45f32.to_radians()) * north;
+
+        assert!(bake(&turned, 0, 0)[0].normal.abs_diff_eq(expected, 1e-6));
+        //The variant turns it further, a quarter turn clockwise seen from above
+        let expected = vec3(-expected.z, expected.y, expected.x);
+        assert!(bake(&turned, 0, 90)[0].normal.abs_diff_eq(expected, 1e-6));
+    }
+
+    #[test]
+    fn declared_cull_faces_turn_with_the_variant() {
+        let slab = slab(true);
+        for (x, y) in rotations() {
+            let faces = bake(&slab, x, y);
+            //The top of the slab is inside its block whichever way it's turned
+            assert_eq!(faces.iter().filter(|face| face.cull_face.is_none()).count(), 1, "x={x} y={y}");
+            for face in faces.iter().filter(|face| face.cull_face.is_some()) {
+                assert_eq!(face.cull_face, boundary_direction(face), "x={x} y={y}");
             }
-        });
-        Ok(result)
+        }
+
+        //Upside down, the slab's only full face covers the top of the block
+        let top = ModelMesh::from_faces(RenderLayer::Solid, &bake(&slab, 180, 0));
+        assert_eq!((top.up.len(), top.down.len(), top.any.len()), (1, 0, 1));
+        assert_eq!(top.cull, 1 << Direction::Up as u8);
+    }
+
+    #[test]
+    fn cull_faces_guessed_without_declarations() {
+        let faces = bake_element(&slab(false), &variant(0, 90), false, texture);
+        //Everything but the top lies against a side of the block
+        assert_eq!(faces.iter().filter(|face| face.cull_face.is_none()).count(), 1);
+        for face in &faces {
+            assert_eq!(face.cull_face, boundary_direction(face));
+        }
+
+        //Faces of models which declare cullfaces elsewhere are never culled if they don't have one
+        let cube = element(&["north", "south", "west", "east", "up", "down"]);
+        assert!(bake(&cube, 0, 90).iter().all(|face| face.cull_face.is_none()));
     }
 }
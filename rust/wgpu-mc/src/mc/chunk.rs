@@ -6,26 +6,70 @@
 //!
 //! Minecraft splits chunks into 16-block tall pieces called chunk sections, for
 //! rendering purposes.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use glam::{ivec3, vec3, IVec2, IVec3, Vec2Swizzles, Vec3, Vec3Swizzles};
+use glam::{ivec3, vec3, IVec2, IVec3, Vec2, Vec2Swizzles, Vec3, Vec3Swizzles};
+use minecraft_assets::schemas::blockstates::multipart::StateValue;
+use parking_lot::Mutex;
 use range_alloc::RangeAllocator;
 
-use crate::mc::block::{BlockModelFace, BlockstateKey, ChunkBlockState, ModelMesh};
+use crate::mc::biome::{pack_rgb, redstone_color, unpack_rgb, Biome, BiomeColors, BlockTint};
+use crate::mc::block::{BlockMeshVertex, BlockModelFace, BlockstateKey, ChunkBlockState, CullQuery, CullRule, ModelMesh};
 use crate::mc::direction::Direction;
-use crate::mc::BlockManager;
+use crate::mc::{position_seed, BlockManager};
+use crate::render::gpu_mesher::{GpuBlock, GpuMesher};
 use crate::render::pipeline::Vertex;
+use crate::texture::UV;
 use crate::WmRenderer;
 
 pub const CHUNK_WIDTH: usize = 16;
 pub const CHUNK_AREA: usize = CHUNK_WIDTH * CHUNK_WIDTH;
-pub const CHUNK_HEIGHT: usize = 384;
 pub const CHUNK_SECTION_HEIGHT: usize = 16;
 pub const SECTION_VOLUME: usize = CHUNK_AREA * CHUNK_SECTION_HEIGHT;
 
+/// The vertical range of a dimension, which datapacks can change. Sections outside it aren't baked or walked through
+/// when culling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WorldHeight {
+    /// The section coordinate of the lowest section
+    pub min_section: i32,
+    /// How many sections high the world is
+    pub sections: i32,
+}
+
+impl WorldHeight {
+    pub fn min_y(&self) -> i32 {
+        self.min_section * CHUNK_SECTION_HEIGHT as i32
+    }
+
+    pub fn height(&self) -> i32 {
+        self.sections * CHUNK_SECTION_HEIGHT as i32
+    }
+
+    /// The section coordinate of the highest section
+    pub fn max_section(&self) -> i32 {
+        self.min_section + self.sections - 1
+    }
+
+    pub fn contains_section(&self, y: i32) -> bool {
+        (self.min_section..=self.max_section()).contains(&y)
+    }
+}
+
+impl Default for WorldHeight {
+    /// The overworld's, from y -64 to 320
+    fn default() -> Self {
+        Self {
+            min_section: -4,
+            sections: 24,
+        }
+    }
+}
+
 
 #[derive(Clone, Copy, Debug)]
 pub struct LightLevel {
@@ -55,26 +99,219 @@ pub trait BlockStateProvider: Send + Sync {
     fn get_light_level(&self, pos:IVec3) -> LightLevel;
 
     fn is_section_empty(&self, rel_pos: IVec3) -> bool;
+
+    /// The biome at a position, which decides the colour of tinted faces like grass and leaves.
+    /// Providers without biome information get the default biome everywhere.
+    fn get_biome(&self, _pos: IVec3) -> Biome {
+        Biome::default()
+    }
 }
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+impl<T: BlockStateProvider + ?Sized> BlockStateProvider for Box<T> {
+    fn get_state(&self, pos: IVec3) -> ChunkBlockState {
+        (**self).get_state(pos)
+    }
+
+    fn get_light_level(&self, pos: IVec3) -> LightLevel {
+        (**self).get_light_level(pos)
+    }
+
+    fn is_section_empty(&self, rel_pos: IVec3) -> bool {
+        (**self).is_section_empty(rel_pos)
+    }
+
+    fn get_biome(&self, pos: IVec3) -> Biome {
+        (**self).get_biome(pos)
+    }
+}
+
+/// Ordered from most to least restrictive, so the layer a mesh needs is the `max` of its faces' layers
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub enum RenderLayer {
     Solid=0,
     Cutout=1,
     Transparent=2,
 }
 
+impl RenderLayer {
+    pub const ALL: [RenderLayer; 3] = [RenderLayer::Solid, RenderLayer::Cutout, RenderLayer::Transparent];
+
+    /// How shader packs refer to the layer
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderLayer::Solid => "solid",
+            RenderLayer::Cutout => "cutout",
+            RenderLayer::Transparent => "transparent",
+        }
+    }
+}
+
+/// Settings which change how sections are meshed. They only apply to sections baked after they're changed.
+#[derive(Debug, Clone)]
+pub struct BakeSettings {
+    /// Vanilla style smooth lighting and ambient occlusion. When disabled every face takes the light level
+    /// of the block it faces.
+    pub smooth_lighting: bool,
+    /// How many blocks away biome colours are blended from, up to 7 like vanilla. 0 disables blending.
+    pub biome_blend_radius: u8,
+    /// How many sections past the view distance simplified meshes of sections are kept and drawn, see [LodCells].
+    /// 0 disables them.
+    pub lod_distance: u8,
+    /// Which [RenderLayer]s get full block faces merged into larger quads where they look the same, see
+    /// [GreedyFaces]. The terrain shaders have to repeat the texture across those quads, so this comes from the
+    /// shader pack.
+    pub greedy_meshing: [bool; RenderLayer::ALL.len()],
+    /// Leave meshing the sections it can handle to a compute shader, see [crate::render::gpu_mesher]. The compute
+    /// shader doesn't merge faces, so while any layer has greedy meshing on every section is meshed on the CPU.
+    pub gpu_meshing: bool,
+    /// How far the camera has to move, in blocks, before a section's transparent quads get sorted again. They're
+    /// always sorted again when the camera moves into another section, see [TransparentQuads::needs_sort].
+    pub transparent_resort_distance: f32,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        Self {
+            smooth_lighting: true,
+            biome_blend_radius: 2,
+            lod_distance: 8,
+            greedy_meshing: [false; RenderLayer::ALL.len()],
+            gpu_meshing: false,
+            transparent_resort_distance: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum Fluid {
+    Water,
+    Lava,
+}
+
+impl Fluid {
+    pub const ALL: [Fluid; 2] = [Fluid::Water, Fluid::Lava];
+
+    /// The still and flowing textures
+    pub fn textures(&self) -> (&'static str, &'static str) {
+        match self {
+            Fluid::Water => ("minecraft:block/water_still", "minecraft:block/water_flow"),
+            Fluid::Lava => ("minecraft:block/lava_still", "minecraft:block/lava_flow"),
+        }
+    }
+
+    pub fn layer(&self) -> RenderLayer {
+        match self {
+            Fluid::Water => RenderLayer::Transparent,
+            Fluid::Lava => RenderLayer::Solid,
+        }
+    }
+}
+
+/// The fluid in a blockstate and how much of it there is
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct FluidState {
+    pub fluid: Fluid,
+    /// The blockstate's `level` property. 0 is a source block, 1 to 7 flow further away from it
+    /// and 8 and up are falling.
+    pub level: u8,
+}
+
+impl FluidState {
+    /// Blocks which are always full of water, without having a `waterlogged` property
+    const ALWAYS_WATERLOGGED: &'static [&'static str] = &[
+        "minecraft:bubble_column",
+        "minecraft:kelp",
+        "minecraft:kelp_plant",
+        "minecraft:seagrass",
+        "minecraft:tall_seagrass",
+    ];
+
+    /// Work out the fluid in a blockstate from its block name and properties, if there is any
+    pub fn from_blockstate<'a>(
+        block_name: &str,
+        mut properties: impl Iterator<Item = (&'a str, &'a StateValue)>,
+    ) -> Option<Self> {
+        let fluid = match block_name {
+            "minecraft:water" => Fluid::Water,
+            "minecraft:lava" => Fluid::Lava,
+            _ if Self::ALWAYS_WATERLOGGED.contains(&block_name) => {
+                return Some(Self { fluid: Fluid::Water, level: 0 })
+            }
+            _ => {
+                return properties
+                    .any(|(name, value)| name == "waterlogged" && matches!(value, StateValue::Bool(true)))
+                    .then_some(Self { fluid: Fluid::Water, level: 0 })
+            }
+        };
+
+        let level = properties
+            .find_map(|(name, value)| match value {
+                StateValue::String(level) if name == "level" => level.parse().ok(),
+                _ => None,
+            })
+            .unwrap_or(0);
+
+        Some(Self { fluid, level })
+    }
+
+    /// Height of the fluid's surface within the block, between 0 and 1. Vanilla has 8 amounts of fluid in 9ths of a block
+    pub fn height(&self) -> f32 {
+        let amount = if self.level == 0 || self.level >= 8 { 8 } else { 8 - self.level };
+        amount as f32 / 9.0
+    }
+}
+
+/// Where the first frame of a fluid's textures are in the block atlas
+#[derive(Debug, Copy, Clone)]
+pub struct FluidSprites {
+    pub still: UV,
+    pub flow: UV,
+}
+
+/// Where a layer of a section is in the chunk buffer. It has no indices there, every quad is drawn with the shared
+/// [crate::render::terrain::QuadIndices]. The light of its vertices comes right after them, 2 bytes each, so it can
+/// be rewritten on its own when only the light changes.
 #[derive(Clone)]
 pub struct SectionRanges {
     pub vertex_range: Range<u32>,
-    pub index_range: Range<u32>,
+    pub light_range: Range<u32>,
+    /// Only for layers meshed by [crate::render::gpu_mesher::GpuMesher], which notes where the light of each vertex
+    /// came from here, one u32 per vertex. Empty for layers meshed on the CPU, which keep their [LightSamples].
+    pub sample_range: Range<u32>,
+}
+impl SectionRanges {
+    fn new(range:Range<u32>,vertices:u32)->Self{
+        let light_end = range.start+vertices+vertices/QUAD_U32S*2;
+        SectionRanges{vertex_range:range.start..range.start+vertices,light_range:range.start+vertices..light_end,sample_range:light_end..range.end}
+    }
+    pub fn quads(&self)->u32{
+        self.vertex_range.len() as u32/QUAD_U32S
+    }
+    /// The whole of the layer in the chunk buffer, vertices, light and light samples
+    pub fn range(&self)->Range<u32>{
+        self.vertex_range.start..self.sample_range.end
+    }
 }
 
+/// Size of a quad's vertices, in u32s
+const QUAD_U32S:u32 = (Vertex::VERTEX_LENGTH as u32/4)*4;
+
+
+/// Once this much of the chunk buffer's free space is outside its largest free range, it gets compacted
+pub const CHUNK_BUFFER_COMPACT_THRESHOLD: f32 = 0.5;
 
 ///The struct representing a Chunk section, with various render layers, split into sections
 pub struct SectionStorage{
     storage:HashMap<IVec3,Section>,
     allocator:RangeAllocator<u32>,
+    /// Size of the chunk buffer, in u32s
+    capacity:u32,
+    /// How much of the chunk buffer sections are using, in u32s
+    used:u32,
+    /// Whether ranges were freed since the fragmentation was last checked
+    freed:bool,
+    /// Changes whenever a section is added, removed or moved
+    version:u64,
     width:i32,
 }
 impl SectionStorage {
@@ -83,54 +320,300 @@ impl SectionStorage {
             storage:HashMap::new(),
             width:0,
             allocator: RangeAllocator::new(0..range),
+            capacity:range,
+            used:0,
+            freed:false,
+            version:0,
         }
     }
     pub fn clear(&mut self){
         self.allocator.reset();
         self.storage.clear();
+        self.used = 0;
+        self.freed = false;
+        self.version += 1;
     }
     pub fn set_width(&mut self,w:i32){
         self.width = w;
     }
-    pub fn trim(&mut self,pos:IVec2){
-        let mut to_remove = vec![];
-        for (k,section) in &self.storage{
+    pub fn width(&self)->i32{
+        self.width
+    }
+    pub fn capacity(&self)->u32{
+        self.capacity
+    }
+    pub fn used(&self)->u32{
+        self.used
+    }
+    pub fn version(&self)->u64{
+        self.version
+    }
+    fn free(&mut self,section:&Section){
+        for l in section.layers.iter().flatten(){
+            self.allocator.free_range(l.range());
+            self.used -= l.range().len() as u32;
+        }
+        self.freed = true;
+        self.version += 1;
+    }
+    /// Unload the sections too far from `pos`, and cancel their pending bakes
+    pub fn trim(&mut self,pos:IVec2,versions:&BakeVersions){
+        let radius = self.width + 2;//temp fix until proper sync
+        versions.retain(|k| {
             let dist = (k.xz()-pos).abs();
-            let radius = self.width + 2;//temp fix until proper sync
-            if dist.x>radius || dist.y>radius{
-                to_remove.push(*k);
-                for layer in &section.layers{
-                    if let Some(l) = layer.as_ref(){
-                        self.allocator.free_range(l.vertex_range.clone());
-                        self.allocator.free_range(l.index_range.clone());
-                    }
+            dist.x<=radius && dist.y<=radius
+        });
+        self.retain_within(pos,radius);
+    }
+    /// Unload the sections more than `radius` columns away from `pos`
+    pub fn retain_within(&mut self,pos:IVec2,radius:i32){
+        let out_of_range = |k:IVec3| {
+            let dist = (k.xz()-pos).abs();
+            dist.x>radius || dist.y>radius
+        };
+
+        let to_remove:Vec<IVec3> = self.storage.keys().copied().filter(|k| out_of_range(*k)).collect();
+        for pos in to_remove{
+            let section = self.storage.remove(&pos).unwrap();
+            self.free(&section);
+        }
+    }
+    /// Put the baked layers of a section in the chunk buffer, in place of what it had before. If they don't fit the
+    /// section is left out, and how many u32s it needs is returned so room can be made with [Self::compact] or
+    /// [Self::evict_farthest].
+    pub fn replace(&mut self, pos:IVec3,baked_layers:&Vec<BakedLayer>,visibility:SectionVisibility)->Result<&Section,u32>{
+        let section = Section{layers:Vec::with_capacity(baked_layers.len()),
+        transparent: baked_layers.get(RenderLayer::Transparent as usize).and_then(TransparentQuads::new),
+        light_samples: baked_layers.iter().map(|layer| layer.light_samples.clone()).collect(),
+        merged: baked_layers.iter().map(|layer| layer.merged.clone()).collect(),
+        gpu_meshed: false,
+        visibility};
+        let sizes:Vec<(u32,u32)> = baked_layers.iter().map(|layer| (layer.size(),layer.vertices.len() as u32/4)).collect();
+        self.insert(pos,section,&sizes)
+    }
+    /// [Self::replace] for a section which [crate::render::gpu_mesher::GpuMesher] meshes once it's in the chunk buffer,
+    /// with `quads` in each layer
+    pub fn replace_gpu(&mut self, pos:IVec3,quads:[u32;RenderLayer::ALL.len()],visibility:SectionVisibility)->Result<&Section,u32>{
+        let section = Section{gpu_meshed:true,visibility,..Section::new()};
+        let sizes:Vec<(u32,u32)> = quads.iter().map(|quads| (quads*GpuMeshInput::QUAD_SIZE,quads*QUAD_U32S)).collect();
+        self.insert(pos,section,&sizes)
+    }
+    /// Give each layer of a section `size` u32s of the chunk buffer, the first `vertices` of which are its vertices
+    /// The new ranges are allocated before the old ones are freed, so if they don't fit the section keeps what it had.
+    fn insert(&mut self,pos:IVec3,mut section:Section,sizes:&[(u32,u32)])->Result<&Section,u32>{
+        for &(size,vertices) in sizes{
+            if vertices==0{
+                section.layers.push(None);
+                continue;
+            }
+            match self.allocator.allocate_range(size){
+                Ok(range) => {
+                    self.used += range.len() as u32;
+                    section.layers.push(Some(SectionRanges::new(range,vertices)));
+                }
+                Err(_) => {
+                    self.free(&section);
+                    let needed = sizes.iter().map(|(size,_)| size).sum();
+                    return Err(needed);
                 }
             }
         }
-        to_remove.iter().for_each(|pos|{self.storage.remove(pos);});
+
+        if let Some(previous_section) = self.storage.insert(pos,section){
+            self.free(&previous_section);
+        }
+        self.version += 1;
+        Ok(&self.storage[&pos])
     }
-    pub fn replace(&mut self, pos:IVec3,baked_layers:&Vec<BakedLayer>)->Section{
-        if let Some(previous_section) = self.storage.get(&pos){
-            for layer in &previous_section.layers{
-                if let Some(l) = layer.as_ref(){
-                    self.allocator.free_range(l.vertex_range.clone());
-                    self.allocator.free_range(l.index_range.clone());
-                }
+    /// How much of the free space in the chunk buffer is outside its largest free range, from 0 to 1
+    pub fn fragmentation(&self)->f32{
+        let mut ranges:Vec<Range<u32>> = self.storage.values()
+            .flat_map(|section| section.layers.iter().flatten())
+            .map(SectionRanges::range)
+            .collect();
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        let mut largest_free = 0;
+        let mut end = 0;
+        for range in ranges{
+            largest_free = largest_free.max(range.start-end);
+            end = range.end;
+        }
+        largest_free = largest_free.max(self.capacity-end);
+
+        let free = self.capacity-self.used;
+        if free==0{
+            0.0
+        }else{
+            1.0-largest_free as f32/free as f32
+        }
+    }
+    /// Whether sections were unloaded since this was last called, and left the chunk buffer fragmented past
+    /// [CHUNK_BUFFER_COMPACT_THRESHOLD]
+    pub fn needs_compaction(&mut self)->bool{
+        if !std::mem::take(&mut self.freed){
+            return false;
+        }
+        self.fragmentation()>CHUNK_BUFFER_COMPACT_THRESHOLD
+    }
+    /// Pack the ranges of every section together at the start of a chunk buffer of `capacity` u32s, which has to
+    /// fit them all. Returns what to copy from the old chunk buffer to the new one, as (old start, new start, length)
+    /// in u32s.
+    pub fn compact(&mut self,capacity:u32)->Vec<(u32,u32,u32)>{
+        assert!(capacity>=self.used, "capacity: {}, used: {}", capacity, self.used);
+
+        let mut ranges:Vec<&mut SectionRanges> = self.storage.values_mut()
+            .flat_map(|section| section.layers.iter_mut().flatten())
+            .collect();
+        ranges.sort_unstable_by_key(|ranges| ranges.vertex_range.start);
+
+        let mut copies:Vec<(u32,u32,u32)> = vec![];
+        let mut end = 0;
+        for ranges in ranges{
+            let range = ranges.range();
+            let len = range.end-range.start;
+            match copies.last_mut(){
+                //Ranges which were already next to each other get copied together
+                Some((src,_,copy_len)) if *src+*copy_len==range.start => *copy_len+=len,
+                _ => copies.push((range.start,end,len)),
             }
+            *ranges = SectionRanges::new(end..end+len,ranges.vertex_range.len() as u32);
+            end += len;
         }
-        let section = Section{layers:baked_layers.iter().map(|layer|{
-            if layer.indices.len()>0{
-                Some(SectionRanges{
-                    vertex_range:self.allocator.allocate_range(layer.vertices.len() as u32/4).unwrap(),
-                    index_range:self.allocator.allocate_range(layer.indices.len() as u32/4).unwrap()
-                })
+
+        self.allocator = RangeAllocator::new(0..capacity);
+        if end>0{
+            self.allocator.allocate_range(end).unwrap();
+        }
+        self.capacity = capacity;
+        self.freed = false;
+        self.version += 1;
+        copies
+    }
+    /// Unload the sections furthest from `camera_section`, as long as they're further away than `pos`, until
+    /// no more than `max_used` u32s are in use. Returns the unloaded sections, or `None` if unloading all of those
+    /// sections wouldn't be enough, in which case none are.
+    pub fn evict_farthest(&mut self,camera_section:IVec2,pos:IVec3,max_used:u32)->Option<Vec<IVec3>>{
+        let distance = |k:IVec3| (k.xz()-camera_section).length_squared();
+
+        let mut further:Vec<IVec3> = self.storage.keys().copied().filter(|k| distance(*k)>distance(pos)).collect();
+        let further_used:u32 = further.iter().map(|k| self.storage[k].size()).sum();
+        if self.used-further_used>max_used{
+            return None;
+        }
+        further.sort_unstable_by_key(|k| distance(*k));
+
+        let mut evicted = vec![];
+        while self.used>max_used{
+            let k = further.pop().unwrap();
+            let section = self.storage.remove(&k).unwrap();
+            self.free(&section);
+            evicted.push(k);
+        }
+        Some(evicted)
+    }
+    /// Sort the transparent quads of every section the camera moved far enough in since they were last sorted, see
+    /// [TransparentQuads::needs_sort]. `camera_pos` is relative to the origin of the `camera_section` column.
+    /// Returns the sorted vertices and light of each section and where they go in the chunk buffer, in u32s.
+    pub fn sort_transparent(&mut self, camera_section:IVec2, camera_pos:Vec3, resort_distance:f32)->Vec<(u32,Vec<u8>)>{
+        self.storage.iter_mut().filter_map(|(pos,section)|{
+            let ranges = section.layers.get(RenderLayer::Transparent as usize)?.as_ref()?;
+            let quads = section.transparent.as_mut()?;
+
+            let origin = ivec3(pos.x-camera_section.x, pos.y, pos.z-camera_section.y).as_vec3()*16.0;
+            let camera = camera_pos-origin;
+            if !quads.needs_sort(camera,resort_distance){
+                return None;
+            }
+
+            let (vertices, light) = quads.sort(camera);
+            Some([(ranges.vertex_range.start, vertices), (ranges.light_range.start, light)])
+        }).flatten().collect()
+    }
+    /// Work out the light of a section's vertices again from the blocks they sampled it from, for when the light
+    /// changed but the blocks didn't. `provider` is the same as for baking it.
+    /// Returns the new light of each layer and where it goes in the chunk buffer, in u32s, and whether the section has
+    /// to be rebaked because the faces merged into one of its quads don't all get the same light any more.
+    pub fn relight<Provider: BlockStateProvider + ?Sized>(&mut self, pos:IVec3, provider:&Provider)->(Vec<(u32,Vec<u8>)>,bool){
+        let Some(section) = self.storage.get_mut(&pos) else {
+            return (vec![],false);
+        };
+
+        let rebake = section.merged.iter().flatten().any(|merged|{
+            let light = merged.samples[0].light(provider);
+            merged.samples.iter().any(|samples| samples.light(provider)!=light)
+        });
+        let relit = section.layers.iter().zip(&section.light_samples).enumerate().filter_map(|(layer,(ranges,samples))|{
+            let ranges = ranges.as_ref()?;
+            let light:Vec<u8> = samples.iter().flat_map(|samples| samples.light(provider)).collect();
+
+            match section.transparent.as_mut(){
+                //The quads are in the order they were last sorted in
+                Some(quads) if layer==RenderLayer::Transparent as usize => {
+                    for (quad,light) in quads.light.iter_mut().zip(light.chunks_exact(8)){
+                        quad.copy_from_slice(light);
+                    }
+                    Some((ranges.light_range.start, quads.sorted_light()))
+                }
+                _ => Some((ranges.light_range.start, light)),
             }
-            else{
-                None
+        }).collect();
+        (relit,rebake)
+    }
+    /// The sections which can be seen from the `camera` section, found by walking outwards from it through the
+    /// faces each section connects, like vanilla and Sodium do. Sections `in_view` returns false for are skipped, and
+    /// so is everything only visible through them. Sections which aren't loaded count as empty, the walk stays within
+    /// the loaded columns and the `height` of the world.
+    pub fn visible_sections(&self,camera:IVec3,height:WorldHeight,mut in_view:impl FnMut(IVec3)->bool)->Vec<IVec3>{
+        let bounds = self.storage.keys().fold(None,|bounds:Option<(IVec3,IVec3)>,pos|{
+            Some(bounds.map_or((*pos,*pos),|(min,max)| (min.min(*pos),max.max(*pos))))
+        });
+        let Some((mut min,mut max)) = bounds else {
+            return vec![];
+        };
+        //Nothing is drawn outside the world's height, so there's no seeing through there
+        min.y = height.min_section;
+        max.y = height.max_section();
+        //From outside the loaded sections, like above the build limit, start from the closest one
+        let start = camera.clamp(min,max);
+
+        let mut visible = vec![];
+        //The faces each queued section was entered through, a bit per Direction
+        let mut entered:HashMap<IVec3,u8> = HashMap::from([(start,0)]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(pos) = queue.pop_front(){
+            visible.push(pos);
+            let from = entered[&pos];
+            let visibility = self.storage.get(&pos).map_or(SectionVisibility::ALL,|section| section.visibility);
+
+            for dir in Direction::ALL{
+                let step = dir.to_vec();
+                //Only walking away from the camera keeps paths from wrapping around behind walls, and means every
+                //way into a section is known by the time it's reached. The camera sees out of its own section
+                //whatever is in it.
+                if (pos-start).dot(step)<0 || (pos!=start && !visibility.connects(from,dir)){
+                    continue;
+                }
+                let next = pos+step;
+                if next.cmplt(min).any() || next.cmpgt(max).any(){
+                    continue;
+                }
+
+                let face = 1<<dir.opposite() as u8;
+                match entered.get_mut(&next){
+                    Some(faces) => *faces |= face,
+                    None if in_view(next) => {
+                        entered.insert(next,face);
+                        queue.push_back(next);
+                    }
+                    None => {}
+                }
             }
-        }).collect()};
-        self.storage.insert(pos,section.clone());
-        section
+        }
+        visible
+    }
+    pub fn get(&self,pos:&IVec3)->Option<&Section>{
+        self.storage.get(pos)
     }
     pub fn iter(&self)->std::collections::hash_map::Iter<IVec3, Section>{
         self.storage.iter()
@@ -140,19 +623,158 @@ impl SectionStorage {
 #[derive(Clone)]
 pub struct Section {
     pub layers: Vec<Option<SectionRanges>>,
+    pub transparent: Option<TransparentQuads>,
+    /// Where each vertex of each layer got its light from, in the order they were baked, see [SectionStorage::relight]
+    pub light_samples: Vec<Vec<LightSamples>>,
+    /// The quads of each layer which cover more than one block, see [BakedLayer::merged]
+    pub merged: Vec<Vec<MergedQuad>>,
+    /// Whether it was meshed by [crate::render::gpu_mesher::GpuMesher], which keeps its light samples in the chunk
+    /// buffer instead, see [SectionRanges::sample_range]
+    pub gpu_meshed: bool,
+    pub visibility: SectionVisibility,
 }
 
 impl Section {
     pub fn new() -> Self {
         Self {
             layers: Vec::new(),
+            transparent: None,
+            light_samples: Vec::new(),
+            merged: Vec::new(),
+            gpu_meshed: false,
+            visibility: SectionVisibility::ALL,
+        }
+    }
+
+    /// How many u32s of the chunk buffer the section takes up
+    pub fn size(&self) -> u32 {
+        self.layers.iter().flatten().map(|ranges| ranges.range().len() as u32).sum()
+    }
+}
+
+/// Which faces of a section can see each other through the blocks in it which aren't opaque, a bit for each pair
+/// of [Direction]s
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionVisibility(u64);
+
+impl SectionVisibility {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self((1 << 36) - 1);
+
+    /// Flood fill the blocks of a section which aren't `opaque`, which takes positions within the section
+    pub fn flood_fill(opaque: impl Fn(IVec3) -> bool) -> Self {
+        let pos = |index: usize| ivec3(index as i32 & 15, index as i32 >> 8, (index as i32 & 255) >> 4);
+        let index = |pos: IVec3| ((pos.y << 8) | (pos.z << 4) | pos.x) as usize;
+
+        //Opaque blocks start out filled, so they're never walked into
+        let mut filled: Vec<bool> = (0..SECTION_VOLUME).map(|block_index| opaque(pos(block_index))).collect();
+        let mut visibility = Self::NONE;
+        let mut stack = vec![];
+
+        for block_index in 0..SECTION_VOLUME {
+            if filled[block_index] {
+                continue;
+            }
+            filled[block_index] = true;
+            stack.push(pos(block_index));
+
+            //The faces of the section this space touches, a bit per Direction
+            let mut faces = 0u8;
+            while let Some(pos) = stack.pop() {
+                for dir in Direction::ALL {
+                    let next = pos + dir.to_vec();
+                    if next.cmplt(IVec3::ZERO).any() || next.cmpgt(IVec3::splat(15)).any() {
+                        faces |= 1 << dir as u8;
+                    } else if !filled[index(next)] {
+                        filled[index(next)] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+
+            for from in Direction::ALL {
+                if faces & 1 << from as u8 != 0 {
+                    visibility.0 |= (faces as u64) << (from as u64 * 6);
+                }
+            }
+        }
+
+        visibility
+    }
+
+    /// Whether any of the `from` faces, a bit per [Direction], can see the `to` face
+    pub fn connects(&self, from: u8, to: Direction) -> bool {
+        Direction::ALL
+            .into_iter()
+            .any(|face| from & 1 << face as u8 != 0 && self.0 & 1 << (face as u64 * 6 + to as u64) != 0)
+    }
+}
+
+/// Transparent quads have to be drawn back to front to blend properly, so a section keeps what it needs
+/// to re-sort them when the camera moves, without re-meshing
+#[derive(Clone)]
+pub struct TransparentQuads {
+    /// Centre of each quad, relative to the section's origin
+    pub centroids: Vec<Vec3>,
+    /// The vertices of each quad, in the order they were baked. Every quad has the same indices, so they're sorted by
+    /// moving the vertices around instead.
+    pub vertices: Vec<[u8; 4 * Vertex::VERTEX_LENGTH]>,
+    /// The light of each quad's vertices, in the order they were baked
+    pub light: Vec<[u8; 8]>,
+    /// Which quad is where in the chunk buffer since they were last sorted
+    pub order: Vec<usize>,
+    /// Where the camera was, relative to the section's origin, when the quads were last sorted
+    pub sorted_for: Option<Vec3>,
+}
+
+impl TransparentQuads {
+    fn new(layer: &BakedLayer) -> Option<Self> {
+        if layer.centroids.is_empty() {
+            return None;
         }
+
+        Some(Self {
+            centroids: layer.centroids.clone(),
+            vertices: layer.vertices.chunks_exact(4 * Vertex::VERTEX_LENGTH).map(|quad| quad.try_into().unwrap()).collect(),
+            light: layer.light.chunks_exact(8).map(|quad| quad.try_into().unwrap()).collect(),
+            order: (0..layer.centroids.len()).collect(),
+            sorted_for: None,
+        })
+    }
+
+    /// Whether the quads have to be sorted again for `camera`, relative to the section's origin. That's when they
+    /// never were, when the camera moved `resort_distance` blocks since, or when it's in another section than it was,
+    /// since whether it's inside the section or on which side of it changes the order the most.
+    pub fn needs_sort(&self, camera: Vec3, resort_distance: f32) -> bool {
+        let section = |pos: Vec3| (pos / 16.0).floor();
+        self.sorted_for.is_none_or(|sorted_for| {
+            sorted_for.distance(camera) >= resort_distance || section(sorted_for) != section(camera)
+        })
+    }
+
+    /// Order the quads from farthest to closest to `camera` and return their vertices and light in that order
+    pub fn sort(&mut self, camera: Vec3) -> (Vec<u8>, Vec<u8>) {
+        let distances = self.centroids.iter().map(|centroid| centroid.distance_squared(camera)).collect::<Vec<f32>>();
+
+        self.order.sort_unstable_by(|a, b| distances[*b].total_cmp(&distances[*a]));
+
+        self.sorted_for = Some(camera);
+        let vertices = self.order.iter()
+            .flat_map(|quad| self.vertices[*quad])
+            .collect();
+        (vertices, self.sorted_light())
+    }
+
+    /// The light of the quads, in the order they were last sorted in
+    pub fn sorted_light(&self) -> Vec<u8> {
+        self.order.iter().flat_map(|quad| self.light[*quad]).collect()
     }
 }
 
 
+/// The model of a block, `world_pos` deciding which one it gets if the state has random models
 #[inline]
-fn get_block(block_manager: &BlockManager, state: ChunkBlockState) -> Option<Arc<ModelMesh>> {
+fn get_block(block_manager: &BlockManager, state: ChunkBlockState, world_pos: IVec3) -> Option<Arc<ModelMesh>> {
     let key = match state {
         ChunkBlockState::Air => return None,
         ChunkBlockState::State(key) => key,
@@ -163,110 +785,2452 @@ fn get_block(block_manager: &BlockManager, state: ChunkBlockState) -> Option<Arc
             .blocks
             .get_index(key.block as usize)?
             .1
-            .get_model(key.augment, 0),
+            .get_model(key.augment, position_seed(world_pos)),
     )
 }
 
-pub fn bake_section<Provider: BlockStateProvider>(pos: IVec3, wm:&WmRenderer ,bsp: &Provider, ) {
-
-    let bm = wm.mc.block_manager.read();
+/// Whether the face on the `dir` side of a block is hidden by its neighbour there
+fn is_face_culled(
+    block_manager: &BlockManager,
+    key: BlockstateKey,
+    dir: Direction,
+    neighbour: ChunkBlockState,
+    neighbour_world_pos: IVec3,
+) -> bool {
+    let neighbour_key = match neighbour {
+        ChunkBlockState::Air => return false,
+        ChunkBlockState::State(neighbour_key) => neighbour_key,
+    };
+    let neighbour_mesh = match get_block(block_manager, neighbour, neighbour_world_pos) {
+        None => return false,
+        Some(mesh) => mesh,
+    };
+    let neighbour_layer = block_manager.get_render_layer(neighbour_key.block, &neighbour_mesh);
 
-    let baked_section = bake_layers(pos, &bm, bsp);
+    //Faces can only be hidden by something that can't be seen through
+    if (neighbour_mesh.cull >> dir.opposite() as u8) & 1 == 1 && neighbour_layer == RenderLayer::Solid {
+        return true;
+    }
 
-    wm.chunk_update_queue.0.send((pos,baked_section)).unwrap();
+    match block_manager.cull_rules.get(&key.block) {
+        None => false,
+        Some(CullRule::SameBlock) => neighbour_key.block == key.block,
+        Some(CullRule::Custom(rule)) => rule(&CullQuery {
+            block: key,
+            dir,
+            neighbour: neighbour_key,
+            neighbour_mesh: &neighbour_mesh,
+            neighbour_layer,
+        }),
+    }
 }
 
-#[derive(Clone, Default)]
-pub struct BakedLayer {
-    pub vertices: Vec<u8>,
-    pub indices: Vec<u8>,
+/// Whether a block is a full, solid cube, which blocks light and casts ambient occlusion
+fn is_opaque(block_manager: &BlockManager, state: ChunkBlockState, world_pos: IVec3) -> bool {
+    let key = match state {
+        ChunkBlockState::Air => return false,
+        ChunkBlockState::State(key) => key,
+    };
+
+    match get_block(block_manager, state, world_pos) {
+        None => false,
+        Some(mesh) => mesh.cull == 0b111111 && block_manager.get_render_layer(key.block, &mesh) == RenderLayer::Solid,
+    }
 }
 
-fn bake_layers<Provider: BlockStateProvider>(
+/// The colour the tinted faces of a block get, as 0xRRGGBB. `pos` is relative to the section at `origin`.
+fn block_tint<Provider: BlockStateProvider + ?Sized>(
     pos: IVec3,
+    origin: IVec3,
+    key: BlockstateKey,
     block_manager: &BlockManager,
+    biome_colors: &BiomeColors,
     state_provider: &Provider,
-) -> Vec<BakedLayer> {
-    let mut layers = vec![BakedLayer::default();3];
+    settings: &BakeSettings,
+) -> u32 {
+    let tint = match block_manager.tints.get(&key.block) {
+        None => return 0xffffff,
+        Some(tint) => *tint,
+    };
 
-    if state_provider.is_section_empty(ivec3(0, 0, 0)) {
-        return layers;
+    match tint {
+        BlockTint::Constant(color) => color,
+        BlockTint::Redstone => {
+            let power = block_manager
+                .blocks
+                .get_index(key.block as usize)
+                .and_then(|(_, block)| block.get_property(key.augment, "power"))
+                .and_then(|power| power.parse().ok())
+                .unwrap_or(0);
+
+            redstone_color(power)
+        }
+        _ => biome_tint(pos, origin, tint, biome_colors, state_provider, settings),
     }
+}
 
-    for block_index in 0..16 * 16 * 16 {
-        let pos = ivec3(block_index & 15, block_index >> 8, (block_index & 255) >> 4);
+/// A biome dependent colour, averaged over a square of columns around the block like vanilla's biome blend
+fn biome_tint<Provider: BlockStateProvider + ?Sized>(
+    pos: IVec3,
+    origin: IVec3,
+    tint: BlockTint,
+    biome_colors: &BiomeColors,
+    state_provider: &Provider,
+    settings: &BakeSettings,
+) -> u32 {
+    let radius = settings.biome_blend_radius.min(7) as i32;
+    let mut sum = [0u32; 3];
 
-        let fpos= vec3(pos.x as f32,pos.y as f32,pos.z as f32);
+    for x in -radius..=radius {
+        for z in -radius..=radius {
+            let column = pos + ivec3(x, 0, z);
+            let rgb = unpack_rgb(biome_colors.get(&state_provider.get_biome(column), tint, origin + column));
+            (0..3).for_each(|channel| sum[channel] += rgb[channel]);
+        }
+    }
 
-        let block_state: ChunkBlockState = state_provider.get_state(pos);
+    let count = ((radius * 2 + 1) * (radius * 2 + 1)) as u32;
+    pack_rgb(sum.map(|channel| channel / count))
+}
 
-        if let Some(model_mesh) = get_block(block_manager, block_state){
-
-            const INDICES: [u32; 6] = [1, 3, 0, 2, 3, 1];
-            let mut add_quad = |face:&BlockModelFace,light_level: LightLevel|{
-                let baked_layer = &mut layers[RenderLayer::Solid as usize];
-                let vec_index = baked_layer.vertices.len()/Vertex::VERTEX_LENGTH;
-
-                baked_layer
-                    .vertices
-                    .extend((0..4).map(|vert_index| {
-                        let model_vertex = face.vertices[vert_index as usize];
-
-                        Vertex {
-                            position: [
-                                fpos.x + model_vertex.position[0],
-                                fpos.y + model_vertex.position[1],
-                                fpos.z + model_vertex.position[2],
-                            ],
-                            uv: model_vertex.tex_coords,
-                            normal: face.normal.to_array(),
-                            color: u32::MAX,
-                            uv_offset: 0,
-                            lightmap_coords: light_level.byte,
-                            dark: false,
-                        }
-                    }).flat_map(Vertex::compressed));
-                baked_layer.indices.extend(INDICES.iter().flat_map(|index| (index + (vec_index as u32)).to_ne_bytes()));
-            };
+/// Which blocks the light of a vertex comes from, so it can be worked out again when only the light changes.
+/// Blocks are within the section or one block outside of it, packed by [LightSamples::block].
+#[derive(Clone, Copy, Debug)]
+pub enum LightSamples {
+    /// The average of 4 blocks, which can repeat
+    Average([u16; 4]),
+    /// The brightest of 2 blocks, for block and sky light separately
+    Brightest([u16; 2]),
+}
 
+impl LightSamples {
+    fn block(pos: IVec3) -> u16 {
+        let pos = pos + 1;
+        ((pos.y * 18 + pos.z) * 18 + pos.x) as u16
+    }
 
-            let mut add_face = |face:&BlockModelFace,dir:Direction|{
-                let cull =if let Some(mesh) = get_block(block_manager, state_provider.get_state(pos + dir.to_vec())){
-                    (mesh.cull>>dir.opposite() as u8)&1 == 1
-                }
-                else {
-                    false
-                };
-                if !cull{
-                    let light_level: LightLevel = state_provider.get_light_level(pos+dir.to_vec());
-                    add_quad(face,light_level);
-                }
-            };
+    fn pos(block: u16) -> IVec3 {
+        let block = block as i32;
+        ivec3(block % 18, block / (18 * 18), (block / 18) % 18) - 1
+    }
 
-            model_mesh.west.iter().for_each(|face|{
-                add_face(face,Direction::West);
-            });
-            model_mesh.east.iter().for_each(|face|{
-                add_face(face,Direction::East);
-            });
-            model_mesh.down.iter().for_each(|face|{
-                add_face(face,Direction::Down);
-            });
-            model_mesh.up.iter().for_each(|face|{
-                add_face(face,Direction::Up);
-            });
-            model_mesh.north.iter().for_each(|face|{
-                add_face(face,Direction::North);
-            });
-            model_mesh.south.iter().for_each(|face|{
-                add_face(face,Direction::South);
-            });
-            model_mesh.any.iter().for_each(|face|{
-                let light_level: LightLevel = state_provider.get_light_level(pos);
-                add_quad(face,light_level);
-            });
+    /// Block and sky light in sixteenths of a light level
+    pub fn light<Provider: BlockStateProvider + ?Sized>(&self, state_provider: &Provider) -> [u8; 2] {
+        match self {
+            Self::Average(blocks) => {
+                let levels = blocks.map(|block| state_provider.get_light_level(Self::pos(block)));
+                [
+                    levels.iter().map(|light| light.get_block_level()).sum::<u8>() * 4,
+                    levels.iter().map(|light| light.get_sky_level()).sum::<u8>() * 4,
+                ]
+            }
+            Self::Brightest(blocks) => {
+                let levels = blocks.map(|block| state_provider.get_light_level(Self::pos(block)));
+                [
+                    levels.iter().map(|light| light.get_block_level()).max().unwrap() * 16,
+                    levels.iter().map(|light| light.get_sky_level()).max().unwrap() * 16,
+                ]
+            }
+        }
+    }
+}
+
+/// Light and ambient occlusion of a single vertex
+#[derive(Clone, Copy, Debug)]
+struct VertexLight {
+    /// Block and sky light in sixteenths of a light level
+    light: [u8; 2],
+    ao: u8,
+    samples: LightSamples,
+}
+
+impl VertexLight {
+    fn new<Provider: BlockStateProvider + ?Sized>(samples: LightSamples, ao: u8, state_provider: &Provider) -> Self {
+        Self {
+            light: samples.light(state_provider),
+            ao,
+            samples,
+        }
+    }
+
+    /// The light of the block at `pos`
+    fn flat<Provider: BlockStateProvider + ?Sized>(pos: IVec3, state_provider: &Provider) -> Self {
+        Self::new(LightSamples::Average([LightSamples::block(pos); 4]), 255, state_provider)
+    }
+}
 
+/// Vanilla style smooth lighting. Each vertex of the face averages the light of the 4 blocks touching that corner
+/// in the plane the face looks into, and gets darker for every opaque block among them.
+fn smooth_light<Provider: BlockStateProvider + ?Sized>(
+    face: &BlockModelFace,
+    dir: Direction,
+    plane: IVec3,
+    origin: IVec3,
+    block_manager: &BlockManager,
+    state_provider: &Provider,
+) -> [VertexLight; 4] {
+    let normal = dir.to_vec();
+    let axis = if normal.x != 0 { 0 } else if normal.y != 0 { 1 } else { 2 };
+    let (axis_a, axis_b) = ((axis + 1) % 3, (axis + 2) % 3);
+
+    let opaque = |pos: IVec3| is_opaque(block_manager, state_provider.get_state(pos), origin + pos);
+    //Opaque blocks have no light of their own, vanilla uses the center's light for them instead
+    let sample = |pos: IVec3, opaque: bool| LightSamples::block(if opaque { plane } else { pos });
+
+    face.vertices.map(|vertex| {
+        let side_a = if vertex.position[axis_a] < 0.5 { -IVec3::AXES[axis_a] } else { IVec3::AXES[axis_a] };
+        let side_b = if vertex.position[axis_b] < 0.5 { -IVec3::AXES[axis_b] } else { IVec3::AXES[axis_b] };
+
+        let side_a_opaque = opaque(plane + side_a);
+        let side_b_opaque = opaque(plane + side_b);
+        //When both sides are opaque the corner can't be seen, so it counts as occluded too
+        let corner_opaque = (side_a_opaque && side_b_opaque) || opaque(plane + side_a + side_b);
+
+        let samples = LightSamples::Average([
+            LightSamples::block(plane),
+            sample(plane + side_a, side_a_opaque),
+            sample(plane + side_b, side_b_opaque),
+            sample(plane + side_a + side_b, corner_opaque),
+        ]);
+        let occluders = side_a_opaque as u8 + side_b_opaque as u8 + corner_opaque as u8;
+
+        VertexLight::new(samples, 255 - occluders * 51, state_provider)
+    })
+}
+
+/// Hands out versions to section bakes, so results of bakes which were superseded or cancelled
+/// while running can be told apart and thrown away
+#[derive(Default)]
+pub struct BakeVersions {
+    next: AtomicU64,
+    /// The version of the latest bake requested for each section, until it's uploaded
+    latest: Mutex<HashMap<IVec3, u64>>,
+}
+
+impl BakeVersions {
+    /// Get the version for a new bake of a section, which supersedes all earlier bakes of it.
+    /// This should be called when the bake is requested rather than when it starts running.
+    pub fn begin(&self, pos: IVec3) -> u64 {
+        let version = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        self.latest.lock().insert(pos, version);
+        version
+    }
+
+    /// Whether a bake is still the latest one for its section
+    pub fn is_current(&self, pos: IVec3, version: u64) -> bool {
+        self.latest.lock().get(&pos) == Some(&version)
+    }
+
+    /// Mark the latest bake of a section as uploaded, which leaves nothing pending for it
+    pub fn finish(&self, pos: IVec3, version: u64) {
+        let mut latest = self.latest.lock();
+        if latest.get(&pos) == Some(&version) {
+            latest.remove(&pos);
         }
     }
-    layers
+
+    /// Whether a bake of a section was requested and hasn't been uploaded yet
+    pub fn is_pending(&self, pos: IVec3) -> bool {
+        self.latest.lock().contains_key(&pos)
+    }
+
+    /// Cancel the pending bakes of every section `keep` returns false for
+    pub fn retain(&self, mut keep: impl FnMut(IVec3) -> bool) {
+        self.latest.lock().retain(|pos, _| keep(*pos));
+    }
+
+    /// Cancel every pending bake
+    pub fn clear(&self) {
+        self.latest.lock().clear();
+    }
+}
+
+/// The meshes of a section, as sent from the bake threads to [WmRenderer::submit_chunk_updates]
+pub struct BakedSection {
+    pub pos: IVec3,
+    /// From [BakeVersions::begin]
+    pub version: u64,
+    pub layers: Vec<BakedLayer>,
+    pub visibility: SectionVisibility,
+    /// When [BakeSettings::lod_distance] isn't 0, what [bake_lod] meshed it from along with the mesh
+    pub lod: Option<(LodCells, BakedLayer)>,
+    /// Set instead of `layers` for sections left to the GPU, from [gpu_mesh_input]
+    pub gpu_input: Option<GpuMeshInput>,
+}
+
+/// Bake a section and queue it to be uploaded. `version` comes from [BakeVersions::begin], if a newer bake of the section
+/// was requested or the section was unloaded in the meantime nothing happens.
+/// Sections outside of [crate::mc::MinecraftState::world_height] aren't baked.
+pub fn bake_section<Provider: BlockStateProvider + ?Sized>(pos: IVec3, version: u64, wm:&WmRenderer ,bsp: &Provider, ) {
+    if !wm.bake_versions.is_current(pos, version) {
+        return;
+    }
+    //Nothing will ever be uploaded for a section outside the world, so it mustn't stay pending
+    if !wm.mc.world_height.read().contains_section(pos.y) {
+        wm.bake_versions.finish(pos, version);
+        return;
+    }
+
+    let bm = wm.mc.block_manager.read();
+    let settings = wm.mc.bake_settings.read().clone();
+    let biome_colors = wm.mc.biome_colors.read();
+
+    let gpu_mesh = match settings.gpu_meshing && !settings.greedy_meshing.contains(&true) {
+        true => gpu_mesh_input(pos, &bm, &biome_colors, bsp, &settings, &wm.gpu_mesher),
+        false => None,
+    };
+    let (layers, visibility, gpu_input) = match gpu_mesh {
+        Some((input, visibility)) => (Vec::new(), visibility, Some(input)),
+        None => {
+            let (layers, visibility) = bake_layers(pos, &bm, &biome_colors, bsp, &settings);
+            (layers, visibility, None)
+        }
+    };
+    let lod = (settings.lod_distance > 0).then(|| {
+        let cells = LodCells::new(pos, &bm, &biome_colors, bsp, &settings);
+        let mesh = bake_lod(pos, &cells, &bm);
+        (cells, mesh)
+    });
+
+    wm.chunk_update_queue.0.send(BakedSection { pos, version, layers, visibility, lod, gpu_input }).unwrap();
+}
+
+#[derive(Clone, Default)]
+pub struct BakedLayer {
+    /// 4 per quad, each quad is drawn with [crate::render::terrain::QUAD_INDICES]
+    pub vertices: Vec<u8>,
+    /// Block and sky light of each vertex, in sixteenths of a light level
+    pub light: Vec<u8>,
+    /// Where the light of each vertex came from
+    pub light_samples: Vec<LightSamples>,
+    /// The quads greedy meshing merged from the faces of several blocks. Their corners only get the light of the faces
+    /// in the corners, so when the light changes they're checked for faces which don't agree any more.
+    pub merged: Vec<MergedQuad>,
+    /// Centre of each quad, only kept for [RenderLayer::Transparent] since those are the ones that get sorted
+    pub centroids: Vec<Vec3>,
+}
+
+/// How bright a face is depending on which way it faces, like vanilla: 1.0 for up, 0.5 for down,
+/// 0.8 for north and south and 0.6 for east and west. Rotated faces blend between those.
+/// Keep in sync with `entity.wgsl`.
+pub fn diffuse_shade(normal: Vec3) -> f32 {
+    let normal = normal.normalize_or_zero();
+    (normal.x * normal.x * 0.6 + normal.y * normal.y * (3.0 + normal.y) / 4.0 + normal.z * normal.z * 0.8).min(1.0)
+}
+
+/// A quad which [GreedyFaces] merged from faces of several blocks, which all had the same light
+#[derive(Clone, Debug)]
+pub struct MergedQuad {
+    /// Which quad of its layer it is, in the order they were baked
+    pub quad: u32,
+    /// Where the light of each vertex of each of the faces came from
+    pub samples: Vec<LightSamples>,
+}
+
+impl BakedLayer {
+    pub fn quads(&self) -> u32 {
+        (self.vertices.len() / (4 * Vertex::VERTEX_LENGTH)) as u32
+    }
+
+    /// How much of the chunk buffer the layer takes up, in u32s
+    pub fn size(&self) -> u32 {
+        ((self.vertices.len() + self.light.len()) / 4) as u32
+    }
+}
+
+/// Append a quad to a layer, `fpos` being the position of the block it belongs to within the section
+fn push_quad(
+    baked_layer: &mut BakedLayer,
+    layer: RenderLayer,
+    fpos: Vec3,
+    face: &BlockModelFace,
+    color: u32,
+    light: [VertexLight; 4],
+) {
+    push_quad_repeated(baked_layer, layer, fpos, face, color, light, [1, 1]);
+}
+
+/// [push_quad] for a quad which repeats its texture `repeat` times along the texture's u and v axes
+fn push_quad_repeated(
+    baked_layer: &mut BakedLayer,
+    layer: RenderLayer,
+    fpos: Vec3,
+    face: &BlockModelFace,
+    color: u32,
+    light: [VertexLight; 4],
+    repeat: [u8; 2],
+) {
+    let color = if face.shade {
+        pack_rgb(unpack_rgb(color).map(|channel| (channel as f32 * diffuse_shade(face.normal)) as u32))
+    } else {
+        color
+    };
+
+    //Split the quad along the brighter diagonal so the AO gradient doesn't come out anisotropic. The shared indices
+    //split it from the first vertex to the third, starting from the second vertex splits it along the other one.
+    let first = if light[0].ao as u16 + light[2].ao as u16 > light[1].ao as u16 + light[3].ao as u16 { 0 } else { 1 };
+
+    for corner in 0..4 {
+        let vert_index = (first + corner) % 4;
+        let model_vertex = face.vertices[vert_index];
+
+        baked_layer.vertices.extend(Vertex {
+            position: [
+                fpos.x + model_vertex.position[0],
+                fpos.y + model_vertex.position[1],
+                fpos.z + model_vertex.position[2],
+            ],
+            uv: model_vertex.tex_coords,
+            repeat,
+            color,
+            ao: light[vert_index].ao,
+        }.compressed());
+        baked_layer.light.extend(light[vert_index].light);
+        baked_layer.light_samples.push(light[vert_index].samples);
+    }
+
+    if layer == RenderLayer::Transparent {
+        let centroid = face.vertices.iter().map(|vertex| vertex.position).sum::<Vec3>() / 4.0;
+        baked_layer.centroids.push(fpos + centroid);
+    }
+}
+
+fn bake_layers<Provider: BlockStateProvider + ?Sized>(
+    section_pos: IVec3,
+    block_manager: &BlockManager,
+    biome_colors: &BiomeColors,
+    state_provider: &Provider,
+    settings: &BakeSettings,
+) -> (Vec<BakedLayer>, SectionVisibility) {
+    let mut layers = vec![BakedLayer::default(); RenderLayer::ALL.len()];
+    //Block positions are relative to the section, this makes them world positions
+    let origin = section_pos * 16;
+
+    if state_provider.is_section_empty(ivec3(0, 0, 0)) {
+        return (layers, SectionVisibility::ALL);
+    }
+
+    let visibility = SectionVisibility::flood_fill(|pos| {
+        is_opaque(block_manager, state_provider.get_state(pos), origin + pos)
+    });
+    let mut greedy = GreedyFaces::default();
+
+    for block_index in 0..16 * 16 * 16 {
+        let pos = ivec3(block_index & 15, block_index >> 8, (block_index & 255) >> 4);
+
+        let fpos= vec3(pos.x as f32,pos.y as f32,pos.z as f32);
+
+        let block_state: ChunkBlockState = state_provider.get_state(pos);
+
+        if let Some(model_mesh) = get_block(block_manager, block_state, origin + pos){
+            let layer = match block_state {
+                ChunkBlockState::State(key) => block_manager.get_render_layer(key.block, &model_mesh),
+                ChunkBlockState::Air => model_mesh.layer,
+            };
+            let offset = match block_state {
+                ChunkBlockState::State(key) => block_manager
+                    .offsets
+                    .get(&key.block)
+                    .map_or(Vec3::ZERO, |offset| offset.get(origin + pos)),
+                ChunkBlockState::Air => Vec3::ZERO,
+            };
+
+            //Only worked out once a tinted face shows up, since blending biomes isn't free
+            let mut tint = None;
+
+            let mut add_quad = |face:&BlockModelFace,dir:Option<Direction>,light: [VertexLight; 4]|{
+                let color = match block_state {
+                    ChunkBlockState::State(key) if face.tint_index >= 0 => *tint.get_or_insert_with(|| {
+                        block_tint(pos, origin, key, block_manager, biome_colors, state_provider, settings)
+                    }),
+                    _ => 0xffffff,
+                };
+
+                let merged = match dir {
+                    Some(dir) if settings.greedy_meshing[layer as usize] && offset == Vec3::ZERO => {
+                        greedy.add(layer, dir, pos, face, color, light)
+                    }
+                    _ => false,
+                };
+                if !merged {
+                    push_quad(&mut layers[layer as usize], layer, fpos + offset, face, color, light);
+                }
+            };
+
+            let face_light = |face:&BlockModelFace, dir: Option<Direction>, plane: IVec3| match dir {
+                Some(dir) if settings.smooth_lighting => smooth_light(face, dir, plane, origin, block_manager, state_provider),
+                _ => [VertexLight::flat(plane, state_provider); 4],
+            };
+
+
+            let mut add_face = |face:&BlockModelFace,dir:Direction|{
+                let neighbour = pos + dir.to_vec();
+                let cull = match block_state {
+                    ChunkBlockState::State(key) => is_face_culled(block_manager, key, dir, state_provider.get_state(neighbour), origin + neighbour),
+                    ChunkBlockState::Air => false,
+                };
+                if !cull{
+                    add_quad(face,Some(dir),face_light(face, Some(dir), pos + dir.to_vec()));
+                }
+            };
+
+            model_mesh.west.iter().for_each(|face|{
+                add_face(face,Direction::West);
+            });
+            model_mesh.east.iter().for_each(|face|{
+                add_face(face,Direction::East);
+            });
+            model_mesh.down.iter().for_each(|face|{
+                add_face(face,Direction::Down);
+            });
+            model_mesh.up.iter().for_each(|face|{
+                add_face(face,Direction::Up);
+            });
+            model_mesh.north.iter().for_each(|face|{
+                add_face(face,Direction::North);
+            });
+            model_mesh.south.iter().for_each(|face|{
+                add_face(face,Direction::South);
+            });
+            model_mesh.any.iter().for_each(|face|{
+                add_quad(face,None,face_light(face, Direction::from_normal(face.normal), pos));
+            });
+
+        }
+
+        if let Some(fluid_state) = get_fluid(block_manager, block_state) {
+            bake_fluid(pos, origin, fluid_state, block_manager, biome_colors, state_provider, settings, &mut layers);
+        }
+    }
+    greedy.mesh(&mut layers);
+    (layers, visibility)
+}
+
+/// What [GpuMesher] meshes a section from, see [gpu_mesh_input]
+pub struct GpuMeshInput {
+    /// Every block of the section, in the order [bake_layers] goes through them
+    pub blocks: Vec<GpuBlock>,
+    /// The section and the blocks right around it, indexed like [LightSamples::block]. Block light, sky light << 8
+    /// and 1 << 16 for opaque blocks.
+    pub region: Vec<u32>,
+    /// How many quads each layer has
+    pub quads: [u32; RenderLayer::ALL.len()],
+    pub smooth_lighting: bool,
+    /// From [GpuMesher::generation], the table `blocks` refer to models in
+    pub generation: u64,
+}
+
+impl GpuMeshInput {
+    /// How much of the chunk buffer a quad takes up with its light and where each vertex got it from, in u32s
+    const QUAD_SIZE: u32 = QUAD_U32S + 2 + 4;
+
+    /// How much of the chunk buffer the section takes up, in u32s
+    pub fn size(&self) -> u32 {
+        self.quads.iter().sum::<u32>() * Self::QUAD_SIZE
+    }
+}
+
+/// The part of meshing a section which is left on the CPU when [BakeSettings::gpu_meshing] is on: which model each
+/// block has, which of its faces are culled, its tint and where its quads go in its layer. The GPU works out the
+/// light and ambient occlusion of the vertices itself, from the light and opacity of the blocks in and around the
+/// section.
+///
+/// Fluids are only meshed by [bake_fluid] and transparent quads have to be sorted on the CPU, so sections with
+/// either, along with empty ones, return `None` and are left to [bake_layers].
+fn gpu_mesh_input<Provider: BlockStateProvider + ?Sized>(
+    section_pos: IVec3,
+    block_manager: &BlockManager,
+    biome_colors: &BiomeColors,
+    state_provider: &Provider,
+    settings: &BakeSettings,
+    gpu_mesher: &GpuMesher,
+) -> Option<(GpuMeshInput, SectionVisibility)> {
+    //Block positions are relative to the section, this makes them world positions
+    let origin = section_pos * 16;
+
+    if state_provider.is_section_empty(ivec3(0, 0, 0)) {
+        return None;
+    }
+
+    //Taken before any model is looked up, so a table cleared halfway through is noticed
+    let generation = gpu_mesher.generation();
+    //Looking models up in the table takes a lock, most sections only have a handful of different ones
+    let mut mesh_indices = HashMap::new();
+    let mut quads = [0; RenderLayer::ALL.len()];
+    let mut blocks = Vec::with_capacity(SECTION_VOLUME);
+
+    for block_index in 0..16 * 16 * 16 {
+        let pos = ivec3(block_index & 15, block_index >> 8, (block_index & 255) >> 4);
+        let block_state = state_provider.get_state(pos);
+
+        if get_fluid(block_manager, block_state).is_some() {
+            return None;
+        }
+        let (key, model_mesh) = match (block_state, get_block(block_manager, block_state, origin + pos)) {
+            (ChunkBlockState::State(key), Some(model_mesh)) => (key, model_mesh),
+            _ => {
+                blocks.push(GpuBlock::EMPTY);
+                continue;
+            }
+        };
+
+        let layer = block_manager.get_render_layer(key.block, &model_mesh);
+        if layer == RenderLayer::Transparent {
+            return None;
+        }
+        let offset = block_manager
+            .offsets
+            .get(&key.block)
+            .map_or(Vec3::ZERO, |offset| offset.get(origin + pos));
+
+        let sides = [
+            (Direction::West, &model_mesh.west),
+            (Direction::East, &model_mesh.east),
+            (Direction::Down, &model_mesh.down),
+            (Direction::Up, &model_mesh.up),
+            (Direction::North, &model_mesh.north),
+            (Direction::South, &model_mesh.south),
+        ];
+        let mut visible_sides = 0;
+        let mut block_quads = model_mesh.any.len() as u32;
+        let mut tinted = model_mesh.any.iter().any(|face| face.tint_index >= 0);
+        for (dir, faces) in sides {
+            let neighbour = pos + dir.to_vec();
+            if faces.is_empty()
+                || is_face_culled(block_manager, key, dir, state_provider.get_state(neighbour), origin + neighbour)
+            {
+                continue;
+            }
+            visible_sides |= 1 << dir as u32;
+            block_quads += faces.len() as u32;
+            tinted |= faces.iter().any(|face| face.tint_index >= 0);
+        }
+
+        let mesh = *mesh_indices
+            .entry(Arc::as_ptr(&model_mesh))
+            .or_insert_with(|| gpu_mesher.mesh_index(&model_mesh));
+        let color = match tinted {
+            true => block_tint(pos, origin, key, block_manager, biome_colors, state_provider, settings),
+            false => 0xffffff,
+        };
+
+        blocks.push(GpuBlock {
+            mesh,
+            first_quad: quads[layer as usize],
+            color,
+            flags: visible_sides | (layer as u32) << 8,
+            offset: offset.extend(0.0).to_array(),
+        });
+        quads[layer as usize] += block_quads;
+    }
+
+    let region = gpu_region(section_pos, block_manager, state_provider);
+    let visibility = SectionVisibility::flood_fill(|pos| {
+        is_opaque(block_manager, state_provider.get_state(pos), origin + pos)
+    });
+
+    let input = GpuMeshInput {
+        blocks,
+        region,
+        quads,
+        smooth_lighting: settings.smooth_lighting,
+        generation,
+    };
+    Some((input, visibility))
+}
+
+/// The light and opacity of a section and the blocks right around it, see [GpuMeshInput::region]. Also what
+/// [GpuMesher::queue_relight] relights a section from.
+pub fn gpu_region<Provider: BlockStateProvider + ?Sized>(
+    section_pos: IVec3,
+    block_manager: &BlockManager,
+    state_provider: &Provider,
+) -> Vec<u32> {
+    let origin = section_pos * 16;
+    (0..18 * 18 * 18)
+        .map(|block| {
+            let pos = LightSamples::pos(block);
+            let light = state_provider.get_light_level(pos);
+            let opaque = is_opaque(block_manager, state_provider.get_state(pos), origin + pos);
+            light.get_block_level() as u32 | (light.get_sky_level() as u32) << 8 | (opaque as u32) << 16
+        })
+        .collect()
+}
+
+/// The axis a side facing `dir` is on, and the two axes along it, the same ones [smooth_light] uses
+fn side_axes(dir: Direction) -> (usize, usize, usize) {
+    let normal = dir.to_vec();
+    let axis = if normal.x != 0 { 0 } else if normal.y != 0 { 1 } else { 2 };
+    (axis, (axis + 1) % 3, (axis + 2) % 3)
+}
+
+/// A full block face waiting to be merged with the faces next to it
+#[derive(Clone, Copy)]
+struct GreedyFace {
+    face: BlockModelFace,
+    color: u32,
+    light: [VertexLight; 4],
+    /// Which corner of the block's side each vertex is on, bit 0 set for the far end of the first axis along the side
+    /// and bit 1 for the second, see [side_axes]
+    corners: [usize; 4],
+}
+
+impl GreedyFace {
+    /// The texture coordinates at each corner of the block's side
+    fn corner_uvs(&self) -> [[u16; 2]; 4] {
+        let mut uvs = [[0; 2]; 4];
+        for (vertex, corner) in self.face.vertices.iter().zip(self.corners) {
+            uvs[corner] = vertex.tex_coords;
+        }
+        uvs
+    }
+
+    /// Whether the texture's u axis runs along the first axis of the side. `None` if the texture isn't a rectangle
+    /// with a corner on each corner of the side, which the shaders need to repeat it.
+    fn u_along_first_axis(&self) -> Option<bool> {
+        let [uv_00, uv_10, uv_01, uv_11] = self.corner_uvs();
+        if uv_00[0] == uv_11[0] || uv_00[1] == uv_11[1] {
+            None
+        } else if uv_10 == [uv_11[0], uv_00[1]] && uv_01 == [uv_00[0], uv_11[1]] {
+            Some(true)
+        } else if uv_10 == [uv_00[0], uv_11[1]] && uv_01 == [uv_11[0], uv_00[1]] {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a quad covering both faces would look the same as they do
+    fn merges_with(&self, other: &Self) -> bool {
+        self.color == other.color
+            && self.face.shade == other.face.shade
+            && self.light[0].light == other.light[0].light
+            && self.light[0].ao == other.light[0].ao
+            && self.corner_uvs() == other.corner_uvs()
+    }
+}
+
+/// Greedy meshing. Faces which cover a whole side of their block are collected while a section is baked, then the
+/// ones next to each other with the same texture, colour and light are merged into as few quads as possible. There's
+/// a grid of faces for each layer, direction and slice of the section along the direction's axis.
+#[derive(Default)]
+struct GreedyFaces {
+    /// Ordered so the quads come out the same every time
+    grids: BTreeMap<(RenderLayer, Direction, i32), Vec<Option<GreedyFace>>>,
+}
+
+impl GreedyFaces {
+    /// Keep a face of the block at `pos` to be merged by [Self::mesh]. Returns false if it can't be merged, because
+    /// it doesn't cover the side of its block, its light differs between vertices, or its texture can't be repeated.
+    fn add(&mut self, layer: RenderLayer, dir: Direction, pos: IVec3, face: &BlockModelFace, color: u32, light: [VertexLight; 4]) -> bool {
+        let (axis, axis_a, axis_b) = side_axes(dir);
+        let side = if dir.to_vec()[axis] > 0 { 1.0 } else { 0.0 };
+        let on_edge = |value: f32| value == 0.0 || value == 1.0;
+
+        let mut corners = [0; 4];
+        for (corner, vertex) in corners.iter_mut().zip(&face.vertices) {
+            let position = vertex.position;
+            if position[axis] != side || !on_edge(position[axis_a]) || !on_edge(position[axis_b]) {
+                return false;
+            }
+            *corner = position[axis_a] as usize | (position[axis_b] as usize) << 1;
+        }
+        if (0..4).any(|corner| !corners.contains(&corner)) {
+            return false;
+        }
+        if light.iter().any(|vertex| vertex.light != light[0].light || vertex.ao != light[0].ao) {
+            return false;
+        }
+
+        let greedy_face = GreedyFace { face: *face, color, light, corners };
+        if greedy_face.u_along_first_axis().is_none() {
+            return false;
+        }
+
+        let grid = self.grids.entry((layer, dir, pos[axis])).or_insert_with(|| vec![None; CHUNK_AREA]);
+        grid[pos[axis_a] as usize + pos[axis_b] as usize * CHUNK_WIDTH] = Some(greedy_face);
+        true
+    }
+
+    /// Merge the faces into quads, widest first then as tall as they go, and append them to their layers
+    fn mesh(self, layers: &mut [BakedLayer]) {
+        let index = |a: usize, b: usize| a + b * CHUNK_WIDTH;
+
+        for ((layer, dir, slice), mut grid) in self.grids {
+            let (axis, axis_a, axis_b) = side_axes(dir);
+
+            for b0 in 0..CHUNK_WIDTH {
+                for a0 in 0..CHUNK_WIDTH {
+                    let Some(first) = grid[index(a0, b0)] else {
+                        continue;
+                    };
+
+                    let merges = |cell: &Option<GreedyFace>| cell.is_some_and(|face| face.merges_with(&first));
+                    let width = (a0..CHUNK_WIDTH).take_while(|a| merges(&grid[index(*a, b0)])).count();
+                    let height = (b0..CHUNK_WIDTH)
+                        .take_while(|b| (a0..a0 + width).all(|a| merges(&grid[index(a, *b)])))
+                        .count();
+
+                    //Each corner of the quad takes its light from the face in that corner, the others are kept so
+                    //relighting can tell whether they still agree
+                    let corner_faces = [0, 1, 2, 3].map(|corner: usize| {
+                        grid[index(a0 + (corner & 1) * (width - 1), b0 + (corner >> 1) * (height - 1))].unwrap()
+                    });
+                    let mut samples = vec![];
+                    for b in b0..b0 + height {
+                        for a in a0..a0 + width {
+                            let face = grid[index(a, b)].take().unwrap();
+                            samples.extend(face.light.map(|vertex| vertex.samples));
+                        }
+                    }
+
+                    let mut face = first.face;
+                    let mut light = first.light;
+                    for (vertex_index, vertex) in face.vertices.iter_mut().enumerate() {
+                        let corner = first.corners[vertex_index];
+                        let mut position = Vec3::ZERO;
+                        position[axis] = slice as f32 + vertex.position[axis];
+                        position[axis_a] = (a0 + (corner & 1) * width) as f32;
+                        position[axis_b] = (b0 + (corner >> 1) * height) as f32;
+                        vertex.position = position;
+
+                        let corner_face = corner_faces[corner];
+                        let corner_vertex = corner_face.corners.iter().position(|other| *other == corner).unwrap();
+                        light[vertex_index] = corner_face.light[corner_vertex];
+                    }
+
+                    let repeat = if first.u_along_first_axis().unwrap() { [width, height] } else { [height, width] };
+                    let baked_layer = &mut layers[layer as usize];
+                    if width * height > 1 {
+                        baked_layer.merged.push(MergedQuad { quad: baked_layer.quads(), samples });
+                    }
+                    push_quad_repeated(baked_layer, layer, Vec3::ZERO, &face, first.color, light, repeat.map(|repeat| repeat as u8));
+                }
+            }
+        }
+    }
+}
+
+fn get_fluid(block_manager: &BlockManager, state: ChunkBlockState) -> Option<FluidState> {
+    match state {
+        ChunkBlockState::Air => None,
+        ChunkBlockState::State(key) => block_manager.fluids.get(&key).copied(),
+    }
+}
+
+/// Adds the weight vanilla gives a height when averaging the heights around a fluid corner
+fn add_corner_height(weighted: &mut (f32, f32), height: f32) {
+    if height >= 0.8 {
+        weighted.0 += height * 10.0;
+        weighted.1 += 10.0;
+    } else if height >= 0.0 {
+        weighted.0 += height;
+        weighted.1 += 1.0;
+    }
+}
+
+/// Mesh the fluid in a block the way vanilla's `FluidRenderer` does. The height of each corner of the surface
+/// is blended from the fluid levels around it, the surface texture flows downhill, and faces against the same
+/// fluid or blocks which cover them are culled.
+fn bake_fluid<Provider: BlockStateProvider + ?Sized>(
+    pos: IVec3,
+    origin: IVec3,
+    fluid_state: FluidState,
+    block_manager: &BlockManager,
+    biome_colors: &BiomeColors,
+    state_provider: &Provider,
+    settings: &BakeSettings,
+    layers: &mut [BakedLayer],
+) {
+    let fluid = fluid_state.fluid;
+    let sprites = match block_manager.fluid_sprites.get(&fluid) {
+        None => return,
+        Some(sprites) => *sprites,
+    };
+
+    let same_fluid = |pos: IVec3| get_fluid(block_manager, state_provider.get_state(pos)).filter(|other| other.fluid == fluid);
+    let opaque = |pos: IVec3| is_opaque(block_manager, state_provider.get_state(pos), origin + pos);
+    //Whether the block next to a face hides it
+    let covered = |dir: Direction| {
+        get_block(block_manager, state_provider.get_state(pos + dir.to_vec()), origin + pos + dir.to_vec())
+            .is_some_and(|mesh| (mesh.cull >> dir.opposite() as u8) & 1 == 1)
+    };
+    //-1 for opaque blocks so they don't pull the corners down, 0 for anything else without this fluid
+    let height_at = |pos: IVec3| match same_fluid(pos) {
+        Some(_) if same_fluid(pos + IVec3::Y).is_some() => 1.0,
+        Some(other) => other.height(),
+        None if opaque(pos) => -1.0,
+        None => 0.0,
+    };
+
+    let fluid_above = same_fluid(pos + IVec3::Y).is_some();
+    let height = if fluid_above { 1.0 } else { fluid_state.height() };
+
+    let corner = |side_a: IVec3, side_b: IVec3| -> f32 {
+        let (height_a, height_b) = (height_at(pos + side_a), height_at(pos + side_b));
+        if height_a >= 1.0 || height_b >= 1.0 {
+            return 1.0;
+        }
+
+        let mut weighted = (0.0, 0.0);
+        //The diagonal only counts when the fluid can reach it through one of the sides
+        if height_a > 0.0 || height_b > 0.0 {
+            let height_diagonal = height_at(pos + side_a + side_b);
+            if height_diagonal >= 1.0 {
+                return 1.0;
+            }
+            add_corner_height(&mut weighted, height_diagonal);
+        }
+        add_corner_height(&mut weighted, height);
+        add_corner_height(&mut weighted, height_a);
+        add_corner_height(&mut weighted, height_b);
+
+        weighted.0 / weighted.1
+    };
+
+    let [north, south, west, east] = [Direction::North, Direction::South, Direction::West, Direction::East]
+        .map(|dir| dir.to_vec());
+    let (north_west, south_west, south_east, north_east) = if fluid_above {
+        (1.0, 1.0, 1.0, 1.0)
+    } else {
+        (corner(north, west), corner(south, west), corner(south, east), corner(north, east))
+    };
+
+    let color = match fluid {
+        Fluid::Water => biome_tint(pos, origin, BlockTint::Water, biome_colors, state_provider, settings),
+        Fluid::Lava => 0xffffff,
+    };
+    let layer = fluid.layer();
+    let samples = LightSamples::Brightest([LightSamples::block(pos), LightSamples::block(pos + IVec3::Y)]);
+    let light = [VertexLight::new(samples, 255, state_provider); 4];
+    let fpos = vec3(pos.x as f32, pos.y as f32, pos.z as f32);
+
+    //Texture coordinates are within the first frame of the sprite, in fractions of its width
+    let quad = |positions: [Vec3; 4], sprite: UV, tex_coords: [Vec2; 4], normal: Vec3| {
+        let frame_width = (sprite.1 .0 - sprite.0 .0) as f32;
+
+        BlockModelFace {
+            vertices: [0, 1, 2, 3].map(|vertex| BlockMeshVertex {
+                position: positions[vertex],
+                tex_coords: [
+                    sprite.0 .0 + (tex_coords[vertex].x * frame_width).round() as u16,
+                    sprite.0 .1 + (tex_coords[vertex].y * frame_width).round() as u16,
+                ],
+            }),
+            normal,
+            animation_uv_offset: 0,
+            tint_index: -1,
+            shade: true,
+            cull_face: None,
+        }
+    };
+
+    if !fluid_above && !(opaque(pos + IVec3::Y) && north_west.min(south_west).min(south_east).min(north_east) >= 1.0) {
+        //Which way the fluid flows, downhill towards lower neighbours or ones it can fall down from
+        let flow = [north, south, west, east].iter().fold(Vec2::ZERO, |flow, offset| {
+            let neighbour = pos + *offset;
+            let difference = match same_fluid(neighbour) {
+                Some(other) => fluid_state.height() - other.height(),
+                None if !opaque(neighbour) => same_fluid(neighbour - IVec3::Y)
+                    .map_or(0.0, |below| fluid_state.height() - (below.height() - 8.0 / 9.0)),
+                None => 0.0,
+            };
+
+            flow + offset.xz().as_vec2() * difference
+        }).normalize_or_zero();
+
+        let (sprite, tex_coords) = if flow == Vec2::ZERO {
+            (sprites.still, [Vec2::new(0.0, 0.0), Vec2::new(0.0, 1.0), Vec2::new(1.0, 1.0), Vec2::new(1.0, 0.0)])
+        } else {
+            //Rotate the flowing texture to point along the flow, sampling the middle half of it like vanilla
+            let angle = flow.y.atan2(flow.x) - std::f32::consts::FRAC_PI_2;
+            let (sin, cos) = (angle.sin() * 0.25, angle.cos() * 0.25);
+            (
+                sprites.flow,
+                [
+                    Vec2::new(0.5 + (-cos - sin), 0.5 + (-cos + sin)),
+                    Vec2::new(0.5 + (-cos + sin), 0.5 + (cos + sin)),
+                    Vec2::new(0.5 + (cos + sin), 0.5 + (cos - sin)),
+                    Vec2::new(0.5 + (cos - sin), 0.5 + (-cos - sin)),
+                ],
+            )
+        };
+
+        let positions = [
+            vec3(0.0, north_west, 0.0),
+            vec3(0.0, south_west, 1.0),
+            vec3(1.0, south_east, 1.0),
+            vec3(1.0, north_east, 0.0),
+        ];
+        push_quad(&mut layers[layer as usize], layer, fpos, &quad(positions, sprite, tex_coords, Vec3::Y), color, light);
+
+        //The surface can be seen from below too, e.g. when swimming
+        if !opaque(pos + IVec3::Y) {
+            let mut positions = positions;
+            let mut tex_coords = tex_coords;
+            positions.reverse();
+            tex_coords.reverse();
+            push_quad(&mut layers[layer as usize], layer, fpos, &quad(positions, sprite, tex_coords, Vec3::NEG_Y), color, light);
+        }
+    }
+
+    if same_fluid(pos - IVec3::Y).is_none() && !covered(Direction::Down) {
+        let positions = [vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 0.0, 1.0), vec3(0.0, 0.0, 1.0)];
+        let tex_coords = [Vec2::new(1.0, 1.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 0.0), Vec2::new(0.0, 1.0)];
+        push_quad(&mut layers[layer as usize], layer, fpos, &quad(positions, sprites.still, tex_coords, Vec3::NEG_Y), color, light);
+    }
+
+    //Each side goes from the bottom and top of one corner to the top and bottom of the other
+    let sides = [
+        (Direction::North, (vec3(0.0, 0.0, 0.0), north_west), (vec3(1.0, 0.0, 0.0), north_east)),
+        (Direction::South, (vec3(1.0, 0.0, 1.0), south_east), (vec3(0.0, 0.0, 1.0), south_west)),
+        (Direction::West, (vec3(0.0, 0.0, 1.0), south_west), (vec3(0.0, 0.0, 0.0), north_west)),
+        (Direction::East, (vec3(1.0, 0.0, 0.0), north_east), (vec3(1.0, 0.0, 1.0), south_east)),
+    ];
+
+    for (dir, (corner_a, height_a), (corner_b, height_b)) in sides {
+        if same_fluid(pos + dir.to_vec()).is_some() || covered(dir) {
+            continue;
+        }
+
+        let positions = [corner_a, corner_a + Vec3::Y * height_a, corner_b + Vec3::Y * height_b, corner_b];
+        let tex_coords = [
+            Vec2::new(0.0, 0.5),
+            Vec2::new(0.0, (1.0 - height_a) * 0.5),
+            Vec2::new(0.5, (1.0 - height_b) * 0.5),
+            Vec2::new(0.5, 0.5),
+        ];
+        let normal = dir.to_vec().as_vec3();
+        push_quad(&mut layers[layer as usize], layer, fpos, &quad(positions, sprites.flow, tex_coords, normal), color, light);
+    }
+}
+
+/// How many blocks wide the cells of [LodCells] are
+pub const LOD_CELL: i32 = 4;
+
+/// The corners of each side of a cube one unit wide, in the order [push_quad] expects them
+const CUBE_FACES: [(Direction, [Vec3; 4]); 6] = [
+    (Direction::West, [vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 1.0), vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 0.0)]),
+    (Direction::East, [vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0), vec3(1.0, 1.0, 1.0), vec3(1.0, 0.0, 1.0)]),
+    (Direction::Down, [vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 0.0, 1.0), vec3(0.0, 0.0, 1.0)]),
+    (Direction::Up, [vec3(0.0, 1.0, 0.0), vec3(0.0, 1.0, 1.0), vec3(1.0, 1.0, 1.0), vec3(1.0, 1.0, 0.0)]),
+    (Direction::North, [vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), vec3(1.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)]),
+    (Direction::South, [vec3(1.0, 0.0, 1.0), vec3(1.0, 1.0, 1.0), vec3(0.0, 1.0, 1.0), vec3(0.0, 0.0, 1.0)]),
+];
+
+/// A solid cell of [LodCells]
+#[derive(Clone, Copy, Debug)]
+pub struct LodCell {
+    /// The highest block in the cell, relative to the section, which the cell looks like
+    pub pos: IVec3,
+    pub state: ChunkBlockState,
+    /// What the block's tinted faces are multiplied by, or the colour of the fluid for blocks without a model
+    pub color: u32,
+    /// The light in front of each side of the block, by [Direction]
+    pub light: [LightLevel; 6],
+}
+
+/// A section downsampled to cells of [LOD_CELL] blocks, which is all [bake_lod] needs. A cell is solid when at least
+/// half of it has blocks or fluids, and looks like the highest of them, since that's usually what's seen from afar.
+///
+/// They're a fraction of the size of the sections, so [crate::mc::Scene::lod_cells] keeps them out to the LOD
+/// distance, where the blocks themselves are long gone, and simplified meshes can be baked again from them.
+#[derive(Clone, Debug)]
+pub struct LodCells {
+    cells: Vec<Option<LodCell>>,
+}
+
+impl LodCells {
+    /// How many cells wide a section is
+    pub const CELLS: i32 = 16 / LOD_CELL;
+
+    fn index(cell: IVec3) -> usize {
+        (cell.x + cell.z * Self::CELLS + cell.y * Self::CELLS * Self::CELLS) as usize
+    }
+
+    fn cell_pos(index: usize) -> IVec3 {
+        let index = index as i32;
+        ivec3(index % Self::CELLS, index / (Self::CELLS * Self::CELLS), (index / Self::CELLS) % Self::CELLS)
+    }
+
+    pub fn new<Provider: BlockStateProvider + ?Sized>(
+        section_pos: IVec3,
+        block_manager: &BlockManager,
+        biome_colors: &BiomeColors,
+        state_provider: &Provider,
+        settings: &BakeSettings,
+    ) -> Self {
+        const VOLUME: i32 = LodCells::CELLS * LodCells::CELLS * LodCells::CELLS;
+        let origin = section_pos * 16;
+
+        if state_provider.is_section_empty(ivec3(0, 0, 0)) {
+            return Self { cells: vec![None; VOLUME as usize] };
+        }
+
+        let cells = (0..VOLUME as usize)
+            .map(|index| {
+                let cell = Self::cell_pos(index);
+                let mut filled = 0;
+                let mut top = None;
+
+                //From the top down, so the first block found is the highest
+                for y in (0..LOD_CELL).rev() {
+                    for z in 0..LOD_CELL {
+                        for x in 0..LOD_CELL {
+                            let pos = cell * LOD_CELL + ivec3(x, y, z);
+                            let state = state_provider.get_state(pos);
+                            let has_block = get_block(block_manager, state, origin + pos).is_some();
+                            if has_block || get_fluid(block_manager, state).is_some() {
+                                filled += 1;
+                                top.get_or_insert((pos, state));
+                            }
+                        }
+                    }
+                }
+
+                let (pos, state) = top.filter(|_| filled * 2 >= LOD_CELL * LOD_CELL * LOD_CELL)?;
+                let mesh = get_block(block_manager, state, origin + pos);
+                let color = match (state, mesh, get_fluid(block_manager, state)) {
+                    (ChunkBlockState::State(key), Some(_), _) => {
+                        block_tint(pos, origin, key, block_manager, biome_colors, state_provider, settings)
+                    }
+                    (_, None, Some(FluidState { fluid: Fluid::Water, .. })) => {
+                        biome_tint(pos, origin, BlockTint::Water, biome_colors, state_provider, settings)
+                    }
+                    _ => 0xffffff,
+                };
+
+                Some(LodCell {
+                    pos,
+                    state,
+                    color,
+                    light: Direction::ALL.map(|dir| state_provider.get_light_level(pos + dir.to_vec())),
+                })
+            })
+            .collect();
+
+        Self { cells }
+    }
+
+    /// Whether no cell is solid, so the section looks like nothing from afar
+    pub fn is_empty(&self) -> bool {
+        self.cells.iter().all(Option::is_none)
+    }
+
+    /// The solid cell at `cell`, in cells from the section's corner
+    pub fn get(&self, cell: IVec3) -> Option<&LodCell> {
+        let inside = cell.cmpge(IVec3::ZERO).all() && cell.cmplt(IVec3::splat(Self::CELLS)).all();
+        self.cells.get(Self::index(cell)).filter(|_| inside)?.as_ref()
+    }
+}
+
+/// Mesh a section for drawing far away from its [LodCells]. Each side of a solid cell which doesn't face another one
+/// is a single quad, coloured with the middle of the texture the block has on that side. Everything goes in the
+/// [RenderLayer::Solid] layer.
+pub fn bake_lod(section_pos: IVec3, cells: &LodCells, block_manager: &BlockManager) -> BakedLayer {
+    let mut layer = BakedLayer::default();
+    let origin = section_pos * 16;
+
+    for (index, lod_cell) in cells.cells.iter().enumerate() {
+        let Some(LodCell { pos, state, color, light }) = *lod_cell else {
+            continue;
+        };
+        let cell = LodCells::cell_pos(index);
+
+        let mesh = get_block(block_manager, state, origin + pos);
+        let fluid_sprites = get_fluid(block_manager, state)
+            .and_then(|fluid_state| block_manager.fluid_sprites.get(&fluid_state.fluid).copied());
+
+        for (dir, corners) in CUBE_FACES {
+            if cells.get(cell + dir.to_vec()).is_some() {
+                continue;
+            }
+
+            //The texture and colour of the block's side, or of the fluid for blocks without a model
+            let (tex_coords, color) = match (&mesh, fluid_sprites) {
+                (Some(mesh), _) => {
+                    let faces = match dir {
+                        Direction::West => &mesh.west,
+                        Direction::East => &mesh.east,
+                        Direction::Down => &mesh.down,
+                        Direction::Up => &mesh.up,
+                        Direction::North => &mesh.north,
+                        Direction::South => &mesh.south,
+                    };
+                    let Some(face) = faces.first().or(mesh.any.first()) else {
+                        continue;
+                    };
+
+                    let color = if face.tint_index >= 0 { color } else { 0xffffff };
+                    (face.vertices.map(|vertex| vertex.tex_coords), color)
+                }
+                (None, Some(sprites)) => {
+                    let (min, max) = sprites.still;
+                    ([[min.0, min.1], [max.0, max.1], [min.0, min.1], [max.0, max.1]], color)
+                }
+                (None, None) => continue,
+            };
+            //Every corner samples the middle of the texture, so the quad comes out one colour
+            let middle = tex_coords
+                .iter()
+                .fold([0u32; 2], |sum, uv| [sum[0] + uv[0] as u32, sum[1] + uv[1] as u32])
+                .map(|sum| (sum / 4) as u16);
+
+            let face = BlockModelFace {
+                vertices: corners.map(|corner| BlockMeshVertex {
+                    position: corner * LOD_CELL as f32,
+                    tex_coords: middle,
+                }),
+                normal: dir.to_vec().as_vec3(),
+                animation_uv_offset: 0,
+                tint_index: -1,
+                shade: true,
+                cull_face: None,
+            };
+            let side_light = light[dir as usize];
+            let light = [VertexLight {
+                light: [side_light.get_block_level() * 16, side_light.get_sky_level() * 16],
+                ao: 255,
+                samples: LightSamples::Average([LightSamples::block(pos + dir.to_vec()); 4]),
+            }; 4];
+
+            push_quad(&mut layer, RenderLayer::Solid, (cell * LOD_CELL).as_vec3(), &face, color, light);
+        }
+    }
+
+    layer
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use glam::{ivec2, ivec3, vec3, IVec2, IVec3, Vec3};
+    use indexmap::IndexMap;
+    use minecraft_assets::schemas::models::Element;
+    use serde_json::json;
+
+    use super::{
+        add_corner_height, bake_layers, bake_lod, gpu_mesh_input, gpu_region, push_quad_repeated, side_axes,
+        BakeSettings, BakeVersions, BakedLayer, BlockStateProvider, Fluid, FluidSprites, FluidState, GreedyFaces,
+        LightLevel, LightSamples, LodCells, RenderLayer, SectionStorage, SectionVisibility, TransparentQuads,
+        VertexLight, WorldHeight, LOD_CELL, QUAD_U32S,
+    };
+    use crate::mc::biome::{BiomeColors, BlockTint};
+    use crate::mc::block::{
+        bake_element, BlockMeshVertex, BlockModelFace, BlockstateKey, ChunkBlockState, CullRule, ModelMesh,
+    };
+    use crate::mc::direction::Direction;
+    use crate::mc::{Block, BlockManager, WeightedMeshes};
+    use crate::render::gpu_mesher::GpuMesher;
+    use crate::render::pipeline::Vertex;
+
+    /// Blocks and light relative to the section being tested, everything else is air
+    struct TestProvider {
+        states: HashMap<IVec3, BlockstateKey>,
+        light: fn(IVec3) -> LightLevel,
+    }
+
+    impl TestProvider {
+        fn new(light: fn(IVec3) -> LightLevel) -> Self {
+            Self {
+                states: HashMap::new(),
+                light,
+            }
+        }
+    }
+
+    impl BlockStateProvider for TestProvider {
+        fn get_state(&self, pos: IVec3) -> ChunkBlockState {
+            self.states.get(&pos).map_or(ChunkBlockState::Air, |key| ChunkBlockState::State(*key))
+        }
+
+        fn get_light_level(&self, pos: IVec3) -> LightLevel {
+            (self.light)(pos)
+        }
+
+        fn is_section_empty(&self, rel_pos: IVec3) -> bool {
+            rel_pos != IVec3::ZERO || self.states.is_empty()
+        }
+    }
+
+    fn grid() -> impl Iterator<Item = IVec3> {
+        (-1..17).flat_map(|y| (-1..17).flat_map(move |z| (-1..17).map(move |x| ivec3(x, y, z))))
+    }
+
+    fn test_light(pos: IVec3) -> LightLevel {
+        LightLevel::from_sky_and_block((pos.x + pos.y + 1).rem_euclid(16) as u8, (pos.z * 3 + 1).rem_euclid(16) as u8)
+    }
+
+    /// A layer of `quads` quads which all sample the light of the block at the origin
+    fn layer(quads: usize) -> BakedLayer {
+        BakedLayer {
+            vertices: vec![0; quads * QUAD_U32S as usize * 4],
+            light: vec![0; quads * 8],
+            light_samples: vec![LightSamples::Average([LightSamples::block(IVec3::ZERO); 4]); quads * 4],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn light_samples_round_trip() {
+        let mut seen = vec![false; 18 * 18 * 18];
+        for pos in grid() {
+            let block = LightSamples::block(pos);
+            assert!(!std::mem::replace(&mut seen[block as usize], true), "{pos} packs like another block");
+            assert_eq!(LightSamples::pos(block), pos);
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    fn light_samples_read_their_blocks() {
+        let provider = TestProvider::new(test_light);
+
+        for pos in grid() {
+            let light = test_light(pos);
+            let average = LightSamples::Average([LightSamples::block(pos); 4]);
+            assert_eq!(average.light(&provider), [light.get_block_level() * 16, light.get_sky_level() * 16]);
+
+            //The block mirrored through the middle of the section
+            let other = 15 - pos;
+            let other_light = test_light(other);
+            let brightest = LightSamples::Brightest([LightSamples::block(pos), LightSamples::block(other)]);
+            assert_eq!(
+                brightest.light(&provider),
+                [
+                    light.get_block_level().max(other_light.get_block_level()) * 16,
+                    light.get_sky_level().max(other_light.get_sky_level()) * 16,
+                ]
+            );
+        }
+
+        let corners = [ivec3(-1, 0, 0), ivec3(0, 0, 0), ivec3(0, 16, 0), ivec3(16, 16, 16)];
+        let sum = |level: fn(&LightLevel) -> u8| corners.iter().map(|pos| level(&test_light(*pos))).sum::<u8>() * 4;
+        assert_eq!(
+            LightSamples::Average(corners.map(LightSamples::block)).light(&provider),
+            [sum(LightLevel::get_block_level), sum(LightLevel::get_sky_level)]
+        );
+    }
+
+    #[test]
+    fn light_follows_vertices() {
+        let mut storage = SectionStorage::new(100_000);
+        let provider = TestProvider::new(test_light);
+        let light = test_light(IVec3::ZERO);
+
+        let check = |storage: &mut SectionStorage, pos: IVec3, quads: [usize; 3]| {
+            let section = storage.get(&pos).unwrap();
+            for (ranges, quads) in section.layers.iter().zip(quads) {
+                let Some(ranges) = ranges else {
+                    assert_eq!(quads, 0);
+                    continue;
+                };
+                assert_eq!(ranges.quads() as usize, quads);
+                assert_eq!(ranges.light_range.start, ranges.vertex_range.end);
+                //2 bytes per vertex
+                assert_eq!(ranges.light_range.len(), quads * 2);
+                assert!(ranges.sample_range.is_empty());
+            }
+
+            let starts: Vec<u32> = section.layers.iter().flatten().map(|ranges| ranges.light_range.start).collect();
+            let (relit, rebake) = storage.relight(pos, &provider);
+            assert!(!rebake);
+            assert_eq!(relit.iter().map(|(start, _)| *start).collect::<Vec<u32>>(), starts);
+            for ((_, relit), quads) in relit.iter().zip(quads.iter().filter(|quads| **quads > 0)) {
+                assert_eq!(relit.len(), quads * 8);
+                assert!(relit.chunks_exact(2).all(|vertex| vertex == [light.get_block_level() * 16, light.get_sky_level() * 16]));
+            }
+        };
+
+        storage.replace(ivec3(0, 0, 0), &vec![layer(3), layer(0), BakedLayer::default()], SectionVisibility::ALL).unwrap();
+        storage.replace(ivec3(1, 0, 0), &vec![layer(5), layer(2), BakedLayer::default()], SectionVisibility::ALL).unwrap();
+        //Leaves a hole at the start of the buffer
+        storage.replace(ivec3(0, 0, 0), &vec![layer(7), layer(1), BakedLayer::default()], SectionVisibility::ALL).unwrap();
+        check(&mut storage, ivec3(0, 0, 0), [7, 1, 0]);
+        check(&mut storage, ivec3(1, 0, 0), [5, 2, 0]);
+
+        let capacity = storage.capacity();
+        let copies = storage.compact(capacity);
+        assert_eq!(copies.first().map(|(_, dst, _)| *dst), Some(0));
+        check(&mut storage, ivec3(0, 0, 0), [7, 1, 0]);
+        check(&mut storage, ivec3(1, 0, 0), [5, 2, 0]);
+    }
+
+    /// Put a section with a single quad, 18 u32s, at each of `positions` in turn
+    fn single_quads(storage: &mut SectionStorage, positions: impl IntoIterator<Item = IVec3>) {
+        for pos in positions {
+            storage.replace(pos, &vec![layer(1)], SectionVisibility::ALL).unwrap();
+        }
+    }
+
+    fn unload(storage: &mut SectionStorage, pos: IVec3) {
+        storage.replace(pos, &vec![BakedLayer::default()], SectionVisibility::ALL).unwrap();
+    }
+
+    fn start(storage: &SectionStorage, pos: IVec3) -> u32 {
+        storage.get(&pos).unwrap().layers[0].as_ref().unwrap().range().start
+    }
+
+    #[test]
+    fn fragmentation_of_free_space() {
+        let mut storage = SectionStorage::new(180);
+        assert_eq!(storage.fragmentation(), 0.0);
+        single_quads(&mut storage, (0..10).map(|x| ivec3(x, 0, 0)));
+        //Full, so nothing is fragmented
+        assert_eq!(storage.fragmentation(), 0.0);
+
+        unload(&mut storage, ivec3(1, 0, 0));
+        unload(&mut storage, ivec3(5, 0, 0));
+        //Two holes of the same size
+        assert_eq!(storage.fragmentation(), 0.5);
+        unload(&mut storage, ivec3(2, 0, 0));
+        assert_eq!(storage.fragmentation(), 1.0 - 36.0 / 54.0);
+    }
+
+    #[test]
+    fn compact_moves_ranges_down() {
+        let mut storage = SectionStorage::new(1000);
+        let positions = (0..5).map(|x| ivec3(x, 0, 0)).collect::<Vec<_>>();
+        single_quads(&mut storage, positions.clone());
+        for (index, pos) in positions.iter().enumerate() {
+            assert_eq!(start(&storage, *pos), index as u32 * 18);
+        }
+        unload(&mut storage, positions[1]);
+        assert!(storage.fragmentation() > 0.0);
+
+        //The first section stays where it is, the three after the hole were next to each other and move as one
+        let copies = storage.compact(1000);
+        assert_eq!(copies, vec![(0, 0, 18), (36, 18, 54)]);
+        assert!(copies.iter().all(|(src, dst, _)| dst <= src));
+        for (index, pos) in [positions[0], positions[2], positions[3], positions[4]].into_iter().enumerate() {
+            assert_eq!(start(&storage, pos), index as u32 * 18);
+        }
+        assert_eq!(storage.used(), 72);
+        assert_eq!(storage.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn evict_farthest_first() {
+        let mut storage = SectionStorage::new(1000);
+        single_quads(&mut storage, (1..5).map(|x| ivec3(x, 0, 0)));
+
+        //Only the 2 sections past x=2 may go, which isn't enough to get down to 1 section
+        assert_eq!(storage.evict_farthest(IVec2::ZERO, ivec3(2, 0, 0), 18), None);
+        assert_eq!(storage.used(), 72);
+
+        assert_eq!(storage.evict_farthest(IVec2::ZERO, ivec3(1, 0, 0), 36), Some(vec![ivec3(4, 0, 0), ivec3(3, 0, 0)]));
+        assert_eq!(storage.used(), 36);
+        assert!(storage.get(&ivec3(1, 0, 0)).is_some() && storage.get(&ivec3(2, 0, 0)).is_some());
+    }
+
+    #[test]
+    fn bake_finishing_after_relight() {
+        let versions = BakeVersions::default();
+        let pos = ivec3(1, 2, 3);
+
+        let first = versions.begin(pos);
+        //The light changed while the first bake was running, so another one was requested
+        let second = versions.begin(pos);
+        assert!(!versions.is_current(pos, first));
+
+        //The first bake finishing doesn't clear the second one
+        versions.finish(pos, first);
+        assert!(versions.is_pending(pos));
+        assert!(versions.is_current(pos, second));
+
+        versions.finish(pos, second);
+        assert!(!versions.is_pending(pos));
+
+        //Finishing again after a newer request, like a relight after the upload, leaves that one pending
+        let third = versions.begin(pos);
+        versions.finish(pos, second);
+        assert!(versions.is_current(pos, third));
+        assert!(versions.is_pending(pos));
+
+        versions.retain(|section| section != pos);
+        assert!(!versions.is_pending(pos));
+        assert!(!versions.is_current(pos, third));
+    }
+
+    /// The top of a full block, with the 16x16 texture at `uv`
+    fn top_face(uv: [u16; 2]) -> BlockModelFace {
+        let vertex = |x: f32, z: f32| BlockMeshVertex {
+            position: vec3(x, 1.0, z),
+            tex_coords: [uv[0] + x as u16 * 16, uv[1] + z as u16 * 16],
+        };
+
+        BlockModelFace {
+            vertices: [vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 1.0), vertex(1.0, 0.0)],
+            normal: vec3(0.0, 1.0, 0.0),
+            animation_uv_offset: 0,
+            tint_index: -1,
+            shade: true,
+            cull_face: Some(Direction::Up),
+        }
+    }
+
+    fn uniform_light(level: u8, ao: u8, pos: IVec3) -> [VertexLight; 4] {
+        [VertexLight {
+            light: [level, level],
+            ao,
+            samples: LightSamples::Average([LightSamples::block(pos + IVec3::Y); 4]),
+        }; 4]
+    }
+
+    /// The 4 u32s of each vertex in a layer
+    fn vertex_words(layer: &BakedLayer) -> Vec<[u32; 4]> {
+        layer
+            .vertices
+            .chunks_exact(16)
+            .map(|vertex| std::array::from_fn(|word| u32::from_le_bytes(vertex[word * 4..word * 4 + 4].try_into().unwrap())))
+            .collect()
+    }
+
+    /// How many times each quad of a layer repeats its texture along u and v
+    fn repeats(layer: &BakedLayer) -> Vec<[u32; 2]> {
+        vertex_words(layer)
+            .chunks_exact(4)
+            .map(|quad| {
+                let [u, v] = [(quad[0][1] >> 16) & 0xff, quad[0][1] >> 24];
+                assert!(quad.iter().all(|vertex| vertex[1] >> 16 == quad[0][1] >> 16));
+                [u, v]
+            })
+            .collect()
+    }
+
+    /// Top faces of the blocks at `positions`, all looking the same, merged
+    fn merge_tops(positions: impl IntoIterator<Item = IVec3>) -> BakedLayer {
+        let mut greedy = GreedyFaces::default();
+        for pos in positions {
+            assert!(greedy.add(RenderLayer::Solid, Direction::Up, pos, &top_face([0, 0]), 0xffffff, uniform_light(240, 255, pos)));
+        }
+        let mut layers = vec![BakedLayer::default(); RenderLayer::ALL.len()];
+        greedy.mesh(&mut layers);
+        layers.swap_remove(0)
+    }
+
+    #[test]
+    fn greedy_merges_slabs() {
+        //A row along x, which the texture's u axis runs along
+        let row = merge_tops((0..4).map(|x| ivec3(x, 0, 0)));
+        assert_eq!(row.quads(), 1);
+        assert_eq!(repeats(&row), [[4, 1]]);
+        assert_eq!(row.merged.len(), 1);
+        assert_eq!(row.merged[0].samples.len(), 4 * 4);
+        let xs: Vec<u32> = vertex_words(&row).iter().map(|vertex| vertex[0] & 0xffff).collect();
+        assert_eq!(xs.iter().min(), Some(&(8 * 2048)));
+        assert_eq!(xs.iter().max(), Some(&(12 * 2048)));
+
+        let rectangle = merge_tops((0..3).flat_map(|x| (0..2).map(move |z| ivec3(x, 5, z))));
+        assert_eq!(repeats(&rectangle), [[3, 2]]);
+
+        let slice = merge_tops((0..16).flat_map(|x| (0..16).map(move |z| ivec3(x, 15, z))));
+        assert_eq!(repeats(&slice), [[16, 16]]);
+
+        //Faces on different slices never merge
+        let stairs = merge_tops((0..4).map(|x| ivec3(x, x, 0)));
+        assert_eq!(repeats(&stairs), [[1, 1]; 4]);
+        assert!(stairs.merged.is_empty());
+
+        //An L shape becomes the widest rectangle first, then what's left
+        let l_shape = merge_tops([ivec3(0, 0, 0), ivec3(0, 0, 1), ivec3(0, 0, 2), ivec3(1, 0, 0)]);
+        let mut l_repeats = repeats(&l_shape);
+        l_repeats.sort();
+        assert_eq!(l_repeats, [[1, 1], [1, 3]]);
+    }
+
+    #[test]
+    fn greedy_keeps_different_faces_apart() {
+        let first = ivec3(0, 0, 0);
+        let second = ivec3(1, 0, 0);
+        let variants: [(&str, BlockModelFace, u32, [VertexLight; 4]); 4] = [
+            ("texture", top_face([16, 0]), 0xffffff, uniform_light(240, 255, second)),
+            ("colour", top_face([0, 0]), 0x80ff80, uniform_light(240, 255, second)),
+            ("ambient occlusion", top_face([0, 0]), 0xffffff, uniform_light(240, 204, second)),
+            ("light", top_face([0, 0]), 0xffffff, uniform_light(224, 255, second)),
+        ];
+
+        for (difference, face, color, light) in variants {
+            let mut greedy = GreedyFaces::default();
+            assert!(greedy.add(RenderLayer::Solid, Direction::Up, first, &top_face([0, 0]), 0xffffff, uniform_light(240, 255, first)));
+            assert!(greedy.add(RenderLayer::Solid, Direction::Up, second, &face, color, light));
+
+            let mut layers = vec![BakedLayer::default(); RenderLayer::ALL.len()];
+            greedy.mesh(&mut layers);
+            assert_eq!(layers[0].quads(), 2, "faces with a different {difference} were merged");
+            assert!(layers[0].merged.is_empty());
+        }
+
+        //Faces whose light changes across them aren't merged at all
+        let mut light = uniform_light(240, 255, first);
+        light[2].ao = 153;
+        let mut greedy = GreedyFaces::default();
+        assert!(!greedy.add(RenderLayer::Solid, Direction::Up, first, &top_face([0, 0]), 0xffffff, light));
+    }
+
+    #[test]
+    fn greedy_mesh_is_deterministic() {
+        let positions: Vec<IVec3> = (0..16).flat_map(|x| (0..16).map(move |y| ivec3(x, y, (x * y) % 3))).collect();
+        let first = merge_tops(positions.iter().copied());
+        for _ in 0..8 {
+            assert_eq!(merge_tops(positions.iter().copied()).vertices, first.vertices);
+        }
+    }
+
+    #[test]
+    fn repeated_quads() {
+        let mut layer = BakedLayer::default();
+        let mut light = uniform_light(160, 255, IVec3::ZERO);
+        light[0].light = [16, 32];
+        push_quad_repeated(&mut layer, RenderLayer::Solid, vec3(2.0, 3.0, 4.0), &top_face([0, 0]), 0xffffff, light, [3, 5]);
+
+        assert_eq!(layer.quads(), 1);
+        assert_eq!(repeats(&layer), [[3, 5]]);
+        assert_eq!(layer.light.len(), 8);
+        assert_eq!(layer.light_samples.len(), 4);
+        //Every vertex keeps its own light, whichever corner the quad starts from
+        let mut vertex_light: Vec<[u8; 2]> = layer.light.chunks_exact(2).map(|light| [light[0], light[1]]).collect();
+        vertex_light.sort();
+        assert_eq!(vertex_light, [[16, 32], [160, 160], [160, 160], [160, 160]]);
+
+        let positions: Vec<[u32; 3]> = vertex_words(&layer)
+            .iter()
+            .map(|vertex| [vertex[0] & 0xffff, vertex[0] >> 16, vertex[1] & 0xffff])
+            .collect();
+        assert!(positions.iter().all(|position| position[1] == (4 + 8) * 2048));
+        assert!(positions.contains(&[10 * 2048, 12 * 2048, 12 * 2048]));
+        assert!(positions.contains(&[11 * 2048, 12 * 2048, 13 * 2048]));
+    }
+
+    #[test]
+    fn merged_quads_relight_until_split() {
+        let row = merge_tops((0..4).map(|x| ivec3(x, 0, 0)));
+        let mut storage = SectionStorage::new(10_000);
+        storage.replace(IVec3::ZERO, &vec![row, BakedLayer::default(), BakedLayer::default()], SectionVisibility::ALL).unwrap();
+
+        //The same light everywhere keeps the faces agreeing
+        let (relit, rebake) = storage.relight(IVec3::ZERO, &TestProvider::new(|_| LightLevel::from_sky_and_block(12, 3)));
+        assert_eq!(relit.len(), 1);
+        assert!(!rebake);
+
+        //Light changing along the row means the quad has to be split up
+        let (_, rebake) = storage.relight(IVec3::ZERO, &TestProvider::new(|pos| LightLevel::from_sky_and_block(15, pos.x as u8)));
+        assert!(rebake);
+    }
+
+    fn side_face(dir: Direction, uv: [u16; 2], tint_index: i32) -> BlockModelFace {
+        let (axis, a, b) = side_axes(dir);
+        let positive = dir.to_vec()[axis] > 0;
+        let vertex = |corner_a: f32, corner_b: f32| {
+            let mut position = Vec3::ZERO;
+            position[axis] = positive as u8 as f32;
+            position[a] = corner_a;
+            position[b] = corner_b;
+            BlockMeshVertex {
+                position,
+                tex_coords: [uv[0] + corner_a as u16 * 16, uv[1] + corner_b as u16 * 16],
+            }
+        };
+
+        BlockModelFace {
+            vertices: [vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 1.0), vertex(1.0, 0.0)],
+            normal: dir.to_vec().as_vec3(),
+            animation_uv_offset: 0,
+            tint_index,
+            shade: true,
+            cull_face: Some(dir),
+        }
+    }
+
+    fn cube(layer: RenderLayer, uv: [u16; 2], tint_index: i32) -> ModelMesh {
+        let faces = |dir| vec![side_face(dir, uv, tint_index)];
+        ModelMesh {
+            north: faces(Direction::North),
+            south: faces(Direction::South),
+            west: faces(Direction::West),
+            east: faces(Direction::East),
+            up: faces(Direction::Up),
+            down: faces(Direction::Down),
+            any: vec![],
+            cull: 0b111111,
+            layer,
+        }
+    }
+
+    /// Two diagonal planes like a flower, which are never culled and only get flat light
+    fn cross() -> ModelMesh {
+        let plane = |from: [f32; 2], to: [f32; 2]| {
+            let vertex = |x: f32, y: f32, z: f32, u: u16, v: u16| BlockMeshVertex {
+                position: vec3(x, y, z),
+                tex_coords: [u, v],
+            };
+            BlockModelFace {
+                vertices: [
+                    vertex(from[0], 1.0, from[1], 32, 0),
+                    vertex(from[0], 0.0, from[1], 32, 16),
+                    vertex(to[0], 0.0, to[1], 48, 16),
+                    vertex(to[0], 1.0, to[1], 48, 0),
+                ],
+                normal: vec3(to[1] - from[1], 0.0, from[0] - to[0]).normalize(),
+                animation_uv_offset: 0,
+                tint_index: 0,
+                shade: false,
+                cull_face: None,
+            }
+        };
+
+        ModelMesh {
+            north: vec![],
+            south: vec![],
+            west: vec![],
+            east: vec![],
+            up: vec![],
+            down: vec![],
+            any: vec![plane([0.15, 0.15], [0.85, 0.85]), plane([0.15, 0.85], [0.85, 0.15])],
+            cull: 0,
+            layer: RenderLayer::Cutout,
+        }
+    }
+
+    /// Vanilla's bottom stairs turned by the `facing=south` variant. The top of the lower half and the inner side of
+    /// the step don't declare a cullface, so they go in `any` and get their light from the way they face
+    fn stairs() -> ModelMesh {
+        let variant = serde_json::from_value(json!({ "model": "minecraft:block/stairs", "y": 90 })).unwrap();
+        let side = |cull_face: &str| json!({ "texture": "stairs", "cullface": cull_face });
+        let inner = json!({ "texture": "stairs" });
+        let elements: Vec<Element> = serde_json::from_value(json!([
+            {
+                "from": [0, 0, 0],
+                "to": [16, 8, 16],
+                "faces": {
+                    "down": side("down"), "up": inner, "north": side("north"),
+                    "south": side("south"), "west": side("west"), "east": side("east"),
+                },
+            },
+            {
+                "from": [8, 8, 0],
+                "to": [16, 16, 16],
+                "faces": {
+                    "up": side("up"), "north": side("north"), "south": side("south"),
+                    "west": inner, "east": side("east"),
+                },
+            },
+        ]))
+        .unwrap();
+
+        let faces = elements
+            .iter()
+            .flat_map(|element| bake_element(element, &variant, true, |_| Some((((48, 0), (64, 16)), 0))))
+            .collect::<Vec<_>>();
+        ModelMesh::from_faces(RenderLayer::Solid, &faces)
+    }
+
+    /// Stone, leaves, a flower and stairs, which are the blocks 0, 1, 2 and 3 with a single state each
+    fn block_manager() -> BlockManager {
+        let block = |mesh: ModelMesh| {
+            Block::Variants(IndexMap::from([(
+                String::new(),
+                WeightedMeshes {
+                    weights: vec![vec![1]],
+                    meshes: vec![Arc::new(mesh)],
+                },
+            )]))
+        };
+
+        BlockManager {
+            blocks: IndexMap::from([
+                ("stone".to_string(), block(cube(RenderLayer::Solid, [0, 0], -1))),
+                ("leaves".to_string(), block(cube(RenderLayer::Cutout, [16, 0], 0))),
+                ("flower".to_string(), block(cross())),
+                ("stairs".to_string(), block(stairs())),
+            ]),
+            render_layers: HashMap::new(),
+            tints: HashMap::from([(1, BlockTint::Constant(0x48b518)), (2, BlockTint::Constant(0xd02040))]),
+            offsets: HashMap::new(),
+            cull_rules: HashMap::new(),
+            fluids: HashMap::new(),
+            fluid_sprites: HashMap::new(),
+        }
+    }
+
+    fn key(block: u16) -> BlockstateKey {
+        BlockstateKey { block, augment: 0 }
+    }
+
+    /// Every quad of a layer, its vertices followed by their light, sorted so the order they were meshed in doesn't
+    /// matter
+    fn sorted_quads(vertices: &[u8], light: &[u8]) -> Vec<Vec<u8>> {
+        let mut quads: Vec<Vec<u8>> = vertices
+            .chunks_exact(QUAD_U32S as usize * 4)
+            .zip(light.chunks_exact(8))
+            .map(|(vertices, light)| [vertices, light].concat())
+            .collect();
+        quads.sort();
+        quads
+    }
+
+    /// Mesh the section of `provider` on the GPU, relight it with the light of `relit` if there is one, and read back
+    /// the vertices and light of each layer
+    fn gpu_bake(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        block_manager: &BlockManager,
+        provider: &TestProvider,
+        relit: Option<&TestProvider>,
+        settings: &BakeSettings,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let gpu_mesher = GpuMesher::new(device);
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+        let (input, visibility) =
+            gpu_mesh_input(IVec3::ZERO, block_manager, &biome_colors, provider, settings, &gpu_mesher).unwrap();
+
+        let mut storage = SectionStorage::new(1_000_000);
+        storage.replace_gpu(IVec3::ZERO, input.quads, visibility).unwrap();
+        let size = storage.capacity() as u64 * 4;
+        let chunk_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        gpu_mesher.queue(IVec3::ZERO, input);
+        gpu_mesher.flush(device, queue, &storage, &chunk_buffer);
+        if let Some(relit) = relit {
+            gpu_mesher.queue_relight(IVec3::ZERO, gpu_region(IVec3::ZERO, block_manager, relit));
+            gpu_mesher.flush(device, queue, &storage, &chunk_buffer);
+        }
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(&chunk_buffer, 0, &readback, 0, size);
+        queue.submit([encoder.finish()]);
+        readback.slice(..).map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::Maintain::Wait);
+
+        let data = readback.slice(..).get_mapped_range();
+        let bytes = |range: &std::ops::Range<u32>| data[range.start as usize * 4..range.end as usize * 4].to_vec();
+        storage
+            .get(&IVec3::ZERO)
+            .unwrap()
+            .layers
+            .iter()
+            .map(|ranges| match ranges {
+                Some(ranges) => (bytes(&ranges.vertex_range), bytes(&ranges.light_range)),
+                None => (vec![], vec![]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gpu_meshing_matches_cpu() {
+        let instance = wgpu::Instance::default();
+        //A software adapter like lavapipe or WARP is enough, without one this fails rather than passing untested
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+            .expect("no adapter to run the mesher's compute shader on");
+        let (device, queue) = pollster::block_on(adapter.request_device(&Default::default(), None)).unwrap();
+        let block_manager = block_manager();
+
+        let mut solid = TestProvider::new(test_light);
+        for pos in (0..16).flat_map(|x| (0..16).map(move |z| ivec3(x, 0, z))) {
+            solid.states.insert(pos, key(0));
+        }
+
+        //Blocks on the floor, in corners and against each other so the floor's corners get every amount of occlusion,
+        //and blocks outside the section which cull and shade the faces next to them
+        let mut ao_corners = TestProvider::new(test_light);
+        ao_corners.states = solid.states.clone();
+        for pos in [ivec3(3, 1, 3), ivec3(4, 1, 4), ivec3(7, 1, 7), ivec3(8, 1, 7), ivec3(7, 1, 8), ivec3(12, 2, 12)] {
+            ao_corners.states.insert(pos, key(0));
+        }
+        for pos in [ivec3(-1, 1, 5), ivec3(16, 1, 9), ivec3(5, 1, -1), ivec3(9, 0, 16), ivec3(0, -1, 0)] {
+            ao_corners.states.insert(pos, key(0));
+        }
+
+        let mut cutout = TestProvider::new(test_light);
+        cutout.states = solid.states.clone();
+        for x in 0..16 {
+            cutout.states.insert(ivec3(x, 1, x), key(2));
+            cutout.states.insert(ivec3(x, 1 + x % 3, 15 - x), key(1));
+        }
+
+        //Light that differs across every edge of the section, with blocks on each of them
+        let mut borders = TestProvider::new(|pos| {
+            let outside = (pos.cmplt(IVec3::ZERO) | pos.cmpgt(IVec3::splat(15))).any();
+            match outside {
+                true => LightLevel::from_sky_and_block(15, 3),
+                false => LightLevel::from_sky_and_block((pos.x + pos.z) as u8 % 16, pos.y as u8 % 16),
+            }
+        });
+        for (a, b) in (0..16).flat_map(|a| [0, 15].map(move |b| (a, b))) {
+            borders.states.insert(ivec3(a, b, 0), key(0));
+            borders.states.insert(ivec3(b, a, 15), key(0));
+            borders.states.insert(ivec3(a, 15, b), key(1));
+        }
+
+        //Rotated stairs in a row, against stone and under it, whose faces in `any` get light from where they turned to
+        let mut stairs = TestProvider::new(test_light);
+        stairs.states = solid.states.clone();
+        for pos in (2..6).map(|x| ivec3(x, 1, 6)).chain([ivec3(9, 1, 9)]) {
+            stairs.states.insert(pos, key(3));
+        }
+        for pos in [ivec3(1, 1, 6), ivec3(3, 2, 6), ivec3(4, 1, 5)] {
+            stairs.states.insert(pos, key(0));
+        }
+
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+        let fixtures = [
+            ("solid", solid),
+            ("ambient occlusion", ao_corners),
+            ("cutout", cutout),
+            ("borders", borders),
+            ("rotated stairs", stairs),
+        ];
+        for (name, provider) in &fixtures {
+            //The same blocks with other light, which the GPU gets to by relighting what it meshed with the old light
+            let mut relit = TestProvider::new(|pos| test_light(ivec3(pos.z, pos.x, pos.y) * 2 + 5));
+            relit.states = provider.states.clone();
+
+            for smooth_lighting in [true, false] {
+                let settings = BakeSettings { smooth_lighting, ..Default::default() };
+                for (expected, relit) in [(provider, None), (&relit, Some(&relit))] {
+                    let (cpu, _) = bake_layers(IVec3::ZERO, &block_manager, &biome_colors, expected, &settings);
+                    let gpu = gpu_bake(&device, &queue, &block_manager, provider, relit, &settings);
+
+                    assert!(cpu.iter().any(|layer| layer.quads() > 0));
+                    for (layer, (cpu, (gpu_vertices, gpu_light))) in cpu.iter().zip(&gpu).enumerate() {
+                        assert_eq!(
+                            sorted_quads(gpu_vertices, gpu_light),
+                            sorted_quads(&cpu.vertices, &cpu.light),
+                            "{name}, layer {layer}, smooth lighting {smooth_lighting}, relit {}",
+                            relit.is_some()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_faces_get_light_from_where_they_face() {
+        //Only the blocks beside the stairs along x are lit, which a face looking north samples and one looking west
+        //doesn't
+        let mut provider = TestProvider::new(|pos| match pos.y == 1 && pos.z == 5 && pos.x != 5 {
+            true => LightLevel::from_sky_and_block(15, 15),
+            false => LightLevel::from_sky_and_block(0, 0),
+        });
+        provider.states.insert(ivec3(5, 1, 5), key(3));
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+        let (layers, _) = bake_layers(IVec3::ZERO, &block_manager(), &biome_colors, &provider, &BakeSettings::default());
+        let layer = &layers[RenderLayer::Solid as usize];
+
+        //The inner side of the step faced west in the model, the variant turned it north
+        let z = |vertex: &[u32; 4]| vertex[1] & 0xffff;
+        let inner = vertex_words(layer)
+            .chunks_exact(4)
+            .position(|quad| quad.iter().all(|vertex| z(vertex) == ((5.5 + 8.0) * 2048.0) as u32))
+            .unwrap();
+        assert!(layer.light[inner * 8..inner * 8 + 8].iter().all(|level| *level > 0));
+    }
+
+    #[test]
+    fn same_block_cull_rule() {
+        //Two leaves next to each other and stone beside one of them, the leaves standing in for glass
+        let mut provider = TestProvider::new(test_light);
+        provider.states.insert(ivec3(4, 4, 4), key(1));
+        provider.states.insert(ivec3(5, 4, 4), key(1));
+        provider.states.insert(ivec3(3, 4, 4), key(0));
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+        let settings = BakeSettings::default();
+        let quads = |block_manager: &BlockManager| {
+            let (layers, _) = bake_layers(IVec3::ZERO, block_manager, &biome_colors, &provider, &settings);
+            [RenderLayer::Solid, RenderLayer::Cutout].map(|layer| layers[layer as usize].quads())
+        };
+
+        //Leaves don't hide anything, but the stone hides the face of the leaves against it
+        let mut block_manager = block_manager();
+        assert_eq!(quads(&block_manager), [6, 11]);
+        //The faces between the leaves go, and the stone still has the face against them
+        block_manager.cull_rules.insert(1, CullRule::SameBlock);
+        assert_eq!(quads(&block_manager), [6, 9]);
+    }
+
+    /// Cells of the section of `provider` and their mesh
+    fn lod(provider: &TestProvider) -> (LodCells, BakedLayer) {
+        let block_manager = block_manager();
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+        let cells = LodCells::new(IVec3::ZERO, &block_manager, &biome_colors, provider, &BakeSettings::default());
+        let mesh = bake_lod(IVec3::ZERO, &cells, &block_manager);
+        (cells, mesh)
+    }
+
+    /// Fill the blocks of a cell from the bottom up, `count` of them
+    fn fill_cell(provider: &mut TestProvider, cell: IVec3, count: i32, block: u16) {
+        for i in 0..count {
+            let pos = cell * LOD_CELL + ivec3(i % LOD_CELL, i / (LOD_CELL * LOD_CELL), (i / LOD_CELL) % LOD_CELL);
+            provider.states.insert(pos, key(block));
+        }
+    }
+
+    #[test]
+    fn lod_of_full_section() {
+        let mut full = TestProvider::new(test_light);
+        for y in 0..LodCells::CELLS {
+            for z in 0..LodCells::CELLS {
+                for x in 0..LodCells::CELLS {
+                    fill_cell(&mut full, ivec3(x, y, z), LOD_CELL * LOD_CELL * LOD_CELL, 0);
+                }
+            }
+        }
+        let (cells, mesh) = lod(&full);
+
+        //Only the outside of the section, a quad per side of each cell on it
+        let cells_per_side = LodCells::CELLS * LodCells::CELLS;
+        assert_eq!(mesh.quads() as i32, 6 * cells_per_side);
+        assert_eq!(mesh.light.len(), mesh.quads() as usize * 8);
+        let words = vertex_words(&mesh);
+        let xs = words.iter().map(|vertex| vertex[0] & 0xffff);
+        assert_eq!((xs.clone().min(), xs.max()), (Some(8 * 2048), Some(24 * 2048)));
+        //The middle of the stone texture, at every corner
+        assert!(words.iter().all(|vertex| vertex[2] == 8 | 8 << 16));
+
+        //Cells look like the top block, which has full blocks above it inside the cell
+        let cell = cells.get(ivec3(1, 2, 3)).unwrap();
+        assert_eq!(cell.pos, ivec3(4, 11, 12));
+        assert_eq!(cell.light[Direction::Up as usize].byte, test_light(ivec3(4, 12, 12)).byte);
+    }
+
+    #[test]
+    fn lod_cells_need_half_their_blocks() {
+        let mut provider = TestProvider::new(test_light);
+        let half = LOD_CELL * LOD_CELL * LOD_CELL / 2;
+        fill_cell(&mut provider, ivec3(0, 0, 0), half - 1, 0);
+        fill_cell(&mut provider, ivec3(2, 1, 0), half, 0);
+        let (cells, mesh) = lod(&provider);
+
+        assert!(cells.get(ivec3(0, 0, 0)).is_none());
+        assert!(cells.get(ivec3(2, 1, 0)).is_some());
+        assert_eq!(mesh.quads(), 6);
+        //Outside the section
+        assert!(cells.get(ivec3(-1, 0, 0)).is_none());
+        assert!(cells.get(ivec3(0, LodCells::CELLS, 0)).is_none());
+    }
+
+    #[test]
+    fn lod_cells_look_like_their_top_block() {
+        let mut provider = TestProvider::new(test_light);
+        //Stone at the bottom with a single leaves block on top
+        fill_cell(&mut provider, ivec3(1, 1, 1), 40, 0);
+        provider.states.insert(ivec3(6, 7, 5), key(1));
+        let (cells, mesh) = lod(&provider);
+
+        let cell = cells.get(ivec3(1, 1, 1)).unwrap();
+        assert_eq!(cell.pos, ivec3(6, 7, 5));
+        assert!(matches!(cell.state, ChunkBlockState::State(BlockstateKey { block: 1, .. })));
+        assert_eq!(cell.color, 0x48b518);
+
+        //The top gets the light above the top block, from the cell
+        let light = test_light(ivec3(6, 8, 5));
+        assert_eq!(cell.light[Direction::Up as usize].byte, light.byte);
+        let expected = [light.get_block_level() * 16, light.get_sky_level() * 16];
+        assert!(mesh.light.chunks_exact(8).any(|quad| quad.chunks_exact(2).all(|vertex| vertex == expected)));
+        //The middle of the leaves texture
+        assert!(vertex_words(&mesh).iter().all(|vertex| vertex[2] == 24 | 8 << 16));
+    }
+
+    #[test]
+    fn lod_culls_faces_between_cells() {
+        let mut provider = TestProvider::new(test_light);
+        fill_cell(&mut provider, ivec3(0, 0, 0), LOD_CELL * LOD_CELL * LOD_CELL, 0);
+        fill_cell(&mut provider, ivec3(1, 0, 0), LOD_CELL * LOD_CELL * LOD_CELL, 0);
+        let (_, mesh) = lod(&provider);
+        assert_eq!(mesh.quads(), 10);
+
+        //Faces on the edge of the section aren't culled by the section next to it
+        let mut edge = TestProvider::new(test_light);
+        fill_cell(&mut edge, ivec3(LodCells::CELLS - 1, 0, 0), LOD_CELL * LOD_CELL * LOD_CELL, 0);
+        assert_eq!(lod(&edge).1.quads(), 6);
+    }
+
+    #[test]
+    fn lod_of_empty_section() {
+        let (cells, mesh) = lod(&TestProvider::new(test_light));
+        assert!(cells.is_empty());
+        assert_eq!(mesh.quads(), 0);
+
+        //A few scattered blocks don't make a cell either
+        let mut sparse = TestProvider::new(test_light);
+        for i in 0..16 {
+            sparse.states.insert(ivec3(i, i, i), key(0));
+        }
+        let (cells, mesh) = lod(&sparse);
+        assert!(cells.is_empty());
+        assert_eq!(mesh.quads(), 0);
+    }
+
+    /// Whether each face of a section can see each other face, by [Direction]
+    fn connections(visibility: SectionVisibility) -> [[bool; 6]; 6] {
+        Direction::ALL.map(|from| Direction::ALL.map(|to| visibility.connects(1 << from as u8, to)))
+    }
+
+    #[test]
+    fn flood_fill_open_and_solid() {
+        assert_eq!(SectionVisibility::flood_fill(|_| false), SectionVisibility::ALL);
+        assert_eq!(SectionVisibility::flood_fill(|_| true), SectionVisibility::NONE);
+        assert_eq!(connections(SectionVisibility::ALL), [[true; 6]; 6]);
+
+        //A single gap anywhere in a solid section sees only the faces it touches
+        let corner = SectionVisibility::flood_fill(|pos| pos != IVec3::ZERO);
+        let faces = [Direction::West, Direction::Down, Direction::North];
+        for from in Direction::ALL {
+            for to in Direction::ALL {
+                assert_eq!(corner.connects(1 << from as u8, to), faces.contains(&from) && faces.contains(&to));
+            }
+        }
+        assert_eq!(SectionVisibility::flood_fill(|pos| pos != IVec3::splat(8)), SectionVisibility::NONE);
+    }
+
+    #[test]
+    fn flood_fill_wall() {
+        //A wall across the middle splits the section in a west and an east half
+        let visibility = SectionVisibility::flood_fill(|pos| pos.x == 8);
+        let connections = connections(visibility);
+        assert!(!connections[Direction::West as usize][Direction::East as usize]);
+        assert!(!connections[Direction::East as usize][Direction::West as usize]);
+        //Both halves touch every other face
+        for dir in [Direction::Down, Direction::Up, Direction::North, Direction::South] {
+            assert!(connections[Direction::West as usize][dir as usize]);
+            assert!(connections[Direction::East as usize][dir as usize]);
+            assert!(connections[dir as usize][Direction::East as usize]);
+        }
+
+        //Seen from both halves at once, everything connects
+        let both = 1 << Direction::West as u8 | 1 << Direction::East as u8;
+        assert!(Direction::ALL.into_iter().all(|to| visibility.connects(both, to)));
+    }
+
+    #[test]
+    fn flood_fill_tunnel() {
+        //An L shaped tunnel through solid blocks, from the middle of the west face up to the top
+        let tunnel = |pos: IVec3| (pos.y == 8 && pos.z == 8 && pos.x <= 8) || (pos.x == 8 && pos.z == 8 && pos.y >= 8);
+        let connections = connections(SectionVisibility::flood_fill(|pos| !tunnel(pos)));
+
+        let mut expected = [[false; 6]; 6];
+        for from in [Direction::West, Direction::Up] {
+            for to in [Direction::West, Direction::Up] {
+                expected[from as usize][to as usize] = true;
+            }
+        }
+        assert_eq!(connections, expected);
+    }
+
+    /// Sections from -2 to 3 along x and z in a world a single section high, all empty but for `walls`
+    fn walk(camera: IVec3, walls: &[(IVec3, SectionVisibility)]) -> Vec<IVec3> {
+        let mut storage = SectionStorage::new(1000);
+        for x in -2..=3 {
+            for z in -2..=3 {
+                let pos = ivec3(x, 0, z);
+                let visibility = walls.iter().find(|(wall, _)| *wall == pos).map_or(SectionVisibility::ALL, |wall| wall.1);
+                storage.replace(pos, &vec![], visibility).unwrap();
+            }
+        }
+
+        let height = WorldHeight { min_section: 0, sections: 1 };
+        let mut visible = storage.visible_sections(camera, height, |_| true);
+        visible.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+        visible
+    }
+
+    #[test]
+    fn visible_sections_stop_at_walls() {
+        let all: Vec<IVec3> = (-2..=3).flat_map(|x| (-2..=3).map(move |z| ivec3(x, 0, z))).collect();
+        assert_eq!(walk(IVec3::ZERO, &[]), all);
+
+        //The camera sees out of its own section whatever is in it
+        assert_eq!(walk(IVec3::ZERO, &[(IVec3::ZERO, SectionVisibility::NONE)]), all);
+
+        //Behind a solid section only what can be walked around to while going away from the camera is visible. The
+        //sections right behind it would need the walk to turn back towards the camera's row, re-entering it through
+        //the north or south faces it already left.
+        let visible = walk(IVec3::ZERO, &[(ivec3(1, 0, 0), SectionVisibility::NONE)]);
+        for hidden in [ivec3(2, 0, 0), ivec3(3, 0, 0)] {
+            assert!(!visible.contains(&hidden), "{hidden} is behind the wall");
+        }
+        assert!(visible.contains(&ivec3(1, 0, 0)));
+        assert!(visible.contains(&ivec3(3, 0, 1)));
+        assert_eq!(visible.len(), all.len() - 2);
+    }
+
+    #[test]
+    fn visible_sections_leave_through_connected_faces() {
+        //A tunnel from the west face to the north one turns the walk north, away from the sections east of it
+        let tunnel = SectionVisibility::flood_fill(|pos| {
+            !((pos.y == 8 && pos.z == 8 && pos.x <= 8) || (pos.y == 8 && pos.x == 8 && pos.z <= 8))
+        });
+        let walls = [
+            (ivec3(1, 0, 0), tunnel),
+            (ivec3(1, 0, -1), SectionVisibility::NONE),
+            (ivec3(1, 0, 1), SectionVisibility::NONE),
+        ];
+        let visible = walk(IVec3::ZERO, &walls);
+
+        assert!(visible.contains(&ivec3(1, 0, 0)));
+        //Entered from the west, the tunnel doesn't reach the east face
+        assert!(!visible.contains(&ivec3(2, 0, 0)));
+        //It does reach the north face, but that's the solid section next to it
+        assert!(visible.contains(&ivec3(1, 0, -1)));
+        assert!(!visible.contains(&ivec3(2, 0, -1)));
+
+        //Seen from the north the tunnel leads west, so the sections east and south of it are hidden
+        let from_north = walk(ivec3(1, 0, -2), &[(ivec3(1, 0, 0), tunnel)]);
+        assert!(from_north.contains(&ivec3(1, 0, 0)));
+        assert!(!from_north.contains(&ivec3(1, 0, 1)));
+    }
+
+    #[test]
+    fn visible_sections_within_world_height() {
+        let mut storage = SectionStorage::new(1000);
+        for y in -1..=3 {
+            storage.replace(ivec3(0, y, 0), &vec![], SectionVisibility::ALL).unwrap();
+        }
+        let height = WorldHeight { min_section: 0, sections: 2 };
+
+        //Sections outside the world's height are never walked into, even when they're loaded
+        let mut visible = storage.visible_sections(IVec3::ZERO, height, |_| true);
+        visible.sort_by_key(|pos| pos.y);
+        assert_eq!(visible, [ivec3(0, 0, 0), ivec3(0, 1, 0)]);
+
+        //Above the build limit the walk starts from the highest section, below it from the lowest
+        assert_eq!(storage.visible_sections(ivec3(0, 20, 0), height, |_| true)[0], ivec3(0, 1, 0));
+        assert_eq!(storage.visible_sections(ivec3(0, -20, 0), height, |_| true)[0], ivec3(0, 0, 0));
+        //And from the nearest loaded column
+        assert_eq!(storage.visible_sections(ivec3(5, 20, -3), height, |_| true)[0], ivec3(0, 1, 0));
+
+        //Skipped sections hide what's only visible through them
+        let below = storage.visible_sections(ivec3(0, 1, 0), height, |pos| pos.y != 0);
+        assert_eq!(below, [ivec3(0, 1, 0)]);
+    }
+
+    #[test]
+    fn world_height() {
+        let overworld = WorldHeight::default();
+        assert_eq!((overworld.min_y(), overworld.height(), overworld.max_section()), (-64, 384, 19));
+        assert!(overworld.contains_section(-4) && overworld.contains_section(19));
+        assert!(!overworld.contains_section(-5) && !overworld.contains_section(20));
+
+        let nether = WorldHeight { min_section: 0, sections: 16 };
+        assert_eq!((nether.min_y(), nether.height(), nether.max_section()), (0, 256, 15));
+        assert!(!nether.contains_section(-1) && !nether.contains_section(16));
+    }
+
+    /// A transparent layer with a quad centred on each of `centroids`, whose vertices and light are all the quad's
+    /// index
+    fn transparent_layer(centroids: &[Vec3]) -> BakedLayer {
+        BakedLayer {
+            vertices: (0..centroids.len() as u8).flat_map(|quad| [quad; 4 * Vertex::VERTEX_LENGTH]).collect(),
+            light: (0..centroids.len() as u8).flat_map(|quad| [quad; 8]).collect(),
+            centroids: centroids.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn transparent_quads_sort_back_to_front() {
+        let centroids = [vec3(8.0, 1.0, 8.0), vec3(8.0, 15.0, 8.0), vec3(8.0, 8.0, 8.0)];
+        let mut quads = TransparentQuads::new(&transparent_layer(&centroids)).unwrap();
+        assert!(TransparentQuads::new(&BakedLayer::default()).is_none());
+
+        //Looking down from above, the bottom quad is the farthest
+        let (vertices, light) = quads.sort(vec3(8.0, 20.0, 8.0));
+        assert_eq!(quads.order, [0, 2, 1]);
+        assert_eq!(vertices.len(), centroids.len() * 4 * Vertex::VERTEX_LENGTH);
+        let vertex_order: Vec<u8> = vertices.chunks_exact(4 * Vertex::VERTEX_LENGTH).map(|quad| quad[0]).collect();
+        let light_order: Vec<u8> = light.chunks_exact(8).map(|quad| quad[0]).collect();
+        assert_eq!(vertex_order, [0, 2, 1]);
+        //The light moves with the vertices
+        assert_eq!(light_order, [0, 2, 1]);
+        assert_eq!(quads.sorted_light(), light);
+
+        //And from below, the other way around
+        quads.sort(vec3(8.0, -4.0, 8.0));
+        assert_eq!(quads.order, [1, 2, 0]);
+        assert_eq!(quads.sorted_for, Some(vec3(8.0, -4.0, 8.0)));
+    }
+
+    #[test]
+    fn transparent_quads_resort_when_the_camera_moves() {
+        let mut quads = TransparentQuads::new(&transparent_layer(&[Vec3::splat(8.0)])).unwrap();
+        assert!(quads.needs_sort(Vec3::splat(4.0), 1.0));
+
+        quads.sort(Vec3::splat(4.0));
+        assert!(!quads.needs_sort(Vec3::splat(4.0), 1.0));
+        assert!(!quads.needs_sort(vec3(4.5, 4.0, 4.0), 1.0));
+        assert!(quads.needs_sort(vec3(5.0, 4.0, 4.0), 1.0));
+        assert!(!quads.needs_sort(vec3(5.0, 4.0, 4.0), 2.0));
+        //However little it moved, the camera left the section
+        quads.sort(vec3(15.9, 4.0, 4.0));
+        assert!(quads.needs_sort(vec3(16.1, 4.0, 4.0), 1.0));
+        quads.sort(vec3(4.0, 4.0, 0.1));
+        assert!(quads.needs_sort(vec3(4.0, 4.0, -0.1), 1.0));
+    }
+
+    #[test]
+    fn sort_transparent_sections() {
+        let mut storage = SectionStorage::new(100_000);
+        let centroids = [vec3(8.0, 2.0, 8.0), vec3(8.0, 14.0, 8.0)];
+        let layers = |transparent: BakedLayer| {
+            let mut layers = vec![BakedLayer::default(); RenderLayer::ALL.len()];
+            layers[RenderLayer::Transparent as usize] = transparent;
+            layers
+        };
+        storage.replace(ivec3(0, 0, 0), &layers(transparent_layer(&centroids)), SectionVisibility::ALL).unwrap();
+        storage.replace(ivec3(3, 1, 0), &layers(transparent_layer(&centroids)), SectionVisibility::ALL).unwrap();
+        storage.replace(ivec3(1, 0, 0), &layers(BakedLayer::default()), SectionVisibility::ALL).unwrap();
+
+        //Every section with transparent quads is sorted the first time, its vertices and light
+        let sorted = storage.sort_transparent(IVec2::ZERO, vec3(8.0, 20.0, 8.0), 1.0);
+        assert_eq!(sorted.len(), 4);
+        for pos in [ivec3(0, 0, 0), ivec3(3, 1, 0)] {
+            let ranges = storage.get(&pos).unwrap().layers[RenderLayer::Transparent as usize].clone().unwrap();
+            let starts: Vec<u32> = sorted.iter().map(|(start, _)| *start).collect();
+            assert!(starts.contains(&ranges.vertex_range.start) && starts.contains(&ranges.light_range.start));
+        }
+
+        //Small moves within a section sort nothing, leaving it sorts everything again
+        assert!(storage.sort_transparent(IVec2::ZERO, vec3(8.0, 20.25, 8.0), 1.0).is_empty());
+        assert_eq!(storage.sort_transparent(IVec2::ZERO, vec3(8.0, 16.5, 8.0), 1.0).len(), 4);
+        assert!(storage.sort_transparent(IVec2::ZERO, vec3(8.0, 16.25, 8.0), 1.0).is_empty());
+        assert_eq!(storage.sort_transparent(IVec2::ZERO, vec3(8.0, 15.5, 8.0), 1.0).len(), 4);
+        let transparent = storage.get(&IVec3::ZERO).unwrap().transparent.as_ref().unwrap();
+        assert_eq!(transparent.order, [0, 1]);
+
+        //The camera moving to another column moves every section relative to it
+        assert!(storage.sort_transparent(ivec2(1, 0), vec3(-8.0, 15.5, 8.0), 1.0).is_empty());
+        assert_eq!(storage.sort_transparent(ivec2(3, 0), vec3(8.0, 15.5, 8.0), 1.0).len(), 4);
+        let far = storage.get(&ivec3(3, 1, 0)).unwrap().transparent.as_ref().unwrap();
+        assert_eq!(far.sorted_for, Some(vec3(8.0, -0.5, 8.0)));
+    }
+
+    #[test]
+    fn corner_heights_are_weighted() {
+        let average = |heights: &[f32]| {
+            let mut weighted = (0.0, 0.0);
+            for height in heights {
+                add_corner_height(&mut weighted, *height);
+            }
+            weighted.0 / weighted.1
+        };
+
+        assert_eq!(average(&[0.5, 0.5, 0.25, 0.25]), 0.375);
+        //Nearly full blocks count 10 times as much
+        assert_eq!(average(&[0.8, 0.0]), 8.0 / 11.0);
+        assert_eq!(average(&[0.875, 0.875, 0.25, 0.875]), (0.875 * 30.0 + 0.25) / 31.0);
+        //Opaque blocks aren't counted at all
+        assert_eq!(average(&[0.5, -1.0, -1.0, 0.25]), 0.375);
+
+        assert_eq!(FluidState { fluid: Fluid::Water, level: 0 }.height(), 8.0 / 9.0);
+        assert_eq!(FluidState { fluid: Fluid::Water, level: 1 }.height(), 7.0 / 9.0);
+        assert_eq!(FluidState { fluid: Fluid::Lava, level: 7 }.height(), 1.0 / 9.0);
+        //Falling
+        assert_eq!(FluidState { fluid: Fluid::Water, level: 9 }.height(), 8.0 / 9.0);
+    }
+
+    /// Water with the `level` property, block 4
+    fn water(level: u8) -> BlockstateKey {
+        BlockstateKey { block: 4, augment: level as u16 }
+    }
+
+    /// The height of each corner of the surface of the water at `pos`, by x and z
+    fn surface_corners(provider: &TestProvider, pos: IVec3) -> HashMap<[u32; 2], f32> {
+        let mut block_manager = block_manager();
+        block_manager.fluids = (0..16).map(|level| (water(level), FluidState { fluid: Fluid::Water, level })).collect();
+        let sprites = FluidSprites { still: ((0, 0), (16, 16)), flow: ((16, 0), (48, 32)) };
+        block_manager.fluid_sprites = HashMap::from([(Fluid::Water, sprites)]);
+        let biome_colors = BiomeColors { grass: None, foliage: None };
+
+        let (layers, _) = bake_layers(IVec3::ZERO, &block_manager, &biome_colors, provider, &BakeSettings::default());
+        let local = |word: u32, block: i32| word as f32 / 2048.0 - 8.0 - block as f32;
+        let corners = vertex_words(&layers[RenderLayer::Transparent as usize]).iter().map(|vertex| {
+            let [x, y, z] = [vertex[0] & 0xffff, vertex[0] >> 16, vertex[1] & 0xffff];
+            ([local(x, pos.x), local(z, pos.z)], local(y, pos.y))
+        }).collect::<Vec<_>>();
+
+        //The surface is the quad with all its corners above the bottom of the block and on its edges
+        let surface = corners
+            .chunks_exact(4)
+            .find(|quad| {
+                quad.iter().all(|([x, z], y)| [*x, *z].iter().all(|side| [0.0, 1.0].contains(side)) && *y > 0.0)
+            })
+            .unwrap();
+        surface.iter().map(|([x, z], y)| ([*x as u32, *z as u32], *y)).collect()
+    }
+
+    /// Checks the corners of the water at `pos` against what they should be, north west, south west, south east and
+    /// north east, to within the precision of the vertices
+    fn assert_corners(provider: &TestProvider, pos: IVec3, expected: [f32; 4]) {
+        let corners = surface_corners(provider, pos);
+        for (corner, expected) in [[0, 0], [0, 1], [1, 1], [1, 0]].iter().zip(expected) {
+            assert!((corners[corner] - expected).abs() < 1.0 / 2048.0, "{corner:?} is {}, not {expected}", corners[corner]);
+        }
+    }
+
+    #[test]
+    fn fluid_corners_average_their_neighbours() {
+        let full = 8.0 / 9.0;
+        let pos = ivec3(5, 5, 5);
+
+        //A lone source block, whose corners are pulled down by the air around it
+        let mut provider = TestProvider::new(|_| LightLevel::from_sky_and_block(15, 0));
+        provider.states.insert(pos, water(0));
+        let lone = full * 10.0 / 12.0;
+        assert_corners(&provider, pos, [lone; 4]);
+
+        //In a lake of source blocks they're as high as the blocks
+        for x in 4..=6 {
+            for z in 4..=6 {
+                provider.states.insert(ivec3(x, 5, z), water(0));
+            }
+        }
+        assert_corners(&provider, pos, [full; 4]);
+
+        //Water with more water above it makes the corners it touches full
+        provider.states.insert(ivec3(4, 6, 4), water(0));
+        assert_corners(&provider, pos, [1.0, full, full, full]);
+
+        //Flowing water to the east pulls those corners down, the diagonals count since the sides have water
+        let mut flowing = TestProvider::new(|_| LightLevel::from_sky_and_block(15, 0));
+        for z in 4..=6 {
+            flowing.states.insert(ivec3(4, 5, z), water(0));
+            flowing.states.insert(ivec3(5, 5, z), water(0));
+            flowing.states.insert(ivec3(6, 5, z), water(4));
+        }
+        let east = (full * 20.0 + 4.0 / 9.0 * 2.0) / 22.0;
+        assert_corners(&flowing, pos, [full, full, east, east]);
+    }
+
+    #[test]
+    fn fluid_corners_ignore_opaque_blocks() {
+        let full = 8.0 / 9.0;
+        let pos = ivec3(5, 5, 5);
+
+        //Stone to the north doesn't pull the northern corners down like air does, the only thing they're averaged
+        //with is the air to the west or east
+        let mut provider = TestProvider::new(|_| LightLevel::from_sky_and_block(15, 0));
+        provider.states.insert(pos, water(0));
+        provider.states.insert(pos + IVec3::NEG_Z, key(0));
+        let north = full * 10.0 / 11.0;
+        let south = full * 10.0 / 12.0;
+        assert_corners(&provider, pos, [north, south, south, north]);
+
+        //Nor does stone in the diagonal, when water to the east lets it be counted
+        provider.states.insert(pos + IVec3::X, water(0));
+        provider.states.insert(pos + ivec3(1, 0, -1), key(0));
+        assert_corners(&provider, pos, [north, south, full * 20.0 / 22.0, full]);
+    }
 }
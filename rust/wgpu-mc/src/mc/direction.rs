@@ -1,4 +1,5 @@
-use glam::{ivec3, IVec3};
+use glam::{ivec3, IVec3, Vec3};
+use minecraft_assets::schemas::models::BlockFace;
 
 static VECTOR:[IVec3;6] = [
     ivec3(-1, 0, 0),
@@ -8,7 +9,7 @@ static VECTOR:[IVec3;6] = [
     ivec3(0, 0, -1),
     ivec3(0, 0, 1),
 ];
-#[derive(Debug,Clone,Copy)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
 pub enum Direction {
     West=0,
     East=1,
@@ -18,6 +19,21 @@ pub enum Direction {
     South=5,
 }
 impl Direction{
+    pub const ALL: [Direction; 6] = [
+        Self::West,
+        Self::East,
+        Self::Down,
+        Self::Up,
+        Self::North,
+        Self::South,
+    ];
+
+    /// The direction an axis aligned normal points in, if it is axis aligned
+    pub fn from_normal(normal: Vec3) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|dir| dir.to_vec().as_vec3().dot(normal) > 0.99)
+    }
     pub fn to_vec(&self)->IVec3{
         VECTOR[*self as usize]
     }
@@ -31,4 +47,17 @@ impl Direction{
             Self::South => Self::North,
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<BlockFace> for Direction {
+    fn from(face: BlockFace) -> Self {
+        match face {
+            BlockFace::Down => Self::Down,
+            BlockFace::Up => Self::Up,
+            BlockFace::North => Self::North,
+            BlockFace::South => Self::South,
+            BlockFace::West => Self::West,
+            BlockFace::East => Self::East,
+        }
+    }
+}
@@ -1,16 +1,17 @@
 //! Rust implementations of minecraft concepts that are important to us.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 use std::sync::{Arc};
 
 use arc_swap::ArcSwap;
-use chunk::SectionStorage;
+use chunk::{BakeSettings, Fluid, FluidSprites, FluidState, LodCells, RenderLayer, SectionStorage, WorldHeight};
 use dashmap::DashMap;
-use glam::{ivec2, ivec3, IVec2, IVec3};
+use glam::{ivec2, ivec3, IVec2, IVec3, Vec3};
 use guillotiere::euclid::default;
 use indexmap::map::IndexMap;
 use minecraft_assets::schemas;
+use minecraft_assets::schemas::blockstates::ModelProperties;
 use parking_lot::{Mutex, RwLock};
 use range_alloc::RangeAllocator;
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
@@ -20,18 +21,24 @@ use crate::mc::chunk::Section;
 use crate::mc::entity::{BundledEntityInstances, Entity};
 use crate::mc::resource::ResourceProvider;
 use crate::render::atlas::{Atlas, TextureManager};
+use crate::render::depth_pyramid::DepthPyramid;
 use crate::render::pipeline::BLOCK_ATLAS;
+use crate::render::terrain::{DrawBuffers, QuadIndices, TerrainCuller};
 use crate::texture::BindableTexture;
 use crate::util::BindableBuffer;
 use crate::{Display, WmRenderer};
 
-use self::block::ModelMesh;
+use self::biome::{BiomeColors, BlockTint};
+use self::block::{BlockstateKey, CullRule, ModelMesh, ModelOffset};
 use self::resource::ResourcePath;
 
+pub mod biome;
 pub mod block;
 pub mod chunk;
 pub mod entity;
 pub mod resource;
+pub mod scheduler;
+pub mod world;
 pub mod direction;
 /// Take in a block name (not a [ResourcePath]!) and optionally a variant state key, e.g. "facing=north" and format it some way
 /// for example, `minecraft:anvil[facing=north]` or `Block{minecraft:anvil}[facing=north]`
@@ -41,16 +48,83 @@ pub struct BlockManager {
     /// This maps block state keys to either a [VariantMesh] or a [Multipart] struct. How the keys are formatted
     /// is defined by the user of wgpu-mc. For example `Block{minecraft:anvil}[facing=west]` or `minecraft:anvil#facing=west`
     pub blocks: IndexMap<String, Block>,
+    /// Per-block [RenderLayer] overrides, keyed by the block's index into `blocks`. Blocks without an
+    /// entry use the layer their [ModelMesh] was baked with, which is derived from its textures' alpha.
+    pub render_layers: HashMap<u16, RenderLayer>,
+    /// How faces with a `tintindex` are coloured, keyed by the block's index into `blocks`.
+    /// Tinted faces of blocks without an entry stay white.
+    pub tints: HashMap<u16, BlockTint>,
+    /// Blocks whose models are randomly moved off the block grid, keyed by the block's index into `blocks`
+    pub offsets: HashMap<u16, ModelOffset>,
+    /// Extra face culling rules, keyed by the index into `blocks` of the block whose faces they hide.
+    /// Blocks without an entry only have faces hidden by full faces of opaque neighbours.
+    pub cull_rules: HashMap<u16, CullRule>,
+    /// The fluid in each blockstate that has one, including waterlogged blocks
+    pub fluids: HashMap<BlockstateKey, FluidState>,
+    /// Atlas locations of the fluid textures, filled in by [MinecraftState::bake_blocks]
+    pub fluid_sprites: HashMap<Fluid, FluidSprites>,
+}
+
+impl BlockManager {
+    /// Force every state of a block into a specific [RenderLayer], like vanilla's render layer table does.
+    /// Returns false if the block isn't known.
+    pub fn set_render_layer(&mut self, block_name: &str, layer: RenderLayer) -> bool {
+        match self.blocks.get_index_of(block_name) {
+            None => false,
+            Some(index) => {
+                self.render_layers.insert(index as u16, layer);
+                true
+            }
+        }
+    }
+
+    pub fn get_render_layer(&self, block: u16, mesh: &ModelMesh) -> RenderLayer {
+        self.render_layers.get(&block).copied().unwrap_or(mesh.layer)
+    }
+
+    /// Set how the tinted faces of a block are coloured. Returns false if the block isn't known.
+    pub fn set_tint(&mut self, block_name: &str, tint: BlockTint) -> bool {
+        match self.blocks.get_index_of(block_name) {
+            None => false,
+            Some(index) => {
+                self.tints.insert(index as u16, tint);
+                true
+            }
+        }
+    }
+
+    /// Set an extra rule for when faces of a block are hidden. Returns false if the block isn't known.
+    pub fn set_cull_rule(&mut self, block_name: &str, rule: CullRule) -> bool {
+        match self.blocks.get_index_of(block_name) {
+            None => false,
+            Some(index) => {
+                self.cull_rules.insert(index as u16, rule);
+                true
+            }
+        }
+    }
+
+    /// Set how a block's model is offset from the block grid. Returns false if the block isn't known.
+    pub fn set_offset(&mut self, block_name: &str, offset: ModelOffset) -> bool {
+        match self.blocks.get_index_of(block_name) {
+            None => false,
+            Some(index) => {
+                self.offsets.insert(index as u16, offset);
+                true
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Block {
     Multipart(Multipart),
-    Variants(IndexMap<String, Vec<Arc<ModelMesh>>>),
+    Variants(IndexMap<String, WeightedMeshes>),
 }
 
 impl Block {
-    pub fn get_model(&self, key: u16, _seed: u8) -> Arc<ModelMesh> {
+    /// The model for a state, `seed` being the [position_seed] of the block so it always gets the same random model
+    pub fn get_model(&self, key: u16, seed: u64) -> Arc<ModelMesh> {
         match &self {
             Block::Multipart(multipart) => multipart
                 .keys
@@ -58,20 +132,31 @@ impl Block {
                 .get_index(key as usize)
                 .unwrap()
                 .1
-                .clone(),
-            //TODO, random variant selection through weight and seed
-            Block::Variants(variants) => variants.get_index(key as usize).unwrap().1[0].clone(),
+                .get(multipart_seed(seed)),
+            Block::Variants(variants) => variants.get_index(key as usize).unwrap().1.get(seed),
         }
     }
 
+    /// The value of a blockstate property in the state `key` points to, e.g. `power` for redstone wire
+    pub fn get_property(&self, key: u16, property: &str) -> Option<String> {
+        let key_string = match &self {
+            Block::Multipart(multipart) => multipart.keys.read().get_index(key as usize)?.0.clone(),
+            Block::Variants(variants) => variants.get_index(key as usize)?.0.clone(),
+        };
+
+        key_string.split(',').find_map(|kv_pair| {
+            let (name, value) = kv_pair.split_once('=')?;
+            (name == property).then(|| value.to_string())
+        })
+    }
+
     pub fn get_model_by_key<'a>(
         &self,
         key: impl IntoIterator<Item = (&'a str, &'a schemas::blockstates::multipart::StateValue)>
             + Clone,
         resource_provider: &dyn ResourceProvider,
         block_atlas: &Atlas,
-        //TODO use this
-        _seed: u8,
+        seed: u64,
     ) -> Option<(Arc<ModelMesh>, u16)> {
         let key_string = key
             .clone()
@@ -98,20 +183,35 @@ impl Block {
             Block::Multipart(multipart) => {
                 {
                     if let Some(full) = multipart.keys.read().get_full(&key_string) {
-                        return Some((full.2.clone(), full.0 as u16));
+                        return Some((full.2.get(multipart_seed(seed)), full.0 as u16));
                     }
                 }
 
-                let mesh = multipart.generate_mesh(key, resource_provider, block_atlas);
+                let meshes = multipart.generate_meshes(key, resource_provider, block_atlas);
+                let mesh = meshes.get(multipart_seed(seed));
 
                 let mut multipart_write = multipart.keys.write();
-                multipart_write.insert(key_string, mesh.clone());
+                multipart_write.insert(key_string, meshes);
 
                 Some((mesh, multipart_write.len() as u16 - 1))
             }
             Block::Variants(variants) => {
-                let full = variants.get_full(&key_string)?;
-                Some((full.2[0].clone(), full.0 as u16))
+                if let Some(full) = variants.get_full(&key_string) {
+                    return Some((full.2.get(seed), full.0 as u16));
+                }
+
+                //Variants don't have to name every property, e.g. `waterlogged` or the `level` of water,
+                //so fall back to the first variant whose properties all match the state
+                let pairs: Vec<&str> = key_string.split(',').collect();
+                let (index, _, meshes) = variants.iter().enumerate().find_map(|(index, (variant_id, meshes))| {
+                    variant_id
+                        .split(',')
+                        .filter(|kv_pair| !kv_pair.is_empty())
+                        .all(|kv_pair| pairs.contains(&kv_pair))
+                        .then_some((index, variant_id, meshes))
+                })?;
+
+                Some((meshes.get(seed), index as u16))
             }
         }
     }
@@ -120,33 +220,154 @@ impl Block {
 #[derive(Debug)]
 pub struct Multipart {
     pub cases: Vec<schemas::blockstates::multipart::Case>,
-    pub keys: RwLock<IndexMap<String, Arc<ModelMesh>>>,
+    pub keys: RwLock<IndexMap<String, WeightedMeshes>>,
 }
 
 impl Multipart {
-    pub fn generate_mesh<'a>(
+    pub fn generate_meshes<'a>(
         &self,
         key: impl IntoIterator<Item = (&'a str, &'a schemas::blockstates::multipart::StateValue)>
             + Clone,
         resource_provider: &dyn ResourceProvider,
         block_atlas: &Atlas,
-    ) -> Arc<ModelMesh> {
-        let apply_variants = self.cases.iter().filter_map(|case| {
+    ) -> WeightedMeshes {
+        let apply_variants: Vec<&[ModelProperties]> = self.cases.iter().filter_map(|case| {
             if case.applies(key.clone()) {
                 Some(case.apply.models())
             } else {
                 None
             }
-        });
+        }).collect();
 
-        let mesh = ModelMesh::bake(
-            apply_variants.into_iter().flatten(),
-            resource_provider,
-            block_atlas,
-        )
-        .unwrap();
+        WeightedMeshes::bake(&apply_variants, resource_provider, block_atlas)
+    }
+}
+
+/// Minecraft's `MathHelper.hashCode`, which vanilla seeds the random model of a block position with
+pub fn position_seed(pos: IVec3) -> u64 {
+    let hash = (pos.x.wrapping_mul(3129871) as i64)
+        ^ (pos.z as i64).wrapping_mul(116129781)
+        ^ pos.y as i64;
+    let hash = hash.wrapping_mul(hash).wrapping_mul(42317861).wrapping_add(hash.wrapping_mul(11));
+    (hash >> 16) as u64
+}
+
+/// A `java.util.Random`, which vanilla's `CheckedRandom` is the same as
+pub struct JavaRandom {
+    state: u64,
+}
+
+impl JavaRandom {
+    const MULTIPLIER: u64 = 0x5DEECE66D;
+    const MASK: u64 = (1 << 48) - 1;
+
+    pub fn new(seed: u64) -> Self {
+        Self { state: (seed ^ Self::MULTIPLIER) & Self::MASK }
+    }
+
+    fn next(&mut self, bits: u32) -> i32 {
+        self.state = self.state.wrapping_mul(Self::MULTIPLIER).wrapping_add(0xB) & Self::MASK;
+        (self.state >> (48 - bits)) as i32
+    }
+
+    pub fn next_long(&mut self) -> i64 {
+        let high = self.next(32) as i64;
+        let low = self.next(32) as i64;
+        high.wrapping_shl(32).wrapping_add(low)
+    }
+
+    /// From 0 up to `bound`, which has to be positive
+    pub fn next_int(&mut self, bound: i32) -> i32 {
+        if (bound as u32).is_power_of_two() {
+            return ((bound as i64 * self.next(31) as i64) >> 31) as i32;
+        }
+        loop {
+            let bits = self.next(31);
+            let value = bits % bound;
+            //Rejects the last few values, which would make the lower results more likely
+            if bits.wrapping_sub(value).wrapping_add(bound - 1) >= 0 {
+                return value;
+            }
+        }
+    }
+
+    pub fn next_double(&mut self) -> f64 {
+        let high = (self.next(26) as i64) << 27;
+        (high + self.next(27) as i64) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The number vanilla picks a weighted model with: `Math.abs((int) random.nextLong())` from a generator seeded with
+/// a [position_seed], or a [multipart_seed] for the parts of multipart models
+fn weighted_random(seed: u64) -> u32 {
+    (JavaRandom::new(seed).next_long() as i32).unsigned_abs()
+}
+
+/// What vanilla's `MultipartBakedModel` reseeds the random with before each of its parts: a long drawn from the
+/// random seeded with the block's [position_seed]
+pub fn multipart_seed(seed: u64) -> u64 {
+    JavaRandom::new(seed).next_long() as u64
+}
+
+/// The meshes a blockstate can randomly end up with. Every `apply` list of the state (just the one for
+/// variant blocks) picks one of its models by weight, all with the same random number like vanilla,
+/// and each combination of picks is baked up front.
+#[derive(Debug)]
+pub struct WeightedMeshes {
+    /// The weights of the models in each list
+    weights: Vec<Vec<u32>>,
+    /// One mesh for every combination of picks, the first list's pick varying fastest
+    meshes: Vec<Arc<ModelMesh>>,
+}
+
+impl WeightedMeshes {
+    pub fn bake(
+        lists: &[&[ModelProperties]],
+        resource_provider: &dyn ResourceProvider,
+        block_atlas: &Atlas,
+    ) -> Self {
+        let combinations = lists.iter().map(|models| models.len().max(1)).product::<usize>();
+
+        let meshes = (0..combinations)
+            .map(|combination| {
+                let mut remaining = combination;
+                let picks = lists.iter().filter_map(|models| {
+                    let pick = models.get(remaining % models.len().max(1));
+                    remaining /= models.len().max(1);
+                    pick
+                });
+
+                Arc::new(ModelMesh::bake(picks, resource_provider, block_atlas).unwrap())
+            })
+            .collect();
+
+        Self {
+            weights: lists
+                .iter()
+                .map(|models| models.iter().map(|model| model.weight.max(1)).collect())
+                .collect(),
+            meshes,
+        }
+    }
 
-        Arc::new(mesh)
+    pub fn get(&self, seed: u64) -> Arc<ModelMesh> {
+        let random = weighted_random(seed);
+
+        let index = self.weights.iter().rev().fold(0, |index, weights| {
+            let mut target = random % weights.iter().sum::<u32>().max(1);
+            let pick = weights
+                .iter()
+                .position(|weight| {
+                    let picked = target < *weight;
+                    target = target.saturating_sub(*weight);
+                    picked
+                })
+                .unwrap_or(0);
+
+            index * weights.len().max(1) + pick
+        });
+
+        self.meshes[index].clone()
     }
 }
 
@@ -181,12 +402,44 @@ pub struct RenderEffectsData {
 }
 
 
+/// Size of [Scene::lod_buffer], in bytes
+const LOD_BUFFER_SIZE: u64 = 32_000_000;
+
+/// Largest staging buffer [Scene::compact_chunk_buffer] uses, in bytes
+const COMPACT_STAGING_SIZE: u64 = 4 << 20;
+
+const CHUNK_BUFFER_USAGES: wgpu::BufferUsages = wgpu::BufferUsages::COPY_DST
+    .union(wgpu::BufferUsages::COPY_SRC)
+    .union(wgpu::BufferUsages::VERTEX)
+    .union(wgpu::BufferUsages::STORAGE)
+    .union(wgpu::BufferUsages::INDEX);
+
 pub struct Scene {
     pub section_storage: RwLock<SectionStorage>,
     pub camera_section_pos: RwLock<IVec2>,
-    pub chunk_buffer: Arc<BindableBuffer>,
-
-    pub indirect_buffer: Arc<wgpu::Buffer>,
+    /// The camera's position relative to the origin of the `camera_section_pos` column, so y is absolute
+    pub camera_pos: RwLock<Vec3>,
+    /// Replaced with a bigger or compacted copy when sections don't fit, see [Scene::make_room]
+    pub chunk_buffer: ArcSwap<BindableBuffer>,
+
+    /// The draws of the visible sections, see [crate::render::terrain]
+    pub draw_buffers: DrawBuffers,
+    pub quad_indices: QuadIndices,
+    pub terrain_culler: Mutex<TerrainCuller>,
+    /// Sections [Scene::make_room] unloaded, or couldn't find room for, which are baked again by
+    /// [WmRenderer::rebake_evicted] once they're nearer than the furthest section in the chunk buffer
+    pub evicted_sections: Mutex<HashSet<IVec3>>,
+
+    /// Simplified sections which are drawn past the view distance, see [chunk::bake_lod]. They don't get any room
+    /// made for them, those furthest away are dropped when the buffer is full.
+    pub lod_storage: RwLock<SectionStorage>,
+    /// What the meshes of `lod_storage` are baked from. The client only has the blocks of the sections in view
+    /// distance, these are kept past it so [WmRenderer::update_lods] can mesh them again once there's room.
+    pub lod_cells: RwLock<HashMap<IVec3, LodCells>>,
+    /// Where the camera was when [WmRenderer::update_lods] last ran
+    pub lod_camera_section: Mutex<Option<IVec2>>,
+    pub lod_buffer: BindableBuffer,
+    pub lod_draw_buffers: DrawBuffers,
 
     pub entity_instances: HashMap<String, BundledEntityInstances>,
     pub sky_state: SkyState,
@@ -197,30 +450,32 @@ pub struct Scene {
     pub render_effects: RenderEffectsData,
 
     pub depth_texture: wgpu::Texture,
+    /// Built from `depth_texture` for shader packs with occlusion culling
+    pub depth_pyramid: Mutex<DepthPyramid>,
 }
 
 impl Scene {
     pub fn new(wm: &WmRenderer, framebuffer_size: wgpu::Extent3d) -> Self {
-        let indirect_buffer = wm
-            .display
-            .device
-            .create_buffer(&wgpu::BufferDescriptor {
-                label: None,
-                size: 4 * 5 * 10000,
-                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::INDIRECT,
-                mapped_at_creation: false,
-            });
         let buffer_size = 100000000u64;
         Self {
             section_storage: RwLock::new(SectionStorage::new((buffer_size/4) as u32)),
             camera_section_pos:RwLock::new(ivec2(0, 0)),
-            chunk_buffer: Arc::new(BindableBuffer::new_deferred(
+            camera_pos:RwLock::new(Vec3::ZERO),
+            chunk_buffer: ArcSwap::new(Arc::new(BindableBuffer::new_deferred(
                 wm,
                 buffer_size,
-                wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDEX ,
+                CHUNK_BUFFER_USAGES,
                 "ssbo"
-            )),
-            indirect_buffer: Arc::new(indirect_buffer),
+            ))),
+            draw_buffers: DrawBuffers::new(wm, 10000),
+            quad_indices: QuadIndices::new(wm, 1 << 14),
+            terrain_culler: Mutex::new(TerrainCuller::new(wm)),
+            evicted_sections: Mutex::new(HashSet::new()),
+            lod_storage: RwLock::new(SectionStorage::new((LOD_BUFFER_SIZE / 4) as u32)),
+            lod_cells: RwLock::new(HashMap::new()),
+            lod_camera_section: Mutex::new(None),
+            lod_buffer: BindableBuffer::new_deferred(wm, LOD_BUFFER_SIZE, CHUNK_BUFFER_USAGES, "ssbo"),
+            lod_draw_buffers: DrawBuffers::new(wm, 1000),
 
             entity_instances: Default::default(),
             sky_state: Default::default(),
@@ -238,16 +493,138 @@ impl Scene {
                     sample_count: 1,
                     dimension: wgpu::TextureDimension::D2,
                     format: wgpu::TextureFormat::Depth32Float,
-                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
                     view_formats: &[],
                 }),
+            depth_pyramid: Mutex::new(DepthPyramid::new(wm, framebuffer_size)),
+        }
+    }
+
+    /// The world position of the section the camera is in
+    pub fn camera_section(&self) -> IVec3 {
+        let column = *self.camera_section_pos.read();
+        ivec3(column.x, (self.camera_pos.read().y / 16.0).floor() as i32, column.y)
+    }
+
+    /// Make room for a section which needs `needed` u32s of the chunk buffer. The buffer is compacted if there's
+    /// enough space left but it's too fragmented, otherwise it grows up to the device's limits, and past those the
+    /// sections further from the camera than `pos` are unloaded. Those are marked pending again and go in
+    /// [Scene::evicted_sections] to be baked once they're back in range. Returns false if there's still no room.
+    pub fn make_room(&self, wm: &WmRenderer, storage: &mut SectionStorage, pos: IVec3, needed: u32) -> bool {
+        let limits = wm.display.device.limits();
+        let max_capacity = (limits.max_buffer_size.min(limits.max_storage_buffer_binding_size as u64) / 4)
+            .min(u32::MAX as u64) as u32;
+
+        if needed > max_capacity {
+            return false;
+        }
+
+        let required = storage.used().saturating_add(needed);
+        if required > max_capacity {
+            let camera_section = *self.camera_section_pos.read();
+            match storage.evict_farthest(camera_section, pos, max_capacity.saturating_sub(needed)) {
+                Some(evicted) => {
+                    let mut evicted_sections = self.evicted_sections.lock();
+                    for section in evicted {
+                        //Nothing is on the way for it, but it isn't baked any more either
+                        wm.bake_versions.begin(section);
+                        evicted_sections.insert(section);
+                    }
+                }
+                None => return false,
+            }
+        }
+
+        let required = storage.used() + needed;
+        let capacity = if required <= storage.capacity() {
+            storage.capacity()
+        } else {
+            storage.capacity().saturating_mul(2).max(required).min(max_capacity)
+        };
+
+        self.resize_chunk_buffer(wm, storage, capacity);
+        true
+    }
+
+    /// Move the sections into a compacted chunk buffer of `capacity` u32s. If the buffer stays the same size they're
+    /// moved down within it instead of into a new one, see [Scene::compact_chunk_buffer].
+    pub fn resize_chunk_buffer(&self, wm: &WmRenderer, storage: &mut SectionStorage, capacity: u32) {
+        let old_buffer = self.chunk_buffer.load_full();
+        if old_buffer.buffer.size() == capacity as u64 * 4 {
+            self.compact_chunk_buffer(wm, storage);
+            return;
+        }
+        let new_buffer = BindableBuffer::new_deferred(wm, capacity as u64 * 4, CHUNK_BUFFER_USAGES, "ssbo");
+
+        let mut encoder = wm
+            .display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for (src, dst, len) in storage.compact(capacity) {
+            encoder.copy_buffer_to_buffer(
+                &old_buffer.buffer,
+                src as u64 * 4,
+                &new_buffer.buffer,
+                dst as u64 * 4,
+                len as u64 * 4,
+            );
         }
+        wm.display.queue.submit([encoder.finish()]);
+
+        self.chunk_buffer.store(Arc::new(new_buffer));
+    }
+
+    /// Compact the chunk buffer in place. A buffer can't be copied onto itself, so the ranges go through a staging
+    /// buffer of at most [COMPACT_STAGING_SIZE] bytes. Ranges only ever move down and are moved in order, so nothing
+    /// gets overwritten before it's copied.
+    fn compact_chunk_buffer(&self, wm: &WmRenderer, storage: &mut SectionStorage) {
+        let copies = storage.compact(storage.capacity());
+        let staging_size = copies
+            .iter()
+            .filter(|(src, dst, _)| src != dst)
+            .map(|(_, _, len)| *len as u64 * 4)
+            .max()
+            .unwrap_or(0)
+            .min(COMPACT_STAGING_SIZE);
+        if staging_size == 0 {
+            return;
+        }
+
+        let device = &wm.display.device;
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("chunk_buffer_compaction"),
+            size: staging_size,
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let chunk_buffer = self.chunk_buffer.load();
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for (src, dst, len) in copies {
+            if src == dst {
+                continue;
+            }
+            let (src, dst, len) = (src as u64 * 4, dst as u64 * 4, len as u64 * 4);
+
+            let mut offset = 0;
+            while offset < len {
+                let size = (len - offset).min(staging_size);
+                encoder.copy_buffer_to_buffer(&chunk_buffer.buffer, src + offset, &staging, 0, size);
+                encoder.copy_buffer_to_buffer(&staging, 0, &chunk_buffer.buffer, dst + offset, size);
+                offset += size;
+            }
+        }
+        wm.display.queue.submit([encoder.finish()]);
     }
 }
 
 /// Minecraft-specific state and data structures go in here
 pub struct MinecraftState {
     pub block_manager: RwLock<BlockManager>,
+    pub bake_settings: RwLock<BakeSettings>,
+    /// The vertical range of the dimension being rendered
+    pub world_height: RwLock<WorldHeight>,
+    pub biome_colors: RwLock<BiomeColors>,
 
     pub entity_models: RwLock<HashMap<String, Arc<Entity>>>,
 
@@ -268,7 +645,16 @@ impl MinecraftState {
 
             block_manager: RwLock::new(BlockManager {
                 blocks: IndexMap::new(),
+                render_layers: HashMap::new(),
+                tints: HashMap::new(),
+                offsets: HashMap::new(),
+                cull_rules: HashMap::new(),
+                fluids: HashMap::new(),
+                fluid_sprites: HashMap::new(),
             }),
+            bake_settings: RwLock::new(BakeSettings::default()),
+            world_height: RwLock::new(WorldHeight::default()),
+            biome_colors: RwLock::new(BiomeColors::default()),
             resource_provider,
 
             animated_block_buffer: ArcSwap::new(Arc::new(None)),
@@ -306,6 +692,9 @@ impl MinecraftState {
         .read();
         let block_atlas = atlases.get(BLOCK_ATLAS).unwrap();
 
+        //The colormaps come from the same resource packs as the models
+        *self.biome_colors.write() = BiomeColors::load(&*self.resource_provider);
+
         //Figure out which block models there are
         block_states
             .into_iter()
@@ -316,25 +705,16 @@ impl MinecraftState {
 
                 let block = match &blockstates {
                     schemas::BlockStates::Variants { variants } => {
-                        let meshes: IndexMap<String, Vec<Arc<ModelMesh>>> = variants
+                        let meshes: IndexMap<String, WeightedMeshes> = variants
                             .iter()
                             .map(|(variant_id, variant)| {
                                 (
                                     variant_id.clone(),
-                                    variant
-                                        .models()
-                                        .iter()
-                                        .map(|variation| {
-                                            Arc::new(
-                                                ModelMesh::bake(
-                                                    std::slice::from_ref(variation),
-                                                    &*self.resource_provider,
-                                                    &block_atlas,
-                                                )
-                                                .unwrap(),
-                                            )
-                                        })
-                                        .collect::<Vec<Arc<ModelMesh>>>(),
+                                    WeightedMeshes::bake(
+                                        &[variant.models()],
+                                        &*self.resource_provider,
+                                        block_atlas,
+                                    ),
                                 )
                             })
                             .collect();
@@ -352,6 +732,70 @@ impl MinecraftState {
                     .insert(String::from(block_name.as_ref()), block);
             });
 
+        //Fluids aren't block models, so their textures have to be added to the atlas separately
+        let fluid_textures: Vec<(ResourcePath, Vec<u8>)> = Fluid::ALL
+            .iter()
+            .flat_map(|fluid| {
+                let (still, flow) = fluid.textures();
+                [still, flow]
+            })
+            .map(ResourcePath::from)
+            .filter(|path| !block_atlas.uv_map.read().contains_key(path))
+            .filter_map(|path| {
+                let bytes = self
+                    .resource_provider
+                    .get_bytes(&path.prepend("textures/").append(".png"))?;
+                Some((path, bytes))
+            })
+            .collect();
+
+        block_atlas.allocate(
+            fluid_textures.iter().map(|(path, bytes)| (path, bytes)),
+            &*self.resource_provider,
+        );
+
+        let uv_map = block_atlas.uv_map.read();
+        block_manager.fluid_sprites = Fluid::ALL
+            .iter()
+            .filter_map(|fluid| {
+                let (still, flow) = fluid.textures();
+                Some((
+                    *fluid,
+                    FluidSprites {
+                        still: *uv_map.get(&ResourcePath::from(still))?,
+                        flow: *uv_map.get(&ResourcePath::from(flow))?,
+                    },
+                ))
+            })
+            .collect();
+        drop(uv_map);
+
         block_atlas.upload(wm);
+        wm.gpu_mesher.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::ivec3;
+
+    use super::{multipart_seed, position_seed, weighted_random};
+
+    #[test]
+    fn matches_vanilla_random() {
+        //Values from MathHelper.hashCode and java.util.Random. Multipart models draw a long and reseed with it first.
+        let expected = [
+            (ivec3(0, 0, 0), 0i64, 723955400, -4962768465676381896i64, 406937919),
+            (ivec3(1, 64, -3), -62687207132611, 1632909026, -9198971123422447902, 9686847),
+            (ivec3(-1234, -60, 98765), -93246470491939, 2029181275, 1837839739702290085, 1010904653),
+            (ivec3(30000000, 319, -30000000), -52266819324747, 1102986478, 289424126848910098, 1517659575),
+        ];
+
+        for (pos, seed, random, part_seed, part_random) in expected {
+            assert_eq!(position_seed(pos), seed as u64);
+            assert_eq!(weighted_random(seed as u64), random);
+            assert_eq!(multipart_seed(seed as u64), part_seed as u64);
+            assert_eq!(weighted_random(part_seed as u64), part_random);
+        }
     }
 }
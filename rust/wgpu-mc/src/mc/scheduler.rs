@@ -0,0 +1,269 @@
+//! Schedules section bakes on worker threads, nearest and visible sections first.
+//!
+//! Requesting a bake for a section which is still waiting replaces the waiting request, so a section that
+//! changes a lot in a short time only gets baked once.
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use glam::{ivec3, IVec2, IVec3, Vec3};
+use parking_lot::{Condvar, Mutex};
+use treeculler::{BVol, Frustum, AABB};
+
+use crate::mc::chunk::{bake_section, BlockStateProvider};
+use crate::WmRenderer;
+
+struct PendingBake {
+    version: u64,
+    provider: Box<dyn BlockStateProvider>,
+}
+
+/// Where the camera is and what it sees, which decides the order sections get baked in
+struct SchedulerCamera {
+    section: IVec2,
+    /// Relative to the origin of the `section` column, so y is absolute
+    pos: Vec3,
+    frustum: Option<Frustum<f32>>,
+}
+
+struct PendingBakes {
+    requests: HashMap<IVec3, PendingBake>,
+    /// Sections waiting to be baked, the most urgent last. May contain sections which were already taken.
+    order: Vec<IVec3>,
+    sorted: bool,
+    camera: SchedulerCamera,
+}
+
+impl PendingBakes {
+    /// Lower is more urgent: sections in view come before ones outside it, then nearer before further
+    fn priority(&self, pos: IVec3) -> (bool, f32) {
+        let camera = &self.camera;
+        let rel_pos = ivec3(pos.x - camera.section.x, pos.y, pos.z - camera.section.y);
+        let min = rel_pos.as_vec3() * 16.0;
+
+        let visible = camera.frustum.as_ref().map_or(true, |frustum| {
+            AABB::new(min.to_array(), (min + 16.0).to_array())
+                .coherent_test_against_frustum(frustum, 0)
+                .0
+        });
+
+        (!visible, (min + 8.0).distance_squared(camera.pos))
+    }
+
+    /// Queue a bake, replacing the one still waiting for the same section
+    fn push(&mut self, pos: IVec3, request: PendingBake) {
+        if self.requests.insert(pos, request).is_none() {
+            self.order.push(pos);
+            self.sorted = false;
+        }
+    }
+
+    fn pop(&mut self) -> Option<(IVec3, PendingBake)> {
+        if !self.sorted {
+            let mut order = std::mem::take(&mut self.order);
+            order.sort_by_cached_key(|pos| {
+                let (hidden, distance) = self.priority(*pos);
+                std::cmp::Reverse((hidden, distance.to_bits()))
+            });
+            self.order = order;
+            self.sorted = true;
+        }
+
+        while let Some(pos) = self.order.pop() {
+            if let Some(request) = self.requests.remove(&pos) {
+                return Some((pos, request));
+            }
+        }
+
+        None
+    }
+}
+
+pub struct BakeScheduler {
+    pending: Mutex<PendingBakes>,
+    available: Condvar,
+    /// The most bytes of section meshes [WmRenderer::submit_chunk_updates] uploads in one frame
+    upload_limit: Mutex<Option<usize>>,
+}
+
+impl Default for BakeScheduler {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(PendingBakes {
+                requests: HashMap::new(),
+                order: Vec::new(),
+                sorted: true,
+                camera: SchedulerCamera {
+                    section: IVec2::ZERO,
+                    pos: Vec3::ZERO,
+                    frustum: None,
+                },
+            }),
+            available: Condvar::new(),
+            upload_limit: Mutex::new(None),
+        }
+    }
+}
+
+impl BakeScheduler {
+    /// Queue a section to be baked from the blocks in `provider`, replacing the request for it if one is still waiting
+    pub fn request(&self, wm: &WmRenderer, pos: IVec3, provider: impl BlockStateProvider + 'static) {
+        let version = wm.bake_versions.begin(pos);
+
+        let request = PendingBake {
+            version,
+            provider: Box::new(provider),
+        };
+        self.pending.lock().push(pos, request);
+
+        self.available.notify_one();
+    }
+
+    /// Update the camera the bake order is worked out from. `frustum` is relative to the camera section column
+    /// like the one passed to [crate::render::graph::RenderGraph::render], without one every section counts as visible.
+    pub fn set_camera(&self, section: IVec2, pos: Vec3, frustum: Option<Frustum<f32>>) {
+        let mut pending = self.pending.lock();
+        pending.camera = SchedulerCamera { section, pos, frustum };
+        pending.sorted = false;
+    }
+
+    /// How many bytes of section meshes can be uploaded per frame, or `None` to upload everything as soon as it's baked.
+    /// At least one section is uploaded each frame whatever the limit.
+    pub fn set_upload_limit(&self, bytes: Option<usize>) {
+        *self.upload_limit.lock() = bytes;
+    }
+
+    pub fn upload_limit(&self) -> Option<usize> {
+        *self.upload_limit.lock()
+    }
+
+    /// How many sections are waiting to be baked
+    pub fn pending(&self) -> usize {
+        self.pending.lock().requests.len()
+    }
+
+    fn next(&self) -> (IVec3, PendingBake) {
+        let mut pending = self.pending.lock();
+        loop {
+            if let Some(next) = pending.pop() {
+                return next;
+            }
+            self.available.wait(&mut pending);
+        }
+    }
+}
+
+impl WmRenderer {
+    /// Start the threads which bake the sections requested from [BakeScheduler::request]
+    pub fn start_bake_workers(&'static self, threads: usize) {
+        for index in 0..threads.max(1) {
+            thread::Builder::new()
+                .name(format!("wgpu-mc section baker {index}"))
+                .spawn(move || loop {
+                    let (pos, request) = self.bake_scheduler.next();
+                    //A section which fails to bake keeps its old mesh instead of taking the worker down with it
+                    let baked = panic::catch_unwind(AssertUnwindSafe(|| {
+                        bake_section(pos, request.version, self, &*request.provider)
+                    }));
+                    if baked.is_err() {
+                        log::error!("Baking section {pos} panicked");
+                        self.bake_versions.finish(pos, request.version);
+                    }
+                })
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{ivec3, vec3, IVec2, IVec3, Mat4, Vec3};
+    use treeculler::Frustum;
+
+    use super::{BakeScheduler, PendingBake, PendingBakes};
+    use crate::mc::block::ChunkBlockState;
+    use crate::mc::chunk::{BlockStateProvider, LightLevel};
+
+    struct Empty;
+
+    impl BlockStateProvider for Empty {
+        fn get_state(&self, _pos: IVec3) -> ChunkBlockState {
+            ChunkBlockState::Air
+        }
+
+        fn get_light_level(&self, _pos: IVec3) -> LightLevel {
+            LightLevel::from_sky_and_block(15, 0)
+        }
+
+        fn is_section_empty(&self, _rel_pos: IVec3) -> bool {
+            true
+        }
+    }
+
+    fn bake(version: u64) -> PendingBake {
+        PendingBake {
+            version,
+            provider: Box::new(Empty),
+        }
+    }
+
+    /// The queue of a scheduler whose camera is at `pos` in the column at the origin, looking north if `frustum`
+    fn pending(pos: Vec3, frustum: bool) -> PendingBakes {
+        let frustum = frustum.then(|| {
+            let view_projection = Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 1000.0)
+                * Mat4::look_to_rh(pos, Vec3::NEG_Z, Vec3::Y);
+            Frustum::from_modelview_projection(view_projection.to_cols_array_2d())
+        });
+
+        let scheduler = BakeScheduler::default();
+        scheduler.set_camera(IVec2::ZERO, pos, frustum);
+        scheduler.pending.into_inner()
+    }
+
+    fn drain(pending: &mut PendingBakes) -> Vec<(IVec3, u64)> {
+        std::iter::from_fn(|| pending.pop()).map(|(pos, request)| (pos, request.version)).collect()
+    }
+
+    #[test]
+    fn visible_then_nearest_first() {
+        let camera = vec3(8.0, 72.0, 8.0);
+        let ahead_near = ivec3(0, 4, -1);
+        let ahead_far = ivec3(0, 4, -4);
+        let behind_near = ivec3(0, 4, 2);
+        let behind_far = ivec3(0, 4, 3);
+        let sections = [behind_far, ahead_far, behind_near, ahead_near];
+
+        let mut pending = pending(camera, true);
+        for (version, pos) in sections.into_iter().enumerate() {
+            pending.push(pos, bake(version as u64));
+        }
+        assert_eq!(drain(&mut pending), [(ahead_near, 3), (ahead_far, 1), (behind_near, 2), (behind_far, 0)]);
+
+        //Without a frustum everything counts as visible and only the distance matters
+        let mut pending = self::pending(camera, false);
+        for (version, pos) in sections.into_iter().enumerate() {
+            pending.push(pos, bake(version as u64));
+        }
+        assert_eq!(drain(&mut pending), [(ahead_near, 3), (behind_near, 2), (behind_far, 0), (ahead_far, 1)]);
+    }
+
+    #[test]
+    fn requests_for_waiting_sections_replace_them() {
+        let near = ivec3(0, 4, 0);
+        let far = ivec3(0, 4, 5);
+
+        let mut pending = pending(vec3(8.0, 72.0, 8.0), false);
+        pending.push(far, bake(1));
+        pending.push(near, bake(2));
+        pending.push(far, bake(3));
+        pending.push(far, bake(4));
+        assert_eq!(pending.requests.len(), 2);
+        assert_eq!(drain(&mut pending), [(near, 2), (far, 4)]);
+
+        //Once taken a section can be queued again, and is baked once more
+        pending.push(near, bake(5));
+        pending.push(near, bake(6));
+        assert_eq!(drain(&mut pending), [(near, 6)]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Single block and light updates.
+//!
+//! Rebaking a section needs the blocks of it and all of its neighbours. With a [BlockStore] keeping a copy of the
+//! world on the Rust side, a changed block only needs its own position and state sent over, and just the sections
+//! whose meshes can see the change get rebaked. When only the light changed nothing gets rebaked, the light of the
+//! vertices is worked out again from the blocks they sampled it from, see [WmRenderer::relight].
+
+use std::collections::HashSet;
+
+use glam::{IVec3, Vec3Swizzles};
+
+use crate::mc::block::ChunkBlockState;
+use crate::mc::chunk::{gpu_region, BlockStateProvider};
+use crate::mc::Scene;
+use crate::WmRenderer;
+
+/// The renderer's copy of the world's blocks
+pub trait BlockStore: Send + Sync {
+    /// Change the block at a world position. Returns false if its section isn't loaded.
+    fn set_block(&self, pos: IVec3, state: ChunkBlockState) -> bool;
+
+    /// A snapshot of everything a bake of `section` needs, which is it and its 26 neighbours, with positions relative
+    /// to `section` like [crate::mc::chunk::bake_section] expects. `None` if the section isn't loaded.
+    fn section_provider(&self, section: IVec3) -> Option<Box<dyn BlockStateProvider>>;
+}
+
+/// The sections whose meshes depend on the block at a world position. That's the block's own section, and when it's
+/// on the edge of it the neighbours across that edge, diagonals included since they sample it for smooth lighting.
+pub fn affected_sections(pos: IVec3) -> Vec<IVec3> {
+    let section = pos >> 4;
+    let local = pos & 15;
+
+    let offsets = |local: i32| match local {
+        0 => -1..=0,
+        15 => 0..=1,
+        _ => 0..=0,
+    };
+
+    offsets(local.x)
+        .flat_map(|x| offsets(local.y).flat_map(move |y| offsets(local.z).map(move |z| IVec3::new(x, y, z))))
+        .map(|offset| section + offset)
+        .collect()
+}
+
+impl WmRenderer {
+    /// Change a block in `store` and rebake the sections that can see the change. Returns false if `store` doesn't
+    /// have the block's section, in which case nothing is rebaked.
+    pub fn set_block(&self, store: &dyn BlockStore, pos: IVec3, state: ChunkBlockState) -> bool {
+        self.set_blocks(store, [(pos, state)]) == 1
+    }
+
+    /// Change many blocks at once, like an explosion does. Each affected section is only rebaked once. Returns how
+    /// many of the blocks were changed.
+    pub fn set_blocks(&self, store: &dyn BlockStore, blocks: impl IntoIterator<Item = (IVec3, ChunkBlockState)>) -> usize {
+        let mut applied = 0;
+        let sections: HashSet<IVec3> = blocks
+            .into_iter()
+            .filter(|(pos, state)| store.set_block(*pos, *state))
+            .inspect(|_| applied += 1)
+            .flat_map(|(pos, _)| affected_sections(pos))
+            .collect();
+
+        for section in sections {
+            if let Some(provider) = store.section_provider(section) {
+                self.bake_scheduler.request(self, section, provider);
+            }
+        }
+
+        applied
+    }
+
+    /// Update the light of the baked sections after the light in `sections` changed in `store`, which is those
+    /// sections and their neighbours since vertices sample the light across section edges. Only the light of their
+    /// vertices gets rewritten in the chunk buffer.
+    pub fn relight(&self, scene: &Scene, store: &dyn BlockStore, sections: impl IntoIterator<Item = IVec3>) {
+        let sections: HashSet<IVec3> = sections
+            .into_iter()
+            .flat_map(|section| {
+                (0..27).map(move |index| section + IVec3::new(index % 3, (index / 3) % 3, index / 9) - 1)
+            })
+            .collect();
+
+        let mut storage = scene.section_storage.write();
+        let evicted = scene.evicted_sections.lock();
+        let chunk_buffer = scene.chunk_buffer.load();
+        for section in sections {
+            //Sections waiting for room are baked by rebake_evicted instead
+            let pending = self.bake_versions.is_pending(section) && !evicted.contains(&section);
+            let gpu_meshed = storage.get(&section).map(|baked| baked.gpu_meshed);
+            if gpu_meshed.is_none() && !pending {
+                continue;
+            }
+            let Some(provider) = store.section_provider(section) else {
+                continue;
+            };
+
+            //Sections meshed on the GPU are relit there, from the light samples it left in the chunk buffer
+            if gpu_meshed == Some(true) && !pending {
+                let region = gpu_region(section, &self.mc.block_manager.read(), &*provider);
+                self.gpu_mesher.queue_relight(section, region);
+                continue;
+            }
+
+            //Quads merged by greedy meshing have to be split up where the light isn't the same across them any more
+            let (relit, split) = storage.relight(section, &*provider);
+            for (start, light) in relit {
+                self.display.queue.write_buffer(&chunk_buffer.buffer, start as u64 * 4, &light);
+            }
+            //A bake which was requested before the change could still have the old light
+            if pending || split {
+                self.bake_scheduler.request(self, section, provider);
+            }
+        }
+
+        self.gpu_mesher.flush(&self.display.device, &self.display.queue, &storage, &chunk_buffer.buffer);
+    }
+
+    /// Request bakes for the sections in [Scene::evicted_sections] which are now nearer the camera than the furthest
+    /// section in the chunk buffer, so there will be room for them. Java isn't told about evicted sections, their blocks
+    /// are still in `store`. Those that went out of view distance are forgotten.
+    pub fn rebake_evicted(&self, scene: &Scene, store: &dyn BlockStore) {
+        if scene.evicted_sections.lock().is_empty() {
+            return;
+        }
+
+        let camera_section = *scene.camera_section_pos.read();
+        let distance = |pos: IVec3| (pos.xz() - camera_section).length_squared();
+        let (radius, farthest) = {
+            let storage = scene.section_storage.read();
+            (storage.width() + 2, storage.iter().map(|(pos, _)| distance(*pos)).max())
+        };
+
+        let mut evicted = scene.evicted_sections.lock();
+
+        let mut requests = vec![];
+        evicted.retain(|pos| {
+            if (pos.xz() - camera_section).abs().max_element() > radius {
+                return false;
+            }
+            if farthest.is_some_and(|farthest| distance(*pos) >= farthest) {
+                return true;
+            }
+            requests.push(*pos);
+            false
+        });
+        drop(evicted);
+
+        for pos in requests {
+            if let Some(provider) = store.section_provider(pos) {
+                self.bake_scheduler.request(self, pos, provider);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use glam::{ivec3, IVec3};
+
+    use super::affected_sections;
+
+    fn sections(pos: IVec3) -> HashSet<IVec3> {
+        let sections = affected_sections(pos);
+        let unique: HashSet<IVec3> = sections.iter().copied().collect();
+        assert_eq!(unique.len(), sections.len(), "{pos} gives the same section twice");
+        unique
+    }
+
+    #[test]
+    fn inside_a_section() {
+        assert_eq!(sections(ivec3(5, 7, 9)), HashSet::from([IVec3::ZERO]));
+        assert_eq!(sections(ivec3(1, 14, 1)), HashSet::from([IVec3::ZERO]));
+        //Negative positions round down to their section
+        assert_eq!(sections(ivec3(-5, -20, -9)), HashSet::from([ivec3(-1, -2, -1)]));
+    }
+
+    #[test]
+    fn on_a_face() {
+        assert_eq!(sections(ivec3(0, 7, 9)), HashSet::from([IVec3::ZERO, ivec3(-1, 0, 0)]));
+        assert_eq!(sections(ivec3(8, 15, 8)), HashSet::from([IVec3::ZERO, ivec3(0, 1, 0)]));
+        assert_eq!(sections(ivec3(8, 8, -16)), HashSet::from([ivec3(0, 0, -1), ivec3(0, 0, -2)]));
+        assert_eq!(sections(ivec3(8, 8, -1)), HashSet::from([ivec3(0, 0, -1), IVec3::ZERO]));
+    }
+
+    #[test]
+    fn on_an_edge_and_corner() {
+        //Two faces, and the diagonal neighbour across both
+        assert_eq!(
+            sections(ivec3(15, 15, 8)),
+            HashSet::from([IVec3::ZERO, ivec3(1, 0, 0), ivec3(0, 1, 0), ivec3(1, 1, 0)])
+        );
+
+        //All 8 sections around the corner
+        let corner: HashSet<IVec3> =
+            (0..=1).flat_map(|x| (-1..=0).flat_map(move |y| (1..=2).map(move |z| ivec3(x, y, z)))).collect();
+        assert_eq!(sections(ivec3(16, -1, 31)), corner);
+    }
+}
@@ -0,0 +1,445 @@
+//! Hierarchical-Z occlusion culling.
+//!
+//! Shader packs which opt in with `occlusion_culling` get the scene's depth turned into a pyramid of mip levels once
+//! the pipeline they name has been drawn, each texel holding the furthest depth under it. The next frame the
+//! [crate::render::terrain::TerrainCuller] projects every section's box with the matrix that depth was drawn with and
+//! leaves the section out if it's behind everything in the texels covering it.
+//!
+//! Devices which cull sections on the CPU get a coarse level of the pyramid copied back instead, see [CpuDepth].
+//! It's a few frames old by the time it's mapped, so it's only tested against with the matrix it was drawn with too.
+//!
+//! Sections which come out from behind something show up a frame or, on the CPU, a few frames late.
+//!
+//! Entities aren't culled against it: no graph draws `@geo_entities` yet, and the transforms Java hands over for
+//! them aren't relative to the camera's section column. [CpuDepth::occluded] takes any box once they are.
+
+use std::sync::Arc;
+
+use glam::{uvec2, vec2, BVec3, IVec2, IVec3, Mat4, Vec2, Vec3, Vec4Swizzles};
+use parking_lot::Mutex;
+
+use crate::render::terrain::TerrainCuller;
+use crate::WmRenderer;
+
+/// Longest side of the level [CpuDepth] is copied from
+const READBACK_SIZE: u32 = 128;
+
+/// What the culling shader needs to know about the pyramid
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct OcclusionParams {
+    /// What the depth in the pyramid was drawn with
+    pub view_projection: [[f32; 4]; 4],
+    /// The camera's section column when it was drawn, which the view projection is relative to
+    pub camera_section: [i32; 2],
+    /// 0 if there's no pyramid to test against
+    pub enabled: u32,
+    pub padding: u32,
+}
+
+impl OcclusionParams {
+    pub const DISABLED: Self = Self {
+        view_projection: [[0.0; 4]; 4],
+        camera_section: [0; 2],
+        enabled: 0,
+        padding: 0,
+    };
+}
+
+/// A level of the pyramid, read back to the CPU
+pub struct CpuDepth {
+    /// The furthest depth under each texel, row by row
+    pub depth: Vec<f32>,
+    pub width: u32,
+    pub height: u32,
+    /// What the depth was drawn with
+    pub view_projection: Mat4,
+    /// The camera's section column when it was drawn, which the view projection is relative to
+    pub camera_section: IVec2,
+}
+
+impl CpuDepth {
+    /// Whether a box was hidden behind what was drawn, `min` and `max` being its corners relative to
+    /// [Self::camera_section]. The same test the culling shader does, on a single level.
+    pub fn occluded(&self, min: Vec3, max: Vec3) -> bool {
+        let mut uv_min = Vec2::MAX;
+        let mut uv_max = Vec2::MIN;
+        let mut nearest = 1.0f32;
+        for i in 0..8 {
+            let corner = Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+            let clip = self.view_projection * corner.extend(1.0);
+            //Part of the box was behind the camera
+            if clip.w <= 0.0 {
+                return false;
+            }
+
+            let ndc = clip.xyz() / clip.w;
+            let uv = vec2(ndc.x, -ndc.y) * 0.5 + 0.5;
+            uv_min = uv_min.min(uv);
+            uv_max = uv_max.max(uv);
+            nearest = nearest.min(ndc.z);
+        }
+
+        //Nothing is known about what was off screen
+        if uv_max.cmplt(Vec2::ZERO).any() || uv_min.cmpgt(Vec2::ONE).any() {
+            return false;
+        }
+
+        let size = uvec2(self.width, self.height);
+        let texel = |uv: Vec2| (uv.clamp(Vec2::ZERO, Vec2::ONE) * size.as_vec2()).as_uvec2().min(size - 1);
+        let (texel_min, texel_max) = (texel(uv_min), texel(uv_max));
+
+        let furthest = (texel_min.y..=texel_max.y)
+            .flat_map(|y| (texel_min.x..=texel_max.x).map(move |x| (y * self.width + x) as usize))
+            .map(|index| self.depth[index])
+            .fold(0.0, f32::max);
+        nearest > furthest
+    }
+
+    /// [Self::occluded] for a section
+    pub fn section_occluded(&self, pos: IVec3) -> bool {
+        let rel_pos = IVec3::new(pos.x - self.camera_section.x, pos.y, pos.z - self.camera_section.y);
+        let min = rel_pos.as_vec3() * 16.0;
+        self.occluded(min, min + 16.0)
+    }
+}
+
+/// Where the copy of a level to the CPU is at
+enum Readback {
+    Idle,
+    /// Recorded into a frame's commands, which have to be submitted before it can be mapped
+    Copied(PendingDepth),
+    Mapping(PendingDepth, Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>),
+}
+
+struct PendingDepth {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    /// Rows of the buffer are padded to [wgpu::COPY_BYTES_PER_ROW_ALIGNMENT]
+    bytes_per_row: u32,
+    view_projection: Mat4,
+    camera_section: IVec2,
+}
+
+pub struct DepthPyramid {
+    downsample_depth: wgpu::ComputePipeline,
+    downsample: wgpu::ComputePipeline,
+    texture: wgpu::Texture,
+    /// Every level, for testing against
+    view: wgpu::TextureView,
+    levels: Vec<wgpu::TextureView>,
+    /// The view projection and camera section column the depth in the pyramid was drawn with
+    built_from: Option<(Mat4, IVec2)>,
+    readback: Readback,
+    cpu_depth: Option<Arc<CpuDepth>>,
+}
+
+impl DepthPyramid {
+    /// A pyramid for a depth texture of `size`, which is built from on [Self::build]
+    pub fn new(wm: &WmRenderer, size: wgpu::Extent3d) -> Self {
+        let device = &wm.display.device;
+        let module = device.create_shader_module(wgpu::include_wgsl!("depth_pyramid.wgsl"));
+
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let downsample_depth = pipeline("downsample_depth");
+        let downsample = pipeline("downsample");
+
+        let (texture, view, levels) = Self::create_texture(wm, size);
+
+        Self {
+            downsample_depth,
+            downsample,
+            texture,
+            view,
+            levels,
+            built_from: None,
+            readback: Readback::Idle,
+            cpu_depth: None,
+        }
+    }
+
+    fn create_texture(wm: &WmRenderer, depth_size: wgpu::Extent3d) -> (wgpu::Texture, wgpu::TextureView, Vec<wgpu::TextureView>) {
+        //The first level is already half the size of the depth
+        let size = wgpu::Extent3d {
+            width: depth_size.width.div_ceil(2).max(1),
+            height: depth_size.height.div_ceil(2).max(1),
+            depth_or_array_layers: 1,
+        };
+        let mip_level_count = size.max_mips(wgpu::TextureDimension::D2);
+
+        let texture = wm.display.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("depth_pyramid"),
+            size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let levels = (0..mip_level_count)
+            .map(|level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        (texture, view, levels)
+    }
+
+    /// Record building the pyramid from `depth_texture`, which was drawn with `view_projection` relative to the
+    /// `camera_section` column
+    pub fn build(
+        &mut self,
+        wm: &WmRenderer,
+        encoder: &mut wgpu::CommandEncoder,
+        depth_texture: &wgpu::Texture,
+        view_projection: Mat4,
+        camera_section: IVec2,
+    ) {
+        let depth_size = depth_texture.size();
+        if self.texture.width() != depth_size.width.div_ceil(2) || self.texture.height() != depth_size.height.div_ceil(2)
+        {
+            (self.texture, self.view, self.levels) = Self::create_texture(wm, depth_size);
+        }
+
+        let depth_view = depth_texture.create_view(&wgpu::TextureViewDescriptor {
+            aspect: wgpu::TextureAspect::DepthOnly,
+            ..Default::default()
+        });
+
+        let bind_groups = (0..self.levels.len())
+            .map(|level| {
+                let (pipeline, source) = match level {
+                    0 => (&self.downsample_depth, &depth_view),
+                    _ => (&self.downsample, &self.levels[level - 1]),
+                };
+
+                wm.display.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: None,
+                    layout: &pipeline.get_bind_group_layout(0),
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: if level == 0 { 0 } else { 1 },
+                            resource: wgpu::BindingResource::TextureView(source),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::TextureView(&self.levels[level]),
+                        },
+                    ],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("depth_pyramid"),
+            timestamp_writes: None,
+        });
+        for (level, bind_group) in bind_groups.iter().enumerate() {
+            let size = self.texture.size().mip_level_size(level as u32, wgpu::TextureDimension::D2);
+
+            pass.set_pipeline(if level == 0 { &self.downsample_depth } else { &self.downsample });
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(8), 1);
+        }
+        drop(pass);
+
+        self.built_from = Some((view_projection, camera_section));
+        if matches!(self.readback, Readback::Idle) && !TerrainCuller::is_supported(wm) {
+            self.copy_to_cpu(wm, encoder, view_projection, camera_section);
+        }
+    }
+
+    /// Record copying the first level no bigger than [READBACK_SIZE] to a buffer, for [Self::cpu_depth]
+    fn copy_to_cpu(
+        &mut self,
+        wm: &WmRenderer,
+        encoder: &mut wgpu::CommandEncoder,
+        view_projection: Mat4,
+        camera_section: IVec2,
+    ) {
+        let level = (0..self.levels.len() as u32)
+            .find(|level| {
+                let size = self.texture.size().mip_level_size(*level, wgpu::TextureDimension::D2);
+                size.width.max(size.height) <= READBACK_SIZE
+            })
+            .unwrap_or(self.levels.len() as u32 - 1);
+        let size = self.texture.size().mip_level_size(level, wgpu::TextureDimension::D2);
+        let bytes_per_row = (size.width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = wm.display.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("depth_pyramid_readback"),
+            size: (bytes_per_row * size.height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: level,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            size,
+        );
+
+        self.readback = Readback::Copied(PendingDepth {
+            buffer,
+            width: size.width,
+            height: size.height,
+            bytes_per_row,
+            view_projection,
+            camera_section,
+        });
+    }
+
+    /// Move the copy to the CPU along, which has to be called between frames: a copy recorded last frame starts being
+    /// mapped, and one which finished mapping replaces [Self::cpu_depth].
+    pub fn poll_readback(&mut self) {
+        self.readback = match std::mem::replace(&mut self.readback, Readback::Idle) {
+            Readback::Idle => Readback::Idle,
+            Readback::Copied(pending) => {
+                let result = Arc::new(Mutex::new(None));
+                let callback_result = result.clone();
+                pending.buffer.slice(..).map_async(wgpu::MapMode::Read, move |mapped| {
+                    *callback_result.lock() = Some(mapped);
+                });
+                Readback::Mapping(pending, result)
+            }
+            Readback::Mapping(pending, result) => {
+                let mapped = result.lock().take();
+                match mapped {
+                    None => Readback::Mapping(pending, result),
+                    Some(Err(_)) => Readback::Idle,
+                    Some(Ok(())) => {
+                        let data = pending.buffer.slice(..).get_mapped_range();
+                        let depth = data
+                            .chunks_exact(pending.bytes_per_row as usize)
+                            .flat_map(|row| bytemuck::cast_slice::<u8, f32>(&row[..pending.width as usize * 4]))
+                            .copied()
+                            .collect();
+                        drop(data);
+                        pending.buffer.unmap();
+
+                        self.cpu_depth = Some(Arc::new(CpuDepth {
+                            depth,
+                            width: pending.width,
+                            height: pending.height,
+                            view_projection: pending.view_projection,
+                            camera_section: pending.camera_section,
+                        }));
+                        Readback::Idle
+                    }
+                }
+            }
+        };
+    }
+
+    /// The last level copied back to the CPU, only for devices which cull sections there
+    pub fn cpu_depth(&self) -> Option<Arc<CpuDepth>> {
+        self.cpu_depth.clone()
+    }
+
+    /// Every level of the pyramid, as an R32Float texture
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub fn params(&self) -> OcclusionParams {
+        match self.built_from {
+            None => OcclusionParams::DISABLED,
+            Some((view_projection, camera_section)) => OcclusionParams {
+                view_projection: view_projection.to_cols_array_2d(),
+                camera_section: camera_section.to_array(),
+                enabled: 1,
+                padding: 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{ivec2, ivec3, vec3, Mat4, Vec3};
+
+    use super::CpuDepth;
+
+    /// Looking down -z from the origin of section column (2, 3), with a wall half way to `far` covering the left half
+    /// of the screen
+    fn half_wall() -> CpuDepth {
+        let view_projection =
+            Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 100.0) * Mat4::look_at_rh(Vec3::ZERO, -Vec3::Z, Vec3::Y);
+        let wall_depth = {
+            let clip = view_projection * vec3(0.0, 0.0, -20.0).extend(1.0);
+            clip.z / clip.w
+        };
+
+        CpuDepth {
+            depth: (0..64).map(|index| if index % 8 < 4 { wall_depth } else { 1.0 }).collect(),
+            width: 8,
+            height: 8,
+            view_projection,
+            camera_section: ivec2(2, 3),
+        }
+    }
+
+    #[test]
+    fn boxes_behind_the_wall() {
+        let depth = half_wall();
+
+        //Behind the wall, in front of it, and straddling the edge of the wall
+        assert!(depth.occluded(vec3(-20.0, -2.0, -40.0), vec3(-10.0, 2.0, -30.0)));
+        assert!(!depth.occluded(vec3(-8.0, -2.0, -12.0), vec3(-4.0, 2.0, -10.0)));
+        assert!(!depth.occluded(vec3(-10.0, -2.0, -40.0), vec3(10.0, 2.0, -30.0)));
+        //Nothing was drawn on the right
+        assert!(!depth.occluded(vec3(10.0, -2.0, -40.0), vec3(20.0, 2.0, -30.0)));
+    }
+
+    #[test]
+    fn boxes_nothing_is_known_about() {
+        let depth = half_wall();
+
+        //Behind the camera, partly behind it, and off screen
+        assert!(!depth.occluded(vec3(-20.0, -2.0, 30.0), vec3(-10.0, 2.0, 40.0)));
+        assert!(!depth.occluded(vec3(-20.0, -2.0, -40.0), vec3(-10.0, 2.0, 5.0)));
+        assert!(!depth.occluded(vec3(-20.0, 100.0, -40.0), vec3(-10.0, 110.0, -30.0)));
+    }
+
+    #[test]
+    fn sections_are_relative_to_the_camera_section() {
+        let depth = half_wall();
+
+        //Section column (0, 0) is 32 blocks left and 48 blocks forward of the camera's
+        assert!(depth.section_occluded(ivec3(0, 0, 0)));
+        assert!(depth.occluded(vec3(-32.0, 0.0, -48.0), vec3(-16.0, 16.0, -32.0)));
+        assert!(!depth.section_occluded(ivec3(3, 0, 0)));
+        //In front of the wall
+        assert!(!depth.section_occluded(ivec3(1, 0, 2)));
+    }
+}
@@ -0,0 +1,43 @@
+// Builds each level of the depth pyramid from the one before it, keeping the furthest depth of every 2x2 block.
+// See src/render/depth_pyramid.rs
+
+@group(0) @binding(0) var depth: texture_depth_2d;
+@group(0) @binding(1) var source: texture_2d<f32>;
+@group(0) @binding(2) var destination: texture_storage_2d<r32float, write>;
+
+// Every texel of the source is covered, the last row and column of an odd sized one clamp onto themselves
+fn source_texel(id: vec2<u32>, offset: vec2<u32>, size: vec2<u32>) -> vec2<u32> {
+    return min(id * 2u + offset, size - 1u);
+}
+
+@compute @workgroup_size(8, 8)
+fn downsample_depth(@builtin(global_invocation_id) id: vec3<u32>) {
+    if(any(id.xy >= textureDimensions(destination))) {
+        return;
+    }
+
+    let size = textureDimensions(depth);
+    var furthest = 0.0;
+    for(var i = 0u; i < 4u; i++) {
+        let texel = source_texel(id.xy, vec2(i & 1u, i >> 1u), size);
+        furthest = max(furthest, textureLoad(depth, texel, 0));
+    }
+
+    textureStore(destination, id.xy, vec4(furthest));
+}
+
+@compute @workgroup_size(8, 8)
+fn downsample(@builtin(global_invocation_id) id: vec3<u32>) {
+    if(any(id.xy >= textureDimensions(destination))) {
+        return;
+    }
+
+    let size = textureDimensions(source);
+    var furthest = 0.0;
+    for(var i = 0u; i < 4u; i++) {
+        let texel = source_texel(id.xy, vec2(i & 1u, i >> 1u), size);
+        furthest = max(furthest, textureLoad(source, texel, 0).r);
+    }
+
+    textureStore(destination, id.xy, vec4(furthest));
+}
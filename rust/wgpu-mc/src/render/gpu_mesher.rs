@@ -0,0 +1,492 @@
+//! Meshing sections with a compute shader.
+//!
+//! With [crate::mc::chunk::BakeSettings::gpu_meshing] on, the bake threads only work out which model each block has,
+//! which of its faces are culled, its tint and where its quads go, see [GpuMeshInput]. [GpuMesher::queue] keeps that
+//! along with the light and opacity of the blocks in and around the section until [GpuMesher::flush], where
+//! `gpu_mesher.wgsl` writes the vertices and their light for every queued section straight into their ranges of the
+//! chunk buffer, the same as [crate::mc::chunk::bake_section] would have on the CPU.
+//!
+//! The shader reads the faces of the models from a table kept on the GPU, which gets every model the bake threads
+//! come across. Sections with fluids or transparent blocks are still meshed on the CPU, and so are the simplified
+//! LOD meshes. Greedy meshing isn't done here, so sections are only meshed on the GPU when no layer has it on.
+//!
+//! Next to their light, GPU meshed layers keep which blocks the light of each vertex came from, see
+//! [crate::mc::chunk::SectionRanges::sample_range]. When only the light changes [GpuMesher::queue_relight] rewrites
+//! the light from those, like [crate::mc::chunk::SectionStorage::relight] does on the CPU.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use glam::IVec3;
+use parking_lot::Mutex;
+
+use crate::mc::block::{BlockModelFace, ModelMesh};
+use crate::mc::chunk::{diffuse_shade, GpuMeshInput, Section, SectionStorage, SECTION_VOLUME};
+use crate::mc::direction::Direction;
+use crate::render::pipeline::TERRAIN_VERTEX_WGSL;
+
+/// A face of a model, as the shader reads it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuFace {
+    /// Positions of the corners within the block, the last component is unused
+    pub positions: [[f32; 4]; 4],
+    /// Texture coordinates of the corners, u | v << 16
+    pub tex_coords: [u32; 4],
+    /// The [Direction] of the side the face is culled with, [GpuFace::NONE] if it's never culled
+    pub side: u32,
+    /// The [Direction] smooth light is sampled in, [GpuFace::NONE] if the face only gets flat light
+    pub light_dir: u32,
+    /// 1 if the face takes the block's tint
+    pub tinted: u32,
+    /// What [diffuse_shade] darkens the face by, 1 for faces which aren't shaded
+    pub shade: f32,
+}
+
+impl GpuFace {
+    pub const NONE: u32 = 6;
+
+    fn new(face: &BlockModelFace, side: Option<Direction>) -> Self {
+        Self {
+            positions: face.vertices.map(|vertex| vertex.position.extend(0.0).to_array()),
+            tex_coords: face.vertices.map(|vertex| vertex.tex_coords[0] as u32 | (vertex.tex_coords[1] as u32) << 16),
+            side: side.map_or(Self::NONE, |dir| dir as u32),
+            light_dir: side.or_else(|| Direction::from_normal(face.normal)).map_or(Self::NONE, |dir| dir as u32),
+            tinted: (face.tint_index >= 0) as u32,
+            shade: if face.shade { diffuse_shade(face.normal) } else { 1.0 },
+        }
+    }
+}
+
+/// A block of a section, as the shader reads it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuBlock {
+    /// Where its model is in the table, u32::MAX for blocks without one
+    pub mesh: u32,
+    /// Where its first quad goes among the quads of its layer
+    pub first_quad: u32,
+    /// 0xRRGGBB its tinted faces are multiplied by
+    pub color: u32,
+    /// A bit for each [Direction] whose faces aren't culled, and its [crate::mc::chunk::RenderLayer] << 8
+    pub flags: u32,
+    /// How far the model is moved off the block grid, the last component is unused
+    pub offset: [f32; 4],
+}
+
+impl GpuBlock {
+    pub const EMPTY: Self = Self {
+        mesh: u32::MAX,
+        first_quad: 0,
+        color: 0,
+        flags: 0,
+        offset: [0.0; 4],
+    };
+}
+
+
+/// Where the layers of a section are in the chunk buffer, in u32s
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct JobParams {
+    vertex_starts: [u32; 4],
+    light_starts: [u32; 4],
+    /// Where the shader notes which blocks the light of each vertex came from, one u32 per vertex
+    sample_starts: [u32; 4],
+    quads: [u32; 4],
+    smooth_lighting: u32,
+    padding: [u32; 3],
+}
+
+impl JobParams {
+    fn new(section: &Section, smooth_lighting: bool) -> Self {
+        let mut params = Self {
+            vertex_starts: [0; 4],
+            light_starts: [0; 4],
+            sample_starts: [0; 4],
+            quads: [0; 4],
+            smooth_lighting: smooth_lighting as u32,
+            padding: [0; 3],
+        };
+        for (layer, ranges) in section.layers.iter().enumerate() {
+            if let Some(ranges) = ranges {
+                params.vertex_starts[layer] = ranges.vertex_range.start;
+                params.light_starts[layer] = ranges.light_range.start;
+                params.sample_starts[layer] = ranges.sample_range.start;
+                params.quads[layer] = ranges.quads();
+            }
+        }
+        params
+    }
+}
+
+fn create_buffer(device: &wgpu::Device, label: &str, size: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// The faces of every model handed to [GpuMesher::mesh_index], in the order the CPU meshes them
+struct MeshTable {
+    /// Indices of the models by address. The models are kept so their addresses can't be reused.
+    indices: HashMap<usize, u32>,
+    models: Vec<Arc<ModelMesh>>,
+    /// The first face and face count of each model
+    meshes: Vec<[u32; 2]>,
+    faces: Vec<GpuFace>,
+    mesh_buffer: wgpu::Buffer,
+    face_buffer: wgpu::Buffer,
+    /// How many of the meshes and faces the buffers have
+    uploaded: (usize, usize),
+}
+
+impl MeshTable {
+    /// Copy the models added since the last upload to the GPU, growing the buffers if they don't fit
+    fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let mesh_bytes = (self.meshes.len() * std::mem::size_of::<[u32; 2]>()) as u64;
+        let face_bytes = (self.faces.len() * std::mem::size_of::<GpuFace>()) as u64;
+
+        if mesh_bytes > self.mesh_buffer.size() || face_bytes > self.face_buffer.size() {
+            self.mesh_buffer = create_buffer(device, "gpu_mesher_meshes", mesh_bytes.max(self.mesh_buffer.size() * 2));
+            self.face_buffer = create_buffer(device, "gpu_mesher_faces", face_bytes.max(self.face_buffer.size() * 2));
+            self.uploaded = (0, 0);
+        }
+
+        let (meshes, faces) = self.uploaded;
+        queue.write_buffer(
+            &self.mesh_buffer,
+            (meshes * std::mem::size_of::<[u32; 2]>()) as u64,
+            bytemuck::cast_slice(&self.meshes[meshes..]),
+        );
+        queue.write_buffer(
+            &self.face_buffer,
+            (faces * std::mem::size_of::<GpuFace>()) as u64,
+            bytemuck::cast_slice(&self.faces[faces..]),
+        );
+        self.uploaded = (self.meshes.len(), self.faces.len());
+    }
+}
+
+/// A storage buffer the inputs of every flush are written into, which only grows
+struct PooledBuffer {
+    label: &'static str,
+    buffer: wgpu::Buffer,
+}
+
+impl PooledBuffer {
+    fn new(device: &wgpu::Device, label: &'static str) -> Self {
+        Self {
+            label,
+            buffer: create_buffer(device, label, 1 << 16),
+        }
+    }
+
+    fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) {
+        if data.len() as u64 > self.buffer.size() {
+            self.buffer = create_buffer(device, self.label, (data.len() as u64).max(self.buffer.size() * 2));
+        }
+        queue.write_buffer(&self.buffer, 0, data);
+    }
+}
+
+/// What's waiting to be done to a section at the next [GpuMesher::flush]
+enum GpuJob {
+    Mesh(GpuMeshInput),
+    /// The section's region with the new light, see [GpuMeshInput::region]
+    Relight(Vec<u32>),
+}
+
+struct JobBuffers {
+    jobs: PooledBuffer,
+    blocks: PooledBuffer,
+    region: PooledBuffer,
+}
+
+impl JobBuffers {
+    fn new(device: &wgpu::Device) -> Self {
+        Self {
+            jobs: PooledBuffer::new(device, "gpu_mesher_jobs"),
+            blocks: PooledBuffer::new(device, "gpu_mesher_blocks"),
+            region: PooledBuffer::new(device, "gpu_mesher_region"),
+        }
+    }
+}
+
+pub struct GpuMesher {
+    mesh_pipeline: wgpu::ComputePipeline,
+    relight_pipeline: wgpu::ComputePipeline,
+    table: Mutex<MeshTable>,
+    /// Bumped by [Self::clear], so inputs the bake threads made with the old table can be told apart
+    generation: AtomicU64,
+    /// Keyed by section, a later job replaces an earlier one
+    jobs: Mutex<HashMap<IVec3, GpuJob>>,
+    mesh_buffers: Mutex<JobBuffers>,
+    relight_buffers: Mutex<JobBuffers>,
+}
+
+impl GpuMesher {
+    /// Most sections meshed or relit by one dispatch, which keeps the blocks of a dispatch well within the smallest
+    /// storage buffer binding a device can have
+    const MAX_BATCH: usize = 256;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("gpu_mesher.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(format!("{TERRAIN_VERTEX_WGSL}\n{}", include_str!("gpu_mesher.wgsl")).into()),
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("gpu_mesher"),
+                layout: None,
+                module: &module,
+                entry_point,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        let table = MeshTable {
+            indices: HashMap::new(),
+            models: Vec::new(),
+            meshes: Vec::new(),
+            faces: Vec::new(),
+            mesh_buffer: create_buffer(device, "gpu_mesher_meshes", 1024 * std::mem::size_of::<[u32; 2]>() as u64),
+            face_buffer: create_buffer(device, "gpu_mesher_faces", 4096 * std::mem::size_of::<GpuFace>() as u64),
+            uploaded: (0, 0),
+        };
+
+        Self {
+            mesh_pipeline: pipeline("mesh"),
+            relight_pipeline: pipeline("relight"),
+            table: Mutex::new(table),
+            generation: AtomicU64::new(0),
+            jobs: Mutex::new(HashMap::new()),
+            mesh_buffers: Mutex::new(JobBuffers::new(device)),
+            relight_buffers: Mutex::new(JobBuffers::new(device)),
+        }
+    }
+
+    /// Which table [Self::mesh_index] hands out indices in. Inputs have to take this before looking up any model.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Whether an input was made with the current table, and can still be meshed
+    pub fn is_current(&self, input: &GpuMeshInput) -> bool {
+        input.generation == self.generation()
+    }
+
+    /// Where a model is in the table, adding it if it isn't there yet. Called from the bake threads, the models they
+    /// add are uploaded before the next [Self::flush].
+    pub fn mesh_index(&self, model: &Arc<ModelMesh>) -> u32 {
+        let mut table = self.table.lock();
+        if let Some(index) = table.indices.get(&(Arc::as_ptr(model) as usize)) {
+            return *index;
+        }
+
+        let first = table.faces.len() as u32;
+        //Same order as bake_layers adds them in
+        let sides = [
+            (Direction::West, &model.west),
+            (Direction::East, &model.east),
+            (Direction::Down, &model.down),
+            (Direction::Up, &model.up),
+            (Direction::North, &model.north),
+            (Direction::South, &model.south),
+        ];
+        for (dir, faces) in sides {
+            table.faces.extend(faces.iter().map(|face| GpuFace::new(face, Some(dir))));
+        }
+        table.faces.extend(model.any.iter().map(|face| GpuFace::new(face, None)));
+
+        let index = table.meshes.len() as u32;
+        let count = table.faces.len() as u32 - first;
+        table.meshes.push([first, count]);
+        table.models.push(model.clone());
+        table.indices.insert(Arc::as_ptr(model) as usize, index);
+        index
+    }
+
+    /// Start a new table, for when the block models are baked again. Bake threads may still be working with the old
+    /// one, what they come up with is dropped rather than meshed with the wrong models.
+    pub fn clear(&self) {
+        let mut table = self.table.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        table.indices.clear();
+        table.models.clear();
+        table.meshes.clear();
+        table.faces.clear();
+        table.uploaded = (0, 0);
+    }
+
+    /// Mesh a section into the ranges [crate::mc::chunk::SectionStorage::replace_gpu] gave it at the next
+    /// [Self::flush]
+    pub fn queue(&self, pos: IVec3, input: GpuMeshInput) {
+        self.jobs.lock().insert(pos, GpuJob::Mesh(input));
+    }
+
+    /// Rewrite the light of a section meshed here at the next [Self::flush], from `region` made by
+    /// [crate::mc::chunk::gpu_region] with the new light
+    pub fn queue_relight(&self, pos: IVec3, region: Vec<u32>) {
+        let mut jobs = self.jobs.lock();
+        match jobs.get_mut(&pos) {
+            //Not meshed yet, so it can just be meshed with the new light
+            Some(GpuJob::Mesh(input)) => input.region = region,
+            _ => {
+                jobs.insert(pos, GpuJob::Relight(region));
+            }
+        }
+    }
+
+    /// Run the queued jobs on the sections where `storage` has them now, which may have moved since they were queued.
+    /// Sections which were unloaded or baked on the CPU since are skipped, and so are inputs from an old table.
+    /// Everything is recorded into one submission, unless there are more than [Self::MAX_BATCH] of a kind.
+    pub fn flush(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        storage: &SectionStorage,
+        chunk_buffer: &wgpu::Buffer,
+    ) {
+        let jobs = std::mem::take(&mut *self.jobs.lock());
+        if jobs.is_empty() {
+            return;
+        }
+        let generation = self.generation();
+
+        let mut meshes = vec![];
+        let mut relights = vec![];
+        for (pos, job) in jobs {
+            let Some(section) = storage.get(&pos).filter(|section| section.gpu_meshed) else {
+                continue;
+            };
+            match job {
+                //Every face was culled
+                GpuJob::Mesh(input) if input.size() == 0 => {}
+                GpuJob::Mesh(input) if input.generation == generation => meshes.push((section, input)),
+                GpuJob::Mesh(_) => {}
+                GpuJob::Relight(region) => relights.push((section, region)),
+            }
+        }
+
+        while !meshes.is_empty() || !relights.is_empty() {
+            let mesh_batch: Vec<_> = meshes.drain(..meshes.len().min(Self::MAX_BATCH)).collect();
+            let relight_batch: Vec<_> = relights.drain(..relights.len().min(Self::MAX_BATCH)).collect();
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("gpu_mesher"),
+            });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("gpu_mesher"),
+                    timestamp_writes: None,
+                });
+                if !mesh_batch.is_empty() {
+                    self.record_meshes(device, queue, &mut pass, &mesh_batch, chunk_buffer);
+                }
+                if !relight_batch.is_empty() {
+                    self.record_relights(device, queue, &mut pass, &relight_batch, chunk_buffer);
+                }
+            }
+            queue.submit([encoder.finish()]);
+        }
+    }
+
+    fn record_meshes(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pass: &mut wgpu::ComputePass,
+        batch: &[(&Section, GpuMeshInput)],
+        chunk_buffer: &wgpu::Buffer,
+    ) {
+        let params: Vec<JobParams> = batch
+            .iter()
+            .map(|(section, input)| JobParams::new(section, input.smooth_lighting))
+            .collect();
+        let blocks: Vec<GpuBlock> = batch.iter().flat_map(|(_, input)| input.blocks.iter().copied()).collect();
+        let region: Vec<u32> = batch.iter().flat_map(|(_, input)| input.region.iter().copied()).collect();
+
+        let mut buffers = self.mesh_buffers.lock();
+        buffers.jobs.write(device, queue, bytemuck::cast_slice(&params));
+        buffers.blocks.write(device, queue, bytemuck::cast_slice(&blocks));
+        buffers.region.write(device, queue, bytemuck::cast_slice(&region));
+
+        let mut table = self.table.lock();
+        table.upload(device, queue);
+
+        let entries = [
+            buffers.jobs.buffer.as_entire_binding(),
+            table.face_buffer.as_entire_binding(),
+            table.mesh_buffer.as_entire_binding(),
+            buffers.blocks.buffer.as_entire_binding(),
+            buffers.region.buffer.as_entire_binding(),
+            chunk_buffer.as_entire_binding(),
+        ];
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("gpu_mesher"),
+            layout: &self.mesh_pipeline.get_bind_group_layout(0),
+            entries: &entries
+                .into_iter()
+                .enumerate()
+                .map(|(binding, resource)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource,
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        pass.set_pipeline(&self.mesh_pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        //One invocation per block, a row of workgroups per section
+        pass.dispatch_workgroups(SECTION_VOLUME as u32 / 64, batch.len() as u32, 1);
+    }
+
+    fn record_relights(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pass: &mut wgpu::ComputePass,
+        batch: &[(&Section, Vec<u32>)],
+        chunk_buffer: &wgpu::Buffer,
+    ) {
+        let params: Vec<JobParams> = batch.iter().map(|(section, _)| JobParams::new(section, false)).collect();
+        let region: Vec<u32> = batch.iter().flat_map(|(_, region)| region.iter().copied()).collect();
+        let max_quads = params.iter().map(|params| params.quads.iter().sum::<u32>()).max().unwrap_or(0);
+        if max_quads == 0 {
+            return;
+        }
+
+        let mut buffers = self.relight_buffers.lock();
+        buffers.jobs.write(device, queue, bytemuck::cast_slice(&params));
+        buffers.region.write(device, queue, bytemuck::cast_slice(&region));
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("gpu_mesher_relight"),
+            layout: &self.relight_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffers.jobs.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: buffers.region.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: chunk_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        pass.set_pipeline(&self.relight_pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        //One invocation per quad, a row of workgroups per section
+        pass.dispatch_workgroups(max_quads.div_ceil(64), batch.len() as u32, 1);
+    }
+}
@@ -0,0 +1,232 @@
+// Meshes sections into the chunk buffer, one invocation per block and a row of workgroups per section, writing the
+// same vertices and light bake_layers would on the CPU. Which faces are culled, the tints and where each block's quads
+// go were worked out on the CPU. Sections meshed here are relit here too, one invocation per quad, from where the mesh
+// pass noted each vertex's light came from. Vertices are packed with the helpers of terrain_vertex.wgsl, which gets put
+// in front of this.
+// See src/render/gpu_mesher.rs and src/mc/chunk.rs
+
+struct Face {
+    positions: array<vec4<f32>, 4>,
+    // u | v << 16
+    tex_coords: vec4<u32>,
+    side: u32,
+    light_dir: u32,
+    tinted: u32,
+    shade: f32
+}
+
+struct Block {
+    mesh: u32,
+    first_quad: u32,
+    color: u32,
+    // A bit for each side which isn't culled, and the render layer << 8
+    flags: u32,
+    offset: vec4<f32>
+}
+
+// Where the layers of a section are in the chunk buffer, in u32s
+struct Job {
+    vertex_starts: vec4<u32>,
+    light_starts: vec4<u32>,
+    // Where the light of each vertex was sampled from, see sample_light
+    sample_starts: vec4<u32>,
+    quads: vec4<u32>,
+    smooth_lighting: u32,
+    padding_a: u32,
+    padding_b: u32,
+    padding_c: u32
+}
+
+struct VertexLight {
+    // Block and sky light in sixteenths of a light level
+    light: vec2<u32>,
+    ao: u32
+}
+
+@group(0) @binding(0) var<storage, read> jobs: array<Job>;
+@group(0) @binding(1) var<storage, read> faces: array<Face>;
+// The first face and face count of each model
+@group(0) @binding(2) var<storage, read> meshes: array<vec2<u32>>;
+// SECTION_VOLUME blocks per job
+@group(0) @binding(3) var<storage, read> blocks: array<Block>;
+// The section and the blocks around it, REGION_VOLUME per job: block light | sky light << 8 | opaque << 16
+@group(0) @binding(4) var<storage, read> region: array<u32>;
+@group(0) @binding(5) var<storage, read_write> chunk_data: array<u32>;
+
+const SECTION_VOLUME: u32 = 4096u;
+const REGION_VOLUME: u32 = 5832u;
+// Faces which aren't on a side, or only get flat light
+const NONE: u32 = 6u;
+const NO_MESH: u32 = 0xffffffffu;
+
+var<private> job: u32;
+
+// Same order as Direction: west, east, down, up, north, south
+fn direction(dir: u32) -> vec3<i32> {
+    var vector = vec3(0);
+    vector[dir / 2u] = select(-1, 1, (dir & 1u) == 1u);
+    return vector;
+}
+
+// Like LightSamples::block
+fn region_index(pos: vec3<i32>) -> u32 {
+    return u32(((pos.y + 1) * 18 + pos.z + 1) * 18 + pos.x + 1);
+}
+
+fn region_pos(index: u32) -> vec3<i32> {
+    let i = i32(index);
+    return vec3(i % 18, i / 324, (i / 18) % 18) - 1;
+}
+
+fn region_block(pos: vec3<i32>) -> u32 {
+    return region[job * REGION_VOLUME + region_index(pos)];
+}
+
+fn light_level(pos: vec3<i32>) -> vec2<u32> {
+    let block = region_block(pos);
+    return vec2(block & 0xffu, (block >> 8u) & 0xffu);
+}
+
+fn is_opaque(pos: vec3<i32>) -> bool {
+    return (region_block(pos) & 0x10000u) != 0u;
+}
+
+// Like smooth_light in chunk.rs, for a single corner. `positive_a` and `positive_b` are which way the corner is from
+// the middle of the face along the two axes of the side.
+fn smooth_light(dir: u32, plane: vec3<i32>, positive_a: bool, positive_b: bool) -> VertexLight {
+    let axis = dir / 2u;
+
+    var side_a = vec3(0);
+    side_a[(axis + 1u) % 3u] = select(-1, 1, positive_a);
+    var side_b = vec3(0);
+    side_b[(axis + 2u) % 3u] = select(-1, 1, positive_b);
+
+    let side_a_opaque = is_opaque(plane + side_a);
+    let side_b_opaque = is_opaque(plane + side_b);
+    // When both sides are opaque the corner can't be seen, so it counts as occluded too
+    let corner_opaque = (side_a_opaque && side_b_opaque) || is_opaque(plane + side_a + side_b);
+
+    // Opaque blocks have no light of their own, vanilla uses the center's light for them instead
+    let sum = light_level(plane)
+        + light_level(select(plane + side_a, plane, side_a_opaque))
+        + light_level(select(plane + side_b, plane, side_b_opaque))
+        + light_level(select(plane + side_a + side_b, plane, corner_opaque));
+    let occluders = u32(side_a_opaque) + u32(side_b_opaque) + u32(corner_opaque);
+
+    return VertexLight(sum * 4u, 255u - occluders * 51u);
+}
+
+// Where a vertex gets its light from: the region index of the block its face looks into | the direction smooth light
+// is sampled in << 13, NONE for flat light | whether the corner is on the positive side of the first axis << 16 and
+// of the second << 17
+fn sample_light(sample: u32) -> VertexLight {
+    let plane = region_pos(sample & 0x1fffu);
+    let dir = (sample >> 13u) & 7u;
+    if(dir == NONE) {
+        return VertexLight(light_level(plane) * 16u, 255u);
+    }
+    return smooth_light(dir, plane, (sample & 0x10000u) != 0u, (sample & 0x20000u) != 0u);
+}
+
+@compute @workgroup_size(64)
+fn mesh(@builtin(global_invocation_id) id: vec3<u32>) {
+    job = id.y;
+    let block = blocks[job * SECTION_VOLUME + id.x];
+    if(block.mesh == NO_MESH) {
+        return;
+    }
+    let params = jobs[job];
+
+    // The order bake_layers goes through the blocks in
+    let pos = vec3<i32>(i32(id.x & 15u), i32(id.x >> 8u), i32((id.x & 255u) >> 4u));
+    let fpos = vec3<f32>(pos) + block.offset.xyz;
+    let layer = block.flags >> 8u;
+    let mesh = meshes[block.mesh];
+
+    var quad = block.first_quad;
+    for(var i = 0u; i < mesh.y; i++) {
+        var face = faces[mesh.x + i];
+        if(face.side != NONE && (block.flags & (1u << face.side)) == 0u) {
+            continue;
+        }
+
+        // Faces on a side get the light of the block they face
+        var plane = pos;
+        if(face.side != NONE) {
+            plane += direction(face.side);
+        }
+
+        var samples: array<u32, 4>;
+        var light: array<VertexLight, 4>;
+        for(var corner = 0u; corner < 4u; corner++) {
+            samples[corner] = region_index(plane) | (NONE << 13u);
+            if(face.light_dir != NONE && params.smooth_lighting != 0u) {
+                let axis = face.light_dir / 2u;
+                let position = face.positions[corner].xyz;
+                samples[corner] = region_index(plane) | (face.light_dir << 13u)
+                    | (u32(position[(axis + 1u) % 3u] >= 0.5) << 16u)
+                    | (u32(position[(axis + 2u) % 3u] >= 0.5) << 17u);
+            }
+            light[corner] = sample_light(samples[corner]);
+        }
+
+        let color = select(0xffffffu, block.color, face.tinted != 0u);
+        let channels = vec3((color >> 16u) & 0xffu, (color >> 8u) & 0xffu, color & 0xffu);
+        let rgb = min(vec3<u32>(vec3<f32>(channels) * face.shade), vec3(255u));
+
+        // Split the quad along the brighter diagonal, like push_quad
+        let first = select(1u, 0u, light[0].ao + light[2].ao > light[1].ao + light[3].ao);
+
+        let vertex_start = params.vertex_starts[layer] + quad * 16u;
+        let sample_start = params.sample_starts[layer] + quad * 4u;
+        var vertex_light: array<u32, 4>;
+        for(var corner = 0u; corner < 4u; corner++) {
+            let vi = (first + corner) % 4u;
+            // The texture isn't repeated
+            let position = pack_terrain_position(fpos + face.positions[vi].xyz, vec2(1u));
+
+            let offset = vertex_start + corner * 4u;
+            chunk_data[offset] = position.x;
+            chunk_data[offset + 1u] = position.y;
+            chunk_data[offset + 2u] = face.tex_coords[vi];
+            chunk_data[offset + 3u] = pack_terrain_color(rgb, light[vi].ao);
+            chunk_data[sample_start + corner] = samples[vi];
+            vertex_light[corner] = light[vi].light.x | (light[vi].light.y << 8u);
+        }
+
+        // 2 bytes per vertex
+        let light_start = params.light_starts[layer] + quad * 2u;
+        chunk_data[light_start] = vertex_light[0] | (vertex_light[1] << 16u);
+        chunk_data[light_start + 1u] = vertex_light[2] | (vertex_light[3] << 16u);
+
+        quad++;
+    }
+}
+
+@compute @workgroup_size(64)
+fn relight(@builtin(global_invocation_id) id: vec3<u32>) {
+    job = id.y;
+    let params = jobs[job];
+
+    // Invocations go through the quads of every layer in turn
+    var quad = id.x;
+    var layer = 0u;
+    while(layer < 4u && quad >= params.quads[layer]) {
+        quad -= params.quads[layer];
+        layer++;
+    }
+    if(layer == 4u) {
+        return;
+    }
+
+    let sample_start = params.sample_starts[layer] + quad * 4u;
+    var vertex_light: array<u32, 4>;
+    for(var corner = 0u; corner < 4u; corner++) {
+        let light = sample_light(chunk_data[sample_start + corner]).light;
+        vertex_light[corner] = light.x | (light.y << 8u);
+    }
+
+    let light_start = params.light_starts[layer] + quad * 2u;
+    chunk_data[light_start] = vertex_light[0] | (vertex_light[1] << 16u);
+    chunk_data[light_start + 1u] = vertex_light[2] | (vertex_light[3] << 16u);
+}
@@ -2,11 +2,10 @@ use std::alloc::alloc;
 use std::collections::HashMap;
 use std::mem;
 use std::sync::Arc;
-use glam::ivec3;
+use glam::Mat4;
 use linked_hash_map::LinkedHashMap;
-use treeculler::{AABB, BVol, Frustum, Vec3};
 
-use wgpu::{BindGroup, BufferAddress, Color, IndexFormat, LoadOp, Operations, RenderPassColorAttachment, RenderPassDepthStencilAttachment, RenderPassDescriptor, SamplerBindingType, ShaderStages, StoreOp};
+use wgpu::{BindGroup, BufferAddress, Color, IndexFormat, LoadOp, Operations, RenderPassColorAttachment, RenderPassDepthStencilAttachment, RenderPassDescriptor, SamplerBindingType, StoreOp};
 use wgpu::util::{DrawIndexedIndirectArgs, DrawIndirectArgs};
 
 use crate::mc::chunk::RenderLayer;
@@ -14,13 +13,14 @@ use crate::mc::entity::InstanceVertex;
 use crate::mc::resource::ResourcePath;
 use crate::mc::Scene;
 use crate::render::entity::EntityVertex;
-use crate::render::pipeline::{QuadVertex, BLOCK_ATLAS};
+use crate::render::pipeline::{QuadVertex, BLOCK_ATLAS, TERRAIN_VERTEX_WGSL};
 use crate::render::shader::WgslShader;
 use crate::render::shaderpack::{
     BindGroupDef, LonghandResourceConfig, PipelineConfig, ShaderPackConfig,
     ShorthandResourceConfig, TypeResourceConfig,
 };
 use crate::render::sky::{SkyVertex, SunMoonVertex};
+use crate::render::terrain::TerrainDraws;
 use crate::texture::TextureAndView;
 use crate::util::WmArena;
 use crate::WmRenderer;
@@ -163,7 +163,7 @@ impl RenderGraph {
                     }
                     BindGroupDef::Resource(resource) => {
                         match (&resource[..], &custom_bind_groups) {
-                            ("@bg_ssbo_chunks", _) => {
+                            ("@bg_ssbo_chunks" | "@bg_ssbo_sections", _) => {
                                 wm.bind_group_layouts.get("ssbo").unwrap()
                             }
                             (_, Some(custom)) => {
@@ -245,16 +245,25 @@ impl RenderGraph {
                     });
 
             let shader = WgslShader::init(
-                &ResourcePath(format!("wgpu_mc:shaders/{}.wgsl", pipeline_name)),
+                &ResourcePath(format!(
+                    "wgpu_mc:shaders/{}.wgsl",
+                    pipeline_config.shader.as_ref().unwrap_or(pipeline_name)
+                )),
                 &*wm.mc.resource_provider,
                 &wm.display.device,
+                match &pipeline_config.geometry[..] {
+                    "@geo_terrain" | "@geo_terrain_cutout" | "@geo_terrain_transparent" | "@geo_terrain_lod" => {
+                        TERRAIN_VERTEX_WGSL
+                    }
+                    _ => "",
+                },
                 "frag".into(),
                 "vert".into(),
             )
             .unwrap();
 
             let vertex_buffer = match &pipeline_config.geometry[..] {
-                "@geo_terrain" => None,
+                "@geo_terrain" | "@geo_terrain_cutout" | "@geo_terrain_transparent" | "@geo_terrain_lod" => None,
                 "@geo_entities" => Some(vec![EntityVertex::desc(), InstanceVertex::desc()]),
                 "@geo_quad" => Some(vec![QuadVertex::desc()]),
                 "@geo_sun_moon" => Some(vec![SunMoonVertex::desc()]),
@@ -302,7 +311,7 @@ impl RenderGraph {
                         depth_stencil: pipeline_config.depth.as_ref().map(|_| {
                             wgpu::DepthStencilState {
                                 format: wgpu::TextureFormat::Depth32Float,
-                                depth_write_enabled: true,
+                                depth_write_enabled: pipeline_config.depth_write,
                                 depth_compare: wgpu::CompareFunction::Less,
                                 stencil: wgpu::StencilState::default(),
                                 bias: Default::default(),
@@ -320,6 +329,7 @@ impl RenderGraph {
                                     Some(wgpu::ColorTargetState {
                                         format: wgpu::TextureFormat::Bgra8Unorm,
                                         blend: Some(match &pipeline_config.blending[..] {
+                                            "replace" => wgpu::BlendState::REPLACE,
                                             "alpha_blending" => wgpu::BlendState::ALPHA_BLENDING,
                                             "premultiplied_alpha_blending" => {
                                                 wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING
@@ -429,6 +439,10 @@ impl RenderGraph {
 
         graph.create_pipelines(wm, custom_bind_groups, custom_geometry);
 
+        //Only the pack knows whether its terrain shaders repeat textures across merged quads
+        wm.mc.bake_settings.write().greedy_meshing = RenderLayer::ALL
+            .map(|layer| graph.config.greedy_meshing.iter().any(|name| name == layer.name()));
+
         graph
     }
 
@@ -440,12 +454,21 @@ impl RenderGraph {
         render_target: &wgpu::TextureView,
         clear_color: [u8; 3],
         geometry: &mut HashMap<String, Box<dyn Geometry>>,
-        frustum: &Frustum<f32>
+        view_projection: Mat4
     ) {
         let arena = WmArena::new(4096);
 
         let mut should_clear_depth = true;
 
+        //Culling the sections can record a compute pass, which can't happen while a render pass is open
+        let draws_geometry = |geometry: &[&str]| self.config.pipelines.pipelines.values()
+            .any(|pipeline_config| geometry.contains(&&pipeline_config.geometry[..]));
+        let occlusion_culling = self.config.occlusion_culling.as_ref();
+        let terrain_draws = draws_geometry(&["@geo_terrain", "@geo_terrain_cutout", "@geo_terrain_transparent"])
+            .then(|| TerrainDraws::new(wm, scene, encoder, view_projection, occlusion_culling.is_some()));
+        let lod_draws = draws_geometry(&["@geo_terrain_lod"])
+            .then(|| TerrainDraws::lod(wm, scene, view_projection));
+
         for (pipeline_name, bound_pipeline) in &self.pipelines {
             let pipeline_config = self.config.pipelines.pipelines.get(pipeline_name).unwrap();
 
@@ -522,14 +545,33 @@ impl RenderGraph {
             });
 
             match &pipeline_config.geometry[..] {
-                "@geo_terrain" => {
+                "@geo_terrain" | "@geo_terrain_cutout" | "@geo_terrain_transparent" | "@geo_terrain_lod" => {
+                    let render_layer = match &pipeline_config.geometry[..] {
+                        "@geo_terrain_cutout" => RenderLayer::Cutout,
+                        "@geo_terrain_transparent" => RenderLayer::Transparent,
+                        _ => RenderLayer::Solid,
+                    };
+
                     render_pass.set_pipeline(&bound_pipeline.pipeline);
 
+                    //Simplified sections have buffers of their own
+                    let lod = pipeline_config.geometry == "@geo_terrain_lod";
+                    let (terrain_draws, draw_buffers) = if lod {
+                        (lod_draws.as_ref().unwrap(), &scene.lod_draw_buffers)
+                    } else {
+                        (terrain_draws.as_ref().unwrap(), &scene.draw_buffers)
+                    };
+                    let full_detail_buffer = scene.chunk_buffer.load_full();
+                    let chunk_buffer = if lod { &scene.lod_buffer } else { &*full_detail_buffer };
+                    let section_draws = draw_buffers.section_draws.load_full();
                     for (index, bind_group) in bound_pipeline.bind_groups.iter() {
                         match bind_group {
                             WmBindGroup::Resource(name) => match &name[..] {
                                 "@bg_ssbo_chunks" => {
-                                    render_pass.set_bind_group(*index,&scene.chunk_buffer.bind_group,&[]);
+                                    render_pass.set_bind_group(*index,&chunk_buffer.bind_group,&[]);
+                                }
+                                "@bg_ssbo_sections" => {
+                                    render_pass.set_bind_group(*index,&section_draws.bind_group,&[]);
                                 }
                                 _ => unimplemented!(),
                             },
@@ -539,43 +581,19 @@ impl RenderGraph {
                         }
                     }
 
-                    render_pass.set_index_buffer(scene.chunk_buffer.buffer.slice(..),wgpu::IndexFormat::Uint32);
-
-                    let sections = scene.section_storage.write();
-                    let camera_pos = scene.camera_section_pos.read().clone();
-                    for (pos, section) in sections.iter() {
-                        let rel_pos = ivec3(pos.x-camera_pos.x, pos.y, pos.z-camera_pos.y);
-                        let a: Vec3<f32> = [rel_pos.x as f32, rel_pos.y as f32, rel_pos.z as f32].into();
-                        let b: Vec3<f32> = a + Vec3::new(1.0, 1.0, 1.0);
-
-                        let bounds: AABB<f32> = AABB::new((a*16.0).into_array(), (b*16.0).into_array());
-
-                        if !bounds.coherent_test_against_frustum(frustum, 0).0 {
-                            continue;
-                        }
-                        if let Some(layer) = &section.layers[RenderLayer::Solid as usize] {
-                            let mut pc:HashMap<String,(Vec<u8>,ShaderStages)> = HashMap::new();
-                            //println!("draw {pos}");
-                            pc.insert("@pc_section_position".to_string(),  (bytemuck::cast_slice(&rel_pos.to_array()).to_vec(),ShaderStages::VERTEX));
-                            set_push_constants(
-                                pipeline_config,
-                                &mut render_pass,
-                                Some(pc),
-                            );
-                            render_pass.draw_indexed(
-                                layer.index_range.clone(),
-                                0,
-                                layer.vertex_range.start..layer.vertex_range.start + 1
-                            );
-                        }
-                    }
+                    let quad_indices = scene.quad_indices.buffer();
+                    render_pass.set_index_buffer(quad_indices.slice(..),wgpu::IndexFormat::Uint32);
 
+                    terrain_draws.draw(wm, draw_buffers, &mut render_pass, render_layer);
 
                     for (index, bind_group) in bound_pipeline.bind_groups.iter() {
                         match bind_group {
                             WmBindGroup::Resource(name) => match &name[..] {
                                 "@bg_ssbo_chunks" => {
-                                    render_pass.set_bind_group(*index, &scene.chunk_buffer.bind_group, &[]);
+                                    render_pass.set_bind_group(*index, &chunk_buffer.bind_group, &[]);
+                                }
+                                "@bg_ssbo_sections" => {
+                                    render_pass.set_bind_group(*index, &section_draws.bind_group, &[]);
                                 }
                                 _ => unimplemented!(),
                             },
@@ -593,6 +611,17 @@ impl RenderGraph {
                     }
                 },
             }
+
+            drop(render_pass);
+            if occlusion_culling.is_some_and(|config| &config.after == pipeline_name) {
+                scene.depth_pyramid.lock().build(
+                    wm,
+                    encoder,
+                    &scene.depth_texture,
+                    view_projection,
+                    *scene.camera_section_pos.read(),
+                );
+            }
         }
     }
 }
@@ -1,7 +1,10 @@
 pub mod atlas;
+pub mod depth_pyramid;
 pub mod entity;
+pub mod gpu_mesher;
 pub mod graph;
 pub mod pipeline;
 pub mod shader;
 pub mod shaderpack;
 pub mod sky;
+pub mod terrain;
@@ -5,80 +5,50 @@ use std::collections::HashMap;
 pub const BLOCK_ATLAS: &str = "wgpu_mc:atlases/block";
 pub const ENTITY_ATLAS: &str = "wgpu_mc:atlases/entity";
 
+/// How terrain vertices are packed and unpacked in the shaders, which is put in front of the shader of every terrain
+/// pipeline and of the GPU mesher. Keep in sync with [Vertex::compressed].
+pub const TERRAIN_VERTEX_WGSL: &str = include_str!("terrain_vertex.wgsl");
+
+/// A vertex of a section's mesh, which goes in the chunk buffer as 16 bytes, see [Vertex::compressed]
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
+    /// Relative to the section's origin, from [Vertex::POSITION_OFFSET] blocks below it to as far above it
     pub position: [f32; 3],
     pub uv: [u16; 2],
-    pub normal: [f32; 3],
+    /// How many times the texture repeats along its u and v axes, more than once for quads merged by greedy meshing
+    pub repeat: [u8; 2],
+    /// 0xRRGGBB the texture gets multiplied by, for tinted faces
     pub color: u32,
-    pub uv_offset: u32,
-    pub lightmap_coords: u8,
-    pub dark: bool,
+    /// Ambient occlusion brightness, 255 is unoccluded
+    pub ao: u8,
 }
 
 impl Vertex {
     pub const VERTEX_LENGTH: usize = 16;
+    /// How far outside of a section its vertices can be, in blocks, so models can stick out of their block
+    pub const POSITION_OFFSET: f32 = 8.0;
+    /// Positions are stored in 2048ths of a block, 128ths of a texel
+    pub const POSITION_SCALE: f32 = 2048.0;
 
     pub fn compressed(self) -> [u8; Self::VERTEX_LENGTH] {
-        // XYZ: 4 bytes (1 for each axis)
-        // Normal: 3 bits
-        // Color: 3 bytes
+        // XYZ: 2 bytes each
+        // Texture repeats: 2 bytes, light goes after the vertices of the section, see crate::mc::chunk::SectionRanges
         // UV: 4 bytes
-        // Animated UV index: 10 bits
-        // XYZ add one flag: 3 bits
-        // Block light nibble: 1 byte (4 bits for block, 4 bits for sky)
-
-        // Total: 101 bits (13 bytes)
-        let mut array = [0; Self::VERTEX_LENGTH];
-
-        let x = self.position[0] * 16.0;
-        let y = self.position[1] * 16.0;
-        let z = self.position[2] * 16.0;
-
-        let x = x as u16;
-        let y = y as u16;
-        let z = z as u16;
-
-        let x_byte = x as u8;
-        let y_byte = y as u8;
-        let z_byte = z as u8;
-
-        let flag_byte = ((x == 256) as u8) | (((y == 256) as u8) << 1) | (((z == 256) as u8) << 2);
-
-        //position
-        array[0] = x_byte;
-        array[1] = y_byte;
-        array[2] = z_byte;
-
-        //color
-        array[3] = (self.color & 0xf) as u8;
-        array[4] = ((self.color >> 8) & 0xf) as u8;
-        array[5] = ((self.color >> 16) & 0xf) as u8;
-
-        //U
-        array[6] = self.uv[0].to_le_bytes()[0];
-        array[7] = self.uv[0].to_le_bytes()[1];
-        //V
-        array[8] = self.uv[1].to_le_bytes()[0];
-        array[9] = self.uv[1].to_le_bytes()[1];
-
-        let normal_bits: u8 = match self.normal {
-            [-1.0, 0.0, 0.0] => 0b00000100,
-            [1.0, 0.0, 0.0] => 0b00000000,
-            [0.0, 1.0, 0.0] => 0b00000001,
-            [0.0, -1.0, 0.0] => 0b00000101,
-            [0.0, 0.0, 1.0] => 0b00000010,
-            [0.0, 0.0, -1.0] => 0b00000110,
-            _ => unreachable!("Invalid vertex normal"),
-        };
+        // Color: 3 bytes
+        // Ambient occlusion: 1 byte
+        let [x, y, z] = self.position.map(|axis| {
+            ((axis + Self::POSITION_OFFSET) * Self::POSITION_SCALE).round().clamp(0.0, u16::MAX as f32) as u16
+        });
 
-        //UV index and normal
-        array[10] = self.uv_offset as u8;
-        array[11] = (((self.uv_offset >> 8) as u8) & 0b11) | (normal_bits << 2) | (flag_byte << 5);
-        array[12] = self.lightmap_coords;
-        array[13] = self.dark as u8;
+        let words = [
+            x as u32 | (y as u32) << 16,
+            z as u32 | (self.repeat[0] as u32) << 16 | (self.repeat[1] as u32) << 24,
+            self.uv[0] as u32 | (self.uv[1] as u32) << 16,
+            //Same as unpack4x8unorm in the shader
+            (self.color >> 16) & 0xff | (self.color & 0xff00) | (self.color & 0xff) << 16 | (self.ao as u32) << 24,
+        ];
 
-        array
+        bytemuck::cast(words.map(u32::to_le))
     }
 }
 
@@ -238,3 +208,54 @@ pub fn create_bind_group_layouts(device: &wgpu::Device) -> HashMap<String, BindG
     .into_iter()
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Vertex, TERRAIN_VERTEX_WGSL};
+
+    /// The words of a compressed vertex, as the shader reads them from the chunk buffer
+    fn words(vertex: Vertex) -> [u32; 4] {
+        bytemuck::cast::<_, [u32; 4]>(vertex.compressed()).map(u32::from_le)
+    }
+
+    /// What unpack_terrain_vertex makes of a packed position
+    fn unpack_position(words: [u32; 4]) -> [f32; 3] {
+        [words[0] & 0xffff, words[0] >> 16, words[1] & 0xffff]
+            .map(|axis| axis as f32 / Vertex::POSITION_SCALE - Vertex::POSITION_OFFSET)
+    }
+
+    #[test]
+    fn compressed_words() {
+        let vertex = Vertex {
+            position: [-8.0, 24.0, 0.5 + 1.0 / 2048.0],
+            uv: [1234, 2047],
+            repeat: [3, 1],
+            color: 0x123456,
+            ao: 200,
+        };
+        let packed = words(vertex);
+
+        //The lowest position is 0, the highest is just short of 24 blocks up
+        assert_eq!(packed[0], 65535 << 16);
+        assert_eq!(packed[1], 17409 | (3 << 16) | (1 << 24));
+        assert_eq!(packed[2], 1234 | (2047 << 16));
+        //unpack4x8unorm reads red from the lowest byte
+        assert_eq!(packed[3], 0x12 | (0x34 << 8) | (0x56 << 16) | (200 << 24));
+        assert_eq!(unpack_position(packed), [-8.0, 24.0 - 1.0 / 2048.0, 0.5 + 1.0 / 2048.0]);
+
+        //Positions between two steps round to the nearest, halves away from zero
+        let rounded = |position: f32| unpack_position(words(Vertex { position: [position; 3], ..vertex }))[0];
+        assert_eq!(rounded(1.0 + 0.4 / 2048.0), 1.0);
+        assert_eq!(rounded(1.0 + 0.5 / 2048.0), 1.0 + 1.0 / 2048.0);
+        //Out of range positions are clamped rather than wrapping around
+        assert_eq!(rounded(-9.0), -8.0);
+        assert_eq!(rounded(100.0), 24.0 - 1.0 / 2048.0);
+    }
+
+    #[test]
+    fn shader_position_constants() {
+        let constant = |name: &str, value: f32| format!("const {name}: f32 = {value:?};");
+        assert!(TERRAIN_VERTEX_WGSL.contains(&constant("POSITION_OFFSET", Vertex::POSITION_OFFSET)));
+        assert!(TERRAIN_VERTEX_WGSL.contains(&constant("POSITION_SCALE", Vertex::POSITION_SCALE)));
+    }
+}
@@ -17,10 +17,12 @@ pub struct WgslShader {
 }
 
 impl WgslShader {
+    /// Load a shader, with `prelude` put in front of its source for definitions the renderer provides
     pub fn init(
         resource: &ResourcePath,
         rp: &dyn ResourceProvider,
         device: &wgpu::Device,
+        prelude: &str,
         frag_entry: String,
         vert_entry: String,
     ) -> Option<Self> {
@@ -30,7 +32,7 @@ impl WgslShader {
 
         let module = device.create_shader_module(ShaderModuleDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::from(shader_src)),
+            source: wgpu::ShaderSource::Wgsl(Cow::from(format!("{prelude}\n{shader_src}"))),
         });
 
         Some(Self {
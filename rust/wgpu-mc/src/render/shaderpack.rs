@@ -17,6 +17,14 @@ pub struct ShaderPackConfig {
     pub support: String,
     pub resources: ResourcesConfig,
     pub pipelines: PipelinesConfig,
+    #[serde(default)]
+    pub occlusion_culling: Option<OcclusionCullingConfig>,
+    /// The render layers (`solid`, `cutout` or `transparent`) whose full block faces get merged into larger quads,
+    /// see [crate::mc::chunk::BakeSettings::greedy_meshing]. The pack's terrain shaders have to repeat textures
+    /// across those quads with `sample_tile`, or `wrap_tile` and the derivatives of the unwrapped coordinates.
+    /// Sections are never meshed by the compute shader while any layer is listed here.
+    #[serde(default)]
+    pub greedy_meshing: Vec<String>,
 }
 
 impl ShaderPackConfig {
@@ -39,6 +47,14 @@ impl ShaderPackConfig {
     }
 }
 
+/// Hides sections behind what was drawn to `@texture_depth` the frame before, see [crate::render::depth_pyramid]
+#[derive(Deserialize, Debug)]
+pub struct OcclusionCullingConfig {
+    /// The pipeline after which the depth is kept for culling. Anything drawn later, like translucent terrain,
+    /// doesn't hide sections.
+    pub after: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ResourcesConfig {
     #[serde(flatten)]
@@ -143,6 +159,10 @@ fn blend_default() -> String {
     "alpha_blending".into()
 }
 
+fn depth_write_default() -> bool {
+    true
+}
+
 #[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum BindGroupDef {
@@ -154,6 +174,10 @@ pub enum BindGroupDef {
 pub struct PipelineConfig {
     pub geometry: String,
 
+    /// Name of the shader to use, defaults to the pipeline's own name
+    #[serde(default)]
+    pub shader: Option<String>,
+
     #[serde(default)]
     pub output: Vec<String>,
 
@@ -170,6 +194,11 @@ pub struct PipelineConfig {
 
     #[serde(default = "blend_default")]
     pub blending: String,
+
+    /// Whether the pipeline writes to its `depth` texture. Translucent geometry turns this off, so what's behind it
+    /// and drawn later still gets blended instead of failing the depth test.
+    #[serde(default = "depth_write_default")]
+    pub depth_write: bool,
 }
 
 #[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
@@ -191,7 +220,7 @@ mod tests {
 
     use serde::Deserialize;
 
-    use super::ShaderPackConfig;
+    use super::{PipelineConfig, ShaderPackConfig};
 
     fn deserialize_and_print_error<'a, T: Debug + Deserialize<'a>>(input: &'a str) {
         let config: Result<T, _> = serde_yaml::from_str(input);
@@ -284,4 +313,13 @@ pipelines:
     fn complete_file() {
         deserialize_and_print_error::<ShaderPackConfig>(FULL_YAML);
     }
+
+    #[test]
+    fn depth_write() {
+        let pipeline = |extra: &str| -> PipelineConfig {
+            serde_yaml::from_str(&format!("geometry: \"@geo_terrain\"\ndepth: \"@texture_depth\"\n{extra}")).unwrap()
+        };
+        assert!(pipeline("").depth_write);
+        assert!(!pipeline("depth_write: false").depth_write);
+    }
 }
@@ -0,0 +1,613 @@
+//! Drawing the chunk sections.
+//!
+//! Every frame the visible sections of each [RenderLayer] are written into the scene's indirect buffer, and where
+//! each of them is goes into a storage buffer which the terrain shaders index with the instance index. A layer is then
+//! drawn with one `multi_draw_indexed_indirect`, or with a `draw_indexed` per section on devices which can't do that.
+//!
+//! On devices with indirect draw counts the sections are frustum culled by a compute pass instead, see
+//! [TerrainCuller], which leaves the CPU with nothing to do per section unless sections change.
+//!
+//! [RenderLayer::Transparent] is drawn farthest section first, so translucent surfaces in different sections blend
+//! over each other in the right order like the quads within a section do. The culling pass doesn't pack those draws,
+//! it leaves the ones it culls empty so the order stays.
+//!
+//! Either way sections hidden behind others are left out first, see
+//! [crate::mc::chunk::SectionStorage::visible_sections], and with shader packs which opt into occlusion culling so
+//! are those behind what was drawn before, see [crate::render::depth_pyramid].
+//!
+//! Past the view distance the simplified sections of [crate::mc::Scene::lod_storage] are drawn the same way from their
+//! own buffers, always culled on the CPU as there are few of them. Full detail sections dither out over the last two
+//! sections of the view distance while the simplified ones dither in.
+
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use bytemuck::{Pod, Zeroable};
+use glam::{ivec3, vec3, IVec3, Mat4, Vec3, Vec4};
+use treeculler::{BVol, Frustum, AABB};
+use wgpu::util::{BufferInitDescriptor, DeviceExt, DrawIndexedIndirectArgs};
+
+use crate::mc::chunk::{RenderLayer, Section};
+use crate::mc::Scene;
+use crate::render::depth_pyramid::OcclusionParams;
+use crate::util::BindableBuffer;
+use crate::WmRenderer;
+
+/// What the terrain shaders know about the section they're drawing
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct SectionDraw {
+    /// Relative to the camera's section column, in sections
+    pub pos: [i32; 3],
+    /// Where the section's vertices start in the chunk buffer, in u32s
+    pub vertex_offset: u32,
+    /// The distance from the camera over which the section dithers out, or in if the start is past the end
+    pub fade: [f32; 2],
+    /// Where the light of the section's vertices starts in the chunk buffer, in u32s
+    pub light_offset: u32,
+    pub padding: u32,
+}
+
+/// A fade which never starts
+pub const NO_FADE: [f32; 2] = [f32::MAX / 2.0, f32::MAX];
+
+/// Where full detail sections fade out, in blocks. Simplified sections fade in over the same distance, reversed.
+pub fn lod_fade(wm: &WmRenderer, scene: &Scene) -> [f32; 2] {
+    let width = scene.section_storage.read().width() as f32;
+    if wm.mc.bake_settings.read().lod_distance == 0 || width <= 0.0 {
+        return NO_FADE;
+    }
+
+    [(width - 2.0).max(0.0) * 16.0, width * 16.0]
+}
+
+/// The indices of a quad, over its 4 vertices
+pub const QUAD_INDICES: [u32; 6] = [0, 1, 2, 2, 3, 0];
+
+/// Every quad of the terrain is drawn with the same indices, so there's one index buffer shared by all sections
+/// instead of indices in the chunk buffer. Its indices start from 0 for every draw, the shaders find where the
+/// section's vertices are from its [SectionDraw].
+pub struct QuadIndices {
+    buffer: ArcSwap<wgpu::Buffer>,
+}
+
+impl QuadIndices {
+    pub fn new(wm: &WmRenderer, quads: u32) -> Self {
+        Self {
+            buffer: ArcSwap::new(Arc::new(Self::create(wm, quads))),
+        }
+    }
+
+    fn create(wm: &WmRenderer, quads: u32) -> wgpu::Buffer {
+        let indices = (0..quads)
+            .flat_map(|quad| QUAD_INDICES.map(|index| quad * 4 + index))
+            .collect::<Vec<u32>>();
+
+        wm.display.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("quad_indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsages::INDEX,
+        })
+    }
+
+    /// Grow the buffer if it doesn't have the indices of `quads` quads
+    pub fn reserve(&self, wm: &WmRenderer, quads: u32) {
+        if self.buffer.load().size() < quads as u64 * size_of::<[u32; 6]>() as u64 {
+            self.buffer.store(Arc::new(Self::create(wm, quads.next_power_of_two())));
+        }
+    }
+
+    pub fn buffer(&self) -> Arc<wgpu::Buffer> {
+        self.buffer.load_full()
+    }
+}
+
+/// The indirect draws of a frame, and what each of them is drawing
+pub struct DrawBuffers {
+    pub indirect: ArcSwap<wgpu::Buffer>,
+    /// A [SectionDraw] for each draw in `indirect`
+    pub section_draws: ArcSwap<BindableBuffer>,
+}
+
+impl DrawBuffers {
+    pub fn new(wm: &WmRenderer, draws: u64) -> Self {
+        Self {
+            indirect: ArcSwap::new(Arc::new(Self::create_indirect(wm, draws))),
+            section_draws: ArcSwap::new(Arc::new(Self::create_section_draws(wm, draws))),
+        }
+    }
+
+    fn create_indirect(wm: &WmRenderer, draws: u64) -> wgpu::Buffer {
+        wm.display.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: draws * size_of::<DrawIndexedIndirectArgs>() as u64,
+            //Written by the culling pass too
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_section_draws(wm: &WmRenderer, draws: u64) -> BindableBuffer {
+        BindableBuffer::new_deferred(
+            wm,
+            draws * size_of::<SectionDraw>() as u64,
+            wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+            "ssbo",
+        )
+    }
+
+    /// Grow the buffers if they don't fit `draws` draws
+    pub fn reserve(&self, wm: &WmRenderer, draws: usize) {
+        let capacity = (draws as u64).next_power_of_two();
+        if self.section_draws.load().size < draws as u64 * size_of::<SectionDraw>() as u64 {
+            self.indirect.store(Arc::new(Self::create_indirect(wm, capacity)));
+            self.section_draws.store(Arc::new(Self::create_section_draws(wm, capacity)));
+        }
+    }
+
+    /// Write the draws of this frame, growing the buffers if they don't fit
+    pub fn upload(&self, wm: &WmRenderer, draws: &[SectionDraw], args: &[DrawIndexedIndirectArgs]) {
+        if draws.is_empty() {
+            return;
+        }
+
+        self.reserve(wm, draws.len());
+
+        let args = args.iter().flat_map(|args| args.as_bytes()).copied().collect::<Vec<u8>>();
+        wm.display.queue.write_buffer(&self.indirect.load(), 0, &args);
+        wm.display.queue.write_buffer(&self.section_draws.load().buffer, 0, bytemuck::cast_slice(draws));
+    }
+}
+
+/// The planes of the frustum of a view projection matrix with wgpu's 0 to 1 depth range, as a normal pointing inwards
+/// and a distance
+pub fn frustum_planes(view_projection: Mat4) -> [Vec4; 6] {
+    let row = |index| view_projection.row(index);
+
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(2),
+        row(3) - row(2),
+    ]
+}
+
+/// Order sections, relative to the camera's section column, from farthest to nearest to `camera`, for drawing their
+/// translucent quads over each other
+fn back_to_front<T>(sections: &mut [(IVec3, T)], camera: Vec3) {
+    let distance = |rel_pos: IVec3| (rel_pos.as_vec3() * 16.0 + 8.0).distance_squared(camera);
+    sections.sort_by(|(a, _), (b, _)| distance(*b).total_cmp(&distance(*a)));
+}
+
+/// Whether a section, relative to the camera's section column, is inside the frustum
+fn in_frustum(frustum: &Frustum<f32>, rel_pos: IVec3) -> bool {
+    let min = rel_pos.as_vec3() * 16.0;
+    AABB::new(min.to_array(), (min + 16.0).to_array())
+        .coherent_test_against_frustum(frustum, 0)
+        .0
+}
+
+/// A section layer which might be drawn, the input of the culling shader
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct CullCandidate {
+    /// In world section coordinates
+    pos: [i32; 3],
+    vertex_offset: u32,
+    index_count: u32,
+    layer: u32,
+    light_offset: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct CullParams {
+    planes: [[f32; 4]; 6],
+    camera_section: [i32; 2],
+    candidates: u32,
+    /// The layer whose draws stay in the order of the candidates, culled ones being left empty
+    ordered_layer: u32,
+    /// Where the draws of each layer start
+    layer_starts: [u32; 4],
+    fade: [f32; 2],
+    fade_padding: [f32; 2],
+    occlusion: OcclusionParams,
+}
+
+/// Frustum culls the sections in a compute pass, which writes the visible ones as packed indirect draws and counts
+/// how many each layer has. The candidates are only uploaded again when the sections change, or the camera moves into
+/// another section and can see different ones.
+pub struct TerrainCuller {
+    pipeline: wgpu::ComputePipeline,
+    params: wgpu::Buffer,
+    candidates: wgpu::Buffer,
+    draw_counts: Arc<wgpu::Buffer>,
+    /// The [crate::mc::chunk::SectionStorage::version] and camera section the candidates were made from
+    candidates_version: Option<(u64, IVec3)>,
+    candidate_count: u32,
+    /// Which candidates, and which draws, belong to each [RenderLayer]
+    layers: [Range<u32>; RenderLayer::ALL.len()],
+}
+
+impl TerrainCuller {
+    pub fn new(wm: &WmRenderer) -> Self {
+        let device = &wm.display.device;
+
+        let module = device.create_shader_module(wgpu::include_wgsl!("terrain_cull.wgsl"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("terrain_cull"),
+            layout: None,
+            module: &module,
+            entry_point: "cull",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            params: device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: size_of::<CullParams>() as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            candidates: Self::create_candidates(wm, 10000),
+            draw_counts: Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: 16,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })),
+            candidates_version: None,
+            candidate_count: 0,
+            layers: Default::default(),
+        }
+    }
+
+    /// Whether the device can draw what the culling pass writes
+    pub fn is_supported(wm: &WmRenderer) -> bool {
+        wm.display.device.features().contains(
+            wgpu::Features::MULTI_DRAW_INDIRECT
+                | wgpu::Features::INDIRECT_FIRST_INSTANCE
+                | wgpu::Features::MULTI_DRAW_INDIRECT_COUNT,
+        )
+    }
+
+    fn create_candidates(wm: &WmRenderer, candidates: u64) -> wgpu::Buffer {
+        wm.display.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: candidates * size_of::<CullCandidate>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn update_candidates(&mut self, wm: &WmRenderer, scene: &Scene) {
+        let sections = scene.section_storage.read();
+        let camera_section = scene.camera_section();
+        if self.candidates_version == Some((sections.version(), camera_section)) {
+            return;
+        }
+
+        let visible = sections
+            .visible_sections(camera_section, *wm.mc.world_height.read(), |_| true)
+            .into_iter()
+            .filter_map(|pos| Some((pos, sections.get(&pos)?)))
+            .collect::<Vec<_>>();
+        //The candidates only change when the camera moves into another section, so translucent ones are sorted from
+        //the middle of that
+        let mut sorted = visible
+            .iter()
+            .map(|(pos, section)| (ivec3(pos.x - camera_section.x, pos.y, pos.z - camera_section.z), (*pos, *section)))
+            .collect::<Vec<_>>();
+        back_to_front(&mut sorted, vec3(8.0, camera_section.y as f32 * 16.0 + 8.0, 8.0));
+        let sorted = sorted.into_iter().map(|(_, section)| section).collect::<Vec<_>>();
+
+        let mut candidates = vec![];
+        self.layers = RenderLayer::ALL.map(|layer| {
+            let start = candidates.len() as u32;
+            let sections = if layer == RenderLayer::Transparent { &sorted } else { &visible };
+            for (pos, section) in sections {
+                let Some(Some(ranges)) = section.layers.get(layer as usize) else {
+                    continue;
+                };
+
+                candidates.push(CullCandidate {
+                    pos: pos.to_array(),
+                    vertex_offset: ranges.vertex_range.start,
+                    index_count: ranges.quads() * 6,
+                    layer: layer as u32,
+                    light_offset: ranges.light_range.start,
+                    padding: 0,
+                });
+            }
+            start..candidates.len() as u32
+        });
+
+        if self.candidates.size() < (candidates.len() * size_of::<CullCandidate>()) as u64 {
+            self.candidates = Self::create_candidates(wm, (candidates.len() as u64).next_power_of_two());
+        }
+        wm.display.queue.write_buffer(&self.candidates, 0, bytemuck::cast_slice(&candidates));
+        scene.draw_buffers.reserve(wm, candidates.len());
+
+        self.candidates_version = Some((sections.version(), camera_section));
+        self.candidate_count = candidates.len() as u32;
+    }
+
+    /// Record the culling pass, which also tests against the [crate::render::depth_pyramid] from the frame before with
+    /// `occlusion_culling`. Returns which draws belong to each [RenderLayer], of which the first
+    /// [Self::draw_counts] are the visible ones.
+    pub fn cull(
+        &mut self,
+        wm: &WmRenderer,
+        scene: &Scene,
+        encoder: &mut wgpu::CommandEncoder,
+        view_projection: Mat4,
+        occlusion_culling: bool,
+    ) -> [Range<u32>; RenderLayer::ALL.len()] {
+        self.update_candidates(wm, scene);
+        if self.candidate_count == 0 {
+            return self.layers.clone();
+        }
+
+        let depth_pyramid = scene.depth_pyramid.lock();
+        let params = CullParams {
+            planes: frustum_planes(view_projection).map(|plane| plane.to_array()),
+            camera_section: scene.camera_section_pos.read().to_array(),
+            candidates: self.candidate_count,
+            ordered_layer: RenderLayer::Transparent as u32,
+            layer_starts: [self.layers[0].start, self.layers[1].start, self.layers[2].start, 0],
+            fade: lod_fade(wm, scene),
+            fade_padding: [0.0; 2],
+            occlusion: if occlusion_culling {
+                depth_pyramid.params()
+            } else {
+                OcclusionParams::DISABLED
+            },
+        };
+        wm.display.queue.write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+        encoder.clear_buffer(&self.draw_counts, 0, None);
+
+        let indirect_buffer = scene.draw_buffers.indirect.load();
+        let section_draws = scene.draw_buffers.section_draws.load();
+        let bind_group = wm.display.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.candidates.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: indirect_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: section_draws.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.draw_counts.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(depth_pyramid.view()),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("terrain_cull"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(self.candidate_count.div_ceil(64), 1, 1);
+
+        self.layers.clone()
+    }
+
+    /// How many of each layer's draws are visible, as a u32 per [RenderLayer]
+    pub fn draw_counts(&self) -> &Arc<wgpu::Buffer> {
+        &self.draw_counts
+    }
+}
+
+/// The draws of the sections visible this frame
+pub enum TerrainDraws {
+    /// Culled by the [TerrainCuller], the draw counts are on the GPU
+    Gpu {
+        layers: [Range<u32>; RenderLayer::ALL.len()],
+        draw_counts: Arc<wgpu::Buffer>,
+    },
+    /// Culled on the CPU
+    Cpu {
+        layers: [Range<u32>; RenderLayer::ALL.len()],
+        args: Vec<DrawIndexedIndirectArgs>,
+    },
+}
+
+impl TerrainDraws {
+    /// Find the sections in view of `view_projection`, which is relative to the camera's section column, and get
+    /// their draws ready. Any culling pass is recorded into `encoder`, with `occlusion_culling` if the shader pack
+    /// builds a [crate::render::depth_pyramid].
+    pub fn new(
+        wm: &WmRenderer,
+        scene: &Scene,
+        encoder: &mut wgpu::CommandEncoder,
+        view_projection: Mat4,
+        occlusion_culling: bool,
+    ) -> Self {
+        if TerrainCuller::is_supported(wm) {
+            let mut culler = scene.terrain_culler.lock();
+            return Self::Gpu {
+                layers: culler.cull(wm, scene, encoder, view_projection, occlusion_culling),
+                draw_counts: culler.draw_counts().clone(),
+            };
+        }
+
+        let frustum = Frustum::from_modelview_projection(view_projection.to_cols_array_2d());
+        let camera_pos = *scene.camera_section_pos.read();
+        let rel_pos = |pos: IVec3| ivec3(pos.x - camera_pos.x, pos.y, pos.z - camera_pos.y);
+        let cpu_depth = occlusion_culling
+            .then(|| {
+                let mut depth_pyramid = scene.depth_pyramid.lock();
+                depth_pyramid.poll_readback();
+                depth_pyramid.cpu_depth()
+            })
+            .flatten();
+        let sections = scene.section_storage.read();
+
+        //Occluded sections are left out after the walk like the culling shader does, what's behind them can still
+        //be seen around them
+        let visible = sections
+            .visible_sections(scene.camera_section(), *wm.mc.world_height.read(), |pos| {
+                in_frustum(&frustum, rel_pos(pos))
+            })
+            .into_iter()
+            .filter(|pos| !cpu_depth.as_ref().is_some_and(|depth| depth.section_occluded(*pos)))
+            .filter_map(|pos| Some((rel_pos(pos), sections.get(&pos)?)))
+            .collect::<Vec<_>>();
+
+        Self::upload_cpu(wm, &scene.draw_buffers, &visible, *scene.camera_pos.read(), lod_fade(wm, scene))
+    }
+
+    /// The simplified sections in view of `view_projection` which reach past where full detail sections start fading
+    /// out. They're never hidden behind others, so there's no walk through them.
+    pub fn lod(wm: &WmRenderer, scene: &Scene, view_projection: Mat4) -> Self {
+        let [fade_start, fade_end] = lod_fade(wm, scene);
+        let frustum = Frustum::from_modelview_projection(view_projection.to_cols_array_2d());
+        let camera_pos = *scene.camera_section_pos.read();
+        let camera = *scene.camera_pos.read();
+        let sections = scene.lod_storage.read();
+
+        let visible = sections
+            .iter()
+            .map(|(pos, section)| (ivec3(pos.x - camera_pos.x, pos.y, pos.z - camera_pos.y), section))
+            .filter(|(rel_pos, _)| {
+                let min = rel_pos.as_vec3() * 16.0;
+                let furthest = (min - camera).abs().max((min + 16.0 - camera).abs());
+                furthest.length() >= fade_start && in_frustum(&frustum, *rel_pos)
+            })
+            .collect::<Vec<_>>();
+
+        Self::upload_cpu(wm, &scene.lod_draw_buffers, &visible, camera, [fade_end, fade_start])
+    }
+
+    /// Write the draws of every layer of `sections`, which are relative to the camera's section column like `camera`
+    fn upload_cpu(
+        wm: &WmRenderer,
+        buffers: &DrawBuffers,
+        sections: &[(IVec3, &Section)],
+        camera: Vec3,
+        fade: [f32; 2],
+    ) -> Self {
+        let mut sorted = sections.to_vec();
+        back_to_front(&mut sorted, camera);
+
+        let mut draws = vec![];
+        let mut args = vec![];
+        let layers = RenderLayer::ALL.map(|layer| {
+            let start = args.len() as u32;
+            let sections = if layer == RenderLayer::Transparent { &sorted[..] } else { sections };
+            for (rel_pos, section) in sections {
+                let Some(Some(ranges)) = section.layers.get(layer as usize) else {
+                    continue;
+                };
+
+                args.push(DrawIndexedIndirectArgs {
+                    index_count: ranges.quads() * 6,
+                    instance_count: 1,
+                    first_index: 0,
+                    base_vertex: 0,
+                    //The shaders find the section's draw from this
+                    first_instance: draws.len() as u32,
+                });
+                draws.push(SectionDraw {
+                    pos: rel_pos.to_array(),
+                    vertex_offset: ranges.vertex_range.start,
+                    fade,
+                    light_offset: ranges.light_range.start,
+                    padding: 0,
+                });
+            }
+            start..args.len() as u32
+        });
+
+        buffers.upload(wm, &draws, &args);
+
+        Self::Cpu { layers, args }
+    }
+
+    /// Draw the sections of a layer. The terrain pipeline and its bind groups have to be set already.
+    pub fn draw(&self, wm: &WmRenderer, buffers: &DrawBuffers, render_pass: &mut wgpu::RenderPass, layer: RenderLayer) {
+        let args_size = size_of::<DrawIndexedIndirectArgs>() as u64;
+
+        match self {
+            Self::Gpu { layers, draw_counts } => {
+                let range = layers[layer as usize].clone();
+                if range.is_empty() {
+                    return;
+                }
+
+                render_pass.multi_draw_indexed_indirect_count(
+                    &buffers.indirect.load(),
+                    range.start as u64 * args_size,
+                    draw_counts,
+                    layer as u64 * 4,
+                    range.end - range.start,
+                );
+            }
+            Self::Cpu { layers, args } => {
+                let range = layers[layer as usize].clone();
+                if range.is_empty() {
+                    return;
+                }
+
+                //Without a first instance in indirect draws the shaders can't tell which section they're drawing
+                let indirect = wgpu::Features::MULTI_DRAW_INDIRECT | wgpu::Features::INDIRECT_FIRST_INSTANCE;
+                if wm.display.device.features().contains(indirect) {
+                    render_pass.multi_draw_indexed_indirect(
+                        &buffers.indirect.load(),
+                        range.start as u64 * args_size,
+                        range.end - range.start,
+                    );
+                } else {
+                    for args in &args[range.start as usize..range.end as usize] {
+                        render_pass.draw_indexed(
+                            args.first_index..args.first_index + args.index_count,
+                            args.base_vertex,
+                            args.first_instance..args.first_instance + 1,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{ivec3, vec3, IVec3};
+
+    use super::back_to_front;
+
+    #[test]
+    fn farthest_sections_first() {
+        let mut sections = [ivec3(0, 0, 0), ivec3(0, 0, 1), ivec3(-3, 0, 0), ivec3(0, 1, 0), ivec3(2, 4, 0)]
+            .map(|pos| (pos, ()));
+        back_to_front(&mut sections, vec3(8.0, 20.0, 8.0));
+        let order: Vec<IVec3> = sections.iter().map(|(pos, _)| *pos).collect();
+        assert_eq!(order, [ivec3(2, 4, 0), ivec3(-3, 0, 0), ivec3(0, 0, 1), ivec3(0, 0, 0), ivec3(0, 1, 0)]);
+    }
+}
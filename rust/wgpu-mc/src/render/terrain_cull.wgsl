@@ -0,0 +1,144 @@
+// Frustum culls the section draws, and occlusion culls them against last frame's depth pyramid, then packs the
+// visible ones together, one range of draws per render layer. The draws of the ordered layer aren't packed, which
+// thread gets to write first changes every frame. They stay where the CPU sorted them and culled ones draw nothing.
+// See src/render/terrain.rs and src/render/depth_pyramid.rs
+
+struct Candidate {
+    pos: vec3<i32>,
+    vertex_offset: u32,
+    index_count: u32,
+    layer: u32,
+    light_offset: u32,
+    padding: u32
+}
+
+struct OcclusionParams {
+    view_projection: mat4x4<f32>,
+    camera_section: vec2<i32>,
+    enabled: u32,
+    padding: u32
+}
+
+struct CullParams {
+    planes: array<vec4<f32>, 6>,
+    camera_section: vec2<i32>,
+    candidates: u32,
+    ordered_layer: u32,
+    layer_starts: vec4<u32>,
+    // Where full detail sections fade out
+    fade: vec2<f32>,
+    fade_padding: vec2<f32>,
+    occlusion: OcclusionParams
+}
+
+struct SectionDraw {
+    pos: vec3<i32>,
+    vertex_offset: u32,
+    fade: vec2<f32>,
+    light_offset: u32,
+    padding: u32
+}
+
+@group(0) @binding(0) var<uniform> params: CullParams;
+@group(0) @binding(1) var<storage, read> candidates: array<Candidate>;
+// DrawIndexedIndirectArgs, 5 u32s each
+@group(0) @binding(2) var<storage, read_write> draw_args: array<u32>;
+@group(0) @binding(3) var<storage, read_write> section_draws: array<SectionDraw>;
+@group(0) @binding(4) var<storage, read_write> draw_counts: array<atomic<u32>, 4>;
+// The furthest depth under each texel, at every level
+@group(0) @binding(5) var depth_pyramid: texture_2d<f32>;
+
+// Whether a section was hidden behind what was drawn last frame, `min` being its corner relative to the camera's
+// section column back then
+fn occluded(min: vec3<f32>) -> bool {
+    let max = min + 16.0;
+
+    // Off screen corners can be anywhere, so these start out past both ends
+    var uv_min = vec2(3.4e38);
+    var uv_max = vec2(-3.4e38);
+    var nearest = 1.0;
+    for(var i = 0u; i < 8u; i++) {
+        let corner = select(min, max, vec3((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u));
+        let clip = params.occlusion.view_projection * vec4(corner, 1.0);
+        // Part of the section was behind the camera
+        if(clip.w <= 0.0) {
+            return false;
+        }
+
+        let ndc = clip.xyz / clip.w;
+        let uv = vec2(ndc.x, -ndc.y) * 0.5 + 0.5;
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        nearest = min(nearest, ndc.z);
+    }
+
+    // Nothing is known about what was off screen
+    if(any(uv_max < vec2(0.0)) || any(uv_min > vec2(1.0))) {
+        return false;
+    }
+    uv_min = saturate(uv_min);
+    uv_max = saturate(uv_max);
+
+    // The level at which the section covers no more than 2x2 texels
+    let extent = (uv_max - uv_min) * vec2<f32>(textureDimensions(depth_pyramid));
+    let level = u32(clamp(ceil(log2(max(extent.x, extent.y))), 0.0, f32(textureNumLevels(depth_pyramid) - 1u)));
+    let size = textureDimensions(depth_pyramid, level);
+    let texel_min = min(vec2<u32>(uv_min * vec2<f32>(size)), size - 1u);
+    let texel_max = min(vec2<u32>(uv_max * vec2<f32>(size)), size - 1u);
+
+    var furthest = 0.0;
+    for(var y = texel_min.y; y <= texel_max.y; y++) {
+        for(var x = texel_min.x; x <= texel_max.x; x++) {
+            furthest = max(furthest, textureLoad(depth_pyramid, vec2(x, y), i32(level)).r);
+        }
+    }
+
+    return nearest > furthest;
+}
+
+@compute @workgroup_size(64)
+fn cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    if(id.x >= params.candidates) {
+        return;
+    }
+
+    let candidate = candidates[id.x];
+    let rel_pos = vec3<i32>(candidate.pos.x - params.camera_section.x, candidate.pos.y, candidate.pos.z - params.camera_section.y);
+    let min = vec3<f32>(rel_pos) * 16.0;
+    let max = min + 16.0;
+
+    var visible = true;
+    for(var i = 0u; i < 6u; i++) {
+        let plane = params.planes[i];
+        // The corner furthest along the plane's normal
+        let corner = select(min, max, plane.xyz >= vec3(0.0));
+        if(dot(plane.xyz, corner) + plane.w < 0.0) {
+            visible = false;
+        }
+    }
+
+    if(visible && params.occlusion.enabled != 0u) {
+        let occlusion_pos = vec3<i32>(candidate.pos.x - params.occlusion.camera_section.x, candidate.pos.y, candidate.pos.z - params.occlusion.camera_section.y);
+        visible = !occluded(vec3<f32>(occlusion_pos) * 16.0);
+    }
+
+    var draw: u32;
+    if(candidate.layer == params.ordered_layer) {
+        // Candidates and draws of a layer start at the same place
+        draw = id.x;
+        atomicMax(&draw_counts[candidate.layer], id.x - params.layer_starts[candidate.layer] + 1u);
+    } else if(visible) {
+        draw = params.layer_starts[candidate.layer] + atomicAdd(&draw_counts[candidate.layer], 1u);
+    } else {
+        return;
+    }
+
+    draw_args[draw * 5u] = select(0u, candidate.index_count, visible);
+    draw_args[draw * 5u + 1u] = 1u;
+    // Every section's indices are the shared quad indices, from the start
+    draw_args[draw * 5u + 2u] = 0u;
+    draw_args[draw * 5u + 3u] = 0u;
+    draw_args[draw * 5u + 4u] = draw;
+
+    section_draws[draw] = SectionDraw(rel_pos, candidate.vertex_offset, params.fade, candidate.light_offset, 0u);
+}
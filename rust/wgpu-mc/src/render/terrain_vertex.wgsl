@@ -0,0 +1,89 @@
+// How terrain vertices are packed in the chunk buffer, 4 u32s each:
+//
+// 0: x | y << 16
+// 1: z | u repeats << 16 | v repeats << 24
+// 2: u | v << 16
+// 3: r | g << 8 | b << 16 | ambient occlusion << 24
+//
+// Positions are in 2048ths of a block from 8 blocks below the section's origin and texture coordinates in 2048ths
+// of the atlas. Quads merged by greedy meshing repeat their texture, every other quad has 1 repeat each way. The light of a layer's vertices comes after them, a u16 each of block light | sky light << 8 in
+// sixteenths of a light level, so it can be updated without the rest. Keep in sync with Vertex::compressed in
+// src/render/pipeline.rs, this gets put in front of the shader of every terrain pipeline and of gpu_mesher.wgsl.
+
+// Vertex::POSITION_OFFSET and Vertex::POSITION_SCALE
+const POSITION_OFFSET: f32 = 8.0;
+const POSITION_SCALE: f32 = 2048.0;
+
+struct TerrainVertex {
+    // Relative to the section's origin
+    position: vec3<f32>,
+    tex_coords: vec2<f32>,
+    color: vec3<f32>,
+    // 1 is unoccluded
+    ao: f32,
+    // Block and sky light, from 0 to 1
+    light: vec2<f32>,
+    // The texture's tile in the atlas, xy being its corner and zw its size. All 0 when the texture doesn't repeat.
+    tile: vec4<f32>,
+    // Where the vertex is in tiles of the texture, or its texture coordinates if the texture doesn't repeat. Goes
+    // through sample_tile to be sampled, or wrap_tile to get the texture coordinates.
+    tile_coords: vec2<f32>
+}
+
+// The first two words of a vertex, from its position relative to the section's origin and how many times its texture
+// repeats along u and v. Rounds half away from zero like Vertex::compressed, the packed positions are never negative.
+fn pack_terrain_position(position: vec3<f32>, repeat: vec2<u32>) -> vec2<u32> {
+    let packed = vec3<u32>(clamp(floor((position + POSITION_OFFSET) * POSITION_SCALE + 0.5), vec3(0.0), vec3(65535.0)));
+    return vec2(packed.x | (packed.y << 16u), packed.z | (repeat.x << 16u) | (repeat.y << 24u));
+}
+
+// The last word of a vertex, from its color channels and ambient occlusion, 0 to 255 each
+fn pack_terrain_color(rgb: vec3<u32>, ao: u32) -> u32 {
+    return rgb.r | (rgb.g << 8u) | (rgb.b << 16u) | (ao << 24u);
+}
+
+// `light` is the vertex's u16 from the light after the vertices, in the low bits. `opposite` is the texture
+// coordinates of the vertex across the quad, packed the same way, which the tile is found from.
+fn unpack_terrain_vertex(packed: vec4<u32>, light: u32, opposite: u32) -> TerrainVertex {
+    var vertex: TerrainVertex;
+
+    let position = vec3(packed.x & 0xffffu, packed.x >> 16u, packed.y & 0xffffu);
+    vertex.position = vec3<f32>(position) / POSITION_SCALE - POSITION_OFFSET;
+    vertex.tex_coords = vec2<f32>(vec2(packed.z & 0xffffu, packed.z >> 16u)) / 2048.0;
+    vertex.light = vec2<f32>(vec2(light & 0xffu, (light >> 8u) & 0xffu)) / 240.0;
+
+    let color = unpack4x8unorm(packed.w);
+    vertex.color = color.rgb;
+    vertex.ao = color.a;
+
+    let repeat = vec2<f32>(vec2((packed.y >> 16u) & 0xffu, packed.y >> 24u));
+    if(all(repeat == vec2(1.0))) {
+        vertex.tile = vec4(0.0);
+        vertex.tile_coords = vertex.tex_coords;
+    } else {
+        // Merged quads always cover the whole tile, with a corner of it on each corner of the quad
+        let opposite_coords = vec2<f32>(vec2(opposite & 0xffffu, opposite >> 16u)) / 2048.0;
+        let tile_min = min(vertex.tex_coords, opposite_coords);
+        let tile_size = abs(vertex.tex_coords - opposite_coords);
+        vertex.tile = vec4(tile_min, tile_size);
+        vertex.tile_coords = (vertex.tex_coords - tile_min) / tile_size * repeat;
+    }
+
+    return vertex;
+}
+
+// The texture coordinates to sample from the interpolated tile_coords and tile of a vertex
+fn wrap_tile(tile_coords: vec2<f32>, tile: vec4<f32>) -> vec2<f32> {
+    if(all(tile.zw == vec2(0.0))) {
+        return tile_coords;
+    }
+    return tile.xy + fract(tile_coords) * tile.zw;
+}
+
+// Sample a texture at the interpolated tile_coords and tile of a vertex. The mip level comes from the derivatives of
+// the coordinates before they're wrapped, those after jump back across the tile at its edges and would pick the
+// smallest mip level along every seam.
+fn sample_tile(tex: texture_2d<f32>, tex_sampler: sampler, tile_coords: vec2<f32>, tile: vec4<f32>) -> vec4<f32> {
+    let unwrapped = select(tile.xy + tile_coords * tile.zw, tile_coords, all(tile.zw == vec2(0.0)));
+    return textureSampleGrad(tex, tex_sampler, wrap_tile(tile_coords, tile), dpdx(unwrapped), dpdy(unwrapped));
+}